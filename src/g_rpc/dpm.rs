@@ -9,6 +9,7 @@ use tokio::time::{Duration, timeout};
 use tonic::transport::{Channel, Endpoint, Error};
 use tracing::{error, info, instrument, warn};
 
+#[derive(Clone)]
 pub struct Connection(DaqClient<Channel>);
 
 type TonicStreamResult<T> =
@@ -87,16 +88,26 @@ pub async fn set_device(
     conn: &Connection, session_id: Option<String>, device: String,
     value: device::Value,
 ) -> _TonicQueryResult<Vec<i32>> {
-    use tonic::{IntoRequest, metadata::MetadataValue};
+    set_devices(conn, session_id, vec![(device, value)]).await
+}
+
+// Sends several settings in one gRPC transaction. The returned vector
+// holds the status of each setting, in the same order as `settings`.
 
-    // Build the setting request. This function only sets one device, so the
-    // request only has a 1-element array containing the setting.
+pub async fn set_devices(
+    conn: &Connection, session_id: Option<String>,
+    settings: Vec<(String, device::Value)>,
+) -> _TonicQueryResult<Vec<i32>> {
+    use tonic::{IntoRequest, metadata::MetadataValue};
 
     let mut req = SettingList {
-        setting: vec![Setting {
-            device,
-            value: Some(value),
-        }],
+        setting: settings
+            .into_iter()
+            .map(|(device, value)| Setting {
+                device,
+                value: Some(value),
+            })
+            .collect(),
     }
     .into_request();
 
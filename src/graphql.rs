@@ -5,16 +5,18 @@
 
 use crate::g_rpc::dpm::build_connection;
 use async_graphql::{
-    EmptyMutation, EmptySubscription, ObjectType, Schema, SubscriptionType,
+    Data, EmptyMutation, EmptySubscription, ObjectType, Schema,
+    SubscriptionType, http::ALL_WEBSOCKET_PROTOCOLS,
 };
 use async_graphql_axum::{
-    GraphQLRequest, GraphQLResponse, GraphQLSubscription,
+    GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLSubscription,
+    GraphQLWebSocket,
 };
 use axum::{
     Router,
-    extract::State,
+    extract::{State, WebSocketUpgrade},
     http::header::{AUTHORIZATION, HeaderMap},
-    response::Html,
+    response::{Html, Response},
     routing::get,
 };
 use http::{Method, header};
@@ -27,10 +29,12 @@ use types::AuthInfo;
 
 mod acsys;
 mod alarms;
+mod auth;
 mod bbm;
 mod devdb;
 mod faas;
 mod scanner;
+mod status;
 mod tlg;
 mod types;
 
@@ -57,6 +61,45 @@ where
     schema.execute(request).await.into()
 }
 
+// Generic function which serves GraphQL subscriptions and adds
+// `AuthInfo` to their context. Browsers can't add headers to a
+// websocket request, so, if the upgrade request doesn't have an
+// `Authorization` header, the `Authorization` field of the client's
+// `connection_init` payload is used.
+
+async fn graphql_ws_handler<Q, M, S>(
+    State(schema): State<Schema<Q, M, S>>, headers: HeaderMap,
+    protocol: GraphQLProtocol, upgrade: WebSocketUpgrade,
+) -> Response
+where
+    Q: ObjectType + Send + Sync + 'static,
+    M: ObjectType + Send + Sync + 'static,
+    S: SubscriptionType + Send + Sync + 'static,
+{
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .on_connection_init(|payload| async move {
+                    let mut data = Data::default();
+
+                    data.insert(AuthInfo::new(header.or_else(|| {
+                        payload
+                            .get("Authorization")
+                            .and_then(|v| v.as_str())
+                            .map(String::from)
+                    })));
+                    Ok(data)
+                })
+                .serve()
+        })
+}
+
 // Returns an HTML document that has links to the various GraphQL APIs.
 
 async fn base_page() -> Html<&'static str> {
@@ -86,17 +129,35 @@ async fn base_page() -> Html<&'static str> {
 async fn create_acsys_router() -> Router {
     const Q_ENDPOINT: &str = "/acsys";
     const S_ENDPOINT: &str = "/acsys/s";
+    const E_ENDPOINT: &str = "/acsys/export";
+
+    // The archive services are shared by the schema and the export
+    // endpoint.
+
+    let conn = build_connection()
+        .await
+        .expect("couldn't make connection to DPM");
+    let cache = acsys::archivecache::ArchiveCache::from_env();
+    let archivers = acsys::archiver::Archivers::from_env();
+    let sources = acsys::archive::ArchiveSources::new(
+        conn.clone(),
+        cache.clone(),
+        archivers.clone(),
+    );
 
     let schema = Schema::build(
         acsys::ACSysQueries,
         acsys::ACSysMutations,
         acsys::ACSysSubscriptions,
     )
-    .data(
-        build_connection()
-            .await
-            .expect("couldn't make connection to DPM"),
-    )
+    .data(conn)
+    .data(acsys::knob::KnobSessions::default())
+    .data(acsys::livemux::LiveMux::default())
+    .data(acsys::userconfig::UserConfigs::from_env())
+    .data(acsys::snapshot::Snapshots::from_env())
+    .data(cache)
+    .data(archivers)
+    .data(acsys::LiveLimits::from_env())
     .finish();
 
     let graphiql = axum::response::Html(
@@ -113,7 +174,8 @@ async fn create_acsys_router() -> Router {
                 .post(graphql_handler)
                 .with_state(schema.clone()),
         )
-        .route_service(S_ENDPOINT, GraphQLSubscription::new(schema))
+        .route(S_ENDPOINT, get(graphql_ws_handler).with_state(schema))
+        .route(E_ENDPOINT, get(acsys::export::handler).with_state(sources))
 }
 
 fn create_alarms_router() -> Router {
@@ -1,29 +1,50 @@
 use crate::g_rpc::{
     devdb, dpm,
+    proto::common::device,
     proto::services::daq::{self, reading_reply},
     proto::services::devdb::{PlotConfigResult, plot_config_result},
 };
 
 use async_graphql::*;
-use futures::future::{self, Either};
+use futures::future;
 use futures_util::{Stream, StreamExt, stream};
-use serde::{Deserialize, Deserializer};
-use std::{collections::HashSet, pin::Pin, sync::Arc};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
-use tokio_util::io::StreamReader;
+use std::{pin::Pin, sync::Arc, time::Duration};
 use tonic::Status;
 use tracing::{error, info, instrument, warn};
 
 // Pull in global types.
 
+use super::status;
 use super::types as global;
 
 // Pull in our local types.
 
+pub mod archive;
+pub mod archivecache;
+pub mod archiver;
+mod basicstatus;
 mod datastream;
+mod drf;
+pub mod export;
+mod instant;
+pub mod knob;
+pub mod livemux;
+mod paging;
+mod settings;
+pub mod snapshot;
+mod statistics;
+pub mod store;
+mod triggered;
 pub mod types;
+pub mod userconfig;
+
+// The limits on the live data a request queues while its archived data
+// is returned. The configured limits are added to the schema's data.
+
+pub use datastream::Limits as LiveLimits;
 
 use crate::g_rpc::dpm::Connection;
+use crate::graphql::devdb::{cache, types::DigStatus};
 
 // Useful function to return the current time as a floating point
 // number.
@@ -36,6 +57,56 @@ fn now() -> f64 {
         / 1_000_000.0
 }
 
+// Returns the account of the client making a request. The client's
+// token is checked by the authentication service, so the account can
+// be used to grant permissions. Requests without verifiable
+// credentials are rejected.
+
+async fn verified_account(ctxt: &Context<'_>) -> Result<String> {
+    match ctxt.data::<global::AuthInfo>() {
+        Ok(auth) => auth.account().await.map_err(|e| {
+            warn!("couldn't verify account -- {}", &e);
+            Error::new(e)
+        }),
+        Err(_) => Err(Error::new("no user credentials provided")),
+    }
+}
+
+// Builds the status used in place of data that DPM couldn't provide,
+// or that couldn't be converted. The message explains the problem.
+
+fn failed(message: String) -> global::DataType {
+    global::DataType::StatusReply(global::StatusReply {
+        status: -1,
+        message: Some(message),
+    })
+}
+
+// Converts one reading from DPM. If the reading doesn't have a
+// timestamp or data, or its data has a type this service doesn't
+// support, a status is returned in its place.
+
+fn to_data_info(
+    timestamp: Option<f64>, data: Option<device::Value>,
+) -> global::DataInfo {
+    let result = match data.map(global::DataType::try_from) {
+        _ if timestamp.is_none() => {
+            failed("DPM sent a reading without a timestamp".into())
+        }
+        Some(Ok(result)) => result,
+        Some(Err(e)) => failed(format!("DPM sent unsupported data -- {}", e)),
+        None => failed("DPM sent a reading without data".into()),
+    };
+
+    if let global::DataType::StatusReply(ref status) = result {
+        warn!("bad reading -- {}", status);
+    }
+    global::DataInfo {
+        timestamp: timestamp.unwrap_or_else(now),
+        result,
+    }
+}
+
 // Converts a gRPC proto::ReadingReply structure into a GraphQL
 // global::DataReply object.
 
@@ -46,14 +117,13 @@ fn reading_to_reply(rdg: daq::ReadingReply) -> global::DataReply {
             data: rdgs
                 .reading
                 .into_iter()
-                .map(|v| global::DataInfo {
-                    timestamp: v
-                        .timestamp
-                        .map(|v| {
+                .map(|v| {
+                    to_data_info(
+                        v.timestamp.map(|v| {
                             v.seconds as f64 + v.nanos as f64 / 1_000_000_000.0
-                        })
-                        .unwrap(),
-                    result: v.data.map(|v| v.try_into()).unwrap().unwrap(),
+                        }),
+                        v.data,
+                    )
                 })
                 .collect(),
         },
@@ -64,10 +134,17 @@ fn reading_to_reply(rdg: daq::ReadingReply) -> global::DataReply {
                 result: global::DataType::StatusReply(global::StatusReply {
                     status: (status.facility_code + status.status_code * 256)
                         as i16,
+                    message: None,
                 }),
             }],
         },
-        None => unreachable!(),
+        None => global::DataReply {
+            ref_id: rdg.index as i32,
+            data: vec![global::DataInfo {
+                timestamp: now(),
+                result: failed("DPM sent an empty reply".into()),
+            }],
+        },
     }
 }
 
@@ -81,7 +158,10 @@ fn xlat_reply(e: Result<daq::ReadingReply, Status>) -> global::DataReply {
                 data: vec![global::DataInfo {
                     timestamp: now(),
                     result: global::DataType::StatusReply(
-                        global::StatusReply { status: -1 },
+                        global::StatusReply {
+                            status: -1,
+                            message: Some(e.message().into()),
+                        },
                     ),
                 }],
             }
@@ -89,6 +169,127 @@ fn xlat_reply(e: Result<daq::ReadingReply, Status>) -> global::DataReply {
     }
 }
 
+// The default amount of time a one-shot read waits for its readings,
+// and the longest a client may ask it to wait.
+
+const READ_TIMEOUT: f64 = 5.0;
+const MAX_READ_TIMEOUT: f64 = 60.0;
+
+// How far in the future, in seconds, a `deviceStatistics` request may
+// end when it includes live data, and how long after the end of the
+// range it waits for late readings.
+
+const MAX_LIVE_STATISTICS: f64 = 3_600.0;
+const LIVE_STATISTICS_GRACE: f64 = 1.0;
+
+// The slowest `maxRate` a live data request may ask for: one reading a
+// day.
+
+const MIN_RATE: f64 = 1.0 / 86_400.0;
+
+// The longest range, in seconds, of a triggered plot built from the
+// archives. The occurrences of the event are gathered before the plot
+// starts, so the range bounds the memory they use.
+
+const MAX_ARCHIVED_TRIGGERED: f64 = 86_400.0;
+
+// Builds the reply used for a device that didn't return a reading.
+
+fn missing_reply(
+    ref_id: usize, status: global::StatusReply,
+) -> global::DataReply {
+    global::DataReply {
+        ref_id: ref_id as i32,
+        data: vec![global::DataInfo {
+            timestamp: now(),
+            result: global::DataType::StatusReply(status),
+        }],
+    }
+}
+
+// Reads each DRF string once. The DRF strings should use an event that
+// only returns one reading (e.g. "@i".) The replies are returned in the
+// same order as the DRF strings.
+//
+// If all the readings haven't arrived within `timeout` seconds (or DPM
+// closes the stream early), the readings that did arrive are returned
+// and the missing ones hold an ACNET_REQTMO status. If the stream
+// fails, the missing ones hold a status whose message is DPM's error.
+// An error is only returned if the request couldn't be made.
+
+async fn read_once(
+    conn: &Connection, token: Option<&String>, drfs: Vec<String>, timeout: f64,
+) -> Result<Vec<global::DataReply>> {
+    let total = drfs.len();
+
+    // Allocate storage for the reply. As replies arrive, the
+    // corresponding entry is filled. When all are filled, the stream
+    // is closed.
+
+    let mut results: Vec<Option<global::DataReply>> = vec![None; total];
+    let mut remaining = total;
+    let mut failure = None;
+
+    let mut s = dpm::acquire_devices(conn, token, drfs)
+        .await
+        .map_err(|e| {
+            warn!("couldn't read devices -- {}", &e);
+            Error::new(e.message())
+        })?
+        .into_inner();
+
+    let collect = async {
+        while remaining > 0 {
+            match s.next().await {
+                Some(Ok(reply)) => {
+                    let index = reply.index as usize;
+
+                    match results.get_mut(index) {
+                        Some(entry @ None) => {
+                            *entry = Some(reading_to_reply(reply));
+                            remaining -= 1;
+                        }
+                        Some(Some(_)) => (),
+                        None => warn!("DPM returned bad index {}", index),
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("DPM stream failed -- {}", &e);
+                    failure = Some(global::StatusReply {
+                        status: -1,
+                        message: Some(e.message().into()),
+                    });
+                    break;
+                }
+                None => break,
+            }
+        }
+    };
+
+    if tokio::time::timeout(Duration::from_secs_f64(timeout), collect)
+        .await
+        .is_err()
+    {
+        warn!("timed-out waiting for {} of {} readings", remaining, total);
+    }
+
+    Ok(results
+        .into_iter()
+        .enumerate()
+        .map(|(idx, v)| {
+            v.unwrap_or_else(|| {
+                missing_reply(
+                    idx,
+                    failure.clone().unwrap_or(global::StatusReply {
+                        status: status::ACNET_REQTMO,
+                        message: None,
+                    }),
+                )
+            })
+        })
+        .collect())
+}
+
 // Create a zero-sized struct to attach the GraphQL handlers.
 
 #[derive(Default)]
@@ -103,7 +304,11 @@ impl ACSysQueries {
     #[doc = "Retrieve the next data point for the specified devices.
 
 Depending upon the event in the DRF string, the data may come back \
-immediately or after a delay."]
+immediately or after a delay.
+
+If a device doesn't return its reading within the timeout, the \
+readings that did arrive are returned and the missing devices return \
+an `ACNET_REQTMO` status."]
     #[instrument(skip(self, ctxt, device_list))]
     async fn accelerator_data(
         &self, ctxt: &Context<'_>,
@@ -112,7 +317,23 @@ immediately or after a delay."]
 		    in the same order as specified in this array."
         )]
         device_list: Vec<String>,
+        #[graphql(desc = "The number of seconds to wait for the readings. \
+		    Defaults to 5 seconds; at most 60 seconds.")]
+        timeout: Option<f64>,
     ) -> Result<Vec<global::DataReply>> {
+        let timeout = timeout.unwrap_or(READ_TIMEOUT);
+
+        if !(timeout.is_finite()
+            && timeout > 0.0
+            && timeout <= MAX_READ_TIMEOUT)
+        {
+            return Err(Error::new(format!(
+                "timeout must be a positive number of seconds, no more than \
+                 {} -- got {}",
+                MAX_READ_TIMEOUT, timeout
+            )));
+        }
+
         // Strip any event designation and append the once-immediate.
 
         let drfs: Vec<_> = device_list
@@ -120,46 +341,83 @@ immediately or after a delay."]
             .map(|v| format!("{}@i", strip_event(v)))
             .collect();
 
-        // Build a set of integers representing the indices of the
-        // request. As replies arrive, the corresponding index will be
-        // removed from the set. When the set is empty, the stream
-        // will close.
-
-        let mut remaining: HashSet<usize> = (0..drfs.len()).collect();
-
-        // Allocate storage for the reply.
-
-        let mut results: Vec<global::DataReply> =
-            vec![global::DataReply::default(); drfs.len()];
-
-        let mut s = dpm::acquire_devices(
-            ctxt.data::<Connection>().unwrap(),
+        read_once(
+            ctxt.data::<Connection>()?,
             ctxt.data::<global::AuthInfo>()
                 .ok()
                 .and_then(global::AuthInfo::token)
                 .as_ref(),
-            drfs.clone(),
+            drfs,
+            timeout,
         )
         .await
-        .unwrap()
-        .into_inner();
+    }
 
-        while let Some(reply) = s.next().await {
-            match reply {
-                Ok(reply) => {
-                    let index = reply.index as usize;
+    #[doc = "Retrieve the decoded basic status of devices.
 
-                    results[index] = reading_to_reply(reply);
+The basic status of each device is read once and decoded using the \
+device's status definition in DevDB. All devices must be ACNET devices \
+with a basic status property."]
+    #[instrument(skip(self, ctxt))]
+    async fn basic_status(
+        &self, ctxt: &Context<'_>,
+        #[graphql(
+            desc = "An array of device names. The returned values will be \
+		    in the same order as specified in this array."
+        )]
+        device_list: Vec<String>,
+    ) -> Result<Vec<types::BasicStatusReply>> {
+        let drfs: Vec<_> =
+            device_list.iter().map(|v| drf::entity(v).into()).collect();
+        let (requests, configs) =
+            basicstatus::prepare(&drfs, "@i").await.map_err(|e| {
+                warn!("can't read basic status -- {}", &e);
+                Error::new(e)
+            })?;
+
+        Ok(read_once(
+            ctxt.data::<Connection>()?,
+            ctxt.data::<global::AuthInfo>()
+                .ok()
+                .and_then(global::AuthInfo::token)
+                .as_ref(),
+            requests,
+            READ_TIMEOUT,
+        )
+        .await?
+        .into_iter()
+        .zip(configs.iter())
+        .map(|(reply, config)| basicstatus::decode_reply(config, reply))
+        .collect())
+    }
 
-                    remaining.remove(&index);
-                    if remaining.is_empty() {
-                        return Ok(results);
-                    }
-                }
-                Err(e) => return Err(Error::new(format!("{}", e).as_str())),
-            }
-        }
-        Err(Error::new("DPM didn't return all data"))
+    #[doc = "Report how live data requests are being shared.
+
+Live data subscriptions that request the same DRF strings, made by the \
+same account, share a single request to DPM. This query returns the \
+active upstream requests along with counts of the requests made and \
+subscriptions served."]
+    #[instrument(skip(self, ctxt))]
+    async fn live_data_statistics(
+        &self, ctxt: &Context<'_>,
+    ) -> Result<types::LiveDataStatistics> {
+        let stats = ctxt.data::<livemux::LiveMux>()?.stats();
+
+        Ok(types::LiveDataStatistics {
+            upstream_requests: stats.upstreams,
+            subscriptions: stats.subscriptions,
+            streams: stats
+                .streams
+                .into_iter()
+                .map(|v| types::LiveStreamStatistics {
+                    drfs: v.drfs,
+                    scoped: v.scoped,
+                    subscribers: v.subscribers,
+                    started: v.started,
+                    replies: v.replies,
+                })
+                .collect(),
+        })
     }
 
     #[doc = "Retrieve plot configuration(s).
@@ -213,16 +471,417 @@ that is included in the request."]
     async fn users_last_configuration(
         &self, ctxt: &Context<'_>,
     ) -> Option<Arc<str>> {
-        if let Ok(auth) = ctxt.data::<global::AuthInfo>() {
-            // TEMPORARY: If there isn't a JWT, use the account
-            // specified by the caller.
+        match Self::load_user_config(ctxt).await {
+            Ok(config) => config.map(|v| v.config),
+            Err(e) => {
+                warn!("unable to determine user : {}", e.message);
+                None
+            }
+        }
+    }
+
+    #[doc = "Obtain the user's last configuration and when it was saved.
+
+This is like `usersLastConfiguration` except it also returns the time \
+the configuration was saved. If there is no configuration for the \
+user, `null` is returned."]
+    #[instrument(skip(self, ctxt))]
+    async fn user_configuration(
+        &self, ctxt: &Context<'_>,
+    ) -> Result<Option<types::UserConfig>> {
+        Ok(Self::load_user_config(ctxt)
+            .await?
+            .map(|v| types::UserConfig {
+                config: v.config,
+                last_modified: v.last_modified,
+            }))
+    }
+
+    #[doc = "Return the statistics of the archived data cache."]
+    #[instrument(skip(self, ctxt))]
+    async fn archive_cache_statistics(
+        &self, ctxt: &Context<'_>,
+    ) -> Result<types::ArchiveCacheStatistics> {
+        Ok(ctxt
+            .data::<archivecache::ArchiveCache>()?
+            .statistics()
+            .into())
+    }
 
-            if let Some(account) = auth.unsafe_account() {
-                info!("using account: {:?}", &account);
+    #[doc = "Retrieve the archived values of devices at an instant.
+
+For each device, the archive is searched for the last reading at, or \
+before, `timestamp` and, if `includeNext` is `true`, the first reading \
+after it. The search is limited to `window` seconds on each side of the \
+instant; devices without a reading in the window return a warning \
+status saying so."]
+    #[instrument(skip(self, ctxt, drfs))]
+    async fn archived_values_at(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The devices to retrieve. The results are in this \
+		   order.")]
+        drfs: Vec<String>,
+        #[graphql(desc = "The instant, in seconds since 1970, UTC.")]
+        timestamp: f64,
+        #[graphql(desc = "How far, in seconds, to search from the instant. \
+		   Defaults to an hour; it may be, at most, a week.")]
+        window: Option<f64>,
+        #[graphql(desc = "If `true`, the first reading after the instant is \
+		   also returned. Defaults to `false`.")]
+        include_next: Option<bool>,
+    ) -> Result<Vec<types::InstantValue>> {
+        let now = now();
+
+        if !(timestamp.is_finite() && timestamp <= now) {
+            return Err(Error::new("timestamp must be in the past"));
+        }
+
+        let window = instant::search_window(window).map_err(Error::new)?;
+        let include_next = include_next.unwrap_or(false);
+        let sources = archive::ArchiveSources::from_context(ctxt)?;
+        let token = ctxt
+            .data::<global::AuthInfo>()
+            .ok()
+            .and_then(global::AuthInfo::token);
+
+        Ok(stream::iter(drfs.iter().enumerate())
+            .map(|(idx, drf)| {
+                instant::value_at(
+                    &sources,
+                    token.clone(),
+                    idx as i32,
+                    drf,
+                    timestamp,
+                    window,
+                    include_next,
+                    now,
+                )
+            })
+            .buffered(instant::PARALLEL)
+            .collect()
+            .await)
+    }
+
+    #[doc = "Retrieve a page of a device's archived data.
+
+The first request gives the range; its reply holds up to `limit` \
+readings and, if there are more, a `nextCursor`. Passing the cursor as \
+`after`, with the same device and range, returns the following page. \
+A page that fails can be requested again with the same cursor."]
+    #[instrument(skip(self, ctxt, after))]
+    async fn archived_data(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The device to retrieve.")] drf: String,
+        #[graphql(desc = "The start of the range, in seconds since 1970, \
+		   UTC.")]
+        start_time: f64,
+        #[graphql(desc = "The end of the range, in seconds since 1970, \
+		   UTC. The range ends no later than the time of the \
+		   request.")]
+        end_time: f64,
+        #[graphql(desc = "The `nextCursor` of the previous page.")]
+        after: Option<String>,
+        #[graphql(desc = "The most readings to return. Defaults to 1000; \
+		   it may be, at most, 10000.")]
+        limit: Option<i32>,
+    ) -> Result<types::ArchivedDataPage> {
+        let end_time = end_time.min(now());
+
+        if start_time >= end_time {
+            return Err(Error::new(
+                "startTime must be before endTime (and the current time)",
+            ));
+        }
+
+        let limit = paging::page_limit(limit).map_err(Error::new)?;
+        let sources = archive::ArchiveSources::from_context(ctxt)?;
+        let token = ctxt
+            .data::<global::AuthInfo>()
+            .ok()
+            .and_then(global::AuthInfo::token);
+
+        paging::page(
+            &sources,
+            token,
+            &drf,
+            start_time,
+            end_time,
+            after.as_deref(),
+            limit,
+        )
+        .await
+        .map_err(Error::new)
+    }
+
+    #[doc = "Search the EPICS archivers for the names of archived PVs.
+
+The names are sorted and each appears once, even if several appliances \
+archive it. Results are cached for a minute."]
+    #[instrument(skip(self, ctxt))]
+    async fn archived_pvs(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The names to find. This is a glob pattern (e.g. \
+		   `ACC-RF*`) unless `regex` is `true`.")]
+        pattern: String,
+        #[graphql(desc = "If `true`, `pattern` is a regular expression. \
+		   Defaults to `false`.")]
+        regex: Option<bool>,
+        #[graphql(desc = "The most names to return. Defaults to, and may \
+		   be at most, 10000.")]
+        limit: Option<i32>,
+    ) -> Result<Vec<String>> {
+        let limit = match limit {
+            None => archiver::MAX_SEARCH,
+            Some(v) if v > 0 && v as usize <= archiver::MAX_SEARCH => {
+                v as usize
+            }
+            Some(v) => {
+                return Err(Error::new(format!(
+                    "limit must be between 1 and {} -- got {}",
+                    archiver::MAX_SEARCH,
+                    v
+                )));
             }
+        };
+
+        ctxt.data::<archiver::Archivers>()?
+            .search(&pattern, regex.unwrap_or(false), limit)
+            .await
+            .map_err(Error::new)
+    }
+
+    #[doc = "Describe how EPICS PVs are archived.
+
+Each reply gives the PV's archiving status, the appliance holding it, \
+its units, precision, sampling and type, and the times of its first \
+and last samples. Results are cached for five minutes."]
+    #[instrument(skip(self, ctxt))]
+    async fn pv_metadata(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The PVs to describe. The results are in this \
+		   order.")]
+        pvs: Vec<String>,
+    ) -> Result<Vec<types::PvMetadata>> {
+        let archivers = ctxt.data::<archiver::Archivers>()?;
+        let now = now();
+
+        stream::iter(pvs.iter())
+            .map(|pv| archivers.metadata(pv, now))
+            .buffered(archiver::DESCRIBE_PARALLEL)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .map_err(Error::new)
+    }
+
+    #[doc = "Compute statistics of devices' readings over a time range.
+
+The readings are retrieved from the archives and reduced by this \
+service, so only the statistics are returned. The range can be divided \
+into bins of `binSize` seconds; otherwise one set of statistics covers \
+the whole range.
+
+If `includeLive` is `true` and `endTime` is in the future, live data is \
+included and the query doesn't return until `endTime`."]
+    #[instrument(skip(self, ctxt, drfs))]
+    async fn device_statistics(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The devices to summarize. The results are in \
+		   this order.")]
+        drfs: Vec<String>,
+        #[graphql(desc = "The start of the range, in seconds since 1970, \
+		   UTC.")]
+        start_time: f64,
+        #[graphql(desc = "The end of the range, in seconds since 1970, \
+		   UTC. Unless live data is included, the range ends no \
+		   later than the time of the request.")]
+        end_time: f64,
+        #[graphql(desc = "The width of the bins, in seconds. Bins start at \
+		   `startTime`.")]
+        bin_size: Option<f64>,
+        #[graphql(desc = "If `true`, live data is used for the part of the \
+		   range that's in the future. It may extend, at most, an \
+		   hour. Defaults to `false`.")]
+        include_live: Option<bool>,
+    ) -> Result<Vec<types::DeviceStatistics>> {
+        let now = now();
+        let include_live = include_live.unwrap_or(false) && end_time > now;
+        let end_time = if include_live {
+            end_time
+        } else {
+            end_time.min(now)
+        };
+
+        if start_time >= end_time {
+            return Err(Error::new(
+                "startTime must be before endTime (and the current time)",
+            ));
+        }
+
+        if include_live && end_time - now > MAX_LIVE_STATISTICS {
+            return Err(Error::new(format!(
+                "live data can only be included for the next {} seconds",
+                MAX_LIVE_STATISTICS
+            )));
+        }
+
+        let width = statistics::bin_width(start_time, end_time, bin_size)
+            .map_err(Error::new)?;
+        let mut collector =
+            statistics::Collector::new(drfs.len(), start_time, end_time, width);
+
+        // Live data is started first so nothing is missed while the
+        // archived data is retrieved. It ends shortly after the end of
+        // the range.
+
+        let live = if include_live {
+            Some(
+                ACSysSubscriptions::live_data(ctxt, &drfs, now)
+                    .await?
+                    .take_until(tokio::time::sleep(Duration::from_secs_f64(
+                        end_time - now + LIVE_STATISTICS_GRACE,
+                    ))),
+            )
+        } else {
+            None
+        };
+
+        let archived = if start_time < now {
+            let sources = archive::ArchiveSources::from_context(ctxt)?;
+            let token = ctxt
+                .data::<global::AuthInfo>()
+                .ok()
+                .and_then(global::AuthInfo::token);
+            let mut streams = Vec::with_capacity(drfs.len());
+
+            for (idx, drf) in drfs.iter().enumerate() {
+                streams.push(
+                    sources
+                        .fetch(
+                            token.clone(),
+                            drf,
+                            start_time,
+                            end_time.min(now),
+                            None,
+                        )
+                        .await?
+                        .map(move |mut reply| {
+                            reply.ref_id = idx as i32;
+                            reply
+                        }),
+                );
+            }
+            Some(stream::select_all(streams))
+        } else {
+            None
+        };
+
+        let mut s = std::pin::pin!(stream::select(
+            stream::iter(archived).flatten(),
+            stream::iter(live).flatten(),
+        ));
+
+        while let Some(reply) = s.next().await {
+            collector.add(&reply);
         }
-        warn!("unable to determine user : no config returned");
-        None
+        Ok(collector.finish())
+    }
+
+    #[doc = "Return the saved device snapshots, sorted by name."]
+    #[instrument(skip(self, ctxt))]
+    async fn snapshots(
+        &self, ctxt: &Context<'_>,
+    ) -> Result<Vec<types::DeviceSnapshot>> {
+        Ok(ctxt
+            .data::<snapshot::Snapshots>()?
+            .list()
+            .await
+            .map_err(Error::new)?
+            .into_iter()
+            .map(types::DeviceSnapshot::from)
+            .collect())
+    }
+
+    #[doc = "Return a device snapshot. If there is no snapshot with the \
+	     name, `null` is returned."]
+    #[instrument(skip(self, ctxt))]
+    async fn snapshot(
+        &self, ctxt: &Context<'_>, name: String,
+    ) -> Result<Option<types::DeviceSnapshot>> {
+        Ok(ctxt
+            .data::<snapshot::Snapshots>()?
+            .load(&name)
+            .await
+            .map_err(Error::new)?
+            .map(types::DeviceSnapshot::from))
+    }
+
+    #[doc = "Compare a snapshot with another snapshot or with the current \
+settings.
+
+If `other` is given, the two snapshots are compared. Otherwise the \
+devices in the snapshot are read and compared with the saved values. \
+Only the devices whose values differ, or which are missing from one of \
+the sets, are returned."]
+    #[instrument(skip(self, ctxt))]
+    async fn compare_snapshot(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The name of the snapshot.")] name: String,
+        #[graphql(desc = "The name of the snapshot to compare against. If \
+		    `null`, the current settings are used.")]
+        other: Option<String>,
+    ) -> Result<Vec<types::SnapshotDifference>> {
+        let store = ctxt.data::<snapshot::Snapshots>()?;
+        let load = |name: String| async move {
+            store.load(&name).await.map_err(Error::new)?.ok_or_else(|| {
+                Error::new(format!("no snapshot named {}", name))
+            })
+        };
+        let saved = load(name).await?;
+        let current = match other {
+            Some(other) => load(other).await?.entries,
+            None => {
+                let drfs: Vec<_> =
+                    saved.entries.iter().map(|e| e.drf.clone()).collect();
+
+                snapshot::read_current(
+                    ctxt.data::<Connection>()?,
+                    ctxt.data::<global::AuthInfo>()
+                        .ok()
+                        .and_then(global::AuthInfo::token)
+                        .as_ref(),
+                    &drfs,
+                )
+                .await
+                .map_err(Error::new)?
+            }
+        };
+
+        Ok(snapshot::compare(&saved.entries, &current)
+            .into_iter()
+            .map(types::SnapshotDifference::from)
+            .collect())
+    }
+}
+
+impl ACSysQueries {
+    // Loads the configuration of the account associated with the
+    // request's credentials.
+
+    async fn load_user_config(
+        ctxt: &Context<'_>,
+    ) -> Result<Option<userconfig::StoredConfig>> {
+        let account = verified_account(ctxt).await?;
+
+        info!("using account: {:?}", &account);
+        ctxt.data::<userconfig::UserConfigs>()?
+            .load(&account)
+            .await
+            .map_err(|e| {
+                error!("couldn't load configuration of {} -- {}", &account, &e);
+                Error::new(e)
+            })
     }
 }
 
@@ -235,10 +894,15 @@ impl ACSysMutations {
 
 Not all devices can be set -- most are read-only. To be able to set a \
 device, your SSO account must be associated with every device you may \
-want to set."]
-    #[instrument(skip(self, _ctxt, _value))]
+want to set.
+
+Before the setting is sent, the device's configuration is retrieved \
+from DevDB. Settings to properties that can't be set are rejected, as \
+are values outside the device's setting limits (unless `clamp` is \
+`true`.)"]
+    #[instrument(skip(self, ctxt, value))]
     async fn set_device(
-        &self, _ctxt: &Context<'_>,
+        &self, ctxt: &Context<'_>,
         #[graphql(
             desc = "The device to be set. This parameter should be expressed \
 		    as a DRF entity. For instance, for ACNET devices, the \
@@ -246,29 +910,330 @@ want to set."]
 		    `.CONTROL`."
         )]
         device: String,
-        #[graphql(desc = "The value of the setting.")] _value: global::DevValue,
+        #[graphql(desc = "The value of the setting.")]
+        mut value: global::DevValue,
+        #[graphql(desc = "If `true`, a value outside the device's limits is \
+		    replaced with the nearest limit. If `false` or `null`, \
+		    out-of-range values are rejected.")]
+        clamp: Option<bool>,
+        #[graphql(
+            desc = "If `true`, the setting is validated but isn't sent to \
+		    the device. A successful validation returns a status \
+		    of 0."
+        )]
+        dry_run: Option<bool>,
     ) -> Result<global::StatusReply> {
-        if let Ok(auth) = _ctxt.data::<global::AuthInfo>() {
+        if let Ok(auth) = ctxt.data::<global::AuthInfo>() {
+            settings::validate(&device, &mut value, clamp.unwrap_or(false))
+                .await
+                .map_err(|e| {
+                    warn!("setting rejected -- {}", &e);
+                    Error::new(e)
+                })?;
+
+            if dry_run.unwrap_or(false) {
+                info!("dry run -- setting not sent");
+                return Ok(global::StatusReply {
+                    status: 0,
+                    message: None,
+                });
+            }
+
             let now = tokio::time::Instant::now();
             let result = dpm::set_device(
-                _ctxt.data::<Connection>().unwrap(),
+                ctxt.data::<Connection>()?,
+                auth.token(),
+                device.clone(),
+                value.into(),
+            )
+            .await;
+
+            info!("done in {} μs", now.elapsed().as_micros());
+
+            match result {
+                Ok(status) => {
+                    let reply = global::StatusReply {
+                        status: status[0] as i16,
+                        message: None,
+                    };
+
+                    if reply.status != 0 {
+                        warn!("setting {} returned {}", &device, &reply);
+                    }
+                    Ok(reply)
+                }
+                Err(e) => Err(Error::new(e.message())),
+            }
+        } else {
+            Err(Error::new("no user credentials provided"))
+        }
+    }
+
+    #[doc = "Sends a digital control command to a device.
+
+The command is given by name (either its short or long name, ignoring \
+case) and is translated to the device's command value using the \
+device's control definition in DevDB. Like `setDevice`, your SSO \
+account must be allowed to set the device."]
+    #[instrument(skip(self, ctxt))]
+    async fn send_control(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The ACNET device to receive the command.")]
+        device: String,
+        #[graphql(desc = "The name of the command (e.g. `RESET`.)")]
+        command: String,
+    ) -> Result<global::StatusReply> {
+        let Ok(auth) = ctxt.data::<global::AuthInfo>() else {
+            return Err(Error::new("no user credentials provided"));
+        };
+        let target = drf::parse_target(&device).ok_or_else(|| {
+            Error::new(format!("{} isn't an ACNET device", &device))
+        })?;
+        let value = cache::device_info(&target.device)
+            .await
+            .and_then(|info| settings::resolve_command(&info, &command))
+            .map_err(|e| {
+                warn!("control rejected -- {}: {}", &target.device, &e);
+                Error::new(format!("{}: {}", &target.device, e))
+            })?;
+
+        info!("sending {} ({}) to {}", &command, value, &target.device);
+
+        let status = dpm::set_device(
+            ctxt.data::<Connection>()?,
+            auth.token(),
+            target.drf(drf::Property::Control),
+            device::Value {
+                value: Some(device::value::Value::Scalar(value as f64)),
+            },
+        )
+        .await
+        .map_err(|e| Error::new(e.message()))?;
+        let reply = global::StatusReply {
+            status: status.first().copied().unwrap_or(0) as i16,
+            message: None,
+        };
+
+        if reply.status != 0 {
+            warn!("command to {} returned {}", &target.device, &reply);
+        }
+        Ok(reply)
+    }
+
+    #[doc = "Starts a knobbing session for a device.
+
+Knobbing adjusts a device's setting in small increments. The device's \
+setting property must be marked as knobbable in DevDB; its step size \
+and limits are also taken from DevDB. Returns a session ID which is \
+passed to `knobDevice`, `endKnobSession` and the `knobReadback` \
+subscription. The session belongs to the account of the credentials \
+used to start it; only that account may use it. Sessions that don't \
+receive any increments for 10 minutes are closed."]
+    #[instrument(skip(self, ctxt))]
+    async fn start_knob_session(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The ACNET device to be knobbed.")] device: String,
+    ) -> Result<String> {
+        let account = verified_account(ctxt).await?;
+        let token = ctxt
+            .data::<global::AuthInfo>()
+            .ok()
+            .and_then(global::AuthInfo::token);
+
+        ctxt.data::<knob::KnobSessions>()?
+            .start(ctxt.data::<Connection>()?, token, account, &device)
+            .await
+            .map_err(|e| {
+                warn!("couldn't start knob session -- {}", &e);
+                Error::new(e)
+            })
+    }
+
+    #[doc = "Moves a knobbed device by a number of steps.
+
+Each step changes the setting by the knob's step size. Negative values \
+decrease the setting. Increments that arrive while a previous setting \
+is in progress are combined into a single setting. The resulting \
+values are reported by the `knobReadback` subscription."]
+    #[instrument(skip(self, ctxt))]
+    async fn knob_device(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The ID returned by `startKnobSession`.")]
+        session_id: String,
+        #[graphql(desc = "The number of steps to move the setting.")]
+        steps: i32,
+    ) -> Result<bool> {
+        let account = verified_account(ctxt).await?;
+
+        ctxt.data::<knob::KnobSessions>()?
+            .step(&session_id, &account, steps)
+            .map(|_| true)
+            .map_err(Error::new)
+    }
+
+    #[doc = "Ends a knobbing session.
+
+Any increments that haven't been applied are discarded and \
+subscriptions to the session's readbacks are closed."]
+    #[instrument(skip(self, ctxt))]
+    async fn end_knob_session(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The ID returned by `startKnobSession`.")]
+        session_id: String,
+    ) -> Result<bool> {
+        let account = verified_account(ctxt).await?;
+
+        ctxt.data::<knob::KnobSessions>()?
+            .stop(&session_id, &account)
+            .map(|_| true)
+            .map_err(Error::new)
+    }
+
+    #[doc = "Saves the settings of a set of devices as a snapshot.
+
+Each device's setting is read and saved under the given name. For ACNET \
+devices, the setting property is saved regardless of the property in \
+the DRF string; events are ignored. Devices that can't be read are \
+saved with the status of the read and are skipped when the snapshot is \
+restored.
+
+The snapshot is owned by the account named in the request's \
+credentials, once the authentication service has verified them. Only \
+the owner may replace it."]
+    #[instrument(skip(self, ctxt))]
+    async fn save_snapshot(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The name of the snapshot. Names may contain \
+		    letters, digits, '.', '_' and '-'.")]
+        name: String,
+        #[graphql(desc = "The devices to save.")] drfs: Vec<String>,
+        #[graphql(desc = "If `true`, an existing snapshot with the same \
+		    name is replaced, if it belongs to the same account. \
+		    Otherwise saving to an existing name is an error.")]
+        overwrite: Option<bool>,
+    ) -> Result<types::DeviceSnapshot> {
+        let account = verified_account(ctxt).await?;
+        let store = ctxt.data::<snapshot::Snapshots>()?;
+        let overwrite = overwrite.unwrap_or(false);
+
+        snapshot::check_name(&name).map_err(Error::new)?;
+
+        if !overwrite && store.load(&name).await.map_err(Error::new)?.is_some()
+        {
+            return Err(Error::new(format!(
+                "snapshot {} already exists",
+                name
+            )));
+        }
+
+        let drfs = drfs
+            .iter()
+            .map(|v| snapshot::setting_drf(v))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::new)?;
+        let entries = snapshot::read_current(
+            ctxt.data::<Connection>()?,
+            ctxt.data::<global::AuthInfo>()
+                .ok()
+                .and_then(global::AuthInfo::token)
+                .as_ref(),
+            &drfs,
+        )
+        .await
+        .map_err(Error::new)?;
+        let result = snapshot::Snapshot {
+            name,
+            owner: Some(account.clone()),
+            created: now(),
+            entries,
+        };
+
+        info!("saving snapshot {} ({} devices)", &result.name, drfs.len());
+        store
+            .save(&result, &account, overwrite)
+            .await
+            .map_err(|e| {
+                error!("couldn't save snapshot {} -- {}", &result.name, &e);
+                Error::new(e)
+            })?;
+        Ok(result.into())
+    }
+
+    #[doc = "Restores the settings saved in a snapshot.
+
+Every saved setting is validated, as `setDevice` does, before any are \
+sent; if any setting is rejected, nothing is sent. The settings are \
+then sent in a single request. Devices that couldn't be read when the \
+snapshot was taken aren't restored. Returns the status of each \
+setting."]
+    #[instrument(skip(self, ctxt))]
+    async fn restore_snapshot(
+        &self, ctxt: &Context<'_>,
+        #[graphql(desc = "The name of the snapshot.")] name: String,
+        #[graphql(desc = "If `true`, the settings are validated but aren't \
+		    sent. Successful validations return a status of 0.")]
+        dry_run: Option<bool>,
+    ) -> Result<Vec<types::RestoreResult>> {
+        let Ok(auth) = ctxt.data::<global::AuthInfo>() else {
+            return Err(Error::new("no user credentials provided"));
+        };
+        let saved = ctxt
+            .data::<snapshot::Snapshots>()?
+            .load(&name)
+            .await
+            .map_err(Error::new)?
+            .ok_or_else(|| Error::new(format!("no snapshot named {}", name)))?;
+        let mut settings = vec![];
+        let mut errors = vec![];
+
+        for entry in saved.entries {
+            if let Some(mut value) = entry.value.to_setting() {
+                match settings::validate(&entry.drf, &mut value, false).await {
+                    Ok(()) => settings.push((entry.drf, value)),
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            warn!("restore of {} rejected -- {}", &name, errors.join("; "));
+            return Err(Error::new(errors.join("; ")));
+        }
+
+        let drfs: Vec<_> =
+            settings.iter().map(|(drf, _)| drf.clone()).collect();
+        let statuses = if dry_run.unwrap_or(false) {
+            info!("dry run -- snapshot {} not restored", &name);
+            vec![0; drfs.len()]
+        } else {
+            info!("restoring {} settings from {}", drfs.len(), &name);
+            dpm::set_devices(
+                ctxt.data::<Connection>()?,
                 auth.token(),
-                device.clone(),
-                _value.into(),
+                settings
+                    .into_iter()
+                    .map(|(drf, value)| (drf, value.into()))
+                    .collect(),
             )
-            .await;
+            .await
+            .map_err(|e| Error::new(e.message()))?
+        };
 
-            info!("done in {} μs", now.elapsed().as_micros());
+        Ok(drfs
+            .into_iter()
+            .zip(statuses)
+            .map(|(drf, status)| {
+                let status = global::StatusReply {
+                    status: status as i16,
+                    message: None,
+                };
 
-            match result {
-                Ok(status) => Ok(global::StatusReply {
-                    status: status[0] as i16,
-                }),
-                Err(e) => Err(Error::new(format!("{}", e).as_str())),
-            }
-        } else {
-            Err(Error::new("no user credentials provided"))
-        }
+                if status.status != 0 {
+                    warn!("restoring {} returned {}", &drf, &status);
+                }
+                types::RestoreResult { drf, status }
+            })
+            .collect())
     }
 
     #[doc = "Add/Update a plot configuration"]
@@ -296,7 +1261,10 @@ want to set."]
                 warn!("{}", msg);
                 Err(Error::new(msg))
             }
-            Ok(_) => Ok(global::StatusReply { status: 0 }),
+            Ok(_) => Ok(global::StatusReply {
+                status: 0,
+                message: None,
+            }),
             Err(e) => {
                 warn!("unexpected gRPC reply: {e}");
                 Err(Error::new(e.to_string()))
@@ -309,21 +1277,26 @@ want to set."]
 The content of the configuration are used to set the default \
 configuration for the user. All fields, except the ID and name \
 fields, are used. The user's account name is obtained from the \
-authentication token that accompanies the request."]
+authentication token that accompanies the request, once the \
+authentication service has verified it."]
     #[instrument(skip(self, ctxt))]
     async fn users_configuration(
-        &self, ctxt: &Context<'_>, _config: Arc<str>,
+        &self, ctxt: &Context<'_>, config: Arc<str>,
     ) -> Result<global::StatusReply> {
-        if let Ok(auth) = ctxt.data::<global::AuthInfo>() {
-            if let Some(account) = auth.unsafe_account() {
-                info!("using account: {:?}", &account);
-                Ok(global::StatusReply { status: 0 })
-            } else {
-                Err(Error::new("unable to verify user credentials"))
-            }
-        } else {
-            Err(Error::new("no user credentials provided"))
-        }
+        let account = verified_account(ctxt).await?;
+
+        info!("using account: {:?}", &account);
+        ctxt.data::<userconfig::UserConfigs>()?
+            .save(&account, config)
+            .await
+            .map_err(|e| {
+                error!("couldn't save configuration of {} -- {}", &account, &e);
+                Error::new(e)
+            })?;
+        Ok(global::StatusReply {
+            status: 0,
+            message: None,
+        })
     }
 }
 
@@ -379,369 +1352,60 @@ fn add_event(
 // requires concrete trait object types.
 type DataStream = Pin<Box<dyn Stream<Item = global::DataReply> + Send>>;
 type PlotStream = Pin<Box<dyn Stream<Item = types::PlotReplyData> + Send>>;
+type KnobStream = Pin<Box<dyn Stream<Item = types::KnobReply> + Send>>;
+type BasicStatusStream =
+    Pin<Box<dyn Stream<Item = types::BasicStatusReply> + Send>>;
+type AlignedStream = Pin<Box<dyn Stream<Item = types::AlignedData> + Send>>;
 
 struct TimeBounds {
     pub end: Option<f64>,
     pub start: Option<f64>,
 }
 
-// Represents a single PV data point (event)
-
-/// Custom deserializer that converts JSON values directly to Vec<f64> or a
-/// scalar, avoiding intermediate Value allocations. This allows us to parse
-/// arrays directly to f64 without heap overhead from Value enum wrappers.
-fn deserialize_archiver_value<'de, D>(
-    deserializer: D,
-) -> Result<ArchiverValue, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    use serde::de::{self, Visitor};
-
-    struct ArchiverValueVisitor;
-
-    impl<'de> Visitor<'de> for ArchiverValueVisitor {
-        type Value = ArchiverValue;
-
-        fn expecting(
-            &self, formatter: &mut std::fmt::Formatter,
-        ) -> std::fmt::Result {
-            formatter.write_str("a number or array of numbers")
-        }
-
-        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            Ok(ArchiverValue::Scalar(v as f64))
-        }
-
-        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            Ok(ArchiverValue::Scalar(v as f64))
-        }
-
-        fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            Ok(ArchiverValue::Scalar(v))
-        }
-
-        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-        where
-            A: de::SeqAccess<'de>,
-        {
-            let mut array = Vec::new();
-            while let Some(val) = seq.next_element::<serde_json::Number>()? {
-                if let Some(f) = val.as_f64() {
-                    array.push(f);
-                }
-            }
-            Ok(ArchiverValue::Array(array))
-        }
-    }
-
-    deserializer.deserialize_any(ArchiverValueVisitor)
-}
-
-/// Optimized representation of archiver event values.
-/// Stores either a scalar f64 or an array of f64s, avoiding Value enum overhead.
-#[derive(Debug)]
-enum ArchiverValue {
-    Scalar(f64),
-    Array(Vec<f64>),
-}
-
-#[derive(Debug, Deserialize)]
-struct ArchiverEvent {
-    pub secs: i64,
-    pub nanos: u32,
-    #[serde(deserialize_with = "deserialize_archiver_value")]
-    pub val: ArchiverValue,
-}
-
 #[derive(Default)]
 pub struct ACSysSubscriptions;
 
-// Converts an f64 representing time since the epoch into an RFC
-// 3339 string.
-
-fn to_iso(timestamp_f64: f64) -> String {
-    use chrono::{TimeZone, Utc};
-
-    let seconds = timestamp_f64.trunc() as i64;
-    let nanoseconds = {
-        let nanos = (timestamp_f64.fract() * 1_000_000_000.0).round() as i64;
-        let nanos = nanos.clamp(0, 999_999_999);
-        nanos as u32
-    };
-    let datetime_utc = Utc
-        .timestamp_opt(seconds, nanoseconds)
-        .earliest()
-        .unwrap_or_else(|| {
-            Utc.timestamp_millis_opt(0).unwrap() // Default to epoch start
-        });
-
-    datetime_utc.format("%Y-%m-%dT%H:%M:%S.%fZ").to_string()
-}
-
-fn transform_event(event: ArchiverEvent) -> global::DataReply {
-    let timestamp = event.secs as f64 + (event.nanos as f64 / 1_000_000_000.0);
-    let result = match event.val {
-        ArchiverValue::Array(arr) => {
-            global::DataType::ScalarArray(global::ScalarArray {
-                scalar_array_value: arr,
-            })
-        }
-        ArchiverValue::Scalar(scalar) => {
-            global::DataType::Scalar(global::Scalar {
-                scalar_value: scalar,
-            })
-        }
-    };
-
-    global::DataReply {
-        ref_id: 0,
-        data: vec![global::DataInfo { timestamp, result }],
-    }
-}
-
 // Private methods used by subscriptions.
 
 impl<'ctx> ACSysSubscriptions {
     // Returns a stream of live data for a list of devices. If an
     // end-time is specified, the stream will end once it is reached.
+    //
+    // The devices are obtained, with a single request, through the
+    // live data multiplexer so other subscriptions, by the same
+    // account, requesting the same DRF strings share the request to
+    // DPM.
 
     async fn live_data(
         ctxt: &Context<'ctx>, drfs: &[String], start_time: f64,
     ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
     {
-        use tokio_stream::StreamExt;
-
-        // Strip any source designation and append the once-immediate.
+        let mux = ctxt.data::<livemux::LiveMux>()?;
+        let conn = ctxt.data::<Connection>()?;
+        let auth = ctxt.data::<global::AuthInfo>().ok();
+        let token = auth.and_then(global::AuthInfo::token);
+        let scope = livemux::Scope::of(auth).await;
 
-        let processed_drfs: Vec<_> =
-            drfs.iter().map(|v| strip_source(v).into()).collect();
+        // Strip any source designation and subscribe to the devices.
 
-        // Make the gRPC data request to DPM.
+        let drfs: Vec<_> = drfs
+            .iter()
+            .map(|drf| strip_source(drf).to_owned())
+            .collect();
 
-        match dpm::acquire_devices(
-            ctxt.data::<Connection>().unwrap(),
-            ctxt.data::<global::AuthInfo>()
-                .ok()
-                .and_then(global::AuthInfo::token)
-                .as_ref(),
-            processed_drfs,
-        )
-        .await
-        {
-            Ok(s) => Ok(StreamExt::filter_map(s.into_inner(), move |v| {
-                let mut reply = xlat_reply(v);
+        Ok(mux.subscribe(conn, token, scope, &drfs).filter_map(
+            move |mut reply| {
                 let idx = reply.data[..]
                     .partition_point(|info| info.timestamp < start_time);
 
                 reply.data.drain(..idx);
-                if reply.data.is_empty() {
+                future::ready(if reply.data.is_empty() {
                     None
                 } else {
                     Some(reply)
-                }
-            })),
-            Err(e) => Err(Error::new(format!("{}", e))),
-        }
-    }
-
-    // Reads a chunked HTTP response until it finds the "data" key.
-    // Once that's found, it advances the stream up to, and including,
-    // the first '[' so that it's pointing to the first object in the
-    // array.
-
-    async fn seek_to_data_array<R: tokio::io::AsyncBufRead + Unpin>(
-        r: &mut R,
-    ) -> std::io::Result<()> {
-        const KEY: &[u8] = b"\"data\"";
-
-        // First find the key.
-
-        loop {
-            let buf = r.fill_buf().await?;
-
-            if buf.is_empty() {
-                return Err(std::io::ErrorKind::UnexpectedEof.into());
-            }
-
-            if let Some(pos) = buf.windows(KEY.len()).position(|w| w == KEY) {
-                r.consume(pos + KEY.len());
-                break;
-            }
-
-            let len = buf.len();
-
-            r.consume(len.saturating_sub(KEY.len()));
-        }
-
-        // Now find the next '[' character.
-
-        loop {
-            if r.read_u8().await? == b'[' {
-                break;
-            }
-        }
-
-        Ok(())
-    }
-
-    async fn extract_next_object<R: tokio::io::AsyncBufRead + Unpin>(
-        r: &mut R,
-    ) -> std::io::Result<Option<Vec<u8>>> {
-        let mut obj_bytes = Vec::new();
-        let mut brace_count = 0;
-        let mut in_object = false;
-
-        loop {
-            let buf = r.fill_buf().await?;
-
-            if buf.is_empty() {
-                return Ok(None);
-            }
-
-            for (i, &b) in buf.iter().enumerate() {
-                if !in_object {
-                    if b == b'{' {
-                        in_object = true;
-                        brace_count = 1;
-                        obj_bytes.push(b);
-                    } else if b == b']' {
-                        return Ok(None); // End of the data array
-                    }
-                    // Skip commas/whitespace
-                    continue;
-                }
-
-                obj_bytes.push(b);
-
-                if b == b'{' {
-                    brace_count += 1;
-                } else if b == b'}' {
-                    brace_count -= 1;
-                    if brace_count == 0 {
-                        r.consume(i + 1);
-                        return Ok(Some(obj_bytes));
-                    }
-                }
-            }
-
-            let len = buf.len();
-
-            r.consume(len);
-        }
-    }
-
-    #[instrument(name = "EPICS_ARCH", skip(device, start_time, end_time))]
-    async fn epics_archived_data(
-        device: &str, start_time: f64, end_time: f64,
-    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
-    {
-        const BASE_URL: &str =
-            "http://archiver1.fnal.gov:17668/retrieval/data/getData.json";
-
-        let request_url = format!(
-            "{}?pv={}&from={}&to={}",
-            BASE_URL,
-            device_name(device),
-            to_iso(start_time.min(end_time)),
-            to_iso(end_time.max(start_time))
-        );
-        let response = reqwest::get(request_url).await?.error_for_status()?;
-
-        let byte_stream = response
-            .bytes_stream()
-            .map(|res| res.map_err(std::io::Error::other));
-
-        let reader = BufReader::new(StreamReader::new(byte_stream));
-
-        let strm =
-            stream::unfold((reader, false), |(mut r, ready)| async move {
-                // SEEK PHASE: Only runs once
-
-                if !ready && Self::seek_to_data_array(&mut r).await.is_err() {
-                    return None;
-                }
-
-                // PARSE PHASE: Extract one object from the array
-
-                match Self::extract_next_object(&mut r).await {
-                    Ok(Some(bytes)) => {
-                        match serde_json::from_slice::<ArchiverEvent>(&bytes) {
-                            Ok(item) => {
-                                Some((transform_event(item), (r, true)))
-                            }
-                            Err(e) => {
-                                error!(
-				    "failed to deserialize archiver data: {e}, offending bytes: {}",
-				    String::from_utf8_lossy(&bytes)
-				);
-                                None
-                            }
-                        }
-                    }
-                    _ => None,
-                }
-            })
-            .boxed();
-
-        Ok(datastream::group_scalars::<500, _>(strm))
-    }
-
-    // Returns a stream containing archived data for a device.
-
-    #[instrument(name = "ACNET_ARCH", skip(ctxt, device, start_time, end_time))]
-    async fn archived_data(
-        ctxt: &Context<'ctx>, device: &str, start_time: f64, end_time: f64,
-    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
-    {
-        use tokio_stream::StreamExt;
-
-        // If the device has a subscript, then the client wants
-        // archived data for an array device. Due to quirks in the
-        // array data logger, we can't specify the subscript or event.
-
-        let device = if device.find('[').is_some() {
-            device_name(device)
-        } else {
-            strip_source(device)
-        };
-
-        // Build the DRF string needed for the archived data.
-
-        let drf = format!(
-            "{}<-LOGGER:{}:{}",
-            device,
-            (start_time * 1_000.0) as u128,
-            (end_time * 1_000.0) as u128
-        );
-
-        // Make the gRPC data request to DPM.
-
-        match dpm::acquire_devices(
-            ctxt.data::<Connection>().unwrap(),
-            ctxt.data::<global::AuthInfo>()
-                .ok()
-                .and_then(global::AuthInfo::token)
-                .as_ref(),
-            vec![drf],
-        )
-        .await
-        {
-            Ok(s) => Ok(datastream::as_archive_stream(StreamExt::map(
-                s.into_inner(),
-                xlat_reply,
-            ))),
-            Err(e) => Err(Error::new(format!("{}", e).as_str())),
-        }
+                })
+            },
+        ))
     }
 
     // A helper method to handle plots that request continuous data.
@@ -775,6 +1439,9 @@ impl<'ctx> ACSysSubscriptions {
                 time_bounds.start,
                 time_bounds.end,
                 Some(false),
+                None,
+                None,
+                None,
             )
             .await?;
         let s =
@@ -880,6 +1547,50 @@ impl<'ctx> ACSysSubscriptions {
         out.trigger_timestamp = Some(ev_ts)
     }
 
+    // Builds a triggered plot from archived data. The occurrences of
+    // the event are found in the archives, too, and the devices' data is
+    // grouped by them.
+
+    async fn handle_archived_triggered(
+        ctxt: &Context<'ctx>, drfs: Vec<String>, trigger_event: u8,
+        start_time: f64, end_time: f64, template: types::PlotReplyData,
+    ) -> Result<PlotStream> {
+        if end_time - start_time > MAX_ARCHIVED_TRIGGERED {
+            return Err(Error::new(format!(
+                "an archived triggered plot may span at most {} seconds -- got {}",
+                MAX_ARCHIVED_TRIGGERED,
+                end_time - start_time
+            )));
+        }
+
+        let sources = archive::ArchiveSources::from_context(ctxt)?;
+        let token = ctxt
+            .data::<global::AuthInfo>()
+            .ok()
+            .and_then(global::AuthInfo::token);
+        let events = triggered::event_history(
+            &sources,
+            token.clone(),
+            trigger_event,
+            start_time,
+            end_time,
+        )
+        .await
+        .map_err(Error::new)?;
+        let mut streams = Vec::with_capacity(drfs.len());
+
+        for drf in drfs.iter() {
+            streams.push(
+                sources
+                    .fetch(token.clone(), drf, start_time, end_time, None)
+                    .await?,
+            );
+        }
+
+        Ok(Box::pin(triggered::bundles(streams, events, template))
+            as PlotStream)
+    }
+
     // A helper method to handle plots that want to sync their data to
     // a clock event.
 
@@ -909,6 +1620,23 @@ impl<'ctx> ACSysSubscriptions {
                 .collect(),
         };
 
+        // Ranges that are entirely in the past are built from the
+        // archives.
+
+        if let (Some(start), Some(end)) = (start_time, end_time)
+            && end <= now()
+        {
+            return Self::handle_archived_triggered(
+                ctxt,
+                drfs,
+                trigger_event,
+                start,
+                end,
+                template,
+            )
+            .await;
+        }
+
         // Subscribe for clock events. Along with the trigger event, we
         // also subscribe to the $0F event. We do this because we don't
         // know when the next trigger event is going to occur. However,
@@ -930,6 +1658,9 @@ impl<'ctx> ACSysSubscriptions {
                 start_time,
                 end_time,
                 Some(false),
+                None,
+                None,
+                None,
             )
             .await?;
 
@@ -1034,7 +1765,15 @@ Accepts a list of DRF strings and streams the resulting data. The \
 `start_time` and `end_time` parameters determine the range in which \
 data should be returned for the device(s). Dates in the past will \
 retrieve data from archivers and dates in the future will return \
-live data."]
+live data.
+
+Archived data for ACNET devices comes from the data loggers and data \
+for EPICS PVs comes from the EPICS archiver. A DRF can choose the \
+archive with a `<-LOGGER`, `<-LOGGER:node` or `<-EPICS` source. When \
+the archive was chosen from the name and the EPICS archiver can't \
+supply the data, the data loggers are used instead and the stream \
+starts with a warning status whose `message` explains why."]
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip(self, ctxt, drfs, validate_timestamp))]
     async fn accelerator_data(
         &self, ctxt: &Context<'ctx>,
@@ -1069,13 +1808,46 @@ live data."]
 		   data to be included, then timestamp validation is always \
 		   done.")]
         validate_timestamp: Option<bool>,
+        #[graphql(desc = "The maximum rate, in Hz, at which live data is \
+		   returned for each device. Faster devices are reduced \
+		   using the `decimation` strategy. If `null`, all live \
+		   data is returned. Archived data isn't affected.")]
+        max_rate: Option<f64>,
+        #[graphql(desc = "How live data is reduced when `maxRate` is \
+		   specified. Defaults to `LATEST`.")]
+        decimation: Option<types::Decimation>,
+        #[graphql(desc = "Reduces archived data to one value per bin. For \
+		   EPICS PVs, the archiver's post-processing operators are \
+		   used. ACNET logger data is reduced by this service in \
+		   the same way. If `null`, every archived sample is \
+		   returned. Live data isn't affected.")]
+        aggregation: Option<types::Aggregation>,
     ) -> Result<DataStream> {
+        if let Some(ref aggregation) = aggregation {
+            datastream::bin_width(aggregation).map_err(Error::new)?;
+        }
+
+        let interval = match max_rate {
+            Some(rate) if rate.is_finite() && rate >= MIN_RATE => {
+                Some(1.0 / rate)
+            }
+            Some(rate) => {
+                return Err(Error::new(format!(
+                    "maxRate must be at least {} Hz (one reading a day) \
+                     -- got {}",
+                    MIN_RATE, rate
+                )));
+            }
+            None => None,
+        };
+
         // TEMPORARY: The validate_timestamp parameter shouldn't be an
         // Option. We're doing it right now so it's backward compatible
         // with the version of `-core` that's out there. If not provided,
         // we default to `false`.
 
         let validate_timestamp = validate_timestamp.unwrap_or(false);
+        let limits = *ctxt.data::<LiveLimits>()?;
 
         let total = drfs.len() as i32;
         let now = now();
@@ -1109,7 +1881,11 @@ live data."]
         // time for the data to also be saved in a data logger.
 
         let s_live = if need_live {
-            Some(ACSysSubscriptions::live_data(ctxt, &drfs, start_live).await?)
+            Some(datastream::decimate(
+                ACSysSubscriptions::live_data(ctxt, &drfs, start_live).await?,
+                interval,
+                decimation.unwrap_or(types::Decimation::Latest),
+            ))
         } else {
             None
         };
@@ -1117,6 +1893,11 @@ live data."]
         // Build up the set of streams that will return archived data.
 
         let s_archived = if let Some(st) = archived_start {
+            let sources = archive::ArchiveSources::from_context(ctxt)?;
+            let token = ctxt
+                .data::<global::AuthInfo>()
+                .ok()
+                .and_then(global::AuthInfo::token);
             let mut streams = tokio_stream::StreamMap::new();
 
             // Since each device is its own stream, all the ref_ids will
@@ -1124,28 +1905,18 @@ live data."]
             // correct ref ID with the stream.
 
             for (ref_id, drf) in drfs.into_iter().enumerate() {
-                let epics_result = ACSysSubscriptions::epics_archived_data(
-                    &drf,
-                    st,
-                    archived_end,
-                )
-                .await;
-                let actual = match epics_result {
-                    Ok(epics) => Either::Left(epics),
-                    Err(_) => {
-                        let acnet = ACSysSubscriptions::archived_data(
-                            ctxt,
+                streams.insert(
+                    ref_id as i32,
+                    sources
+                        .fetch(
+                            token.clone(),
                             &drf,
                             st,
                             archived_end,
+                            aggregation,
                         )
-                        .await?;
-
-                        Either::Right(acnet)
-                    }
-                };
-
-                streams.insert(ref_id as i32, actual);
+                        .await?,
+                );
             }
 
             // Modify incoming DataReplies by updating their ref IDs.
@@ -1159,19 +1930,93 @@ live data."]
         };
 
         Ok(Box::pin(datastream::end_stream_at(
-            datastream::merge(s_archived, s_live),
+            datastream::merge(s_archived, s_live, limits),
             total,
             end_time,
         )) as DataStream)
     }
 
+    #[doc = "Retrieve the archived data of several devices on a common \
+time grid.
+
+Rows are returned either every `interval` seconds, starting at \
+`startTime`, or at each timestamp of the `master` device. Each row holds \
+the value of every device at that time, found using `interpolation`. \
+The archives are chosen as in `acceleratorData`. Exactly one of \
+`interval` and `master` must be specified."]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, ctxt, drfs))]
+    async fn aligned_data(
+        &self, ctxt: &Context<'ctx>,
+        #[graphql(desc = "The devices to retrieve. The values of each row \
+		   are in this order.")]
+        drfs: Vec<String>,
+        #[graphql(desc = "The start of the range, in seconds since 1970, \
+		   UTC.")]
+        start_time: f64,
+        #[graphql(desc = "The end of the range, in seconds since 1970, \
+		   UTC. Only archived data is returned so the range ends \
+		   no later than the time of the request.")]
+        end_time: f64,
+        #[graphql(desc = "The number of seconds between rows.")]
+        interval: Option<f64>,
+        #[graphql(desc = "The index, in `drfs`, of the device whose \
+		   timestamps are used for the rows.")]
+        master: Option<i32>,
+        #[graphql(desc = "How values are found for times between a \
+		   device's readings. Defaults to `PREVIOUS`.")]
+        interpolation: Option<types::Interpolation>,
+    ) -> Result<AlignedStream> {
+        let end_time = end_time.min(now());
+
+        if start_time >= end_time {
+            return Err(Error::new(
+                "startTime must be before endTime (and the current time)",
+            ));
+        }
+
+        let grid = datastream::Grid::new(
+            start_time,
+            end_time,
+            interval,
+            master,
+            drfs.len(),
+        )
+        .map_err(Error::new)?;
+        let sources = archive::ArchiveSources::from_context(ctxt)?;
+        let token = ctxt
+            .data::<global::AuthInfo>()
+            .ok()
+            .and_then(global::AuthInfo::token);
+        let mut streams = Vec::with_capacity(drfs.len());
+
+        for drf in drfs.iter() {
+            streams.push(
+                sources
+                    .fetch(token.clone(), drf, start_time, end_time, None)
+                    .await?,
+            );
+        }
+
+        Ok(Box::pin(datastream::align(
+            streams,
+            grid,
+            interpolation.unwrap_or(types::Interpolation::Previous),
+        )) as AlignedStream)
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[doc = "Retrieve correlated plot data.
 
 This query sets up a request which returns a stream of data, presumably \
 used for plotting. Unlike the `acceleratorData` query, this stream \
 returns data for all the devices in one reply. Since the data is \
-correlated, all the devices are collected on the same event."]
+correlated, all the devices are collected on the same event.
+
+If `triggerEvent` is specified and the time range is entirely in the \
+past, the data comes from the archives and is grouped by the past \
+occurrences of the event, which are read from a logged clock event \
+device. Such a range may span at most a day."]
     #[instrument(skip(self, ctxt, drf_list))]
     async fn start_plot(
         &self, ctxt: &Context<'ctx>,
@@ -1248,12 +2093,145 @@ correlated, all the devices are collected on the same event."]
             .await
         }
     }
+
+    #[doc = "Stream the decoded basic status of devices.
+
+Each DRF string selects a device and, optionally, the event on which \
+to sample its basic status (1 Hz, if no event is given.) Each reading \
+is decoded using the device's status definition in DevDB. All devices \
+must be ACNET devices with a basic status property."]
+    #[instrument(skip(self, ctxt))]
+    async fn basic_status(
+        &self, ctxt: &Context<'ctx>,
+        #[graphql(
+            desc = "A array of DRF strings. Each entry of the returned stream \
+		    will have a index to associate the reading with the DRF \
+		    that started it."
+        )]
+        drfs: Vec<String>,
+    ) -> Result<BasicStatusStream> {
+        let drfs: Vec<_> =
+            drfs.iter().map(|v| strip_source(v).into()).collect();
+        let (requests, configs) =
+            basicstatus::prepare(&drfs, "@p,1000").await.map_err(|e| {
+                warn!("can't stream basic status -- {}", &e);
+                Error::new(e)
+            })?;
+        let s = dpm::acquire_devices(
+            ctxt.data::<Connection>()?,
+            ctxt.data::<global::AuthInfo>()
+                .ok()
+                .and_then(global::AuthInfo::token)
+                .as_ref(),
+            requests,
+        )
+        .await
+        .map_err(|e| Error::new(e.message()))?
+        .into_inner();
+
+        // Channel errors are reported with a `ref_id` of -1, which
+        // doesn't have a status definition.
+
+        let no_config = DigStatus {
+            entries: vec![],
+            ext_entries: vec![],
+        };
+
+        Ok(Box::pin(s.map(xlat_reply).map(move |reply| {
+            let config = usize::try_from(reply.ref_id)
+                .ok()
+                .and_then(|idx| configs.get(idx))
+                .unwrap_or(&no_config);
+
+            basicstatus::decode_reply(config, reply)
+        })) as BasicStatusStream)
+    }
+
+    #[doc = "Streams the progress of a knobbing session.
+
+The stream starts with the most recent setting of the device and \
+reports the new setting, and its readback, each time the session \
+applies increments. The stream ends when the session is closed. Only \
+the account that started the session may subscribe."]
+    #[instrument(skip(self, ctxt))]
+    async fn knob_readback(
+        &self, ctxt: &Context<'ctx>,
+        #[graphql(desc = "The ID returned by `startKnobSession`.")]
+        session_id: String,
+    ) -> Result<KnobStream> {
+        let account = verified_account(ctxt).await?;
+
+        Ok(Box::pin(
+            ctxt.data::<knob::KnobSessions>()?
+                .watch(&session_id, &account)
+                .map_err(Error::new)?,
+        ) as KnobStream)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    // -----------------------------------------------------------------------
+    // missing_reply
+
+    #[test]
+    fn test_missing_reply() {
+        let reply = missing_reply(
+            3,
+            global::StatusReply {
+                status: status::ACNET_REQTMO,
+                message: None,
+            },
+        );
+
+        assert_eq!(reply.ref_id, 3);
+        assert_eq!(reply.data.len(), 1);
+        assert_eq!(
+            reply.data[0].result,
+            global::DataType::StatusReply(global::StatusReply {
+                status: status::ACNET_REQTMO,
+                message: None,
+            })
+        );
+    }
+
+    // -----------------------------------------------------------------------
+    // to_data_info
+
+    #[test]
+    fn test_bad_readings() {
+        let value = device::Value {
+            value: Some(device::value::Value::Scalar(1.5)),
+        };
+        let is_status = |info: &global::DataInfo| {
+            matches!(
+                info.result,
+                global::DataType::StatusReply(global::StatusReply {
+                    status: -1,
+                    message: Some(_),
+                })
+            )
+        };
+
+        assert_eq!(
+            to_data_info(Some(10.0), Some(value.clone())),
+            global::DataInfo {
+                timestamp: 10.0,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: 1.5
+                }),
+            }
+        );
+        assert!(is_status(&to_data_info(None, Some(value))));
+        assert!(is_status(&to_data_info(Some(10.0), None)));
+        assert!(is_status(&to_data_info(
+            Some(10.0),
+            Some(device::Value { value: None })
+        )));
+    }
+
     // -----------------------------------------------------------------------
     // strip_event
 
@@ -1769,6 +2747,7 @@ mod test {
                 timestamp: 0.75,
                 result: global::DataType::StatusReply(global::StatusReply {
                     status: -17 * 256 + 17,
+                    message: None,
                 }),
             },
             global::DataInfo {
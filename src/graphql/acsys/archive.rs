@@ -0,0 +1,272 @@
+// Retrieves the archived data of a DRF from the archive that holds it:
+// the ACNET data loggers (through DPM) or an EPICS Archiver Appliance.
+// The services needed to do this are gathered in `ArchiveSources` so
+// archived data can be retrieved outside of a GraphQL request (e.g. by
+// the export endpoint.)
+
+use super::{
+    archivecache, archiver, datastream, device_name, drf, global, now, status,
+    strip_source, types, xlat_reply,
+};
+use crate::g_rpc::dpm::{self, Connection};
+use async_graphql::{Context, Error, Result};
+use futures::future::Either;
+use futures_util::{Stream, StreamExt, stream};
+use tracing::{instrument, warn};
+
+// How archived data is requested from the data loggers: the length of
+// each request, in seconds, and how many run at once.
+
+const LOGGER_CHUNK: f64 = 86_400.0;
+const LOGGER_PARALLEL: usize = 4;
+
+// Builds the warning that precedes archived data which came from the
+// data loggers because the EPICS archiver couldn't supply it.
+
+fn fallback_reply(reason: &str) -> global::DataReply {
+    global::DataReply {
+        ref_id: 0,
+        data: vec![global::DataInfo {
+            timestamp: now(),
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: status::EXTAPI_FALLBACK,
+                message: Some(format!(
+                    "not available from the EPICS archiver ({}) -- using \
+                     the ACNET data loggers",
+                    reason
+                )),
+            }),
+        }],
+    }
+}
+
+#[derive(Clone)]
+pub struct ArchiveSources {
+    conn: Connection,
+    cache: archivecache::ArchiveCache,
+    archivers: archiver::Archivers,
+}
+
+impl ArchiveSources {
+    pub fn new(
+        conn: Connection, cache: archivecache::ArchiveCache,
+        archivers: archiver::Archivers,
+    ) -> Self {
+        ArchiveSources {
+            conn,
+            cache,
+            archivers,
+        }
+    }
+
+    // Gathers the archive services from a GraphQL request's data.
+
+    pub fn from_context(ctxt: &Context<'_>) -> Result<Self> {
+        Ok(ArchiveSources::new(
+            ctxt.data::<Connection>()?.clone(),
+            ctxt.data::<archivecache::ArchiveCache>()?.clone(),
+            ctxt.data::<archiver::Archivers>()?.clone(),
+        ))
+    }
+
+    // Returns a stream containing archived data for a device from the
+    // data loggers. If `node` is specified, the data is retrieved from
+    // that data logger. Long ranges are requested from DPM in chunks,
+    // several at a time.
+
+    #[instrument(
+        name = "ACNET_ARCH",
+        skip(self, token, device, start_time, end_time, node)
+    )]
+    async fn logger_data(
+        &self, token: Option<String>, device: &str, start_time: f64,
+        end_time: f64, node: Option<&str>,
+    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
+    {
+        // If the device has a subscript, then the client wants
+        // archived data for an array device. Due to quirks in the
+        // array data logger, we can't specify the subscript or event.
+
+        let device = if device.find('[').is_some() {
+            device_name(device)
+        } else {
+            strip_source(device)
+        }
+        .to_owned();
+        let node = node.map(|v| format!(":{}", v)).unwrap_or_default();
+        let source = format!("LOGGER{}", node);
+        let cache = self.cache.clone();
+        let conn = self.conn.clone();
+
+        datastream::retrieve_chunks(
+            datastream::split_range(start_time, end_time, LOGGER_CHUNK),
+            LOGGER_PARALLEL,
+            move |start, end| {
+                // Build the DRF string needed for the archived data.
+
+                let drf = format!(
+                    "{}<-LOGGER:{}:{}{}",
+                    device,
+                    (start * 1_000.0) as u128,
+                    (end * 1_000.0) as u128,
+                    node
+                );
+                let key = archivecache::Key::new(&source, &device, start, end);
+                let conn = conn.clone();
+                let token = token.clone();
+
+                // Make the gRPC data request to DPM and stream the
+                // chunk's data.
+
+                cache.fetch(key, async move {
+                    let s =
+                        dpm::acquire_devices(&conn, token.as_ref(), vec![drf])
+                            .await
+                            .map_err(|e| e.to_string())?;
+
+                    Ok::<_, String>(
+                        datastream::as_archive_stream(
+                            s.into_inner().map(xlat_reply),
+                        )
+                        .boxed(),
+                    )
+                })
+            },
+        )
+        .await
+        .map_err(Error::new)
+    }
+
+    // Returns a stream of the archived data of a DRF from the given
+    // archive. There's no fallback; if the archive can't supply the
+    // data, an error is returned.
+
+    pub async fn fetch_from(
+        &self, token: Option<String>, drf: &str, source: &drf::ArchiveSource,
+        start_time: f64, end_time: f64,
+        aggregation: Option<types::Aggregation>,
+    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
+    {
+        match source {
+            drf::ArchiveSource::Epics => Ok(Either::Left(
+                self.archivers
+                    .fetch(drf, start_time, end_time, aggregation, &self.cache)
+                    .await?,
+            )),
+            drf::ArchiveSource::Logger(node) => {
+                let acnet = self
+                    .logger_data(
+                        token,
+                        drf,
+                        start_time,
+                        end_time,
+                        node.as_deref(),
+                    )
+                    .await?;
+
+                Ok(Either::Right(
+                    datastream::aggregate(acnet, aggregation)
+                        .map_err(Error::new)?,
+                ))
+            }
+        }
+    }
+
+    // Returns a stream of the archived data of a DRF, along with the
+    // archive it came from. The archive is chosen by
+    // `drf::archive_source`. EPICS PVs are retrieved from the archiver;
+    // if the source was only inferred from the name, the device may
+    // still be an ACNET device, so the data loggers are tried next and
+    // the stream starts with a warning explaining why.
+
+    pub async fn fetch_with_source(
+        &self, token: Option<String>, drf: &str, start_time: f64,
+        end_time: f64, aggregation: Option<types::Aggregation>,
+    ) -> Result<(
+        drf::ArchiveSource,
+        impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+    )> {
+        let (source, explicit) =
+            drf::archive_source(drf).map_err(Error::new)?;
+
+        match self
+            .fetch_from(
+                token.clone(),
+                drf,
+                &source,
+                start_time,
+                end_time,
+                aggregation,
+            )
+            .await
+        {
+            Ok(s) => Ok((source, Either::Left(s))),
+            Err(e) if explicit || source != drf::ArchiveSource::Epics => Err(e),
+            Err(e) => {
+                warn!(
+                    "EPICS archiver failed for {} -- using the data loggers: {}",
+                    drf, &e.message
+                );
+
+                let source = drf::ArchiveSource::Logger(None);
+                let acnet = self
+                    .fetch_from(
+                        token,
+                        drf,
+                        &source,
+                        start_time,
+                        end_time,
+                        aggregation,
+                    )
+                    .await?;
+
+                Ok((
+                    source,
+                    Either::Right(
+                        stream::iter([fallback_reply(&e.message)]).chain(acnet),
+                    ),
+                ))
+            }
+        }
+    }
+
+    // Returns a stream of the archived data of a DRF. See
+    // `fetch_with_source` for how the archive is chosen.
+
+    pub async fn fetch(
+        &self, token: Option<String>, drf: &str, start_time: f64,
+        end_time: f64, aggregation: Option<types::Aggregation>,
+    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
+    {
+        self.fetch_with_source(token, drf, start_time, end_time, aggregation)
+            .await
+            .map(|(_, s)| s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fallback_reply() {
+        let reply = fallback_reply("HTTP status 404");
+
+        assert_eq!(reply.data.len(), 1);
+
+        let global::DataType::StatusReply(ref status) = reply.data[0].result
+        else {
+            panic!("expected a status reply");
+        };
+
+        assert_eq!(status.status, status::EXTAPI_FALLBACK);
+        assert!(status.status > 0);
+        assert!(
+            status
+                .message
+                .as_deref()
+                .unwrap()
+                .contains("HTTP status 404")
+        );
+    }
+}
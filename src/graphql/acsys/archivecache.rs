@@ -0,0 +1,516 @@
+// Keeps recently retrieved archived data in memory. Archived data is
+// requested in chunks (see `datastream::retrieve_chunks`) and each
+// chunk is cached by its source, device and time range, so re-plotting
+// the same period is served without going back to the archive. Only
+// ranges that ended a while ago are cached; more recent data may still
+// be making its way into the archives.
+//
+// The cache is bounded by the approximate memory used by its data. When
+// it's full, the least recently used chunks are dropped.
+
+use super::{datastream::Replies, global, now, types};
+use async_stream::stream;
+use futures::{Future, StreamExt, stream};
+use rust_env_var_lib::env_var;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+const CACHE_SIZE: &str = "ARCHIVE_CACHE_MB";
+
+// The cache size used when `ARCHIVE_CACHE_MB` isn't set.
+
+const DEFAULT_CACHE_MB: usize = 256;
+
+// How long, in seconds, after the end of a range before its data is
+// considered complete.
+
+const SETTLE_TIME: f64 = 3_600.0;
+
+// Identifies a chunk of archived data. The times are kept in
+// milliseconds so the key can be hashed.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    source: String,
+    device: String,
+    start: i64,
+    end: i64,
+}
+
+impl Key {
+    pub fn new(source: &str, device: &str, start: f64, end: f64) -> Self {
+        Key {
+            source: source.into(),
+            device: device.into(),
+            start: (start * 1_000.0).round() as i64,
+            end: (end * 1_000.0).round() as i64,
+        }
+    }
+}
+
+struct Entry {
+    data: Arc<Vec<global::DataReply>>,
+    size: usize,
+    used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl State {
+    // Marks an entry as the most recently used.
+
+    fn touch(&mut self, key: &Key) -> Option<Arc<Vec<global::DataReply>>> {
+        let entry = self.entries.get_mut(key)?;
+
+        self.clock += 1;
+        self.lru.remove(&entry.used);
+        self.lru.insert(self.clock, key.clone());
+        entry.used = self.clock;
+        Some(entry.data.clone())
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+// The cache's counters.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl From<Statistics> for types::ArchiveCacheStatistics {
+    fn from(v: Statistics) -> Self {
+        types::ArchiveCacheStatistics {
+            hits: v.hits,
+            misses: v.misses,
+            evictions: v.evictions,
+            entries: v.entries as u64,
+            bytes: v.bytes as u64,
+            capacity: v.capacity as u64,
+        }
+    }
+}
+
+// Returns the approximate memory used by a reading.
+
+pub(super) fn info_size(info: &global::DataInfo) -> usize {
+    std::mem::size_of::<global::DataInfo>()
+        + match info.result {
+            global::DataType::ScalarArray(ref v) => {
+                v.scalar_array_value.len() * std::mem::size_of::<f64>()
+            }
+            global::DataType::Raw(ref v) => v.raw_value.len(),
+            global::DataType::Text(ref v) => v.text_value.len(),
+            global::DataType::TextArray(ref v) => v
+                .text_array_value
+                .iter()
+                .map(|v| v.len() + std::mem::size_of::<String>())
+                .sum(),
+            global::DataType::EnumValue(ref v) => {
+                v.label.as_ref().map(String::len).unwrap_or(0)
+            }
+            global::DataType::StatusReply(ref v) => {
+                v.message.as_ref().map(String::len).unwrap_or(0)
+            }
+            _ => 0,
+        }
+}
+
+fn data_size(data: &[global::DataReply]) -> usize {
+    data.iter()
+        .map(|reply| {
+            std::mem::size_of::<global::DataReply>()
+                + reply.data.iter().map(info_size).sum::<usize>()
+        })
+        .sum()
+}
+
+// Returns `true` if the data can be cached. Data containing an error
+// status is incomplete.
+
+fn is_complete(data: &[global::DataReply]) -> bool {
+    !data.iter().flat_map(|v| v.data.iter()).any(|v| {
+        matches!(v.result, global::DataType::StatusReply(ref s) if s.status < 0)
+    })
+}
+
+// Returns `true` if a reply marks the clean end of a chunk: an empty
+// reply or a lone status, as the data loggers end their streams. A
+// stream that stops without one was cut short.
+
+fn is_end(reply: &global::DataReply) -> bool {
+    matches!(
+        reply.data.as_slice(),
+        [] | [global::DataInfo {
+            result: global::DataType::StatusReply(_),
+            ..
+        }]
+    )
+}
+
+struct Inner {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Clone)]
+pub struct ArchiveCache(Arc<Inner>);
+
+impl ArchiveCache {
+    // Creates a cache holding, approximately, `capacity` bytes of data.
+    // A capacity of zero disables the cache.
+
+    pub fn new(capacity: usize) -> Self {
+        ArchiveCache(Arc::new(Inner {
+            capacity,
+            state: Mutex::new(State::default()),
+        }))
+    }
+
+    // Creates the cache described by `ARCHIVE_CACHE_MB`.
+
+    pub fn from_env() -> Self {
+        let mb = match env_var::get(CACHE_SIZE).to_option::<String>() {
+            Some(v) => v.trim().parse::<usize>().unwrap_or_else(|e| {
+                panic!("bad {} setting -- {}", CACHE_SIZE, e)
+            }),
+            None => DEFAULT_CACHE_MB,
+        };
+
+        info!("caching up to {} MB of archived data", mb);
+        ArchiveCache::new(mb * 1_048_576)
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Arc<Vec<global::DataReply>>> {
+        let mut state = self.0.state.lock().unwrap();
+        let result = state.touch(key);
+
+        if result.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        result
+    }
+
+    // Adds a chunk to the cache, making room for it by dropping the
+    // least recently used chunks. Chunks larger than the cache aren't
+    // saved.
+
+    pub fn insert(&self, key: Key, data: Arc<Vec<global::DataReply>>) {
+        let capacity = self.0.capacity;
+        let size = data_size(&data);
+
+        if size > capacity {
+            return;
+        }
+
+        let mut state = self.0.state.lock().unwrap();
+
+        state.remove(&key);
+        while state.bytes + size > capacity {
+            let Some((_, oldest)) = state.lru.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = state.entries.remove(&oldest) {
+                state.bytes -= entry.size;
+                state.evictions += 1;
+            }
+        }
+
+        state.clock += 1;
+
+        let used = state.clock;
+
+        state.lru.insert(used, key.clone());
+        state.entries.insert(key, Entry { data, size, used });
+        state.bytes += size;
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        let state = self.0.state.lock().unwrap();
+
+        Statistics {
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+            entries: state.entries.len(),
+            bytes: state.bytes,
+            capacity: self.0.capacity,
+        }
+    }
+
+    // Returns a chunk's data from the cache or, if it isn't there,
+    // opens it with `fetch`. Ranges that haven't settled are always
+    // retrieved. Retrieved data is saved as it's passed along and, once
+    // all of it has been read and the source has marked its end, added
+    // to the cache. Data that outgrows the cache isn't kept.
+
+    pub fn fetch<Fut>(
+        &self, key: Key, fetch: Fut,
+    ) -> impl Future<Output = Result<Replies, String>> + Send + 'static
+    where
+        Fut: Future<Output = Result<Replies, String>> + Send + 'static,
+    {
+        let cache = self.clone();
+        let settled = self.0.capacity > 0
+            && (key.end as f64 / 1_000.0) + SETTLE_TIME < now();
+
+        async move {
+            if !settled {
+                return fetch.await;
+            }
+
+            if let Some(data) = cache.get(&key) {
+                return Ok(stream::iter(
+                    (0..data.len()).map(move |idx| data[idx].clone()),
+                )
+                .boxed());
+            }
+
+            let mut replies = fetch.await?;
+
+            Ok(stream! {
+                let mut saved = Some(vec![]);
+                let mut size = 0;
+                let mut ended = false;
+
+                while let Some(reply) = replies.next().await {
+                    let data = std::slice::from_ref(&reply);
+
+                    ended = is_end(&reply);
+                    size += data_size(data);
+                    if size > cache.0.capacity || !is_complete(data) {
+                        saved = None;
+                    }
+                    if let Some(ref mut saved) = saved {
+                        saved.push(reply.clone());
+                    }
+                    yield reply;
+                }
+
+                if let Some(saved) = saved
+                    && ended
+                {
+                    cache.insert(key, Arc::new(saved));
+                }
+            }
+            .boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(ts: &[f64]) -> Vec<global::DataReply> {
+        vec![global::DataReply {
+            ref_id: 0,
+            data: ts
+                .iter()
+                .map(|v| global::DataInfo {
+                    timestamp: *v,
+                    result: global::DataType::Scalar(global::Scalar {
+                        scalar_value: *v,
+                    }),
+                })
+                .collect(),
+        }]
+    }
+
+    // Builds a chunk as a source returns it: its data followed by the
+    // empty reply marking its end.
+
+    fn finished(ts: &[f64]) -> Vec<global::DataReply> {
+        let mut data = chunk(ts);
+
+        data.push(global::DataReply::default());
+        data
+    }
+
+    #[test]
+    fn test_lru() {
+        let size = data_size(&chunk(&[1.0, 2.0]));
+        let cache = ArchiveCache::new(size * 2);
+        let key = |v: f64| Key::new("LOGGER", "M:OUTTMP", v, v + 1.0);
+
+        cache.insert(key(1.0), Arc::new(chunk(&[1.0, 2.0])));
+        cache.insert(key(2.0), Arc::new(chunk(&[2.0, 3.0])));
+
+        // Using the first chunk makes the second one the oldest.
+
+        assert!(cache.get(&key(1.0)).is_some());
+        cache.insert(key(3.0), Arc::new(chunk(&[3.0, 4.0])));
+
+        assert!(cache.get(&key(2.0)).is_none());
+        assert!(cache.get(&key(1.0)).is_some());
+        assert!(cache.get(&key(3.0)).is_some());
+
+        // Chunks that don't fit aren't saved.
+
+        cache.insert(key(4.0), Arc::new(chunk(&[0.0; 10])));
+        assert!(cache.get(&key(4.0)).is_none());
+
+        assert_eq!(
+            cache.statistics(),
+            Statistics {
+                hits: 3,
+                misses: 2,
+                evictions: 1,
+                entries: 2,
+                bytes: size * 2,
+                capacity: size * 2,
+            }
+        );
+    }
+
+    async fn read(
+        data: Result<Replies, String>,
+    ) -> Result<Vec<global::DataReply>, String> {
+        Ok(data?.collect().await)
+    }
+
+    fn replies(data: Vec<global::DataReply>) -> Result<Replies, String> {
+        Ok(stream::iter(data).boxed())
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let cache = ArchiveCache::new(1_048_576);
+        let old = Key::new("EPICS", "PV:A", 100.0, 200.0);
+        let recent = Key::new("EPICS", "PV:A", now() - 60.0, now());
+
+        // Settled ranges are retrieved once.
+
+        for _ in 0..2 {
+            let data = cache
+                .fetch(old.clone(), async { replies(finished(&[150.0])) })
+                .await;
+
+            assert_eq!(read(data).await, Ok(finished(&[150.0])));
+        }
+
+        // Recent ranges, and failures, aren't cached.
+
+        let _ = read(
+            cache
+                .fetch(recent.clone(), async { replies(finished(&[1.0])) })
+                .await,
+        )
+        .await;
+        let _ = cache
+            .fetch(Key::new("EPICS", "PV:B", 1.0, 2.0), async {
+                Err(String::from("failed"))
+            })
+            .await;
+
+        let stats = cache.statistics();
+
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+        assert!(cache.get(&recent).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_partial_fetch() {
+        let size = data_size(&chunk(&[1.0]));
+        let cache = ArchiveCache::new(size * 2);
+        let key = |v: f64| Key::new("EPICS", "PV:A", v, v + 1.0);
+        let data = || {
+            let mut data = chunk(&[1.0]);
+
+            data.extend(chunk(&[2.0]));
+            data.extend(finished(&[3.0]));
+            data
+        };
+
+        // Chunks that weren't completely read aren't saved.
+
+        let partial: Vec<_> = cache
+            .fetch(key(1.0), async move { replies(data()) })
+            .await
+            .unwrap()
+            .take(1)
+            .collect()
+            .await;
+
+        assert_eq!(partial.len(), 1);
+        assert!(cache.get(&key(1.0)).is_none());
+
+        // Nor are chunks that outgrow the cache, though all their data
+        // is returned.
+
+        let all =
+            read(cache.fetch(key(2.0), async move { replies(data()) }).await)
+                .await;
+
+        assert_eq!(all, Ok(data()));
+        assert!(cache.get(&key(2.0)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch() {
+        let cache = ArchiveCache::new(1_048_576);
+        let key = |v: f64| Key::new("EPICS", "PV:A", v, v + 1.0);
+        let error = global::DataReply {
+            ref_id: 0,
+            data: vec![global::DataInfo {
+                timestamp: 2.0,
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status: -1,
+                    message: Some("connection reset".into()),
+                }),
+            }],
+        };
+
+        // A download that fails partway through a chunk reports the
+        // failure after the data it read. None of it is saved.
+
+        let mut data = chunk(&[1.0]);
+
+        data.push(error);
+
+        let expected = data.clone();
+        let result =
+            read(cache.fetch(key(1.0), async move { replies(data) }).await)
+                .await;
+
+        assert_eq!(result, Ok(expected));
+        assert!(cache.get(&key(1.0)).is_none());
+
+        // Nor is a chunk whose stream stopped before marking its end.
+
+        let _ = read(
+            cache
+                .fetch(key(2.0), async { replies(chunk(&[2.0, 3.0])) })
+                .await,
+        )
+        .await;
+
+        assert!(cache.get(&key(2.0)).is_none());
+    }
+}
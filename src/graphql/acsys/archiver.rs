@@ -0,0 +1,1621 @@
+// Retrieves archived data from EPICS Archiver Appliances. The service
+// can be configured with several appliances (the `EPICS_ARCHIVERS`
+// environment variable.) Each PV is archived by one of them so, before
+// data is requested, the appliance holding the PV is found by asking
+// each appliance's management interface (`getPVStatus` and
+// `getApplianceInfo`.) The results are cached so the lookups are only
+// done occasionally.
+
+use super::{archivecache, datastream, device_name, global, now, types};
+use async_graphql::{Error, Result};
+use futures_util::{Stream, StreamExt, future, stream};
+use reqwest::Url;
+use rust_env_var_lib::env_var;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio_util::io::StreamReader;
+use tracing::{error, info, instrument, warn};
+
+mod metadata;
+#[cfg(test)]
+pub mod mock;
+mod pb;
+
+pub use metadata::{DESCRIBE_PARALLEL, MAX_SEARCH};
+
+const ARCHIVERS: &str = "EPICS_ARCHIVERS";
+const ARCHIVER_FORMAT: &str = "EPICS_ARCHIVER_FORMAT";
+
+// The appliance used when `EPICS_ARCHIVERS` isn't set.
+
+const DEFAULT_ARCHIVER: &str = "http://archiver1.fnal.gov:17668/retrieval";
+
+// How long the location of a PV is remembered. PVs that weren't found
+// are looked up again sooner since they may be added to an appliance
+// at any time.
+
+const ROUTE_TTL: Duration = Duration::from_secs(600);
+const MISS_TTL: Duration = Duration::from_secs(60);
+
+// The time allowed for a management request.
+
+const BPL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How archived data is requested from an appliance: the length of each
+// request, in seconds, and how many run at once. The appliance handles
+// long requests well, so the chunks are larger than the ones used for
+// the ACNET loggers.
+
+const ARCHIVER_CHUNK: f64 = 7.0 * 86_400.0;
+const ARCHIVER_PARALLEL: usize = 4;
+
+// The fields of EPICS's binary (`bi`, `bo`) and multi-bit (`mbbi`,
+// `mbbo`) records that name their states, in state order. When the
+// appliance is configured to archive them, they're returned with the
+// PV's metadata.
+
+const BINARY_LABELS: [&str; 2] = ["ZNAM", "ONAM"];
+const MULTIBIT_LABELS: [&str; 16] = [
+    "ZRST", "ONST", "TWST", "THST", "FRST", "FVST", "SXST", "SVST", "EIST",
+    "NIST", "TEST", "ELST", "TVST", "TTST", "FTST", "FFST",
+];
+
+// The names of the states of an enumerated PV. An empty set means the
+// PV's states aren't known.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct EnumLabels(Vec<Option<String>>);
+
+impl EnumLabels {
+    // Finds the labels in a PV's metadata fields. Blank labels are
+    // treated as missing.
+
+    fn from_fields<'a>(
+        fields: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Self {
+        let fields: HashMap<&str, &str> = fields
+            .into_iter()
+            .filter(|(_, v)| !v.trim().is_empty())
+            .collect();
+        let names: &[&str] =
+            if MULTIBIT_LABELS.iter().any(|v| fields.contains_key(v)) {
+                &MULTIBIT_LABELS
+            } else {
+                &BINARY_LABELS
+            };
+        let labels: Vec<_> = names
+            .iter()
+            .map(|v| fields.get(v).map(|v| v.to_string()))
+            .collect();
+
+        if labels.iter().all(Option::is_none) {
+            EnumLabels::default()
+        } else {
+            EnumLabels(labels)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Returns the reading of a state.
+
+    fn value(&self, state: i32) -> global::DataType {
+        global::DataType::EnumValue(global::EnumValue {
+            enum_value: state,
+            label: usize::try_from(state)
+                .ok()
+                .and_then(|idx| self.0.get(idx).cloned().flatten()),
+        })
+    }
+}
+
+// Represents a single PV data point (event)
+
+/// Custom deserializer that converts JSON values directly to Vec<f64>,
+/// Vec<String> or a scalar, avoiding intermediate Value allocations. This
+/// allows us to parse arrays directly to f64 without heap overhead from
+/// Value enum wrappers.
+fn deserialize_archiver_value<'de, D>(
+    deserializer: D,
+) -> Result<ArchiverValue, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::{self, Visitor};
+
+    struct ArchiverValueVisitor;
+
+    impl<'de> Visitor<'de> for ArchiverValueVisitor {
+        type Value = ArchiverValue;
+
+        fn expecting(
+            &self, formatter: &mut std::fmt::Formatter,
+        ) -> std::fmt::Result {
+            formatter.write_str(
+                "a number, a string or an array of numbers or strings",
+            )
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(ArchiverValue::Integer(v))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(i64::try_from(v)
+                .map(ArchiverValue::Integer)
+                .unwrap_or(ArchiverValue::Scalar(v as f64)))
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(ArchiverValue::Scalar(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(ArchiverValue::Text(v.into()))
+        }
+
+        fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(ArchiverValue::Text(v))
+        }
+
+        // The type of an array is decided by its first element. An
+        // empty array is an empty waveform.
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let Some(first) = seq.next_element::<ArrayElement>()? else {
+                return Ok(ArchiverValue::Array(vec![]));
+            };
+
+            match first {
+                ArrayElement::Number(v) => {
+                    let mut array = vec![v];
+
+                    while let Some(val) =
+                        seq.next_element::<serde_json::Number>()?
+                    {
+                        if let Some(f) = val.as_f64() {
+                            array.push(f);
+                        }
+                    }
+                    Ok(ArchiverValue::Array(array))
+                }
+                ArrayElement::Text(v) => {
+                    let mut array = vec![v];
+
+                    while let Some(val) = seq.next_element::<String>()? {
+                        array.push(val);
+                    }
+                    Ok(ArchiverValue::TextArray(array))
+                }
+            }
+        }
+    }
+
+    deserializer.deserialize_any(ArchiverValueVisitor)
+}
+
+// The first element of an array value, which is either a number or a
+// string.
+
+enum ArrayElement {
+    Number(f64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for ArrayElement {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::{self, Visitor};
+
+        struct ElementVisitor;
+
+        impl Visitor<'_> for ElementVisitor {
+            type Value = ArrayElement;
+
+            fn expecting(
+                &self, formatter: &mut std::fmt::Formatter,
+            ) -> std::fmt::Result {
+                formatter.write_str("a number or a string")
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(ArrayElement::Number(v as f64))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(ArrayElement::Number(v as f64))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(ArrayElement::Number(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(ArrayElement::Text(v.into()))
+            }
+        }
+
+        deserializer.deserialize_any(ElementVisitor)
+    }
+}
+
+/// Optimized representation of archiver event values.
+/// Stores a number, a string or an array of either, avoiding Value enum
+/// overhead. Integers are kept apart since they may be enumerated states.
+#[derive(Debug)]
+enum ArchiverValue {
+    Integer(i64),
+    Scalar(f64),
+    Array(Vec<f64>),
+    Text(String),
+    TextArray(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchiverEvent {
+    pub secs: i64,
+    pub nanos: u32,
+    #[serde(deserialize_with = "deserialize_archiver_value")]
+    pub val: ArchiverValue,
+}
+
+// Converts an f64 representing time since the epoch into an RFC
+// 3339 string.
+
+fn to_iso(timestamp_f64: f64) -> String {
+    use chrono::{TimeZone, Utc};
+
+    let seconds = timestamp_f64.trunc() as i64;
+    let nanoseconds = {
+        let nanos = (timestamp_f64.fract() * 1_000_000_000.0).round() as i64;
+        let nanos = nanos.clamp(0, 999_999_999);
+        nanos as u32
+    };
+    let datetime_utc = Utc
+        .timestamp_opt(seconds, nanoseconds)
+        .earliest()
+        .unwrap_or_else(|| {
+            Utc.timestamp_millis_opt(0).unwrap() // Default to epoch start
+        });
+
+    datetime_utc.format("%Y-%m-%dT%H:%M:%S.%fZ").to_string()
+}
+
+// Converts a sample to a reply. The JSON format doesn't give the type
+// of a PV so, if the PV's metadata has state labels, its integer
+// values are treated as enumerated states.
+
+fn transform_event(
+    event: ArchiverEvent, labels: &EnumLabels,
+) -> global::DataReply {
+    let timestamp = event.secs as f64 + (event.nanos as f64 / 1_000_000_000.0);
+    let result = match event.val {
+        ArchiverValue::Integer(v) if !labels.is_empty() => {
+            match i32::try_from(v) {
+                Ok(state) => labels.value(state),
+                Err(_) => global::DataType::Scalar(global::Scalar {
+                    scalar_value: v as f64,
+                }),
+            }
+        }
+        ArchiverValue::Integer(v) => global::DataType::Scalar(global::Scalar {
+            scalar_value: v as f64,
+        }),
+        ArchiverValue::Array(arr) => {
+            global::DataType::ScalarArray(global::ScalarArray {
+                scalar_array_value: arr,
+            })
+        }
+        ArchiverValue::Scalar(scalar) => {
+            global::DataType::Scalar(global::Scalar {
+                scalar_value: scalar,
+            })
+        }
+        ArchiverValue::Text(text) => {
+            global::DataType::Text(global::Text { text_value: text })
+        }
+        ArchiverValue::TextArray(arr) => {
+            global::DataType::TextArray(global::TextArray {
+                text_array_value: arr,
+            })
+        }
+    };
+
+    global::DataReply {
+        ref_id: 0,
+        data: vec![global::DataInfo { timestamp, result }],
+    }
+}
+
+// An archiver appliance. `retrieval` is the base URL of the retrieval
+// service (e.g. "http://archiver1.fnal.gov:17668/retrieval".) If
+// `mgmt` is given, it's the base URL of the management BPL (e.g.
+// "http://archiver1.fnal.gov:17665/mgmt/bpl") and is used to find
+// which PVs the appliance archives.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Appliance {
+    pub retrieval: String,
+    pub mgmt: Option<String>,
+}
+
+// Parses the list of appliances. Entries are separated by commas and
+// each entry is a retrieval URL optionally followed by '|' and a
+// management URL.
+
+pub fn parse_config(config: &str) -> Result<Vec<Appliance>, String> {
+    let url = |v: &str| -> Result<String, String> {
+        let v = v.trim().trim_end_matches('/');
+
+        if v.starts_with("http://") || v.starts_with("https://") {
+            Ok(v.to_owned())
+        } else {
+            Err(format!("bad archiver URL: {:?}", v))
+        }
+    };
+    let result = config
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|entry| match entry.split_once('|') {
+            Some((retrieval, mgmt)) => Ok(Appliance {
+                retrieval: url(retrieval)?,
+                mgmt: Some(url(mgmt)?),
+            }),
+            None => Ok(Appliance {
+                retrieval: url(entry)?,
+                mgmt: None,
+            }),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if result.is_empty() {
+        Err("no archivers specified".into())
+    } else {
+        Ok(result)
+    }
+}
+
+// The format used to retrieve data. `Json` is the appliance's JSON
+// format. `Raw` is its PB/HTTP format, which is more compact and much
+// cheaper to decode, especially for waveforms.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Raw,
+}
+
+impl Format {
+    pub fn parse(v: &str) -> Result<Self, String> {
+        match v.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "raw" | "pb" => Ok(Format::Raw),
+            _ => Err(format!("unknown archiver format: {:?}", v)),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Raw => "raw",
+        }
+    }
+}
+
+// Returns the `pv` parameter of a data request. If an aggregation is
+// given, the PV name is wrapped in the equivalent post-processing
+// operator (e.g. "mean_3600(PV)".)
+
+pub fn pv_param(
+    pv: &str, aggregation: Option<&types::Aggregation>,
+) -> Result<String, String> {
+    use types::AggregationOperator as Op;
+
+    let Some(aggregation) = aggregation else {
+        return Ok(pv.into());
+    };
+    let operator = match aggregation.operator {
+        Op::Mean => "mean",
+        Op::Min => "min",
+        Op::Max => "max",
+        Op::First => "firstSample",
+        Op::Last => "lastSample",
+        Op::Count => "count",
+        Op::Std => "std",
+        Op::Median => "median",
+        Op::Nth => "nth",
+    };
+
+    Ok(format!(
+        "{}_{}({})",
+        operator,
+        datastream::bin_width(aggregation)?,
+        pv
+    ))
+}
+
+// The replies of the management BPL that are used. Other fields are
+// ignored.
+
+#[derive(Debug, Deserialize)]
+struct PvStatus {
+    status: String,
+    appliance: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplianceInfo {
+    #[serde(rename = "dataRetrievalURL")]
+    data_retrieval_url: String,
+}
+
+// A cached PV location. If `url` is `None`, no appliance archives the
+// PV.
+
+struct Route {
+    expires: Instant,
+    url: Option<Arc<str>>,
+}
+
+struct Inner {
+    appliances: Vec<Appliance>,
+    format: Format,
+    client: reqwest::Client,
+    routes: Mutex<HashMap<String, Route>>,
+    info: metadata::Caches,
+}
+
+// The archiver appliances used by the ACSys schema. An instance of
+// this is added to the schema's data.
+
+#[derive(Clone)]
+pub struct Archivers(Arc<Inner>);
+
+// Reads the response until one of `keys` has been read and returns
+// the index of the key that was found. A key may be split across
+// reads, so the end of each read is kept and searched along with the
+// next one.
+
+async fn skip_past<R: tokio::io::AsyncBufRead + Unpin>(
+    r: &mut R, keys: &[&[u8]],
+) -> std::io::Result<usize> {
+    let longest = keys.iter().map(|k| k.len()).max().unwrap_or(0);
+    let mut text: Vec<u8> = vec![];
+
+    loop {
+        let buf = r.fill_buf().await?;
+
+        if buf.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let kept = text.len();
+
+        text.extend_from_slice(buf);
+
+        let found = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, key)| {
+                let pos = text.windows(key.len()).position(|w| w == *key)?;
+
+                Some((pos + key.len(), idx))
+            })
+            .min();
+
+        if let Some((end, idx)) = found {
+            r.consume(end - kept);
+            return Ok(idx);
+        }
+
+        let len = text.len() - kept;
+
+        r.consume(len);
+        text.drain(..text.len().saturating_sub(longest.saturating_sub(1)));
+    }
+}
+
+// Advances the response, which has been read past the "data" key, up
+// to, and including, the first '[' so that it's pointing to the first
+// object in the array.
+
+async fn seek_to_data_array<R: tokio::io::AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> std::io::Result<()> {
+    loop {
+        if r.read_u8().await? == b'[' {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Reads the next object of an array and returns its text, or `None`
+// if the array has ended. Braces inside strings, like a text sample or
+// a metadata label holding `{` or `}`, don't count toward the object's
+// nesting.
+
+async fn extract_next_object<R: tokio::io::AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut obj_bytes = Vec::new();
+    let mut brace_count = 0;
+    let mut in_object = false;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    loop {
+        let buf = r.fill_buf().await?;
+
+        // The response ended before the array was closed.
+
+        if buf.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        for (i, &b) in buf.iter().enumerate() {
+            if !in_object {
+                if b == b'{' {
+                    in_object = true;
+                    brace_count = 1;
+                    obj_bytes.push(b);
+                } else if b == b']' {
+                    return Ok(None); // End of the data array
+                }
+                // Skip commas/whitespace
+                continue;
+            }
+
+            obj_bytes.push(b);
+
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_string = false;
+                }
+            } else if b == b'"' {
+                in_string = true;
+            } else if b == b'{' {
+                brace_count += 1;
+            } else if b == b'}' {
+                brace_count -= 1;
+                if brace_count == 0 {
+                    r.consume(i + 1);
+                    return Ok(Some(obj_bytes));
+                }
+            }
+        }
+
+        let len = buf.len();
+
+        r.consume(len);
+    }
+}
+
+// Reads the response up to, and including, the "data" key. If the
+// metadata precedes it, as the appliance sends it, the labels of an
+// enumerated PV's states are returned.
+
+async fn read_labels<R: tokio::io::AsyncBufRead + Unpin>(
+    r: &mut R,
+) -> std::io::Result<EnumLabels> {
+    const META: &[u8] = b"\"meta\"";
+    const DATA: &[u8] = b"\"data\"";
+
+    if skip_past(r, &[META, DATA]).await? == 1 {
+        return Ok(EnumLabels::default());
+    }
+
+    let labels = extract_next_object(r)
+        .await?
+        .and_then(|bytes| {
+            serde_json::from_slice::<HashMap<String, serde_json::Value>>(&bytes)
+                .ok()
+        })
+        .map(|meta| {
+            EnumLabels::from_fields(
+                meta.iter()
+                    .filter_map(|(k, v)| Some((k.as_str(), v.as_str()?))),
+            )
+        })
+        .unwrap_or_default();
+
+    skip_past(r, &[DATA]).await?;
+    Ok(labels)
+}
+
+// Builds the status reported when a response couldn't be decoded. It
+// ends the stream so the client knows the data was cut short.
+
+fn error_reply(message: String) -> global::DataReply {
+    error!("{}", message);
+    global::DataReply {
+        ref_id: 0,
+        data: vec![global::DataInfo {
+            timestamp: now(),
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: Some(message),
+            }),
+        }],
+    }
+}
+
+// Returns `true` if a reply holds an error status.
+
+fn is_failure(reply: &global::DataReply) -> bool {
+    reply.data.iter().any(|info| {
+        matches!(info.result, global::DataType::StatusReply(ref v) if v.status < 0)
+    })
+}
+
+// Returns a stream of the samples in a JSON response. A response that
+// ends before its "data" key holds no samples. Any other failure is
+// reported with an error status, which ends the stream.
+
+fn json_events<R>(
+    reader: R,
+) -> impl Stream<Item = global::DataReply> + Send + 'static
+where
+    R: tokio::io::AsyncBufRead + Unpin + Send + 'static,
+{
+    stream::unfold(Some((reader, None)), |state| async move {
+        let (mut r, labels) = state?;
+
+        // SEEK PHASE: Only runs once
+
+        let labels = match labels {
+            Some(labels) => labels,
+            None => {
+                let found = async {
+                    let labels = read_labels(&mut r).await?;
+
+                    seek_to_data_array(&mut r).await?;
+                    Ok::<_, std::io::Error>(labels)
+                }
+                .await;
+
+                match found {
+                    Ok(labels) => labels,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return None;
+                    }
+                    Err(e) => {
+                        let msg = format!("failed to read archiver data: {e}");
+
+                        return Some((error_reply(msg), None));
+                    }
+                }
+            }
+        };
+
+        // PARSE PHASE: Extract one object from the array
+
+        let bytes = match extract_next_object(&mut r).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return None,
+            Err(e) => {
+                let msg = format!("failed to read archiver data: {e}");
+
+                return Some((error_reply(msg), None));
+            }
+        };
+
+        match serde_json::from_slice::<ArchiverEvent>(&bytes) {
+            Ok(item) => {
+                let reply = transform_event(item, &labels);
+
+                Some((reply, Some((r, Some(labels)))))
+            }
+            Err(e) => {
+                let msg = format!(
+                    "failed to deserialize archiver data: {e}, offending bytes: {}",
+                    String::from_utf8_lossy(&bytes)
+                );
+
+                Some((error_reply(msg), None))
+            }
+        }
+    })
+}
+
+// Returns the length of the requests used to retrieve a PV. Aggregated
+// data is requested in whole numbers of bins so a bin is never split
+// between two requests. `NTH` counts samples from the start of the
+// request, so it isn't split at all.
+
+fn chunk_size(aggregation: Option<&types::Aggregation>) -> f64 {
+    match aggregation {
+        Some(agg) if agg.operator == types::AggregationOperator::Nth => {
+            f64::INFINITY
+        }
+        Some(agg) => {
+            let width = datastream::bin_width(agg).unwrap_or(1) as f64;
+
+            (ARCHIVER_CHUNK / width).ceil() * width
+        }
+        None => ARCHIVER_CHUNK,
+    }
+}
+
+// Adds the range of a chunk to a `getData` URL.
+
+fn with_range(url: &Url, start: f64, end: f64) -> Url {
+    let mut url = url.clone();
+
+    url.query_pairs_mut()
+        .append_pair("from", &to_iso(start))
+        .append_pair("to", &to_iso(end));
+    url
+}
+
+// Opens the stream of one chunk of archived data. The data is decoded
+// as it arrives.
+
+async fn download(
+    client: reqwest::Client, url: Url, format: Format,
+) -> Result<datastream::Replies, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+
+    let byte_stream = response
+        .bytes_stream()
+        .map(|res| res.map_err(std::io::Error::other));
+
+    let reader = BufReader::new(StreamReader::new(byte_stream));
+
+    let strm = match format {
+        Format::Json => json_events(reader).boxed(),
+        Format::Raw => pb::events(reader).boxed(),
+    };
+
+    // A response that was completely decoded is followed by an empty
+    // reply, as the data loggers end their chunks, so the archive cache
+    // knows the chunk is whole. A failed one ends with its error status.
+
+    let mut failed = false;
+
+    Ok(datastream::group_scalars::<500, _>(strm)
+        .map(Some)
+        .chain(stream::iter([None]))
+        .filter_map(move |reply| {
+            future::ready(match reply {
+                Some(reply) => {
+                    failed |= is_failure(&reply);
+                    Some(reply)
+                }
+                None => (!failed).then(global::DataReply::default),
+            })
+        })
+        .boxed())
+}
+
+impl Archivers {
+    pub fn new(appliances: Vec<Appliance>, format: Format) -> Self {
+        Archivers(Arc::new(Inner {
+            appliances,
+            format,
+            client: reqwest::Client::new(),
+            routes: Mutex::new(HashMap::new()),
+            info: metadata::Caches::default(),
+        }))
+    }
+
+    // Creates the set of appliances described by `EPICS_ARCHIVERS`. If
+    // it isn't set, the laboratory's main appliance is used. Data is
+    // retrieved in the format given by `EPICS_ARCHIVER_FORMAT`
+    // (defaults to JSON.)
+
+    pub fn from_env() -> Self {
+        let config = env_var::get(ARCHIVERS)
+            .to_option::<String>()
+            .unwrap_or_else(|| DEFAULT_ARCHIVER.into());
+        let format = env_var::get(ARCHIVER_FORMAT)
+            .to_option::<String>()
+            .map(|v| Format::parse(&v))
+            .unwrap_or(Ok(Format::Json))
+            .unwrap_or_else(|e| {
+                panic!("bad {} setting -- {}", ARCHIVER_FORMAT, e)
+            });
+
+        match parse_config(&config) {
+            Ok(appliances) => {
+                for appliance in &appliances {
+                    info!("using EPICS archiver {}", &appliance.retrieval);
+                }
+                info!("retrieving archived data as {:?}", format);
+                Archivers::new(appliances, format)
+            }
+            Err(e) => panic!("bad {} setting -- {}", ARCHIVERS, e),
+        }
+    }
+
+    // Asks an appliance whether it archives a PV. If it does, the URL
+    // of the retrieval service holding the data is returned.
+
+    async fn lookup(
+        &self, appliance: &Appliance, mgmt: &str, pv: &str,
+    ) -> Result<Option<String>, String> {
+        let url = Url::parse_with_params(
+            &format!("{}/getPVStatus", mgmt),
+            [("pv", pv)],
+        )
+        .map_err(|e| e.to_string())?;
+        let status: Vec<PvStatus> = self
+            .0
+            .client
+            .get(url)
+            .timeout(BPL_TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let Some(id) = status
+            .into_iter()
+            .filter(|v| v.status != "Not being archived")
+            .find_map(|v| v.appliance)
+        else {
+            return Ok(None);
+        };
+
+        // In a cluster, the PV may be archived by a different member
+        // than the one asked so its retrieval URL is looked up. If that
+        // fails, the appliance's own retrieval service will proxy the
+        // request.
+
+        let url = Url::parse_with_params(
+            &format!("{}/getApplianceInfo", mgmt),
+            [("id", id.as_str())],
+        )
+        .map_err(|e| e.to_string())?;
+        let info = self
+            .0
+            .client
+            .get(url)
+            .timeout(BPL_TIMEOUT)
+            .send()
+            .await
+            .and_then(|v| v.error_for_status());
+
+        match info {
+            Ok(reply) => match reply.json::<ApplianceInfo>().await {
+                Ok(info) => Ok(Some(
+                    info.data_retrieval_url.trim_end_matches('/').into(),
+                )),
+                Err(e) => {
+                    warn!("bad appliance info for {} -- {}", &id, &e);
+                    Ok(Some(appliance.retrieval.clone()))
+                }
+            },
+            Err(e) => {
+                warn!("couldn't get appliance info for {} -- {}", &id, &e);
+                Ok(Some(appliance.retrieval.clone()))
+            }
+        }
+    }
+
+    // Returns the URL of the retrieval service holding a PV's data.
+    //
+    // Appliances with a management URL are asked, in order, whether
+    // they archive the PV. If none do, the first appliance without a
+    // management URL is used. The result is cached unless an appliance
+    // couldn't be reached.
+
+    pub async fn route(&self, pv: &str) -> Result<Arc<str>, String> {
+        if let Some(route) = self.0.routes.lock().unwrap().get(pv)
+            && route.expires > Instant::now()
+        {
+            return route
+                .url
+                .clone()
+                .ok_or_else(|| format!("{} isn't archived", pv));
+        }
+
+        let mut cacheable = true;
+        let mut found = None;
+
+        for appliance in &self.0.appliances {
+            let Some(mgmt) = appliance.mgmt.as_deref() else {
+                continue;
+            };
+
+            match self.lookup(appliance, mgmt, pv).await {
+                Ok(Some(url)) => {
+                    found = Some(Arc::<str>::from(url));
+                    break;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("couldn't reach archiver {} -- {}", mgmt, &e);
+                    cacheable = false;
+                }
+            }
+        }
+
+        let url = found.or_else(|| {
+            self.0
+                .appliances
+                .iter()
+                .find(|v| v.mgmt.is_none())
+                .map(|v| Arc::from(v.retrieval.as_str()))
+        });
+
+        if cacheable {
+            self.0.routes.lock().unwrap().insert(
+                pv.to_owned(),
+                Route {
+                    expires: Instant::now()
+                        + if url.is_some() { ROUTE_TTL } else { MISS_TTL },
+                    url: url.clone(),
+                },
+            );
+        }
+
+        url.ok_or_else(|| format!("{} isn't archived", pv))
+    }
+
+    // Returns a stream of the archived data of a PV. Long ranges are
+    // retrieved in chunks, several at a time, and settled chunks are
+    // kept in `cache`.
+
+    #[instrument(name = "EPICS_ARCH", skip(self, device, start_time, end_time))]
+    pub async fn fetch(
+        &self, device: &str, start_time: f64, end_time: f64,
+        aggregation: Option<types::Aggregation>,
+        cache: &archivecache::ArchiveCache,
+    ) -> Result<impl Stream<Item = global::DataReply> + Send + 'static + Unpin>
+    {
+        let pv = device_name(device);
+        let param = pv_param(pv, aggregation.as_ref()).map_err(Error::new)?;
+        let base = self.route(pv).await.map_err(Error::new)?;
+        let request_url = Url::parse_with_params(
+            &format!("{}/data/getData.{}", base, self.0.format.extension()),
+            [("pv", param.as_str())],
+        )
+        .map_err(|e| Error::new(e.to_string()))?;
+        let client = self.0.client.clone();
+        let format = self.0.format;
+        let cache = cache.clone();
+
+        datastream::retrieve_chunks(
+            datastream::split_range(
+                start_time.min(end_time),
+                end_time.max(start_time),
+                chunk_size(aggregation.as_ref()),
+            ),
+            ARCHIVER_PARALLEL,
+            move |start, end| {
+                cache.fetch(
+                    archivecache::Key::new("EPICS", &param, start, end),
+                    download(
+                        client.clone(),
+                        with_range(&request_url, start, end),
+                        format,
+                    ),
+                )
+            },
+        )
+        .await
+        .map_err(Error::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn no_cache() -> archivecache::ArchiveCache {
+        archivecache::ArchiveCache::new(0)
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            parse_config(
+                "http://a:17668/retrieval/, \
+		 http://b:17668/retrieval|http://b:17665/mgmt/bpl"
+            ),
+            Ok(vec![
+                Appliance {
+                    retrieval: "http://a:17668/retrieval".into(),
+                    mgmt: None,
+                },
+                Appliance {
+                    retrieval: "http://b:17668/retrieval".into(),
+                    mgmt: Some("http://b:17665/mgmt/bpl".into()),
+                },
+            ])
+        );
+        assert!(parse_config("").is_err());
+        assert!(parse_config("archiver1:17668").is_err());
+        assert!(parse_config("http://a|b").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::parse("JSON"), Ok(Format::Json));
+        assert_eq!(Format::parse(" raw"), Ok(Format::Raw));
+        assert_eq!(Format::parse("pb"), Ok(Format::Raw));
+        assert!(Format::parse("xml").is_err());
+    }
+
+    #[test]
+    fn test_pv_param() {
+        use types::{Aggregation, AggregationOperator};
+
+        let agg = |operator, bin_size| Aggregation { operator, bin_size };
+
+        assert_eq!(pv_param("PV:A", None), Ok("PV:A".into()));
+        assert_eq!(
+            pv_param("PV:A", Some(&agg(AggregationOperator::Mean, 3600.0))),
+            Ok("mean_3600(PV:A)".into())
+        );
+        assert_eq!(
+            pv_param("PV:A", Some(&agg(AggregationOperator::First, 59.6))),
+            Ok("firstSample_60(PV:A)".into())
+        );
+        assert_eq!(
+            pv_param("PV:A", Some(&agg(AggregationOperator::Nth, 5.0))),
+            Ok("nth_5(PV:A)".into())
+        );
+        assert!(
+            pv_param("PV:A", Some(&agg(AggregationOperator::Max, 0.0)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_to_iso() {
+        assert_eq!(to_iso(0.0), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(to_iso(1.5), "1970-01-01T00:00:01.500000000Z");
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let a = mock::start("appliance0", vec![("A:ONE", vec![])]).await;
+        let b = mock::start("appliance1", vec![("B:TWO", vec![])]).await;
+        let archivers =
+            Archivers::new(vec![a.appliance(), b.appliance()], Format::Json);
+
+        assert_eq!(
+            archivers.route("B:TWO").await.as_deref(),
+            Ok(b.retrieval().as_str())
+        );
+        assert_eq!(
+            archivers.route("A:ONE").await.as_deref(),
+            Ok(a.retrieval().as_str())
+        );
+
+        // The second lookup of a PV comes from the cache.
+
+        let hits = b.status_requests();
+
+        assert!(archivers.route("B:TWO").await.is_ok());
+        assert_eq!(b.status_requests(), hits);
+
+        assert!(archivers.route("C:THREE").await.is_err());
+        assert!(archivers.route("C:THREE").await.is_err());
+        assert_eq!(b.status_requests(), hits + 1);
+    }
+
+    #[tokio::test]
+    async fn test_unrouted_fallback() {
+        let a = mock::start("appliance0", vec![("A:ONE", vec![])]).await;
+        let archivers = Archivers::new(
+            vec![
+                a.appliance(),
+                Appliance {
+                    retrieval: "http://fallback/retrieval".into(),
+                    mgmt: None,
+                },
+            ],
+            Format::Json,
+        );
+
+        assert_eq!(
+            archivers.route("Z:ZERO").await.as_deref(),
+            Ok("http://fallback/retrieval")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let a = mock::start(
+            "appliance0",
+            vec![("A:ONE", vec![]), ("A:TWO", vec![]), ("B:ONE", vec![])],
+        )
+        .await;
+        let b = mock::start("appliance1", vec![("A:ONE", vec![])]).await;
+        let archivers =
+            Archivers::new(vec![a.appliance(), b.appliance()], Format::Json);
+
+        assert_eq!(
+            archivers.search("A:*", false, 10).await,
+            Ok(vec!["A:ONE".into(), "A:TWO".into()])
+        );
+        assert_eq!(
+            archivers.search(".*:ONE", true, 10).await,
+            Ok(vec!["A:ONE".into(), "B:ONE".into()])
+        );
+        assert_eq!(
+            archivers.search("*", false, 2).await,
+            Ok(vec!["A:ONE".into(), "A:TWO".into()])
+        );
+        assert_eq!(archivers.search("Z*", false, 10).await, Ok(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let a = mock::start(
+            "appliance0",
+            vec![(
+                "A:ONE",
+                vec![
+                    mock::sample(100, 0, json!(1.0)),
+                    mock::sample(200, 0, json!(2.0)),
+                ],
+            )],
+        )
+        .await;
+        let archivers = Archivers::new(vec![a.appliance()], Format::Json);
+        let md = archivers.metadata("A:ONE", 300.0).await.unwrap();
+
+        assert!(md.archived);
+        assert_eq!(md.appliance.as_deref(), Some("appliance0"));
+        assert_eq!(md.units.as_deref(), Some("V"));
+        assert_eq!(md.precision, Some(2));
+        assert_eq!(md.archived_since, Some(50.0));
+        assert_eq!(md.first_sample, Some(100.0));
+        assert_eq!(md.last_sample, Some(200.0));
+
+        // A second request is answered from the cache.
+
+        let requests = a.data_requests();
+
+        assert_eq!(archivers.metadata("A:ONE", 300.0).await, Ok(md));
+        assert_eq!(a.data_requests(), requests);
+
+        let md = archivers.metadata("Z:ZERO", 300.0).await.unwrap();
+
+        assert!(!md.archived);
+        assert_eq!(md.status, "Not being archived");
+        assert_eq!(md.first_sample, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let a = mock::start(
+            "appliance0",
+            vec![(
+                "A:ONE",
+                vec![
+                    mock::sample(100, 0, json!(1.0)),
+                    mock::sample(101, 500_000_000, json!(2)),
+                    mock::sample(200, 0, json!(3.0)),
+                ],
+            )],
+        )
+        .await;
+        let expected = vec![
+            global::DataInfo {
+                timestamp: 100.0,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: 1.0,
+                }),
+            },
+            global::DataInfo {
+                timestamp: 101.5,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: 2.0,
+                }),
+            },
+        ];
+
+        // Both formats return the same data.
+
+        for (idx, format) in [Format::Json, Format::Raw].into_iter().enumerate()
+        {
+            let archivers = Archivers::new(vec![a.appliance()], format);
+            let data: Vec<_> = archivers
+                .fetch("A:ONE@p,1000", 99.0, 150.0, None, &no_cache())
+                .await
+                .unwrap()
+                .flat_map(|v| stream::iter(v.data))
+                .collect()
+                .await;
+
+            assert_eq!(data, expected, "{:?}", format);
+            assert_eq!(a.data_requests(), idx + 1);
+        }
+
+        let archivers = Archivers::new(vec![a.appliance()], Format::Raw);
+
+        assert!(
+            archivers
+                .fetch("A:TWO", 99.0, 150.0, None, &no_cache())
+                .await
+                .is_err()
+        );
+    }
+
+    // Characters that are special in a URL query reach the appliance
+    // intact.
+
+    #[tokio::test]
+    async fn test_encoded_names() {
+        let pv = "A:B+C&D=1#2";
+        let a = mock::start(
+            "appliance0",
+            vec![(pv, vec![mock::sample(100, 0, json!(1.0))])],
+        )
+        .await;
+        let archivers = Archivers::new(vec![a.appliance()], Format::Json);
+
+        assert_eq!(
+            archivers.route(pv).await.as_deref(),
+            Ok(a.retrieval().as_str())
+        );
+
+        let data: Vec<_> = archivers
+            .fetch(pv, 99.0, 150.0, None, &no_cache())
+            .await
+            .unwrap()
+            .flat_map(|v| stream::iter(v.data))
+            .collect()
+            .await;
+
+        assert_eq!(data.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_text() {
+        let a = mock::start(
+            "appliance0",
+            vec![
+                (
+                    "A:TEXT",
+                    vec![
+                        mock::sample(100, 0, json!("idle")),
+                        mock::sample(101, 0, json!("ramping")),
+                    ],
+                ),
+                ("A:TEXTS", vec![mock::sample(100, 0, json!(["a", "b"]))]),
+            ],
+        )
+        .await;
+        let text = |v: &str| {
+            global::DataType::Text(global::Text {
+                text_value: v.into(),
+            })
+        };
+
+        // Both formats return text and text arrays.
+
+        for format in [Format::Json, Format::Raw] {
+            let archivers = Archivers::new(vec![a.appliance()], format);
+            let data: Vec<_> = archivers
+                .fetch("A:TEXT", 99.0, 150.0, None, &no_cache())
+                .await
+                .unwrap()
+                .flat_map(|v| stream::iter(v.data))
+                .map(|v| v.result)
+                .collect()
+                .await;
+
+            assert_eq!(
+                data,
+                vec![text("idle"), text("ramping")],
+                "{:?}",
+                format
+            );
+
+            let data: Vec<_> = archivers
+                .fetch("A:TEXTS", 99.0, 150.0, None, &no_cache())
+                .await
+                .unwrap()
+                .flat_map(|v| stream::iter(v.data))
+                .map(|v| v.result)
+                .collect()
+                .await;
+
+            assert_eq!(
+                data,
+                vec![global::DataType::TextArray(global::TextArray {
+                    text_array_value: vec!["a".into(), "b".into()]
+                })],
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    // Integer samples of a PV whose metadata has state labels are
+    // enumerated values.
+
+    #[tokio::test]
+    async fn test_json_enums() {
+        let events = |response: &'static str| {
+            json_events(std::io::Cursor::new(response.as_bytes()))
+                .map(|v| v.data[0].result.clone())
+                .collect::<Vec<_>>()
+        };
+        let state = |enum_value, label: Option<&str>| {
+            global::DataType::EnumValue(global::EnumValue {
+                enum_value,
+                label: label.map(String::from),
+            })
+        };
+
+        // The appliance sends the metadata before the data.
+
+        assert_eq!(
+            events(
+                r#"[{"meta": {"name": "A:ENUM", "ZRST": "Off", "ONST": "On"},
+                     "data": [{"secs": 1, "nanos": 0, "val": 1},
+                              {"secs": 2, "nanos": 0, "val": 7}]}]"#
+            )
+            .await,
+            vec![state(1, Some("On")), state(7, None)]
+        );
+
+        // Without labels, integers are scalars.
+
+        assert_eq!(
+            events(
+                r#"[{"meta": {"name": "A:INT"},
+                     "data": [{"secs": 1, "nanos": 0, "val": 1}]}]"#
+            )
+            .await,
+            vec![global::DataType::Scalar(global::Scalar {
+                scalar_value: 1.0
+            })]
+        );
+    }
+
+    // Braces and escaped quotes inside strings don't end an object
+    // early.
+
+    #[tokio::test]
+    async fn test_json_strings() {
+        const RESPONSE: &str = r#"[{"meta": {"name": "A:ENUM", "ZRST": "{Off}", "ONST": "On}"},
+                                   "data": [{"secs": 1, "nanos": 0, "val": 1},
+                                            {"secs": 2, "nanos": 0, "val": 0}]}]"#;
+
+        let data: Vec<_> =
+            json_events(std::io::Cursor::new(RESPONSE.as_bytes()))
+                .map(|v| v.data[0].result.clone())
+                .collect()
+                .await;
+
+        assert_eq!(
+            data,
+            vec![
+                global::DataType::EnumValue(global::EnumValue {
+                    enum_value: 1,
+                    label: Some("On}".into()),
+                }),
+                global::DataType::EnumValue(global::EnumValue {
+                    enum_value: 0,
+                    label: Some("{Off}".into()),
+                }),
+            ]
+        );
+
+        let data: Vec<_> = json_events(std::io::Cursor::new(
+            &br#"[{"data": [{"secs": 3, "nanos": 0, "val": "a \"}\" b{"},
+                            {"secs": 4, "nanos": 0, "val": "\\"}]}]"#[..],
+        ))
+        .map(|v| v.data[0].result.clone())
+        .collect()
+        .await;
+
+        assert_eq!(
+            data,
+            vec![
+                global::DataType::Text(global::Text {
+                    text_value: "a \"}\" b{".into(),
+                }),
+                global::DataType::Text(global::Text {
+                    text_value: "\\".into(),
+                }),
+            ]
+        );
+    }
+
+    // Keys are found even when the reader returns only a few bytes at a
+    // time, or a key is split between reads.
+
+    #[tokio::test]
+    async fn test_small_reads() {
+        const RESPONSE: &str = r#"[{"meta": {"name": "A:ENUM", "ZRST": "Off"},
+                                   "data": [{"secs": 1, "nanos": 0, "val": 0}]}]"#;
+
+        for size in 1..=8 {
+            let reader = BufReader::with_capacity(
+                size,
+                std::io::Cursor::new(RESPONSE.as_bytes()),
+            );
+            let data: Vec<_> = json_events(reader)
+                .map(|v| v.data[0].result.clone())
+                .collect()
+                .await;
+
+            assert_eq!(
+                data,
+                vec![global::DataType::EnumValue(global::EnumValue {
+                    enum_value: 0,
+                    label: Some("Off".into()),
+                })],
+                "buffer of {} bytes",
+                size
+            );
+        }
+
+        // A response without the key ends the stream.
+
+        let reader =
+            BufReader::with_capacity(4, std::io::Cursor::new(&b"[{}]"[..]));
+
+        assert_eq!(json_events(reader).count().await, 0);
+
+        // A response cut off partway through the data returns the
+        // samples that were read followed by an error status.
+
+        let reader = std::io::Cursor::new(
+            &br#"{"data": [{"secs": 1, "nanos": 0, "val": 1.0}, {"secs": 2"#[..],
+        );
+        let data: Vec<_> = json_events(reader).collect().await;
+
+        assert_eq!(data.len(), 2);
+        assert!(!is_failure(&data[0]));
+        assert!(is_failure(&data[1]));
+    }
+
+    #[test]
+    fn test_enum_labels() {
+        let labels =
+            EnumLabels::from_fields([("ZNAM", "Closed"), ("ONAM", " ")]);
+
+        assert_eq!(labels, EnumLabels(vec![Some("Closed".into()), None]));
+        assert!(EnumLabels::from_fields([("EGU", "V")]).is_empty());
+        assert_eq!(
+            EnumLabels::from_fields([("TWST", "Fault")]).value(2),
+            global::DataType::EnumValue(global::EnumValue {
+                enum_value: 2,
+                label: Some("Fault".into()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_fetch() {
+        let a = mock::start(
+            "appliance0",
+            vec![("A:ONE", vec![mock::sample(100, 0, json!(1.0))])],
+        )
+        .await;
+        let archivers = Archivers::new(vec![a.appliance()], Format::Json);
+        let cache = archivecache::ArchiveCache::new(1_048_576);
+
+        for _ in 0..2 {
+            let data: Vec<_> = archivers
+                .fetch("A:ONE", 99.0, 150.0, None, &cache)
+                .await
+                .unwrap()
+                .collect()
+                .await;
+
+            // The sample is followed by the reply marking the end of
+            // the chunk.
+
+            assert_eq!(data.len(), 2);
+            assert!(data[1].data.is_empty());
+        }
+        assert_eq!(a.data_requests(), 1);
+
+        assert_eq!(cache.statistics().hits, 1);
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        use types::{Aggregation, AggregationOperator};
+
+        let agg = |operator, bin_size| Aggregation { operator, bin_size };
+
+        assert_eq!(chunk_size(None), ARCHIVER_CHUNK);
+        assert_eq!(
+            chunk_size(Some(&agg(AggregationOperator::Mean, 3600.0))),
+            ARCHIVER_CHUNK
+        );
+        assert_eq!(
+            chunk_size(Some(&agg(AggregationOperator::Mean, 1000.0))),
+            605_000.0
+        );
+        assert_eq!(
+            chunk_size(Some(&agg(AggregationOperator::Nth, 10.0))),
+            f64::INFINITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunked_fetch() {
+        let boundary = ARCHIVER_CHUNK as i64;
+        let a = mock::start(
+            "appliance0",
+            vec![(
+                "A:ONE",
+                vec![
+                    mock::sample(100, 0, json!(1.0)),
+                    mock::sample(boundary, 0, json!(2.0)),
+                    mock::sample(boundary + 100, 0, json!(3.0)),
+                ],
+            )],
+        )
+        .await;
+        let archivers = Archivers::new(vec![a.appliance()], Format::Json);
+        let data: Vec<_> = archivers
+            .fetch("A:ONE", 0.0, ARCHIVER_CHUNK + 200.0, None, &no_cache())
+            .await
+            .unwrap()
+            .flat_map(|v| stream::iter(v.data))
+            .map(|v| v.timestamp)
+            .collect()
+            .await;
+
+        // The sample on the boundary is only returned once.
+
+        assert_eq!(data, vec![100.0, ARCHIVER_CHUNK, ARCHIVER_CHUNK + 100.0]);
+        assert_eq!(a.data_requests(), 2);
+    }
+}
@@ -0,0 +1,421 @@
+// Searches the names of archived PVs and describes how a PV is
+// archived. The information comes from the appliances' management BPL
+// (`getAllPVs`, `getPVStatus` and `getPVTypeInfo`); the times of the
+// first and last samples come from the retrieval service. Results are
+// cached for a few minutes since clients tend to ask repeatedly while
+// browsing.
+
+use super::{
+    Archivers, BPL_TIMEOUT, PvStatus, download, global, types, with_range,
+};
+use futures_util::{StreamExt, future, stream};
+use reqwest::Url;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+// How long search results and PV descriptions are remembered.
+
+const SEARCH_TTL: Duration = Duration::from_secs(60);
+const METADATA_TTL: Duration = Duration::from_secs(300);
+
+// The most entries each cache holds. When it's full, expired entries
+// are removed; if that doesn't make room, the result isn't cached.
+
+const MAX_ENTRIES: usize = 10_000;
+
+// The most names a search returns.
+
+pub const MAX_SEARCH: usize = 10_000;
+
+// How many PVs are described at once. Each description makes several
+// requests to the appliances.
+
+pub const DESCRIBE_PARALLEL: usize = 8;
+
+// The windows, in seconds, searched for the first and last samples.
+// Each is tried in turn until a sample is found. They start small so a
+// frequently sampled PV is found without reading much data.
+
+const SAMPLE_WINDOWS: [f64; 4] = [60.0, 3_600.0, 86_400.0, 7.0 * 86_400.0];
+
+struct Entry<V> {
+    expires: Instant,
+    value: V,
+}
+
+// A map whose entries expire.
+
+struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, Entry<V>>>,
+}
+
+impl<K: std::hash::Hash + Eq, V: Clone> TtlCache<K, V> {
+    fn new(ttl: Duration) -> Self {
+        TtlCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|v| v.expires > Instant::now())
+            .map(|v| v.value.clone())
+    }
+
+    fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, v| v.expires > now);
+        }
+        if entries.len() < MAX_ENTRIES {
+            entries.insert(
+                key,
+                Entry {
+                    expires: now + self.ttl,
+                    value,
+                },
+            );
+        }
+    }
+}
+
+// The caches used by the queries in this module.
+
+pub(super) struct Caches {
+    searches: TtlCache<(String, bool, usize), Vec<String>>,
+    metadata: TtlCache<String, types::PvMetadata>,
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Caches {
+            searches: TtlCache::new(SEARCH_TTL),
+            metadata: TtlCache::new(METADATA_TTL),
+        }
+    }
+}
+
+// Returns a field of a PV's type info as a string. The appliance
+// reports most fields as strings but numbers are accepted, too.
+
+fn field(info: &HashMap<String, Value>, key: &str) -> Option<String> {
+    match info.get(key)? {
+        Value::String(v) if !v.trim().is_empty() => Some(v.trim().into()),
+        Value::Number(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+// Returns a field stored with the PV's samples (e.g. `EGU`.)
+
+fn extra_field(info: &HashMap<String, Value>, key: &str) -> Option<String> {
+    info.get("extraFields")?
+        .get(key)?
+        .as_str()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+// Fills in a description from a PV's type info.
+
+fn apply_type_info(md: &mut types::PvMetadata, info: &HashMap<String, Value>) {
+    let number =
+        |key: &str| field(info, key).and_then(|v| v.parse::<f64>().ok());
+
+    md.units = field(info, "units").or_else(|| extra_field(info, "EGU"));
+    md.precision = number("precision")
+        .or_else(|| extra_field(info, "PREC").and_then(|v| v.parse().ok()))
+        .map(|v| v as i32);
+    md.sampling_method = field(info, "samplingMethod");
+    md.sampling_period = number("samplingPeriod");
+    md.data_type = field(info, "DBRType");
+    md.element_count = number("elementCount").map(|v| v as i32);
+    md.archived_since = field(info, "creationTime")
+        .and_then(|v| chrono::DateTime::parse_from_rfc3339(&v).ok())
+        .map(|v| v.timestamp_micros() as f64 / 1_000_000.0);
+}
+
+impl Archivers {
+    // Returns the names of the archived PVs matching `pattern`, sorted.
+    // The pattern is a glob (e.g. "ACC-RF*") unless `regex` is set.
+    // Every appliance with a management URL is asked; an appliance that
+    // can't be reached is skipped unless none can be.
+
+    pub async fn search(
+        &self, pattern: &str, regex: bool, limit: usize,
+    ) -> Result<Vec<String>, String> {
+        let key = (pattern.to_owned(), regex, limit);
+
+        if let Some(result) = self.0.info.searches.get(&key) {
+            return Ok(result);
+        }
+
+        let param = if regex { "regex" } else { "pv" };
+        let limit_param = limit.to_string();
+        let mut result = vec![];
+        let mut failure = None;
+        let mut asked = 0;
+
+        for mgmt in self.0.appliances.iter().filter_map(|v| v.mgmt.as_ref()) {
+            asked += 1;
+
+            let reply = async {
+                let url = Url::parse_with_params(
+                    &format!("{}/getAllPVs", mgmt),
+                    [(param, pattern), ("limit", limit_param.as_str())],
+                )
+                .map_err(|e| e.to_string())?;
+
+                self.0
+                    .client
+                    .get(url)
+                    .timeout(BPL_TIMEOUT)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+                    .map_err(|e| e.to_string())?
+                    .json::<Vec<String>>()
+                    .await
+                    .map_err(|e| e.to_string())
+            };
+
+            match reply.await {
+                Ok(names) => result.extend(names),
+                Err(e) => {
+                    warn!("couldn't search archiver {} -- {}", mgmt, &e);
+                    failure = Some(e);
+                }
+            }
+        }
+
+        if asked == 0 {
+            return Err("no archiver can be searched".into());
+        }
+        if let Some(ref e) = failure
+            && result.is_empty()
+        {
+            return Err(format!("couldn't search the archivers -- {}", e));
+        }
+
+        result.sort();
+        result.dedup();
+        result.truncate(limit);
+        if failure.is_none() {
+            self.0.info.searches.insert(key, result.clone());
+        }
+        Ok(result)
+    }
+
+    // Asks the management BPLs how a PV is archived. Returns the
+    // status, the type info and, if the PV is archived, its appliance.
+
+    async fn describe(
+        &self, pv: &str,
+    ) -> Result<(String, Option<String>, HashMap<String, Value>), String> {
+        for mgmt in self.0.appliances.iter().filter_map(|v| v.mgmt.as_ref()) {
+            let get = |op: &str| {
+                let url = Url::parse_with_params(
+                    &format!("{}/{}", mgmt, op),
+                    [("pv", pv)],
+                );
+
+                async move {
+                    self.0
+                        .client
+                        .get(url.map_err(|e| e.to_string())?)
+                        .timeout(BPL_TIMEOUT)
+                        .send()
+                        .await
+                        .and_then(reqwest::Response::error_for_status)
+                        .map_err(|e| e.to_string())
+                }
+            };
+            let status: Vec<PvStatus> = get("getPVStatus")
+                .await?
+                .json()
+                .await
+                .map_err(|e| e.to_string())?;
+            let Some(status) = status
+                .into_iter()
+                .find(|v| v.status != "Not being archived")
+            else {
+                continue;
+            };
+
+            // A PV that's still being set up has no type info yet.
+
+            let info = match get("getPVTypeInfo").await {
+                Ok(reply) => reply.json().await.unwrap_or_default(),
+                Err(e) => {
+                    warn!("no type info for {} -- {}", pv, &e);
+                    HashMap::new()
+                }
+            };
+
+            return Ok((status.status, status.appliance, info));
+        }
+        Ok(("Not being archived".into(), None, HashMap::new()))
+    }
+
+    // Searches `windows`, in order, for a sample of a PV. Returns the
+    // time of the earliest (if `first` is set) or latest sample in the
+    // first window that has one. The samples arrive in time order, so
+    // the search for the earliest stops at the first one and the search
+    // for the latest keeps only the last one read. Samples preceding
+    // the window, which the appliance also returns, are ignored.
+
+    async fn find_sample(
+        &self, base: &str, pv: &str, windows: Vec<(f64, f64)>, first: bool,
+    ) -> Option<f64> {
+        for (start, end) in windows {
+            let url = match Url::parse_with_params(
+                &format!("{}/data/getData.{}", base, self.0.format.extension()),
+                [("pv", pv)],
+            ) {
+                Ok(url) => with_range(&url, start, end),
+                Err(e) => {
+                    warn!("bad retrieval URL for {} -- {}", pv, &e);
+                    return None;
+                }
+            };
+
+            let replies =
+                match download(self.0.client.clone(), url, self.0.format).await
+                {
+                    Ok(replies) => replies,
+                    Err(e) => {
+                        warn!("couldn't retrieve samples of {} -- {}", pv, &e);
+                        return None;
+                    }
+                };
+            let mut times = replies
+                .flat_map(|v| stream::iter(v.data))
+                .filter(move |v| {
+                    future::ready(
+                        !matches!(v.result, global::DataType::StatusReply(_))
+                            && v.timestamp >= start,
+                    )
+                })
+                .map(|v| v.timestamp);
+            let found = if first {
+                times.next().await
+            } else {
+                times.fold(None, |_, t| future::ready(Some(t))).await
+            };
+
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    // Describes how a PV is archived.
+
+    pub async fn metadata(
+        &self, pv: &str, now: f64,
+    ) -> Result<types::PvMetadata, String> {
+        if let Some(result) = self.0.info.metadata.get(&pv.to_owned()) {
+            return Ok(result);
+        }
+
+        let (status, appliance, info) = self.describe(pv).await?;
+        let mut result = types::PvMetadata {
+            name: pv.into(),
+            archived: appliance.is_some(),
+            status,
+            appliance,
+            ..Default::default()
+        };
+
+        apply_type_info(&mut result, &info);
+
+        if result.archived
+            && let Ok(base) = self.route(pv).await
+        {
+            let since = result.archived_since.unwrap_or(0.0);
+
+            result.first_sample = self
+                .find_sample(
+                    &base,
+                    pv,
+                    SAMPLE_WINDOWS
+                        .iter()
+                        .map(|v| (since, (since + v).min(now)))
+                        .collect(),
+                    true,
+                )
+                .await;
+            result.last_sample = self
+                .find_sample(
+                    &base,
+                    pv,
+                    SAMPLE_WINDOWS.iter().map(|v| (now - v, now)).collect(),
+                    false,
+                )
+                .await;
+        }
+
+        self.0.info.metadata.insert(pv.to_owned(), result.clone());
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_type_info() {
+        let info: HashMap<String, Value> =
+            serde_json::from_value(serde_json::json!({
+                "pvName": "A:ONE",
+                "DBRType": "DBR_SCALAR_DOUBLE",
+                "samplingMethod": "MONITOR",
+                "samplingPeriod": "1.0",
+                "elementCount": "1",
+                "precision": "0.0",
+                "units": "",
+                "creationTime": "2024-01-01T00:00:00.500Z",
+                "extraFields": { "EGU": "mA", "PREC": "3" },
+            }))
+            .unwrap();
+        let mut md = types::PvMetadata::default();
+
+        apply_type_info(&mut md, &info);
+        assert_eq!(md.units.as_deref(), Some("mA"));
+        assert_eq!(md.precision, Some(0));
+        assert_eq!(md.sampling_method.as_deref(), Some("MONITOR"));
+        assert_eq!(md.sampling_period, Some(1.0));
+        assert_eq!(md.data_type.as_deref(), Some("DBR_SCALAR_DOUBLE"));
+        assert_eq!(md.element_count, Some(1));
+        assert_eq!(md.archived_since, Some(1_704_067_200.5));
+    }
+
+    #[test]
+    fn test_ttl_cache() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+
+        let cache = TtlCache::new(Duration::ZERO);
+
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), None);
+    }
+}
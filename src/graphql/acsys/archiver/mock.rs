@@ -0,0 +1,369 @@
+// A mock EPICS Archiver Appliance used by the tests. It listens on a
+// local port and implements the parts of the management BPL and the
+// retrieval service that this service uses, so the archive path can be
+// tested without the network.
+
+use super::{Appliance, pb};
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use prost::Message;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+// One archived sample of a PV.
+
+#[derive(Clone)]
+pub struct Sample {
+    pub secs: i64,
+    pub nanos: u32,
+    pub val: Value,
+}
+
+pub fn sample(secs: i64, nanos: u32, val: Value) -> Sample {
+    Sample { secs, nanos, val }
+}
+
+struct Data {
+    identity: String,
+    url: String,
+    pvs: HashMap<String, Vec<Sample>>,
+    status_requests: AtomicUsize,
+    data_requests: AtomicUsize,
+}
+
+type Params = Query<HashMap<String, String>>;
+
+// A running mock appliance. The server stops when the test's runtime
+// shuts down.
+
+pub struct MockArchiver(Arc<Data>);
+
+impl MockArchiver {
+    // The URL of the mock's retrieval service.
+
+    pub fn retrieval(&self) -> String {
+        format!("{}/retrieval", self.0.url)
+    }
+
+    // The URL of the mock's management BPL.
+
+    pub fn mgmt(&self) -> String {
+        format!("{}/mgmt/bpl", self.0.url)
+    }
+
+    // The configuration entry describing the mock.
+
+    pub fn appliance(&self) -> Appliance {
+        Appliance {
+            retrieval: self.retrieval(),
+            mgmt: Some(self.mgmt()),
+        }
+    }
+
+    // The number of `getPVStatus` requests received.
+
+    pub fn status_requests(&self) -> usize {
+        self.0.status_requests.load(Ordering::Relaxed)
+    }
+
+    // The number of `getData.json` requests received.
+
+    pub fn data_requests(&self) -> usize {
+        self.0.data_requests.load(Ordering::Relaxed)
+    }
+}
+
+async fn pv_status(
+    State(data): State<Arc<Data>>, Query(q): Params,
+) -> Response {
+    let pv = q.get("pv").cloned().unwrap_or_default();
+
+    data.status_requests.fetch_add(1, Ordering::Relaxed);
+    if data.pvs.contains_key(&pv) {
+        Json(json!([{
+            "pvName": pv,
+            "status": "Being archived",
+            "appliance": data.identity,
+        }]))
+        .into_response()
+    } else {
+        Json(json!([{ "pvName": pv, "status": "Not being archived" }]))
+            .into_response()
+    }
+}
+
+async fn appliance_info(
+    State(data): State<Arc<Data>>, Query(q): Params,
+) -> Response {
+    if q.get("id") == Some(&data.identity) {
+        Json(json!({
+            "identity": data.identity,
+            "dataRetrievalURL": format!("{}/retrieval", data.url),
+            "mgmtURL": format!("{}/mgmt/bpl", data.url),
+        }))
+        .into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+// Matches a name against a glob pattern, which may use `*` and `?`.
+
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob(rest, name) || (!name.is_empty() && glob(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, tail))) => glob(rest, tail),
+        (Some((p, rest)), Some((n, tail))) => p == n && glob(rest, tail),
+        (Some(_), None) => false,
+    }
+}
+
+// Handles `getAllPVs`. The mock only understands regular expressions
+// made of literals, `.` and `.*`, which are matched as globs.
+
+async fn all_pvs(State(data): State<Arc<Data>>, Query(q): Params) -> Response {
+    let pattern = match (q.get("pv"), q.get("regex")) {
+        (Some(pv), _) => pv.clone(),
+        (None, Some(re)) => re
+            .trim_start_matches('^')
+            .trim_end_matches('$')
+            .replace(".*", "*")
+            .replace('.', "?"),
+        (None, None) => "*".into(),
+    };
+    let limit = q
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(usize::MAX);
+    let mut names: Vec<_> = data
+        .pvs
+        .keys()
+        .filter(|v| glob(pattern.as_bytes(), v.as_bytes()))
+        .cloned()
+        .collect();
+
+    names.sort();
+    names.truncate(limit);
+    Json(names).into_response()
+}
+
+// Handles `getPVTypeInfo`. Every PV is described as a double that
+// started being archived 50 seconds after 1970.
+
+async fn type_info(
+    State(data): State<Arc<Data>>, Query(q): Params,
+) -> Response {
+    let pv = q.get("pv").cloned().unwrap_or_default();
+
+    if data.pvs.contains_key(&pv) {
+        Json(json!({
+            "pvName": pv,
+            "DBRType": "DBR_SCALAR_DOUBLE",
+            "samplingMethod": "MONITOR",
+            "samplingPeriod": "1.0",
+            "elementCount": "1",
+            "precision": "2.0",
+            "units": "V",
+            "creationTime": "1970-01-01T00:00:50.000Z",
+            "extraFields": {},
+        }))
+        .into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+// Converts a `from` or `to` parameter to seconds since 1970.
+
+fn seconds(q: &HashMap<String, String>, key: &str) -> Option<f64> {
+    chrono::DateTime::parse_from_rfc3339(q.get(key)?)
+        .ok()
+        .map(|v| v.timestamp_micros() as f64 / 1_000_000.0)
+}
+
+// Handles `getData.json` and `getData.raw` requests.
+
+async fn get_data(
+    data: Arc<Data>, q: HashMap<String, String>, raw: bool,
+) -> Response {
+    data.data_requests.fetch_add(1, Ordering::Relaxed);
+
+    let pv = q.get("pv").cloned().unwrap_or_default();
+    let Some(samples) = data.pvs.get(&pv) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let from = seconds(&q, "from").unwrap_or(f64::MIN);
+    let to = seconds(&q, "to").unwrap_or(f64::MAX);
+    let samples: Vec<_> = samples
+        .iter()
+        .filter(|v| {
+            let ts = v.secs as f64 + v.nanos as f64 / 1_000_000_000.0;
+
+            ts >= from && ts <= to
+        })
+        .collect();
+
+    if raw {
+        return encode_raw(&pv, &samples).into_response();
+    }
+
+    let samples: Vec<_> = samples
+        .into_iter()
+        .map(|v| {
+            json!({
+                "secs": v.secs,
+                "nanos": v.nanos,
+                "val": v.val,
+                "severity": 0,
+                "status": 0,
+            })
+        })
+        .collect();
+
+    Json(json!([{ "meta": { "name": pv }, "data": samples }])).into_response()
+}
+
+// Encodes a sample's value in the PB format. Numbers are sent as
+// doubles, arrays of strings as string waveforms, other arrays as
+// double waveforms and anything else as a string.
+
+fn encode_sample(
+    secondsintoyear: u32, nano: u32, val: &Value,
+) -> (pb::PayloadType, Vec<u8>) {
+    match val {
+        Value::Number(v) => (
+            pb::PayloadType::ScalarDouble,
+            pb::ScalarDouble {
+                secondsintoyear,
+                nano,
+                val: v.as_f64().unwrap_or(0.0),
+            }
+            .encode_to_vec(),
+        ),
+        Value::Array(v) if v.first().is_some_and(Value::is_string) => (
+            pb::PayloadType::WaveformString,
+            pb::VectorString {
+                secondsintoyear,
+                nano,
+                val: v
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect(),
+            }
+            .encode_to_vec(),
+        ),
+        Value::Array(v) => (
+            pb::PayloadType::WaveformDouble,
+            pb::VectorDouble {
+                secondsintoyear,
+                nano,
+                val: v.iter().filter_map(Value::as_f64).collect(),
+            }
+            .encode_to_vec(),
+        ),
+        v => (
+            pb::PayloadType::ScalarString,
+            pb::ScalarString {
+                secondsintoyear,
+                nano,
+                val: v
+                    .as_str()
+                    .map(String::from)
+                    .unwrap_or_else(|| v.to_string()),
+            }
+            .encode_to_vec(),
+        ),
+    }
+}
+
+// Builds a PB/HTTP response. A new chunk is started whenever the year,
+// or the type of the samples, changes.
+
+fn encode_raw(pv: &str, samples: &[&Sample]) -> Vec<u8> {
+    use chrono::{Datelike, TimeZone, Utc};
+
+    let mut result = vec![];
+    let mut current = None;
+
+    for sample in samples {
+        let year = Utc.timestamp_opt(sample.secs, 0).unwrap().year();
+        let start = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap();
+        let (kind, data) = encode_sample(
+            (sample.secs - start.timestamp()) as u32,
+            sample.nanos,
+            &sample.val,
+        );
+
+        if current != Some((year, kind)) {
+            if current.is_some() {
+                result.push(b'\n');
+            }
+
+            let info = pb::PayloadInfo {
+                r#type: kind as i32,
+                pvname: pv.into(),
+                year,
+                element_count: None,
+                headers: vec![],
+            };
+
+            result.extend(pb::escape(&info.encode_to_vec()));
+            result.push(b'\n');
+            current = Some((year, kind));
+        }
+        result.extend(pb::escape(&data));
+        result.push(b'\n');
+    }
+    result
+}
+
+// Starts a mock appliance that archives the given PVs.
+
+pub async fn start(
+    identity: &str, pvs: Vec<(&str, Vec<Sample>)>,
+) -> MockArchiver {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let data = Arc::new(Data {
+        identity: identity.into(),
+        url: format!("http://{}", listener.local_addr().unwrap()),
+        pvs: pvs.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        status_requests: AtomicUsize::new(0),
+        data_requests: AtomicUsize::new(0),
+    });
+    let app = Router::new()
+        .route("/mgmt/bpl/getPVStatus", get(pv_status))
+        .route("/mgmt/bpl/getApplianceInfo", get(appliance_info))
+        .route("/mgmt/bpl/getAllPVs", get(all_pvs))
+        .route("/mgmt/bpl/getPVTypeInfo", get(type_info))
+        .route(
+            "/retrieval/data/getData.json",
+            get(|State(data): State<Arc<Data>>, Query(q): Params| {
+                get_data(data, q, false)
+            }),
+        )
+        .route(
+            "/retrieval/data/getData.raw",
+            get(|State(data): State<Arc<Data>>, Query(q): Params| {
+                get_data(data, q, true)
+            }),
+        )
+        .with_state(data.clone());
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    MockArchiver(data)
+}
@@ -0,0 +1,535 @@
+// Decodes the Archiver Appliance's PB/HTTP (`.raw`) format. A response
+// is a series of chunks. Each chunk starts with a line holding a
+// `PayloadInfo` message, which gives the type of the samples and the
+// year in which their timestamps start, followed by a line for each
+// sample. Chunks are separated by an empty line. Since the messages are
+// binary, newlines, carriage returns and the escape character inside a
+// message are escaped.
+//
+// The messages are defined in the appliance's `EPICSEvent.proto`. Only
+// the fields used by this service are declared here; prost skips the
+// others.
+
+use super::{EnumLabels, error_reply, global};
+use futures_util::{Stream, stream};
+use prost::Message;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+const ESCAPE: u8 = 0x1b;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration,
+)]
+#[repr(i32)]
+pub(super) enum PayloadType {
+    ScalarString = 0,
+    ScalarShort = 1,
+    ScalarFloat = 2,
+    ScalarEnum = 3,
+    ScalarByte = 4,
+    ScalarInt = 5,
+    ScalarDouble = 6,
+    WaveformString = 7,
+    WaveformShort = 8,
+    WaveformFloat = 9,
+    WaveformEnum = 10,
+    WaveformByte = 11,
+    WaveformInt = 12,
+    WaveformDouble = 13,
+    V4GenericBytes = 14,
+}
+
+// A metadata field of the PV (e.g. its engineering units or the
+// labels of its states.)
+
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct FieldValue {
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(string, required, tag = "2")]
+    pub val: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct PayloadInfo {
+    #[prost(enumeration = "PayloadType", required, tag = "1")]
+    pub r#type: i32,
+    #[prost(string, required, tag = "2")]
+    pub pvname: String,
+    #[prost(int32, required, tag = "3")]
+    pub year: i32,
+    #[prost(int32, optional, tag = "4")]
+    pub element_count: Option<i32>,
+    #[prost(message, repeated, tag = "15")]
+    pub headers: Vec<FieldValue>,
+}
+
+// Declares the message of a sample type. All samples share the
+// timestamp fields; they differ in the type of `val`.
+
+macro_rules! sample {
+    ($name:ident, $ty:ty, $($kind:tt)*) => {
+        #[derive(Clone, PartialEq, Message)]
+        pub(super) struct $name {
+            #[prost(uint32, required, tag = "1")]
+            pub secondsintoyear: u32,
+            #[prost(uint32, required, tag = "2")]
+            pub nano: u32,
+            #[prost($($kind)*, tag = "3")]
+            pub val: $ty,
+        }
+    };
+}
+
+sample!(ScalarString, String, string, required);
+sample!(ScalarShort, i32, sint32, required);
+sample!(ScalarFloat, f32, float, required);
+sample!(ScalarEnum, i32, sint32, required);
+sample!(ScalarByte, Vec<u8>, bytes = "vec", required);
+sample!(ScalarInt, i32, sfixed32, required);
+sample!(ScalarDouble, f64, double, required);
+sample!(VectorString, Vec<String>, string, repeated);
+sample!(VectorShort, Vec<i32>, sint32, repeated, packed = "true");
+sample!(VectorFloat, Vec<f32>, float, repeated, packed = "true");
+sample!(VectorEnum, Vec<i32>, sint32, repeated, packed = "true");
+sample!(VectorChar, Vec<u8>, bytes = "vec", required);
+sample!(VectorInt, Vec<i32>, sfixed32, repeated, packed = "true");
+sample!(VectorDouble, Vec<f64>, double, repeated, packed = "true");
+
+// Removes the escape sequences from a line.
+
+pub(super) fn unescape(line: &[u8]) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(line.len());
+    let mut iter = line.iter();
+
+    while let Some(&b) = iter.next() {
+        if b != ESCAPE {
+            result.push(b);
+            continue;
+        }
+        match iter.next() {
+            Some(1) => result.push(ESCAPE),
+            Some(2) => result.push(b'\n'),
+            Some(3) => result.push(b'\r'),
+            Some(v) => return Err(format!("bad escape sequence 0x{:02x}", v)),
+            None => return Err("line ends with an escape character".into()),
+        }
+    }
+    Ok(result)
+}
+
+// Adds the escape sequences to a message so it can be sent as a line.
+
+#[cfg(test)]
+pub(super) fn escape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+
+    for &b in data {
+        match b {
+            ESCAPE => result.extend([ESCAPE, 1]),
+            b'\n' => result.extend([ESCAPE, 2]),
+            b'\r' => result.extend([ESCAPE, 3]),
+            _ => result.push(b),
+        }
+    }
+    result
+}
+
+// Returns the start of a year, in seconds since 1970, UTC.
+
+fn year_start(year: i32) -> Result<f64, String> {
+    use chrono::{TimeZone, Utc};
+
+    Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+        .single()
+        .map(|v| v.timestamp() as f64)
+        .ok_or_else(|| format!("bad year in payload info: {}", year))
+}
+
+// EPICS CHAR values are signed.
+
+fn chars(data: &[u8]) -> Vec<f64> {
+    data.iter().map(|v| *v as i8 as f64).collect()
+}
+
+fn scalar(v: f64) -> global::DataType {
+    global::DataType::Scalar(global::Scalar { scalar_value: v })
+}
+
+fn scalar_array(v: Vec<f64>) -> global::DataType {
+    global::DataType::ScalarArray(global::ScalarArray {
+        scalar_array_value: v,
+    })
+}
+
+// Decodes a sample of the given type. Returns the seconds into the
+// year, the nanoseconds and the value. `labels` names the states of
+// enumerated samples.
+
+fn decode_sample(
+    kind: PayloadType, labels: &EnumLabels, data: &[u8],
+) -> Result<(u32, u32, global::DataType), String> {
+    macro_rules! decode {
+        ($msg:ident, $xlat:expr) => {{
+            let v = $msg::decode(data).map_err(|e| e.to_string())?;
+
+            (v.secondsintoyear, v.nano, $xlat(v.val))
+        }};
+    }
+
+    Ok(match kind {
+        PayloadType::ScalarString => decode!(ScalarString, |v| {
+            global::DataType::Text(global::Text { text_value: v })
+        }),
+        PayloadType::ScalarShort => {
+            decode!(ScalarShort, |v: i32| scalar(v.into()))
+        }
+        PayloadType::ScalarFloat => {
+            decode!(ScalarFloat, |v: f32| scalar(v.into()))
+        }
+        PayloadType::ScalarEnum => {
+            decode!(ScalarEnum, |v: i32| labels.value(v))
+        }
+        PayloadType::ScalarByte => decode!(ScalarByte, |v: Vec<u8>| {
+            scalar(chars(&v).first().copied().unwrap_or(0.0))
+        }),
+        PayloadType::ScalarInt => decode!(ScalarInt, |v: i32| scalar(v.into())),
+        PayloadType::ScalarDouble => decode!(ScalarDouble, scalar),
+        PayloadType::WaveformString => decode!(VectorString, |v| {
+            global::DataType::TextArray(global::TextArray {
+                text_array_value: v,
+            })
+        }),
+        PayloadType::WaveformShort => decode!(VectorShort, |v: Vec<i32>| {
+            scalar_array(v.into_iter().map(f64::from).collect())
+        }),
+        PayloadType::WaveformFloat => decode!(VectorFloat, |v: Vec<f32>| {
+            scalar_array(v.into_iter().map(f64::from).collect())
+        }),
+        PayloadType::WaveformEnum => decode!(VectorEnum, |v: Vec<i32>| {
+            scalar_array(v.into_iter().map(f64::from).collect())
+        }),
+        PayloadType::WaveformByte => {
+            decode!(VectorChar, |v: Vec<u8>| scalar_array(chars(&v)))
+        }
+        PayloadType::WaveformInt => decode!(VectorInt, |v: Vec<i32>| {
+            scalar_array(v.into_iter().map(f64::from).collect())
+        }),
+        PayloadType::WaveformDouble => decode!(VectorDouble, scalar_array),
+        PayloadType::V4GenericBytes => {
+            return Err("V4 payloads aren't supported".into());
+        }
+    })
+}
+
+struct Chunk {
+    kind: PayloadType,
+    year_start: f64,
+    labels: EnumLabels,
+}
+
+// Tracks the chunk being decoded. Lines are passed to `line()` which
+// returns a reply for each sample.
+
+#[derive(Default)]
+pub(super) struct Decoder {
+    chunk: Option<Chunk>,
+}
+
+impl Decoder {
+    pub fn line(
+        &mut self, line: &[u8],
+    ) -> Result<Option<global::DataReply>, String> {
+        // An empty line ends the chunk. The next line is the header of
+        // the next chunk.
+
+        if line.is_empty() {
+            self.chunk = None;
+            return Ok(None);
+        }
+
+        let data = unescape(line)?;
+
+        match self.chunk {
+            None => {
+                let info = PayloadInfo::decode(&data[..])
+                    .map_err(|e| format!("bad payload info: {}", e))?;
+                let kind =
+                    PayloadType::try_from(info.r#type).map_err(|_| {
+                        format!("unknown payload type {}", info.r#type)
+                    })?;
+
+                self.chunk = Some(Chunk {
+                    kind,
+                    year_start: year_start(info.year)?,
+                    labels: EnumLabels::from_fields(
+                        info.headers
+                            .iter()
+                            .map(|v| (v.name.as_str(), v.val.as_str())),
+                    ),
+                });
+                Ok(None)
+            }
+            Some(Chunk {
+                kind,
+                year_start,
+                ref labels,
+            }) => {
+                let (secs, nano, result) =
+                    decode_sample(kind, labels, &data)
+                        .map_err(|e| format!("bad {:?} sample: {}", kind, e))?;
+
+                Ok(Some(global::DataReply {
+                    ref_id: 0,
+                    data: vec![global::DataInfo {
+                        timestamp: year_start
+                            + secs as f64
+                            + nano as f64 / 1_000_000_000.0,
+                        result,
+                    }],
+                }))
+            }
+        }
+    }
+}
+
+// Returns a stream of the samples in a `.raw` response. Errors are
+// reported with an error status, which ends the stream.
+
+pub(super) fn events<R>(
+    reader: R,
+) -> impl Stream<Item = global::DataReply> + Send + 'static
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    stream::unfold(Some((reader, Decoder::default())), |state| async move {
+        let (mut r, mut decoder) = state?;
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            match r.read_until(b'\n', &mut line).await {
+                Ok(0) => return None,
+                Ok(_) => {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                    }
+                    match decoder.line(&line) {
+                        Ok(Some(reply)) => {
+                            return Some((reply, Some((r, decoder))));
+                        }
+                        Ok(None) => (),
+                        Err(e) => {
+                            let msg = format!(
+                                "failed to decode archiver data: {}",
+                                e
+                            );
+
+                            return Some((error_reply(msg), None));
+                        }
+                    }
+                }
+                Err(e) => {
+                    let msg = format!("failed to read archiver data: {}", e);
+
+                    return Some((error_reply(msg), None));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::StreamExt;
+
+    // Builds the lines of a chunk.
+
+    fn chunk<M: Message>(
+        kind: PayloadType, year: i32, samples: &[M],
+    ) -> Vec<u8> {
+        let info = PayloadInfo {
+            r#type: kind as i32,
+            pvname: "PV:TEST".into(),
+            year,
+            element_count: None,
+            headers: vec![],
+        };
+        let mut result = escape(&info.encode_to_vec());
+
+        result.push(b'\n');
+        for sample in samples {
+            result.extend(escape(&sample.encode_to_vec()));
+            result.push(b'\n');
+        }
+        result
+    }
+
+    #[test]
+    fn test_escaping() {
+        let data = vec![0, b'\n', 1, ESCAPE, 2, b'\r', 3];
+        let escaped = escape(&data);
+
+        assert!(!escaped.contains(&b'\n'));
+        assert_eq!(unescape(&escaped), Ok(data));
+        assert!(unescape(&[ESCAPE]).is_err());
+        assert!(unescape(&[ESCAPE, 9]).is_err());
+    }
+
+    #[test]
+    fn test_decode_samples() {
+        let none = EnumLabels::default();
+        let v = ScalarShort {
+            secondsintoyear: 1,
+            nano: 0,
+            val: -3,
+        };
+
+        assert_eq!(
+            decode_sample(PayloadType::ScalarShort, &none, &v.encode_to_vec()),
+            Ok((1, 0, scalar(-3.0)))
+        );
+
+        let v = VectorChar {
+            secondsintoyear: 2,
+            nano: 5,
+            val: vec![1, 0xff],
+        };
+
+        assert_eq!(
+            decode_sample(PayloadType::WaveformByte, &none, &v.encode_to_vec()),
+            Ok((2, 5, scalar_array(vec![1.0, -1.0])))
+        );
+
+        let v = VectorString {
+            secondsintoyear: 3,
+            nano: 0,
+            val: vec!["a".into(), "b".into()],
+        };
+
+        assert_eq!(
+            decode_sample(
+                PayloadType::WaveformString,
+                &none,
+                &v.encode_to_vec()
+            ),
+            Ok((
+                3,
+                0,
+                global::DataType::TextArray(global::TextArray {
+                    text_array_value: vec!["a".into(), "b".into()]
+                })
+            ))
+        );
+        assert!(
+            decode_sample(PayloadType::ScalarDouble, &none, &[0xff]).is_err()
+        );
+    }
+
+    // Samples with a newline in their encoding and samples spanning
+    // two chunks (and two years) are decoded.
+
+    #[tokio::test]
+    async fn test_events() {
+        let sample = |secondsintoyear, val| ScalarDouble {
+            secondsintoyear,
+            nano: 500_000_000,
+            val,
+        };
+        let mut data = chunk(
+            PayloadType::ScalarDouble,
+            2023,
+            &[sample(10, 1.0), sample(b'\n' as u32, 2.0)],
+        );
+
+        data.push(b'\n');
+        data.extend(chunk(PayloadType::ScalarDouble, 2024, &[sample(0, 3.0)]));
+
+        let replies: Vec<_> =
+            events(std::io::Cursor::new(data)).collect().await;
+        let y2023 = year_start(2023).unwrap();
+        let y2024 = year_start(2024).unwrap();
+
+        assert_eq!(
+            replies
+                .iter()
+                .map(|v| (v.data[0].timestamp, v.data[0].result.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (y2023 + 10.5, scalar(1.0)),
+                (y2023 + 10.5, scalar(2.0)),
+                (y2024 + 0.5, scalar(3.0)),
+            ]
+        );
+    }
+
+    // Enumerated samples are labelled with the state names found in the
+    // chunk's headers.
+
+    #[test]
+    fn test_enum_labels() {
+        let info = PayloadInfo {
+            r#type: PayloadType::ScalarEnum as i32,
+            pvname: "PV:TEST".into(),
+            year: 2024,
+            element_count: None,
+            headers: [("ZNAM", "Off"), ("ONAM", "On"), ("EGU", "")]
+                .into_iter()
+                .map(|(name, val)| FieldValue {
+                    name: name.into(),
+                    val: val.into(),
+                })
+                .collect(),
+        };
+        let sample = |val| ScalarEnum {
+            secondsintoyear: 0,
+            nano: 0,
+            val,
+        };
+        let mut decoder = Decoder::default();
+
+        assert_eq!(decoder.line(&escape(&info.encode_to_vec())), Ok(None));
+        for (val, label) in [(1, Some("On")), (0, Some("Off")), (5, None)] {
+            assert_eq!(
+                decoder
+                    .line(&escape(&sample(val).encode_to_vec()))
+                    .unwrap()
+                    .map(|v| v.data[0].result.clone()),
+                Some(global::DataType::EnumValue(global::EnumValue {
+                    enum_value: val,
+                    label: label.map(String::from),
+                }))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_data_ends_stream() {
+        let mut data = chunk(
+            PayloadType::ScalarDouble,
+            2024,
+            &[ScalarDouble {
+                secondsintoyear: 1,
+                nano: 0,
+                val: 1.0,
+            }],
+        );
+
+        data.extend(b"\xff\xff\n");
+
+        let replies: Vec<_> =
+            events(std::io::Cursor::new(data)).collect().await;
+
+        // The sample that was decoded is followed by an error status.
+
+        assert_eq!(replies.len(), 2);
+        assert!(matches!(
+            replies[1].data[0].result,
+            global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+// Decodes basic status readings. DPM returns the basic status of an
+// ACNET device as a raw bit pattern. DevDB holds the definition of
+// those bits: either as "power supply" style entries, which compare a
+// masked status against a value, or as per-bit definitions. This
+// module combines the two so clients get the decoded form.
+
+use super::{drf, global, types};
+use crate::graphql::devdb::{cache, types::DigStatus};
+
+// Converts the data from a basic status reading into a bit pattern.
+// The raw form of the status is sent in little-endian order; statuses
+// wider than 32 bits aren't supported so extra bytes are ignored.
+
+pub fn raw_status(data: &global::DataType) -> Option<u32> {
+    match data {
+        global::DataType::Raw(global::Raw { raw_value }) => Some(
+            raw_value
+                .iter()
+                .take(4)
+                .enumerate()
+                .fold(0, |acc, (idx, b)| acc | ((*b as u32) << (idx * 8))),
+        ),
+        global::DataType::Scalar(global::Scalar { scalar_value }) => {
+            Some(*scalar_value as u32)
+        }
+        _ => None,
+    }
+}
+
+// Decodes a bit pattern using the device's status definition. The
+// legacy entries are reported first, followed by the per-bit
+// definitions.
+
+pub fn decode(raw: u32, config: &DigStatus) -> Vec<types::StatusBit> {
+    let legacy = config.entries.iter().map(|e| {
+        let value = if e.invert { !raw } else { raw };
+        let good = (value & e.mask_val) == e.match_val;
+
+        types::StatusBit {
+            name: e.short_name.clone(),
+            description: e.long_name.clone(),
+            value: good,
+            text: if good { &e.true_str } else { &e.false_str }.clone(),
+            color: if good { e.true_color } else { e.false_color },
+            character: Some(
+                if good { &e.true_char } else { &e.false_char }.clone(),
+            ),
+        }
+    });
+    let extended = config.ext_entries.iter().map(|e| {
+        let set = e.bit_no < 32 && (raw & (1 << e.bit_no)) != 0;
+
+        types::StatusBit {
+            name: if set { &e.name1 } else { &e.name0 }.clone(),
+            description: e.description.clone(),
+            value: set,
+            text: if set { &e.name1 } else { &e.name0 }.clone(),
+            color: if set { e.color1 } else { e.color0 },
+            character: None,
+        }
+    });
+
+    legacy.chain(extended).collect()
+}
+
+// Decodes a reply from DPM. Readings that hold a status code are
+// passed along in the `status` field.
+
+pub fn decode_reply(
+    config: &DigStatus, reply: global::DataReply,
+) -> types::BasicStatusReply {
+    types::BasicStatusReply {
+        ref_id: reply.ref_id,
+        data: reply
+            .data
+            .into_iter()
+            .map(|v| {
+                let raw = raw_status(&v.result);
+
+                types::BasicStatus {
+                    timestamp: v.timestamp,
+                    raw_status: raw,
+                    bits: raw
+                        .map(|raw| decode(raw, config))
+                        .unwrap_or_default(),
+                    status: match v.result {
+                        global::DataType::StatusReply(status) => Some(status),
+                        _ => None,
+                    },
+                }
+            })
+            .collect(),
+    }
+}
+
+// Builds the DPM requests for a set of DRF strings. Each request reads
+// the raw basic status of the device using the event of the original
+// DRF string (or `default_event`, if it doesn't have one.) The status
+// definition of each device is also returned. All devices must be
+// ACNET devices with a basic status property.
+
+pub async fn prepare(
+    drfs: &[String], default_event: &str,
+) -> Result<(Vec<String>, Vec<DigStatus>), String> {
+    let mut requests = vec![];
+    let mut configs = vec![];
+
+    for entry in drfs {
+        let target = drf::parse_target(entry)
+            .ok_or_else(|| format!("{} isn't an ACNET device", entry))?;
+        let info = cache::device_info(&target.device)
+            .await
+            .map_err(|e| format!("{}: {}", target.device, e))?;
+
+        let Some(ref config) = info.dig_status else {
+            return Err(format!(
+                "{} doesn't have a basic status property",
+                target.device
+            ));
+        };
+        let event = drf::event(entry).unwrap_or(default_event);
+
+        requests.push(format!("{}.STATUS.RAW{}", target.device, event));
+        configs.push(config.clone());
+    }
+    Ok((requests, configs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::devdb::types::{DigExtStatusEntry, DigStatusEntry};
+
+    fn entry(mask_val: u32, match_val: u32, invert: bool) -> DigStatusEntry {
+        DigStatusEntry {
+            mask_val,
+            match_val,
+            invert,
+            short_name: "On/Off".into(),
+            long_name: "Power".into(),
+            true_str: "On".into(),
+            true_color: 2,
+            true_char: ".".into(),
+            false_str: "Off".into(),
+            false_color: 1,
+            false_char: "*".into(),
+        }
+    }
+
+    fn ext_entry(bit_no: u32) -> DigExtStatusEntry {
+        DigExtStatusEntry {
+            bit_no,
+            color0: 1,
+            name0: "Tripped".into(),
+            color1: 2,
+            name1: "Ready".into(),
+            description: "Ready/Tripped".into(),
+        }
+    }
+
+    #[test]
+    fn test_raw_status() {
+        assert_eq!(
+            raw_status(&global::DataType::Raw(global::Raw {
+                raw_value: vec![0x34, 0x12]
+            })),
+            Some(0x1234)
+        );
+        assert_eq!(
+            raw_status(&global::DataType::Raw(global::Raw {
+                raw_value: vec![1, 2, 3, 4, 5]
+            })),
+            Some(0x04030201)
+        );
+        assert_eq!(
+            raw_status(&global::DataType::Scalar(global::Scalar {
+                scalar_value: 5.0
+            })),
+            Some(5)
+        );
+        assert_eq!(
+            raw_status(&global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: None,
+            })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_legacy_entries() {
+        let config = DigStatus {
+            entries: vec![entry(0x3, 0x1, false), entry(0x1, 0x0, true)],
+            ext_entries: vec![],
+        };
+        let bits = decode(0x1, &config);
+
+        assert_eq!(bits.len(), 2);
+        assert!(bits[0].value);
+        assert_eq!(bits[0].text, "On");
+        assert_eq!(bits[0].color, 2);
+        assert_eq!(bits[0].character.as_deref(), Some("."));
+
+        // The inverted entry sees 0xfffffffe, so the masked value
+        // matches.
+
+        assert!(bits[1].value);
+
+        let bits = decode(0x2, &config);
+
+        assert!(!bits[0].value);
+        assert_eq!(bits[0].text, "Off");
+        assert_eq!(bits[0].color, 1);
+        assert_eq!(bits[0].character.as_deref(), Some("*"));
+        assert!(!bits[1].value);
+    }
+
+    #[test]
+    fn test_extended_entries() {
+        let config = DigStatus {
+            entries: vec![],
+            ext_entries: vec![ext_entry(4), ext_entry(40)],
+        };
+        let bits = decode(0x10, &config);
+
+        assert!(bits[0].value);
+        assert_eq!(bits[0].text, "Ready");
+        assert_eq!(bits[0].color, 2);
+        assert_eq!(bits[0].description, "Ready/Tripped");
+        assert_eq!(bits[0].character, None);
+        assert!(!bits[1].value);
+        assert_eq!(bits[1].text, "Tripped");
+    }
+
+    #[test]
+    fn test_decode_reply() {
+        let config = DigStatus {
+            entries: vec![entry(0x1, 0x1, false)],
+            ext_entries: vec![],
+        };
+        let reply = decode_reply(
+            &config,
+            global::DataReply {
+                ref_id: 3,
+                data: vec![
+                    global::DataInfo {
+                        timestamp: 1.0,
+                        result: global::DataType::Raw(global::Raw {
+                            raw_value: vec![1, 0],
+                        }),
+                    },
+                    global::DataInfo {
+                        timestamp: 2.0,
+                        result: global::DataType::StatusReply(
+                            global::StatusReply {
+                                status: -6 * 256 + 1,
+                                message: None,
+                            },
+                        ),
+                    },
+                ],
+            },
+        );
+
+        assert_eq!(reply.ref_id, 3);
+        assert_eq!(reply.data[0].raw_status, Some(1));
+        assert!(reply.data[0].bits[0].value);
+        assert_eq!(reply.data[0].status, None);
+        assert!(reply.data[1].bits.is_empty());
+        assert_eq!(
+            reply.data[1].status,
+            Some(global::StatusReply {
+                status: -6 * 256 + 1,
+                message: None,
+            })
+        );
+    }
+}
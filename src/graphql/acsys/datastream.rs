@@ -1,13 +1,23 @@
-use super::global;
+use super::{archivecache, global, now, types};
 
+mod aggregate;
+mod align;
 mod archivestream;
+mod chunked;
 mod datachannel;
 mod datamerge;
+mod decimate;
 mod endondate;
 mod groupscalars;
+#[cfg(test)]
+pub mod testing;
 
+pub use aggregate::{aggregate, bin_width};
+pub use align::{Grid, align};
 pub use archivestream::as_archive_stream;
-pub use datachannel::DataChannel;
+pub use chunked::{Replies, retrieve_chunks, split_range};
+pub use datachannel::{DataChannel, Limits};
 pub use datamerge::merge;
+pub use decimate::decimate;
 pub use endondate::end_stream_at;
 pub use groupscalars::group_scalars;
@@ -0,0 +1,381 @@
+// Defines a stream that reduces archived data to one value per time
+// bin. It's the local equivalent of the Archiver Appliance's
+// post-processing operators and is used on ACNET logger data so that
+// both sources return comparable results. Like the appliance, each bin
+// is timestamped with the start of the bin.
+//
+// Only scalar readings are reduced. Other readings, and statuses, end
+// the current bin and are passed along unmodified. The empty reply
+// that marks the end of archived data is also passed along, after the
+// last bin.
+
+use super::{
+    global,
+    types::{Aggregation, AggregationOperator},
+};
+use futures::Stream;
+use futures_util::StreamExt;
+use std::{collections::VecDeque, pin::Pin, task::Poll};
+
+// Returns the bin width of an aggregation as a whole number of
+// seconds (or samples, for `NTH`.)
+
+pub fn bin_width(aggregation: &Aggregation) -> Result<u64, String> {
+    let width = aggregation.bin_size.round();
+
+    if width.is_finite() && width >= 1.0 {
+        Ok(width as u64)
+    } else {
+        Err(format!(
+            "aggregation bin size must be at least 1 -- got {}",
+            aggregation.bin_size
+        ))
+    }
+}
+
+// Reduces the samples of a bin. `values` is never empty.
+
+pub fn reduce(operator: AggregationOperator, values: &mut [f64]) -> f64 {
+    let n = values.len() as f64;
+
+    match operator {
+        AggregationOperator::Mean => values.iter().sum::<f64>() / n,
+        AggregationOperator::Min => {
+            values.iter().copied().fold(f64::INFINITY, f64::min)
+        }
+        AggregationOperator::Max => {
+            values.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+        }
+        AggregationOperator::First | AggregationOperator::Nth => values[0],
+        AggregationOperator::Last => values[values.len() - 1],
+        AggregationOperator::Count => n,
+        AggregationOperator::Std if values.len() < 2 => 0.0,
+        AggregationOperator::Std => {
+            let mean = values.iter().sum::<f64>() / n;
+            let sum_sq: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+
+            (sum_sq / (n - 1.0)).sqrt()
+        }
+        AggregationOperator::Median => {
+            let mid = values.len() / 2;
+
+            values.sort_unstable_by(f64::total_cmp);
+            if values.len().is_multiple_of(2) {
+                (values[mid - 1] + values[mid]) / 2.0
+            } else {
+                values[mid]
+            }
+        }
+    }
+}
+
+struct Bin {
+    index: i64,
+    values: Vec<f64>,
+}
+
+pub struct Aggregate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    s: Option<S>,
+    operator: AggregationOperator,
+    width: u64,
+    ref_id: i32,
+    bin: Option<Bin>,
+    samples: u64,
+    ready: VecDeque<global::DataReply>,
+}
+
+impl<S> Aggregate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    pub fn new(s: S, operator: AggregationOperator, width: u64) -> Self {
+        Aggregate {
+            s: Some(s),
+            operator,
+            width,
+            ref_id: 0,
+            bin: None,
+            samples: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    // Reduces the current bin, if there is one.
+
+    fn flush(&mut self) -> Option<global::DataInfo> {
+        self.bin.take().map(|mut bin| global::DataInfo {
+            timestamp: (bin.index * self.width as i64) as f64,
+            result: global::DataType::Scalar(global::Scalar {
+                scalar_value: reduce(self.operator, &mut bin.values),
+            }),
+        })
+    }
+
+    // Adds a scalar sample. If it starts a new bin, the reduced value
+    // of the previous bin is returned.
+
+    fn add(&mut self, timestamp: f64, value: f64) -> Option<global::DataInfo> {
+        // `NTH` isn't time based; it keeps every nth sample.
+
+        if self.operator == AggregationOperator::Nth {
+            let keep = self.samples.is_multiple_of(self.width);
+
+            self.samples += 1;
+            return keep.then(|| global::DataInfo {
+                timestamp,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: value,
+                }),
+            });
+        }
+
+        let index = (timestamp / self.width as f64).floor() as i64;
+
+        match self.bin {
+            Some(ref mut bin) if bin.index == index => {
+                bin.values.push(value);
+                None
+            }
+            _ => {
+                let result = self.flush();
+
+                self.bin = Some(Bin {
+                    index,
+                    values: vec![value],
+                });
+                result
+            }
+        }
+    }
+
+    fn process(&mut self, reply: global::DataReply) {
+        self.ref_id = reply.ref_id;
+
+        if reply.data.is_empty() {
+            self.finish();
+            self.ready.push_back(reply);
+            return;
+        }
+
+        let mut out = vec![];
+
+        for info in reply.data {
+            if let global::DataType::Scalar(global::Scalar { scalar_value }) =
+                info.result
+            {
+                out.extend(self.add(info.timestamp, scalar_value));
+            } else {
+                out.extend(self.flush());
+                out.push(info);
+            }
+        }
+
+        if !out.is_empty() {
+            self.ready.push_back(global::DataReply {
+                ref_id: self.ref_id,
+                data: out,
+            });
+        }
+    }
+
+    // Sends the last bin.
+
+    fn finish(&mut self) {
+        if let Some(info) = self.flush() {
+            self.ready.push_back(global::DataReply {
+                ref_id: self.ref_id,
+                data: vec![info],
+            });
+        }
+    }
+}
+
+// Reduces a stream of archived data. If `aggregation` is `None`, the
+// stream is returned unmodified.
+
+#[inline(never)]
+pub fn aggregate(
+    s: impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+    aggregation: Option<Aggregation>,
+) -> Result<
+    impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+    String,
+> {
+    use futures::future::Either;
+
+    match aggregation {
+        Some(aggregation) => Ok(Either::Left(Aggregate::new(
+            s,
+            aggregation.operator,
+            bin_width(&aggregation)?,
+        ))),
+        None => Ok(Either::Right(s)),
+    }
+}
+
+impl<S> Stream for Aggregate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    type Item = global::DataReply;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, ctxt: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(reply) = self.ready.pop_front() {
+                break Poll::Ready(Some(reply));
+            }
+
+            let Some(s) = self.s.as_mut() else {
+                break Poll::Ready(None);
+            };
+
+            match s.poll_next_unpin(ctxt) {
+                Poll::Ready(Some(reply)) => self.process(reply),
+                Poll::Ready(None) => {
+                    self.s = None;
+                    self.finish();
+                }
+                Poll::Pending => break Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn scalar_info(ts: f64, v: f64) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::Scalar(global::Scalar {
+                scalar_value: v,
+            }),
+        }
+    }
+
+    fn reply(data: Vec<global::DataInfo>) -> global::DataReply {
+        global::DataReply { ref_id: 2, data }
+    }
+
+    fn agg(operator: AggregationOperator, bin_size: f64) -> Aggregation {
+        Aggregation { operator, bin_size }
+    }
+
+    #[test]
+    fn test_reduce() {
+        let values = [4.0, 1.0, 3.0, 2.0];
+        let check = |op| reduce(op, &mut values.clone());
+
+        assert_eq!(check(AggregationOperator::Mean), 2.5);
+        assert_eq!(check(AggregationOperator::Min), 1.0);
+        assert_eq!(check(AggregationOperator::Max), 4.0);
+        assert_eq!(check(AggregationOperator::First), 4.0);
+        assert_eq!(check(AggregationOperator::Last), 2.0);
+        assert_eq!(check(AggregationOperator::Count), 4.0);
+        assert_eq!(check(AggregationOperator::Median), 2.5);
+        assert!((check(AggregationOperator::Std) - 1.290_994).abs() < 1e-6);
+        assert_eq!(reduce(AggregationOperator::Std, &mut [5.0]), 0.0);
+    }
+
+    #[test]
+    fn test_bin_width() {
+        assert_eq!(
+            bin_width(&agg(AggregationOperator::Mean, 3600.0)),
+            Ok(3600)
+        );
+        assert_eq!(bin_width(&agg(AggregationOperator::Mean, 1.4)), Ok(1));
+        assert!(bin_width(&agg(AggregationOperator::Mean, 0.2)).is_err());
+        assert!(bin_width(&agg(AggregationOperator::Mean, f64::NAN)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bins() {
+        let input = vec![
+            reply(vec![
+                scalar_info(100.0, 1.0),
+                scalar_info(105.0, 3.0),
+                scalar_info(110.0, 5.0),
+            ]),
+            reply(vec![scalar_info(125.0, 7.0)]),
+            reply(vec![]),
+        ];
+        let s = aggregate(
+            stream::iter(input),
+            Some(agg(AggregationOperator::Mean, 10.0)),
+        )
+        .unwrap();
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(
+            result,
+            vec![
+                reply(vec![scalar_info(100.0, 2.0)]),
+                reply(vec![scalar_info(110.0, 5.0)]),
+                reply(vec![scalar_info(120.0, 7.0)]),
+                reply(vec![]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status_ends_bin() {
+        let status = global::DataInfo {
+            timestamp: 103.0,
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: None,
+            }),
+        };
+        let input = vec![reply(vec![
+            scalar_info(100.0, 1.0),
+            scalar_info(101.0, 3.0),
+            status.clone(),
+        ])];
+        let s = aggregate(
+            stream::iter(input),
+            Some(agg(AggregationOperator::Max, 10.0)),
+        )
+        .unwrap();
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(result, vec![reply(vec![scalar_info(100.0, 3.0), status])]);
+    }
+
+    #[tokio::test]
+    async fn test_nth() {
+        let input = vec![reply(
+            (0..7).map(|v| scalar_info(v as f64, v as f64)).collect(),
+        )];
+        let s = aggregate(
+            stream::iter(input),
+            Some(agg(AggregationOperator::Nth, 3.0)),
+        )
+        .unwrap();
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(
+            result,
+            vec![reply(vec![
+                scalar_info(0.0, 0.0),
+                scalar_info(3.0, 3.0),
+                scalar_info(6.0, 6.0),
+            ])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_aggregation() {
+        let input = vec![reply(vec![scalar_info(1.0, 1.0)])];
+        let s = aggregate(stream::iter(input.clone()), None).unwrap();
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(result, input);
+    }
+}
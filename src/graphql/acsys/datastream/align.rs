@@ -0,0 +1,441 @@
+// Aligns the archived data of several devices to a common time grid.
+// Each device's archive stream has its own timestamps; this stage
+// produces rows holding every device's value at the same time. The
+// rows are either spaced at a fixed interval or follow the timestamps
+// of one of the devices (the "master" clock.)
+//
+// Each device keeps its latest reading at, or before, the row's time
+// and the reading that follows it, which is all the interpolation
+// needs. The streams are read only as far as the current row, so
+// memory use doesn't depend on the length of the range. Rows are sent
+// in blocks.
+
+use super::{
+    global,
+    types::{AlignedData, AlignedRow, AlignedStatus, Interpolation},
+};
+use futures::{Stream, stream};
+use futures_util::StreamExt;
+use std::collections::VecDeque;
+
+// The most rows sent in one block.
+
+const BLOCK_ROWS: usize = 500;
+
+// The most rows an interval grid may have.
+
+const MAX_ROWS: f64 = 1_000_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grid {
+    // A row every `step` seconds, from `start` through `end`.
+    Interval { start: f64, end: f64, step: f64 },
+
+    // A row at each timestamp of the device with this index.
+    Master(usize),
+}
+
+impl Grid {
+    // Builds the grid described by a request's parameters. Exactly one
+    // of `interval` and `master` must be given.
+
+    pub fn new(
+        start: f64, end: f64, interval: Option<f64>, master: Option<i32>,
+        devices: usize,
+    ) -> Result<Self, String> {
+        match (interval, master) {
+            (Some(step), None) if step.is_finite() && step > 0.0 => {
+                if (end - start) / step >= MAX_ROWS {
+                    Err(format!(
+                        "an interval of {} seconds results in more than {} \
+                         rows",
+                        step, MAX_ROWS
+                    ))
+                } else {
+                    Ok(Grid::Interval { start, end, step })
+                }
+            }
+            (Some(step), None) => Err(format!(
+                "interval must be a positive number -- got {}",
+                step
+            )),
+            (None, Some(idx)) if idx >= 0 && (idx as usize) < devices => {
+                Ok(Grid::Master(idx as usize))
+            }
+            (None, Some(idx)) => Err(format!(
+                "master must be the index of one of the DRFs -- got {}",
+                idx
+            )),
+            _ => {
+                Err("exactly one of interval and master must be specified"
+                    .into())
+            }
+        }
+    }
+}
+
+// The state of one device's data.
+
+struct Track<S> {
+    s: Option<S>,
+    queue: VecDeque<global::DataInfo>,
+    prev: Option<global::DataInfo>,
+}
+
+impl<S> Track<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    // Reads the device's stream until a reading is queued or the data
+    // ends. Warnings are added to `statuses`. An error status is
+    // returned; the device's data is incomplete after it.
+
+    async fn fill(
+        &mut self, ref_id: i32, statuses: &mut Vec<AlignedStatus>,
+    ) -> Result<(), global::StatusReply> {
+        while self.queue.is_empty() {
+            let Some(s) = self.s.as_mut() else {
+                break;
+            };
+            let Some(reply) = s.next().await else {
+                self.s = None;
+                break;
+            };
+
+            for info in reply.data {
+                match info.result {
+                    global::DataType::StatusReply(ref status)
+                        if status.status < 0 =>
+                    {
+                        self.s = None;
+                        return Err(status.clone());
+                    }
+                    global::DataType::StatusReply(ref status) => {
+                        statuses.push(AlignedStatus {
+                            ref_id,
+                            status: status.clone(),
+                        })
+                    }
+                    _ => self.queue.push_back(info),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Consumes the readings up to, and including, time `t`. The last
+    // of them becomes the previous reading.
+
+    async fn advance(
+        &mut self, t: f64, ref_id: i32, statuses: &mut Vec<AlignedStatus>,
+    ) -> Result<(), global::StatusReply> {
+        loop {
+            self.fill(ref_id, statuses).await?;
+            match self.queue.front() {
+                Some(v) if v.timestamp <= t => {
+                    self.prev = self.queue.pop_front()
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Returns the device's value at time `t`. The track must have been
+    // advanced to `t`.
+
+    fn value(
+        &self, t: f64, interpolation: Interpolation,
+    ) -> Option<global::DataType> {
+        let prev = self.prev.as_ref()?;
+
+        if prev.timestamp == t {
+            return Some(prev.result.clone());
+        }
+
+        match interpolation {
+            Interpolation::Previous => Some(prev.result.clone()),
+            Interpolation::None => None,
+            Interpolation::Linear => match (&prev.result, self.queue.front()) {
+                (
+                    global::DataType::Scalar(a),
+                    Some(global::DataInfo {
+                        timestamp,
+                        result: global::DataType::Scalar(b),
+                    }),
+                ) => {
+                    let frac =
+                        (t - prev.timestamp) / (timestamp - prev.timestamp);
+
+                    Some(global::DataType::Scalar(global::Scalar {
+                        scalar_value: a.scalar_value
+                            + (b.scalar_value - a.scalar_value) * frac,
+                    }))
+                }
+                (global::DataType::Scalar(_), _) => None,
+                (v, _) => Some(v.clone()),
+            },
+        }
+    }
+}
+
+struct Aligner<S> {
+    tracks: Vec<Track<S>>,
+    grid: Grid,
+    interpolation: Interpolation,
+    row: u64,
+    done: bool,
+}
+
+impl<S> Aligner<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    // Returns the time of the next row or `None`, if the grid is
+    // complete. Errors are returned with the index of the device that
+    // reported them.
+
+    async fn next_time(
+        &mut self, statuses: &mut Vec<AlignedStatus>,
+    ) -> Result<Option<f64>, (i32, global::StatusReply)> {
+        match self.grid {
+            Grid::Interval { start, end, step } => {
+                let t = start + self.row as f64 * step;
+
+                self.row += 1;
+                Ok((t <= end).then_some(t))
+            }
+            Grid::Master(idx) => {
+                let track = &mut self.tracks[idx];
+
+                track
+                    .fill(idx as i32, statuses)
+                    .await
+                    .map_err(|e| (idx as i32, e))?;
+                Ok(track.queue.front().map(|v| v.timestamp))
+            }
+        }
+    }
+
+    async fn next_row(
+        &mut self, statuses: &mut Vec<AlignedStatus>,
+    ) -> Result<Option<AlignedRow>, (i32, global::StatusReply)> {
+        let Some(t) = self.next_time(statuses).await? else {
+            return Ok(None);
+        };
+
+        for (idx, track) in self.tracks.iter_mut().enumerate() {
+            track
+                .advance(t, idx as i32, statuses)
+                .await
+                .map_err(|e| (idx as i32, e))?;
+        }
+
+        Ok(Some(AlignedRow {
+            timestamp: t,
+            values: self
+                .tracks
+                .iter()
+                .map(|v| v.value(t, self.interpolation))
+                .collect(),
+        }))
+    }
+
+    async fn next_block(&mut self) -> Option<AlignedData> {
+        if self.done {
+            return None;
+        }
+
+        let mut rows = vec![];
+        let mut statuses = vec![];
+
+        while rows.len() < BLOCK_ROWS {
+            match self.next_row(&mut statuses).await {
+                Ok(Some(row)) => rows.push(row),
+                Ok(None) => {
+                    self.done = true;
+                    break;
+                }
+                Err((ref_id, status)) => {
+                    statuses.push(AlignedStatus { ref_id, status });
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        (!rows.is_empty() || !statuses.is_empty())
+            .then_some(AlignedData { rows, statuses })
+    }
+}
+
+// Returns a stream of the aligned data of `streams`, which hold the
+// archived data of each device in the order of the request.
+
+pub fn align<S>(
+    streams: Vec<S>, grid: Grid, interpolation: Interpolation,
+) -> impl Stream<Item = AlignedData> + Send + 'static
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    let aligner = Aligner {
+        tracks: streams
+            .into_iter()
+            .map(|s| Track {
+                s: Some(s),
+                queue: VecDeque::new(),
+                prev: None,
+            })
+            .collect(),
+        grid,
+        interpolation,
+        row: 0,
+        done: false,
+    };
+
+    stream::unfold(aligner, |mut aligner| async move {
+        aligner.next_block().await.map(|v| (v, aligner))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::{
+        archive, scalar, scalar_info,
+    };
+
+    fn status(ts: f64, status: i16) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::StatusReply(global::StatusReply {
+                status,
+                message: None,
+            }),
+        }
+    }
+
+    async fn rows(
+        streams: Vec<
+            impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+        >,
+        grid: Grid, interpolation: Interpolation,
+    ) -> Vec<(f64, Vec<Option<global::DataType>>)> {
+        align(streams, grid, interpolation)
+            .flat_map(|v| stream::iter(v.rows))
+            .map(|v| (v.timestamp, v.values))
+            .collect()
+            .await
+    }
+
+    fn devices()
+    -> Vec<impl Stream<Item = global::DataReply> + Send + 'static + Unpin> {
+        vec![
+            archive(vec![scalar_info(1.0, 10.0), scalar_info(3.0, 30.0)]),
+            archive(vec![scalar_info(2.0, 0.0), scalar_info(4.0, 4.0)]),
+        ]
+    }
+
+    #[test]
+    fn test_grid() {
+        assert_eq!(
+            Grid::new(0.0, 10.0, Some(1.0), None, 2),
+            Ok(Grid::Interval {
+                start: 0.0,
+                end: 10.0,
+                step: 1.0
+            })
+        );
+        assert_eq!(Grid::new(0.0, 10.0, None, Some(1), 2), Ok(Grid::Master(1)));
+        assert!(Grid::new(0.0, 10.0, None, None, 2).is_err());
+        assert!(Grid::new(0.0, 10.0, Some(1.0), Some(0), 2).is_err());
+        assert!(Grid::new(0.0, 10.0, Some(0.0), None, 2).is_err());
+        assert!(Grid::new(0.0, 10.0, None, Some(2), 2).is_err());
+        assert!(Grid::new(0.0, 10.0, None, Some(-1), 2).is_err());
+        assert!(Grid::new(0.0, 1e9, Some(1.0), None, 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interval() {
+        let grid = Grid::Interval {
+            start: 1.0,
+            end: 4.0,
+            step: 1.0,
+        };
+
+        assert_eq!(
+            rows(devices(), grid, Interpolation::Previous).await,
+            vec![
+                (1.0, vec![scalar(10.0), None]),
+                (2.0, vec![scalar(10.0), scalar(0.0)]),
+                (3.0, vec![scalar(30.0), scalar(0.0)]),
+                (4.0, vec![scalar(30.0), scalar(4.0)]),
+            ]
+        );
+        assert_eq!(
+            rows(devices(), grid, Interpolation::Linear).await,
+            vec![
+                (1.0, vec![scalar(10.0), None]),
+                (2.0, vec![scalar(20.0), scalar(0.0)]),
+                (3.0, vec![scalar(30.0), scalar(2.0)]),
+                (4.0, vec![None, scalar(4.0)]),
+            ]
+        );
+        assert_eq!(
+            rows(devices(), grid, Interpolation::None).await,
+            vec![
+                (1.0, vec![scalar(10.0), None]),
+                (2.0, vec![None, scalar(0.0)]),
+                (3.0, vec![scalar(30.0), None]),
+                (4.0, vec![None, scalar(4.0)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_master() {
+        assert_eq!(
+            rows(devices(), Grid::Master(1), Interpolation::Previous).await,
+            vec![
+                (2.0, vec![scalar(10.0), scalar(0.0)]),
+                (4.0, vec![scalar(30.0), scalar(4.0)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statuses() {
+        let grid = Grid::Interval {
+            start: 1.0,
+            end: 4.0,
+            step: 1.0,
+        };
+
+        // Warnings are reported and the data continues.
+
+        let s = vec![
+            archive(vec![status(0.0, 1 + 3 * 256), scalar_info(1.0, 1.0)]),
+            archive(vec![scalar_info(1.0, 2.0)]),
+        ];
+        let blocks: Vec<_> =
+            align(s, grid, Interpolation::Previous).collect().await;
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].rows.len(), 4);
+        assert_eq!(blocks[0].statuses.len(), 1);
+        assert_eq!(blocks[0].statuses[0].ref_id, 0);
+
+        // An error ends the stream.
+
+        let s = vec![
+            archive(vec![scalar_info(1.0, 1.0)]),
+            archive(vec![scalar_info(1.0, 2.0), status(5.0, -1)]),
+        ];
+        let blocks: Vec<_> =
+            align(s, grid, Interpolation::Previous).collect().await;
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].rows.len(), 0);
+        assert_eq!(blocks[0].statuses[0].ref_id, 1);
+        assert_eq!(blocks[0].statuses[0].status.status, -1);
+    }
+}
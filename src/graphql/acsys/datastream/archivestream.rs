@@ -104,6 +104,7 @@ mod test {
             timestamp: ts,
             result: global::DataType::StatusReply(global::StatusReply {
                 status: code,
+                message: None,
             }),
         }
     }
@@ -0,0 +1,326 @@
+// Retrieves a long range of archived data as a series of shorter
+// requests. The requests run concurrently, up to a limit, but their
+// data is returned in time order so the result looks like a single
+// archive stream.
+//
+// Chunk boundaries fall on multiples of the chunk size (counting from
+// 1970) so the same range is always split the same way and, when the
+// chunk size is a multiple of an aggregation's bin width, bins never
+// straddle two chunks.
+
+use super::{global, now};
+use futures::{Future, Stream, stream, stream::BoxStream};
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// Splits `start..end` into ranges that are, at most, `size` seconds
+// long. A `size` that isn't positive, or is infinite, results in a
+// single range.
+
+pub fn split_range(start: f64, end: f64, size: f64) -> Vec<(f64, f64)> {
+    let mut result = vec![];
+    let mut from = start;
+
+    if size.is_finite() && size > 0.0 {
+        loop {
+            let next = ((from / size).floor() + 1.0) * size;
+
+            if next >= end {
+                break;
+            }
+            result.push((from, next));
+            from = next;
+        }
+    }
+    result.push((from, end));
+    result
+}
+
+// The stream of one chunk's data.
+
+pub type Replies = BoxStream<'static, global::DataReply>;
+
+// The most replies of a chunk that are read ahead of the client. This
+// bounds the memory used by chunks waiting their turn.
+
+const READ_AHEAD: usize = 8;
+
+// Removes the data of a reply that belongs to the following chunk.
+// Archives return the samples at both ends of a range, so readings at
+// the chunk's end time are dropped. The empty reply marking the end of
+// the chunk's data is dropped, too. Statuses are kept since their
+// timestamps aren't related to the data.
+
+fn trim(mut reply: global::DataReply, end: f64) -> Option<global::DataReply> {
+    reply.data.retain(|info| {
+        matches!(info.result, global::DataType::StatusReply(_))
+            || info.timestamp < end
+    });
+    (!reply.data.is_empty()).then_some(reply)
+}
+
+// Returns `true` if the reply contains an error status. Archive streams
+// end after reporting an error.
+
+fn is_fatal(reply: &global::DataReply) -> bool {
+    reply.data.iter().any(|info| {
+        matches!(info.result, global::DataType::StatusReply(ref v) if v.status < 0)
+    })
+}
+
+// Builds the status reported when a chunk couldn't be retrieved.
+
+fn error_reply(message: String) -> global::DataReply {
+    global::DataReply {
+        ref_id: 0,
+        data: vec![global::DataInfo {
+            timestamp: now(),
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: Some(message),
+            }),
+        }],
+    }
+}
+
+// Starts retrieving a chunk in its own task so it makes progress while
+// earlier chunks are being sent to the client. The task reads, at
+// most, `READ_AHEAD` replies ahead and stops if the receiver is
+// dropped. A failure to open the chunk is sent as an `Err`.
+
+fn spawn_chunk<Fut>(
+    chunk: Fut, end: Option<f64>,
+) -> mpsc::Receiver<Result<global::DataReply, String>>
+where
+    Fut: Future<Output = Result<Replies, String>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(READ_AHEAD);
+
+    tokio::spawn(async move {
+        let mut replies = match chunk.await {
+            Ok(replies) => replies,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        while let Some(reply) = replies.next().await {
+            let reply = match end {
+                Some(end) => trim(reply, end),
+                None => Some(reply),
+            };
+
+            if let Some(reply) = reply
+                && tx.send(Ok(reply)).await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Converts a chunk's channel into its stream of replies. A failure to
+// open the chunk becomes an error status.
+
+fn chunk_replies(
+    rx: mpsc::Receiver<Result<global::DataReply, String>>,
+) -> impl Stream<Item = global::DataReply> + Send + 'static {
+    ReceiverStream::new(rx).map(|v| v.unwrap_or_else(error_reply))
+}
+
+// Retrieves the archived data in `ranges`. `fetch` opens the stream of
+// one range's data and, at most, `parallel` of them are read at once.
+// Chunks are streamed to the client so, rather than holding a chunk's
+// data, only a few replies of each are read ahead. The first range is
+// opened before returning so a source that can't supply the data is
+// reported as an error. A later failure ends the stream with an error
+// status.
+
+pub async fn retrieve_chunks<F, Fut>(
+    ranges: Vec<(f64, f64)>, parallel: usize, mut fetch: F,
+) -> Result<
+    impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+    String,
+>
+where
+    F: FnMut(f64, f64) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Replies, String>> + Send + 'static,
+{
+    let last = ranges.len().saturating_sub(1);
+    let mut chunks = stream::iter(ranges.into_iter().enumerate())
+        .map(move |(idx, (start, end))| {
+            let rx =
+                spawn_chunk(fetch(start, end), (idx != last).then_some(end));
+
+            futures::future::ready(rx)
+        })
+        .buffered(parallel.max(1))
+        .boxed();
+
+    // Waits for the first reply of the first chunk so an archive that
+    // can't supply the data is reported as an error.
+
+    let mut first = match chunks.next().await {
+        Some(rx) => rx,
+        None => return Ok(stream::empty().boxed()),
+    };
+    let head = match first.recv().await {
+        Some(Err(e)) => return Err(e),
+        head => head,
+    };
+
+    Ok(stream::iter(head)
+        .map(|v| v.unwrap_or_else(error_reply))
+        .chain(chunk_replies(first))
+        .chain(chunks.flat_map(chunk_replies))
+        .scan(false, |done, reply| {
+            // Nothing follows an error.
+
+            let result = (!*done).then(|| {
+                *done = is_fatal(&reply);
+                reply
+            });
+
+            futures::future::ready(result)
+        })
+        .boxed())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::{info, reply};
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+    use std::time::Duration;
+
+    // A fake archive holding a reading every 5 seconds. Like the ACNET
+    // loggers, it ends the data of each request with an empty reply.
+
+    fn archive(start: f64, end: f64) -> Vec<global::DataReply> {
+        let first = (start / 5.0).ceil() as i64;
+        let last = (end / 5.0).floor() as i64;
+
+        vec![
+            reply((first..=last).map(|v| info(v as f64 * 5.0)).collect()),
+            reply(vec![]),
+        ]
+    }
+
+    #[test]
+    fn test_split_range() {
+        assert_eq!(
+            split_range(5.0, 25.0, 10.0),
+            vec![(5.0, 10.0), (10.0, 20.0), (20.0, 25.0)]
+        );
+        assert_eq!(split_range(10.0, 20.0, 10.0), vec![(10.0, 20.0)]);
+        assert_eq!(split_range(3.0, 7.0, 10.0), vec![(3.0, 7.0)]);
+        assert_eq!(split_range(3.0, 7.0, f64::INFINITY), vec![(3.0, 7.0)]);
+        assert_eq!(split_range(3.0, 7.0, 0.0), vec![(3.0, 7.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_in_order() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (r, m) = (running.clone(), most.clone());
+
+        // Earlier chunks take longer so they finish out of order.
+
+        let s = retrieve_chunks(
+            split_range(0.0, 100.0, 20.0),
+            3,
+            move |start, end| {
+                let (r, m) = (r.clone(), m.clone());
+
+                async move {
+                    m.fetch_max(
+                        r.fetch_add(1, Ordering::SeqCst) + 1,
+                        Ordering::SeqCst,
+                    );
+                    tokio::time::sleep(Duration::from_millis(
+                        (100.0 - start) as u64 / 5,
+                    ))
+                    .await;
+                    r.fetch_sub(1, Ordering::SeqCst);
+                    Ok(stream::iter(archive(start, end)).boxed())
+                }
+            },
+        )
+        .await
+        .unwrap();
+        let result: Vec<_> = s.collect().await;
+        let times: Vec<_> = result
+            .iter()
+            .flat_map(|v| v.data.iter().map(|v| v.timestamp))
+            .collect();
+
+        assert_eq!(times, (0..=20).map(|v| v as f64 * 5.0).collect::<Vec<_>>());
+        assert_eq!(result.last(), Some(&reply(vec![])));
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+
+    // A chunk's data is sent as it arrives rather than once the chunk
+    // has been read.
+
+    #[tokio::test]
+    async fn test_chunks_are_streamed() {
+        let s = retrieve_chunks(vec![(0.0, 100.0)], 2, |_, _| async {
+            Ok(stream::iter(0..)
+                .map(|v| reply(vec![info(v as f64)]))
+                .boxed())
+        })
+        .await
+        .unwrap();
+        let result: Vec<_> = s.take(100).collect().await;
+
+        assert_eq!(result.len(), 100);
+        assert_eq!(result[99], reply(vec![info(99.0)]));
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        // A failure of the first chunk is returned to the caller.
+
+        let result =
+            retrieve_chunks(split_range(0.0, 100.0, 20.0), 2, |_, _| async {
+                Err::<Replies, _>(String::from("no data"))
+            })
+            .await;
+
+        assert!(result.is_err());
+
+        // A later failure ends the stream with an error status.
+
+        let s = retrieve_chunks(
+            split_range(0.0, 100.0, 20.0),
+            2,
+            |start, end| async move {
+                if start < 40.0 {
+                    Ok(stream::iter(archive(start, end)).boxed())
+                } else {
+                    Err(String::from("no data"))
+                }
+            },
+        )
+        .await
+        .unwrap();
+        let result: Vec<_> = s.collect().await;
+        let last = result.last().unwrap();
+
+        assert_eq!(result.len(), 3);
+        assert!(is_fatal(last));
+        assert!(matches!(
+            last.data[0].result,
+            global::DataType::StatusReply(global::StatusReply {
+                message: Some(ref msg),
+                ..
+            }) if msg == "no data"
+        ));
+    }
+}
@@ -3,9 +3,644 @@
 // While the archive stream returns data, any data from the live
 // stream is queued up. Once the archived data stream is empty, then
 // the live data is returned.
+//
+// A long archive pull on a fast device can queue up a lot of live
+// data. The queue is bounded by the approximate memory used by its
+// readings (`LIVE_BUFFER_MB`); when that's full, newer readings are
+// spilled to a temporary file (`LIVE_SPILL_MB`). Only when the file is
+// full, or every channel's files together fill the service's budget
+// (`LIVE_SPILL_TOTAL_MB`), is the data dropped. Once the archive is
+// done, the queue is returned in pieces no larger than the memory
+// bound.
+//
+// The spill file is written and read on tokio's blocking thread pool,
+// so reading the spilled data is asynchronous; see
+// `DataChannel::poll_buffer`.
+
+use super::{archivecache, global};
+use futures::FutureExt;
+use rust_env_var_lib::env_var;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, ready},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+const BUFFER_SIZE: &str = "LIVE_BUFFER_MB";
+const SPILL_SIZE: &str = "LIVE_SPILL_MB";
+const SPILL_TOTAL: &str = "LIVE_SPILL_TOTAL_MB";
+
+// The limits used when `LIVE_BUFFER_MB`, `LIVE_SPILL_MB` and
+// `LIVE_SPILL_TOTAL_MB` aren't set.
+
+const DEFAULT_BUFFER_MB: usize = 16;
+const DEFAULT_SPILL_MB: usize = 1_024;
+const DEFAULT_SPILL_TOTAL_MB: usize = 8_192;
+
+// Returns the number of megabytes given by the environment variable
+// `name`, or `default` if it isn't set.
+
+fn megabytes(name: &str, default: usize) -> usize {
+    match env_var::get(name).to_option::<String>() {
+        Some(v) => v
+            .trim()
+            .parse::<usize>()
+            .unwrap_or_else(|e| panic!("bad {} setting -- {}", name, e)),
+        None => default,
+    }
+}
+
+// The disk space, in bytes, that spill files may use together. Each
+// spill file reserves space for its readings before they're written
+// and returns it when the file is removed.
+
+#[derive(Debug)]
+pub struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl Budget {
+    pub const fn new(limit: usize) -> Self {
+        Budget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    // Reserves `size` bytes. Returns `false`, and reserves nothing, if
+    // that would go past the limit.
+
+    fn reserve(&self, size: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+                v.checked_add(size).filter(|v| *v <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::SeqCst);
+    }
+}
+
+// How much live data a channel may queue, in bytes. `memory` is the
+// approximate size of the readings held in memory and `disk` is the
+// size of the spill file. If `disk` is zero, nothing is spilled.
+// `budget` is shared by the spill files of every channel.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub memory: usize,
+    pub disk: usize,
+    pub budget: &'static Budget,
+}
+
+impl Limits {
+    // Returns the limits described by `LIVE_BUFFER_MB`, `LIVE_SPILL_MB`
+    // and `LIVE_SPILL_TOTAL_MB`. This is called once, when the schema
+    // is built, so the budget lives as long as the service.
+
+    pub fn from_env() -> Self {
+        let memory = megabytes(BUFFER_SIZE, DEFAULT_BUFFER_MB);
+        let disk = megabytes(SPILL_SIZE, DEFAULT_SPILL_MB);
+        let total = megabytes(SPILL_TOTAL, DEFAULT_SPILL_TOTAL_MB);
+
+        info!(
+            "queueing up to {} MB of live data per device in memory and {} MB on disk",
+            memory, disk
+        );
+        info!("spilling up to {} MB of live data to disk in all", total);
+        Limits {
+            memory: memory * 1_048_576,
+            disk: disk * 1_048_576,
+            budget: Box::leak(Box::new(Budget::new(total * 1_048_576))),
+        }
+    }
+}
+
+// Readings are spilled in a compact binary form: the timestamp, as the
+// bits of an `f64`, followed by a tag byte identifying the type of the
+// value and the value itself. Numbers are little-endian; lengths are
+// `u32`s. Unlike JSON, this preserves every `f64` (including NaN and
+// the infinities) exactly.
+
+const TAG_STATUS: u8 = 0;
+const TAG_SCALAR: u8 = 1;
+const TAG_SCALAR_ARRAY: u8 = 2;
+const TAG_RAW: u8 = 3;
+const TAG_TEXT: u8 = 4;
+const TAG_TEXT_ARRAY: u8 = 5;
+const TAG_ENUM: u8 = 6;
+const TAG_STRUCT: u8 = 7;
+
+fn put_len(buf: &mut Vec<u8>, len: usize) {
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_len(buf, s.len());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_opt_str(buf: &mut Vec<u8>, s: &Option<String>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            put_str(buf, s)
+        }
+        None => buf.push(0),
+    }
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &global::DataType) {
+    match value {
+        global::DataType::StatusReply(v) => {
+            buf.push(TAG_STATUS);
+            buf.extend_from_slice(&v.status.to_le_bytes());
+            put_opt_str(buf, &v.message);
+        }
+        global::DataType::Scalar(v) => {
+            buf.push(TAG_SCALAR);
+            buf.extend_from_slice(&v.scalar_value.to_le_bytes());
+        }
+        global::DataType::ScalarArray(v) => {
+            buf.push(TAG_SCALAR_ARRAY);
+            put_len(buf, v.scalar_array_value.len());
+            for x in &v.scalar_array_value {
+                buf.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        global::DataType::Raw(v) => {
+            buf.push(TAG_RAW);
+            put_len(buf, v.raw_value.len());
+            buf.extend_from_slice(&v.raw_value);
+        }
+        global::DataType::Text(v) => {
+            buf.push(TAG_TEXT);
+            put_str(buf, &v.text_value);
+        }
+        global::DataType::TextArray(v) => {
+            buf.push(TAG_TEXT_ARRAY);
+            put_len(buf, v.text_array_value.len());
+            for s in &v.text_array_value {
+                put_str(buf, s);
+            }
+        }
+        global::DataType::EnumValue(v) => {
+            buf.push(TAG_ENUM);
+            buf.extend_from_slice(&v.enum_value.to_le_bytes());
+            put_opt_str(buf, &v.label);
+        }
+        global::DataType::StructData(v) => {
+            buf.push(TAG_STRUCT);
+            put_str(buf, &v.key);
+            encode_value(buf, &v.struct_value);
+        }
+    }
+}
+
+fn encode(buf: &mut Vec<u8>, info: &global::DataInfo) {
+    buf.extend_from_slice(&info.timestamp.to_le_bytes());
+    encode_value(buf, &info.result);
+}
+
+fn get<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn get_len(r: &mut impl Read) -> io::Result<usize> {
+    Ok(u32::from_le_bytes(get(r)?) as usize)
+}
+
+fn get_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; get_len(r)?];
+
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn get_str(r: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(get_bytes(r)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn get_opt_str(r: &mut impl Read) -> io::Result<Option<String>> {
+    match get::<1>(r)? {
+        [0] => Ok(None),
+        _ => get_str(r).map(Some),
+    }
+}
+
+fn decode_value(r: &mut impl Read) -> io::Result<global::DataType> {
+    Ok(match get::<1>(r)?[0] {
+        TAG_STATUS => global::DataType::StatusReply(global::StatusReply {
+            status: i16::from_le_bytes(get(r)?),
+            message: get_opt_str(r)?,
+        }),
+        TAG_SCALAR => global::DataType::Scalar(global::Scalar {
+            scalar_value: f64::from_le_bytes(get(r)?),
+        }),
+        TAG_SCALAR_ARRAY => {
+            let len = get_len(r)?;
+
+            global::DataType::ScalarArray(global::ScalarArray {
+                scalar_array_value: (0..len)
+                    .map(|_| get(r).map(f64::from_le_bytes))
+                    .collect::<io::Result<_>>()?,
+            })
+        }
+        TAG_RAW => global::DataType::Raw(global::Raw {
+            raw_value: get_bytes(r)?,
+        }),
+        TAG_TEXT => global::DataType::Text(global::Text {
+            text_value: get_str(r)?,
+        }),
+        TAG_TEXT_ARRAY => {
+            let len = get_len(r)?;
+
+            global::DataType::TextArray(global::TextArray {
+                text_array_value: (0..len)
+                    .map(|_| get_str(r))
+                    .collect::<io::Result<_>>()?,
+            })
+        }
+        TAG_ENUM => global::DataType::EnumValue(global::EnumValue {
+            enum_value: i32::from_le_bytes(get(r)?),
+            label: get_opt_str(r)?,
+        }),
+        TAG_STRUCT => global::DataType::StructData(global::StructData {
+            key: get_str(r)?,
+            struct_value: Box::new(decode_value(r)?),
+        }),
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown type tag {}", tag),
+            ));
+        }
+    })
+}
+
+fn decode(r: &mut impl Read) -> io::Result<global::DataInfo> {
+    Ok(global::DataInfo {
+        timestamp: f64::from_le_bytes(get(r)?),
+        result: decode_value(r)?,
+    })
+}
+
+// The spill file itself. It's only used by the spill's worker task.
+// The file is removed when this is dropped.
 
-use super::global;
-use tracing::warn;
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    pending: usize,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf) -> Result<Self, String> {
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| {
+                format!("couldn't create {} -- {}", path.display(), e)
+            })?;
+        let reader = File::open(&path).map_err(|e| {
+            let _ = fs::remove_file(&path);
+
+            format!("couldn't open {} -- {}", path.display(), e)
+        })?;
+
+        Ok(SpillFile {
+            path,
+            writer: BufWriter::new(file),
+            reader: BufReader::new(reader),
+            pending: 0,
+        })
+    }
+
+    fn write(&mut self, data: &[u8], count: usize) -> Result<(), String> {
+        self.writer.write_all(data).map_err(|e| {
+            format!("couldn't write {} -- {}", self.path.display(), e)
+        })?;
+        self.pending += count;
+        Ok(())
+    }
+
+    // Reads the oldest readings, stopping once they use about `max`
+    // bytes of memory. At least one reading is returned, if there are
+    // any.
+
+    fn read(&mut self, max: usize) -> Result<Vec<global::DataInfo>, String> {
+        let error = |e: io::Error| {
+            format!("couldn't read {} -- {}", self.path.display(), e)
+        };
+        let mut result = vec![];
+        let mut size = 0;
+
+        self.writer.flush().map_err(error)?;
+        while self.pending > 0 && (result.is_empty() || size < max) {
+            let info = decode(&mut self.reader).map_err(error)?;
+
+            size += archivecache::info_size(&info);
+            self.pending -= 1;
+            result.push(info);
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("couldn't remove {} -- {}", self.path.display(), e);
+        }
+    }
+}
+
+type ReadResult = Result<Vec<global::DataInfo>, String>;
+
+// The requests handled by a spill's worker task, in the order they're
+// sent. A write holds encoded readings and how many there are.
+
+enum Request {
+    Write(Vec<u8>, usize),
+    Read(usize, oneshot::Sender<ReadResult>),
+}
+
+// Runs a file operation on tokio's blocking thread pool.
+
+async fn blocking<T, F>(op: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    tokio::task::spawn_blocking(op).await.unwrap_or_else(|e| {
+        Err(format!("the spill file's task failed -- {}", e))
+    })
+}
+
+// Creates the spill file at `path` and handles requests until the
+// spill is dropped. Each operation is handed, with the file, to the
+// blocking thread pool; between requests, the worker waits without
+// holding a thread. Once an operation fails, every following read
+// reports the failure.
+
+async fn serve(path: PathBuf, mut requests: mpsc::UnboundedReceiver<Request>) {
+    let mut file = blocking(move || SpillFile::create(path)).await;
+
+    while let Some(request) = requests.recv().await {
+        let mut f = match file {
+            Ok(f) => f,
+            Err(e) => {
+                if let Request::Read(_, reply) = request {
+                    let _ = reply.send(Err(e.clone()));
+                }
+                file = Err(e);
+                continue;
+            }
+        };
+
+        match request {
+            Request::Write(data, count) => {
+                file = blocking(move || f.write(&data, count).map(|_| f)).await;
+            }
+            Request::Read(max, reply) => {
+                let result = blocking(move || {
+                    let data = f.read(max)?;
+
+                    Ok((f, data))
+                })
+                .await;
+
+                match result {
+                    Ok((f, data)) => {
+                        file = Ok(f);
+                        let _ = reply.send(Ok(data));
+                    }
+                    Err(e) => {
+                        file = Err(e.clone());
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    // Removing the file touches the disk, too.
+
+    if let Ok(f) = file {
+        tokio::task::spawn_blocking(move || drop(f));
+    }
+}
+
+// Holds readings that didn't fit in memory. The file is handled by a
+// worker task which runs each operation on tokio's blocking thread
+// pool, so the executor never waits on the disk. Writes are queued to
+// the worker without waiting; since they've been counted against the
+// spill's limits, the queue never holds more than the file may. Reads
+// are answered in order, so a read returns the readings written before
+// it.
+
+struct Spill {
+    requests: mpsc::UnboundedSender<Request>,
+    reading: Option<oneshot::Receiver<ReadResult>>,
+    budget: &'static Budget,
+    written: usize,
+    pending: usize,
+}
+
+impl Spill {
+    fn new(dir: PathBuf, budget: &'static Budget) -> Self {
+        let path =
+            dir.join(format!("extapi-live-{}.spill", uuid::Uuid::new_v4()));
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(serve(path, rx));
+        Spill {
+            requests: tx,
+            reading: None,
+            budget,
+            written: 0,
+            pending: 0,
+        }
+    }
+
+    fn closed<T>(_: T) -> String {
+        "the live data spill file was closed".into()
+    }
+
+    // Appends readings to the file. Fails if the file would grow past
+    // `max` bytes or if the budget is used up.
+
+    fn write(
+        &mut self, data: &[global::DataInfo], max: usize,
+    ) -> Result<(), String> {
+        let mut buf = vec![];
+
+        for info in data {
+            encode(&mut buf, info);
+        }
+        if self.written + buf.len() > max {
+            return Err("the live data spill file is full".into());
+        }
+        if !self.budget.reserve(buf.len()) {
+            return Err("the live data spill files are using all of their \
+                        disk budget"
+                .into());
+        }
+        self.written += buf.len();
+        self.pending += data.len();
+        self.requests
+            .send(Request::Write(buf, data.len()))
+            .map_err(Self::closed)
+    }
+
+    // Reads the oldest readings, stopping once they use about `max`
+    // bytes of memory. At least one reading is returned, if there are
+    // any.
+
+    fn poll_read(
+        &mut self, ctxt: &mut Context<'_>, max: usize,
+    ) -> Poll<ReadResult> {
+        let reading = match self.reading {
+            Some(ref mut rx) => rx,
+            None => {
+                let (tx, rx) = oneshot::channel();
+
+                if let Err(e) = self.requests.send(Request::Read(max, tx)) {
+                    return Poll::Ready(Err(Self::closed(e)));
+                }
+                self.reading.insert(rx)
+            }
+        };
+        let result = ready!(reading.poll_unpin(ctxt))
+            .unwrap_or_else(|e| Err(Self::closed(e)));
+
+        self.reading = None;
+        if let Ok(ref data) = result {
+            self.pending -= data.len();
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        self.budget.release(self.written);
+    }
+}
+
+// The live data queued by a channel. The oldest readings are kept in
+// memory; once a spill file exists, new readings go to it so they stay
+// in order.
+
+pub struct Buffer {
+    limits: Limits,
+    data: Vec<global::DataInfo>,
+    size: usize,
+    spill: Option<Spill>,
+}
+
+impl Buffer {
+    fn new(limits: Limits) -> Self {
+        Buffer {
+            limits,
+            data: vec![],
+            size: 0,
+            spill: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.spill.is_none()
+    }
+
+    // Queues readings. A packet is always accepted by an empty buffer,
+    // regardless of its size.
+
+    fn push(&mut self, data: Vec<global::DataInfo>) -> Result<(), String> {
+        let size: usize = data.iter().map(archivecache::info_size).sum();
+
+        if self.spill.is_none()
+            && (self.data.is_empty() || self.size + size <= self.limits.memory)
+        {
+            self.size += size;
+            self.data.extend(data);
+            return Ok(());
+        }
+        if self.limits.disk == 0 {
+            return Err("the live data buffer is full".into());
+        }
+
+        let spill = match self.spill {
+            Some(ref mut spill) => spill,
+            None => self
+                .spill
+                .insert(Spill::new(std::env::temp_dir(), self.limits.budget)),
+        };
+
+        spill.write(&data, self.limits.disk)
+    }
+
+    // Removes and returns the readings held in memory.
+
+    fn take_memory(&mut self) -> Option<Vec<global::DataInfo>> {
+        (!self.data.is_empty()).then(|| {
+            self.size = 0;
+            std::mem::take(&mut self.data)
+        })
+    }
+
+    // Removes and returns the oldest queued readings. Readings in
+    // memory are returned right away; spilled readings are ready once
+    // the worker has read them.
+
+    fn poll_take(
+        &mut self, ctxt: &mut Context<'_>,
+    ) -> Poll<Result<Option<Vec<global::DataInfo>>, String>> {
+        if let Some(data) = self.take_memory() {
+            return Poll::Ready(Ok(Some(data)));
+        }
+
+        let Some(spill) = self.spill.as_mut() else {
+            return Poll::Ready(Ok(None));
+        };
+
+        match ready!(spill.poll_read(ctxt, self.limits.memory)) {
+            Ok(data) => {
+                if spill.pending == 0 {
+                    self.spill = None;
+                }
+                Poll::Ready(Ok((!data.is_empty()).then_some(data)))
+            }
+            Err(e) => {
+                self.spill = None;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.size = 0;
+        self.spill = None;
+    }
+}
+
+// The outcome of handing data to a channel. `Overflow` means queued
+// live data was lost, either because the queue was full or because the
+// spilled data couldn't be read back.
 
 #[derive(Debug, PartialEq)]
 pub enum BufferResult {
@@ -14,12 +649,13 @@ pub enum BufferResult {
 }
 
 // Implements the merge logic for a data channel. When the channel is
-// in buffering mode, it adds any new live data to its buffer. In feed
-// through mode, all live data is simply forwarded on.
+// in buffering mode, it adds any new live data to its buffer. Once the
+// archive is done, the channel drains its buffer, adding any new live
+// data to the end, a piece at a time. In feed through mode, all live
+// data is simply forwarded on.
 pub enum DataChannel {
-    Buffering {
-        buffered_data: Vec<global::DataInfo>,
-    },
+    Buffering { buffered_data: Buffer },
+    Draining { buffered_data: Buffer },
     FeedThrough,
 }
 
@@ -27,33 +663,78 @@ impl DataChannel {
     // Creates a new data channel. Channels start in buffering mode with
     // an empty buffer.
 
-    pub fn new() -> Self {
+    pub fn new(limits: Limits) -> Self {
         DataChannel::Buffering {
-            buffered_data: vec![],
+            buffered_data: Buffer::new(limits),
         }
     }
 
-    // Returns the buffered data, if any.
+    // Returns `true` if the archive is done but buffered data remains
+    // to be returned by `poll_buffer`.
 
-    pub fn get_buffer(&mut self) -> Option<Vec<global::DataInfo>> {
-        match self {
-            Self::FeedThrough => None,
-            Self::Buffering { buffered_data } => {
-                let result = std::mem::take(buffered_data);
+    pub fn is_draining(&self) -> bool {
+        matches!(self, Self::Draining { .. })
+    }
 
-                if result.is_empty() {
-                    None
-                } else {
-                    Some(result)
+    // Returns the next piece of buffered data, if any. The piece isn't
+    // ready until it has been read back from the spill file; if that
+    // fails, the buffer is cleared and `Overflow` is returned. When a
+    // draining channel's buffer is empty, it switches to feed-through
+    // mode.
+
+    pub fn poll_buffer(
+        &mut self, ctxt: &mut Context<'_>,
+    ) -> Poll<BufferResult> {
+        let result = match self {
+            Self::FeedThrough => return Poll::Ready(BufferResult::Data(None)),
+            Self::Buffering { buffered_data }
+            | Self::Draining { buffered_data } => {
+                match ready!(buffered_data.poll_take(ctxt)) {
+                    Ok(data) => BufferResult::Data(data),
+                    Err(e) => {
+                        warn!("lost spilled live data -- {}", e);
+                        buffered_data.clear();
+                        BufferResult::Overflow
+                    }
                 }
             }
+        };
+
+        if let Self::Draining { buffered_data } = self
+            && buffered_data.is_empty()
+        {
+            *self = Self::FeedThrough;
         }
+        Poll::Ready(result)
+    }
+
+    // Switches a buffering channel to draining mode and returns the
+    // first piece of its buffer, if it's in memory. Spilled pieces are
+    // returned by `poll_buffer`.
+
+    fn drain(&mut self) -> Option<Vec<global::DataInfo>> {
+        if matches!(self, Self::Buffering { .. })
+            && let Self::Buffering { buffered_data } =
+                std::mem::replace(self, Self::FeedThrough)
+        {
+            *self = Self::Draining { buffered_data };
+        }
+
+        let Self::Draining { buffered_data } = self else {
+            return None;
+        };
+        let result = buffered_data.take_memory();
+
+        if buffered_data.is_empty() {
+            *self = Self::FeedThrough;
+        }
+        result
     }
 
     // Processes a chunk of live data.
 
     pub fn process_live_data(
-        &mut self, mut live_data: Vec<global::DataInfo>, archive_done: bool,
+        &mut self, live_data: Vec<global::DataInfo>, archive_done: bool,
     ) -> BufferResult {
         // If there's no live data to process, just return None. This should
         // never happen. If it does, we'll log the incident but won't update
@@ -68,28 +749,23 @@ impl DataChannel {
             // In feedthrough mode, we simply pass on the live data.
             Self::FeedThrough => BufferResult::Data(Some(live_data)),
 
-            // If in buffering mode, we append the data and return
-            // `None` so the caller knows there's nothing to emit yet.
-            Self::Buffering { buffered_data } => {
-                if archive_done {
-                    let mut result = std::mem::take(buffered_data);
-                    *self = Self::FeedThrough;
-                    BufferResult::Data(if result.is_empty() {
-                        Some(live_data)
-                    } else {
-                        result.append(&mut live_data);
-                        Some(result)
-                    })
+            // If buffering or draining, we append the data. While the
+            // archive is still streaming, `None` is returned so the
+            // caller knows there's nothing to emit yet; otherwise the
+            // next piece of the buffer is returned, if it's in memory.
+            Self::Buffering { buffered_data }
+            | Self::Draining { buffered_data } => {
+                if let Err(e) = buffered_data.push(live_data) {
+                    warn!(
+                        "live data buffer overflowed; dropping data -- {}",
+                        e
+                    );
+                    buffered_data.clear();
+                    return BufferResult::Overflow;
+                }
+                if archive_done || self.is_draining() {
+                    BufferResult::Data(self.drain())
                 } else {
-                    if buffered_data.is_empty() {
-                        *buffered_data = live_data;
-                    } else if buffered_data.len() + live_data.len() <= 1000 {
-                        buffered_data.append(&mut live_data);
-                    } else {
-                        warn!("live data buffer overflowed; dropping data");
-                        buffered_data.clear();
-                        return BufferResult::Overflow;
-                    }
                     BufferResult::Data(None)
                 }
             }
@@ -106,7 +782,7 @@ impl DataChannel {
             // feed-through mode. The producer made a mistake. Generate
             // a log message and pass on the data; the timestamps will
             // probably be earlier and will get filtered by a later stage.
-            Self::FeedThrough => {
+            Self::FeedThrough | Self::Draining { .. } => {
                 warn!("received archived data after end was specified");
                 if archive_data.is_empty() {
                     None
@@ -117,16 +793,9 @@ impl DataChannel {
 
             // If we're in buffer mode, the contents of this archive
             // packet determines what comes next.
-            Self::Buffering { buffered_data } => {
+            Self::Buffering { .. } => {
                 if archive_data.is_empty() {
-                    let result = std::mem::take(buffered_data);
-
-                    *self = Self::FeedThrough;
-                    if result.is_empty() {
-                        None
-                    } else {
-                        Some(result)
-                    }
+                    self.drain()
                 } else {
                     Some(archive_data)
                 }
@@ -137,8 +806,42 @@ impl DataChannel {
 
 #[cfg(test)]
 mod test {
-    use super::{BufferResult, DataChannel};
+    use super::{Budget, BufferResult, DataChannel, Limits, Spill};
     use crate::graphql::types as global;
+    use futures::{FutureExt, future::poll_fn};
+
+    // Returns limits that hold `points` scalar readings in memory and
+    // `disk` bytes in the spill file.
+
+    fn limits(points: usize, disk: usize) -> Limits {
+        Limits {
+            memory: points * std::mem::size_of::<global::DataInfo>(),
+            disk,
+            budget: Box::leak(Box::new(Budget::new(usize::MAX))),
+        }
+    }
+
+    // Returns the next piece of a channel's buffer, which has to be in
+    // memory.
+
+    fn get_buffer(chan: &mut DataChannel) -> Option<Vec<global::DataInfo>> {
+        match poll_fn(|ctxt| chan.poll_buffer(ctxt)).now_or_never() {
+            Some(BufferResult::Data(data)) => data,
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Returns the next piece of a channel's buffer, waiting for it to
+    // be read from the spill file.
+
+    async fn next_piece(
+        chan: &mut DataChannel,
+    ) -> Option<Vec<global::DataInfo>> {
+        match poll_fn(|ctxt| chan.poll_buffer(ctxt)).await {
+            BufferResult::Data(data) => data,
+            BufferResult::Overflow => panic!("spilled data was lost"),
+        }
+    }
 
     fn data_info(ts: f64) -> global::DataInfo {
         global::DataInfo {
@@ -154,7 +857,7 @@ mod test {
 
     #[test]
     fn test_data_channel() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
 
         // Assert a new channel is in buffer mode.
 
@@ -223,18 +926,18 @@ mod test {
 
     #[test]
     fn test_new_channel_is_buffering() {
-        let chan = DataChannel::new();
+        let chan = DataChannel::new(limits(1_000, 0));
         assert!(matches!(chan, DataChannel::Buffering { .. }));
     }
 
     // -----------------------------------------------------------------------
-    // get_buffer tests
+    // poll_buffer tests
 
     // A fresh channel has an empty buffer → get_buffer returns None.
     #[test]
     fn test_get_buffer_on_empty_buffering_channel_returns_none() {
-        let mut chan = DataChannel::new();
-        assert_eq!(chan.get_buffer(), None);
+        let mut chan = DataChannel::new(limits(1_000, 0));
+        assert_eq!(get_buffer(&mut chan), None);
         // Channel must still be in Buffering state.
         assert!(matches!(chan, DataChannel::Buffering { .. }));
     }
@@ -242,24 +945,24 @@ mod test {
     // After buffering live data, get_buffer returns it and clears the buffer.
     #[test]
     fn test_get_buffer_returns_buffered_data_and_clears() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_live_data(vec![data_info(1.0), data_info(2.0)], false);
 
-        let buf = chan.get_buffer();
+        let buf = get_buffer(&mut chan);
         assert_eq!(buf, Some(vec![data_info(1.0), data_info(2.0)]));
 
         // Buffer must now be empty.
-        assert_eq!(chan.get_buffer(), None);
+        assert_eq!(get_buffer(&mut chan), None);
     }
 
     // get_buffer on a FeedThrough channel always returns None.
     #[test]
     fn test_get_buffer_on_feedthrough_returns_none() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         // Transition to FeedThrough via the empty-archive sentinel.
         chan.process_archive_data(vec![]);
         assert!(matches!(chan, DataChannel::FeedThrough));
-        assert_eq!(chan.get_buffer(), None);
+        assert_eq!(get_buffer(&mut chan), None);
     }
 
     // -----------------------------------------------------------------------
@@ -268,7 +971,7 @@ mod test {
     // Non-empty archive data in Buffering mode is returned as-is.
     #[test]
     fn test_process_archive_data_nonempty_in_buffering_returns_data() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         let result =
             chan.process_archive_data(vec![data_info(10.0), data_info(20.0)]);
         assert_eq!(result, Some(vec![data_info(10.0), data_info(20.0)]));
@@ -280,7 +983,7 @@ mod test {
     // FeedThrough.
     #[test]
     fn test_process_archive_sentinel_with_empty_buffer_returns_none() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         let result = chan.process_archive_data(vec![]);
         assert_eq!(result, None);
         assert!(matches!(chan, DataChannel::FeedThrough));
@@ -290,7 +993,7 @@ mod test {
     // switches to FeedThrough.
     #[test]
     fn test_process_archive_sentinel_with_buffered_data_returns_buffer() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_live_data(vec![data_info(50.0), data_info(60.0)], false);
 
         let result = chan.process_archive_data(vec![]);
@@ -301,7 +1004,7 @@ mod test {
     // Non-empty archive data in FeedThrough mode is returned (with a warning).
     #[test]
     fn test_process_archive_data_nonempty_in_feedthrough_returns_data() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_archive_data(vec![]); // → FeedThrough
         let result = chan.process_archive_data(vec![data_info(99.0)]);
         assert_eq!(result, Some(vec![data_info(99.0)]));
@@ -310,7 +1013,7 @@ mod test {
     // Empty archive data in FeedThrough mode returns None (with a warning).
     #[test]
     fn test_process_archive_sentinel_in_feedthrough_returns_none() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_archive_data(vec![]); // → FeedThrough
         let result = chan.process_archive_data(vec![]);
         assert_eq!(result, None);
@@ -323,19 +1026,19 @@ mod test {
     // state (the guard branch).
     #[test]
     fn test_process_live_data_empty_in_buffering_returns_none() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         assert_eq!(
             chan.process_live_data(vec![], false),
             BufferResult::Data(None)
         );
         // Buffer must still be empty.
-        assert_eq!(chan.get_buffer(), None);
+        assert_eq!(get_buffer(&mut chan), None);
     }
 
     // Empty live data in FeedThrough mode also returns Data(None).
     #[test]
     fn test_process_live_data_empty_in_feedthrough_returns_none() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_archive_data(vec![]); // → FeedThrough
         assert_eq!(
             chan.process_live_data(vec![], false),
@@ -346,13 +1049,13 @@ mod test {
     // Live data in Buffering mode with archive_done=false is buffered.
     #[test]
     fn test_process_live_data_buffering_archive_not_done_buffers_data() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         let result =
             chan.process_live_data(vec![data_info(1.0), data_info(2.0)], false);
         assert_eq!(result, BufferResult::Data(None));
         // Data must be in the buffer.
         assert_eq!(
-            chan.get_buffer(),
+            get_buffer(&mut chan),
             Some(vec![data_info(1.0), data_info(2.0)])
         );
     }
@@ -360,13 +1063,13 @@ mod test {
     // Multiple live packets accumulate in the buffer.
     #[test]
     fn test_process_live_data_multiple_packets_accumulate() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_live_data(vec![data_info(1.0)], false);
         chan.process_live_data(vec![data_info(2.0)], false);
         chan.process_live_data(vec![data_info(3.0)], false);
 
         assert_eq!(
-            chan.get_buffer(),
+            get_buffer(&mut chan),
             Some(vec![data_info(1.0), data_info(2.0), data_info(3.0)])
         );
     }
@@ -375,7 +1078,7 @@ mod test {
     // → returns the live data directly (no prepend), transitions to FeedThrough.
     #[test]
     fn test_process_live_data_archive_done_empty_buffer_returns_live() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         let result = chan
             .process_live_data(vec![data_info(10.0), data_info(20.0)], true);
         assert_eq!(
@@ -389,7 +1092,7 @@ mod test {
     // → prepends the buffer to the live data, transitions to FeedThrough.
     #[test]
     fn test_process_live_data_archive_done_nonempty_buffer_prepends() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         // Buffer two items first.
         chan.process_live_data(vec![data_info(1.0), data_info(2.0)], false);
 
@@ -412,7 +1115,7 @@ mod test {
     // archive_done.
     #[test]
     fn test_process_live_data_feedthrough_passes_through() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1_000, 0));
         chan.process_archive_data(vec![]); // → FeedThrough
 
         let result =
@@ -426,10 +1129,10 @@ mod test {
     // -----------------------------------------------------------------------
     // Buffer overflow tests
 
-    // Exactly 1000 items in the buffer is still accepted (boundary).
+    // Readings up to the memory limit are held in memory (boundary).
     #[test]
     fn test_process_live_data_buffer_at_capacity_is_accepted() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1000, 0));
         // Fill the buffer to exactly 1000 items across two packets.
         let first: Vec<_> = (0..500).map(|i| data_info(i as f64)).collect();
         let second: Vec<_> = (500..1000).map(|i| data_info(i as f64)).collect();
@@ -444,14 +1147,15 @@ mod test {
         );
 
         // Buffer must hold all 1000 items.
-        let buf = chan.get_buffer().expect("buffer should not be empty");
+        let buf = get_buffer(&mut chan).expect("buffer should not be empty");
         assert_eq!(buf.len(), 1000);
     }
 
-    // Adding one more item beyond 1000 triggers Overflow and clears the buffer.
+    // Without a spill file, going past the memory limit triggers
+    // Overflow and clears the buffer.
     #[test]
     fn test_process_live_data_overflow_clears_buffer_and_returns_overflow() {
-        let mut chan = DataChannel::new();
+        let mut chan = DataChannel::new(limits(1000, 0));
         // Fill to 1000.
         let first: Vec<_> = (0..1000).map(|i| data_info(i as f64)).collect();
         chan.process_live_data(first, false);
@@ -461,6 +1165,237 @@ mod test {
         assert_eq!(result, BufferResult::Overflow);
 
         // Buffer must have been cleared.
-        assert_eq!(chan.get_buffer(), None);
+        assert_eq!(get_buffer(&mut chan), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // Spill tests
+
+    // A long archive pull: live data past the memory limit is spilled
+    // to disk and, once the archive is done, every reading is returned,
+    // in order, in pieces no larger than the memory limit.
+    #[tokio::test]
+    async fn test_long_archive_pull_spills_live_data() {
+        let mut chan = DataChannel::new(limits(100, 1_000_000));
+
+        for i in 0..5000 {
+            assert_eq!(
+                chan.process_live_data(vec![data_info(i as f64)], false),
+                BufferResult::Data(None)
+            );
+            if i % 100 == 0 {
+                assert_eq!(
+                    chan.process_archive_data(vec![data_info(i as f64)]),
+                    Some(vec![data_info(i as f64)])
+                );
+            }
+        }
+
+        let mut result = chan.process_archive_data(vec![]).unwrap();
+
+        assert!(chan.is_draining());
+        assert_eq!(result.len(), 100);
+
+        // Live data that arrives while draining goes to the end of the
+        // spill file.
+
+        assert_eq!(
+            chan.process_live_data(vec![data_info(5000.0)], false),
+            BufferResult::Data(None)
+        );
+        while let Some(data) = next_piece(&mut chan).await {
+            assert!(data.len() <= 100);
+            result.extend(data);
+        }
+
+        assert!(matches!(chan, DataChannel::FeedThrough));
+        assert_eq!(
+            result,
+            (0..=5000).map(|i| data_info(i as f64)).collect::<Vec<_>>()
+        );
+    }
+
+    // Once the spill file is full, the data is dropped.
+    #[tokio::test]
+    async fn test_spill_file_limit_overflows() {
+        let mut chan = DataChannel::new(limits(10, 1_000));
+        let mut result = BufferResult::Data(None);
+
+        for i in 0..1000 {
+            result = chan.process_live_data(vec![data_info(i as f64)], false);
+            if result != BufferResult::Data(None) {
+                break;
+            }
+        }
+
+        assert_eq!(result, BufferResult::Overflow);
+        assert_eq!(get_buffer(&mut chan), None);
+    }
+
+    // The spill files of every channel share the budget. Space is
+    // returned once a channel's file is gone.
+    #[tokio::test]
+    async fn test_spill_budget_is_shared() {
+        let budget: &'static Budget = Box::leak(Box::new(Budget::new(1_000)));
+        let limits = Limits {
+            budget,
+            ..limits(1, 1_000)
+        };
+        let mut chans: Vec<_> =
+            (0..2).map(|_| DataChannel::new(limits)).collect();
+        let mut accepted = 0;
+
+        'fill: for i in 0..100 {
+            for chan in &mut chans {
+                if chan.process_live_data(vec![data_info(i as f64)], false)
+                    == BufferResult::Overflow
+                {
+                    break 'fill;
+                }
+            }
+            accepted = i;
+        }
+
+        // Either file could hold more than 50 readings on its own, so
+        // the two of them used up the budget.
+
+        assert!(accepted > 0 && accepted < 40);
+
+        drop(chans);
+        assert_eq!(budget.used.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    // Spills only use a blocking thread while they read or write, so
+    // more of them can be open than the pool has threads.
+    #[test]
+    fn test_spills_share_blocking_threads() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut chans: Vec<_> = (0..4)
+                .map(|_| DataChannel::new(limits(0, 1_000_000)))
+                .collect();
+
+            for (i, chan) in chans.iter_mut().enumerate() {
+                for ts in [i as f64, i as f64 + 0.5] {
+                    chan.process_live_data(vec![data_info(ts)], false);
+                }
+            }
+            for (i, chan) in chans.iter_mut().enumerate() {
+                let mut result = vec![];
+
+                while let Some(v) = next_piece(chan).await {
+                    result.extend(v);
+                }
+                assert_eq!(
+                    result,
+                    vec![data_info(i as f64), data_info(i as f64 + 0.5)]
+                );
+            }
+        });
+    }
+
+    // A spill file that can't be written or read is reported as an
+    // error rather than as the end of the data.
+    #[tokio::test]
+    async fn test_spill_errors_are_reported() {
+        let budget: &'static Budget =
+            Box::leak(Box::new(Budget::new(usize::MAX)));
+        let mut spill = Spill::new(
+            std::env::temp_dir().join("no-such-directory").join("spill"),
+            budget,
+        );
+
+        assert!(spill.write(&[data_info(1.0)], 1_000).is_ok());
+        assert!(poll_fn(|ctxt| spill.poll_read(ctxt, 1_000)).await.is_err());
+    }
+
+    // Readings of every type survive the trip through the spill file.
+    #[tokio::test]
+    async fn test_spilled_types_round_trip() {
+        let mut chan = DataChannel::new(limits(0, 1_000_000));
+        let data: Vec<_> = [
+            global::DataType::Scalar(global::Scalar {
+                scalar_value: 0.1 + 0.2,
+            }),
+            global::DataType::ScalarArray(global::ScalarArray {
+                scalar_array_value: vec![1.0 / 3.0, -2.5, f64::INFINITY],
+            }),
+            global::DataType::Raw(global::Raw {
+                raw_value: vec![0, 255],
+            }),
+            global::DataType::Text(global::Text {
+                text_value: "idle".into(),
+            }),
+            global::DataType::TextArray(global::TextArray {
+                text_array_value: vec!["".into(), "µA".into()],
+            }),
+            global::DataType::EnumValue(global::EnumValue {
+                enum_value: 1,
+                label: Some("On".into()),
+            }),
+            global::DataType::StructData(global::StructData {
+                key: "current".into(),
+                struct_value: Box::new(global::DataType::Scalar(
+                    global::Scalar { scalar_value: -0.0 },
+                )),
+            }),
+            global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: None,
+            }),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, result)| global::DataInfo {
+            timestamp: 1_700_000_000.123_456_7 + i as f64,
+            result,
+        })
+        .collect();
+
+        for info in &data {
+            chan.process_live_data(vec![info.clone()], false);
+        }
+
+        let mut result = vec![];
+
+        while let Some(v) = next_piece(&mut chan).await {
+            result.extend(v);
+        }
+        assert_eq!(result, data);
+    }
+
+    // NaN readings (a device that isn't reading, for instance) are
+    // spilled without error and come back as NaN.
+    #[tokio::test]
+    async fn test_spilled_nan_round_trips() {
+        let mut chan = DataChannel::new(limits(0, 1_000_000));
+
+        for _ in 0..2 {
+            chan.process_live_data(
+                vec![global::DataInfo {
+                    timestamp: 1.0,
+                    result: global::DataType::Scalar(global::Scalar {
+                        scalar_value: f64::NAN,
+                    }),
+                }],
+                false,
+            );
+        }
+
+        let mut result = vec![];
+
+        while let Some(v) = next_piece(&mut chan).await {
+            result.extend(v);
+        }
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|v| matches!(
+            v.result,
+            global::DataType::Scalar(global::Scalar { scalar_value })
+                if scalar_value.is_nan()
+        )));
     }
 }
@@ -1,4 +1,8 @@
-use super::{DataChannel, datachannel::BufferResult, global};
+use super::{
+    DataChannel,
+    datachannel::{BufferResult, Limits},
+    global,
+};
 use futures::Stream;
 use futures_util::StreamExt;
 use std::{
@@ -16,7 +20,8 @@ use tracing::warn;
 //   2) We pull data from all streams so that producers don't close our
 //      incoming streams. This means the stream supplying live data should
 //      be polled and the data buffered until the archived data has been
-//      delivered.
+//      delivered. The buffer is bounded (see `DataChannel`) and, once
+//      the archived data is done, is returned a piece at a time.
 
 pub struct DataMerge<SA, SL>
 where
@@ -26,6 +31,7 @@ where
     archived: Option<SA>,
     live: Option<SL>,
     pending: HashMap<i32, (DataChannel, f64)>,
+    limits: Limits,
 }
 
 // Useful combinator that assembles the internal stream type.
@@ -34,13 +40,13 @@ where
 
 #[inline(never)]
 pub fn merge<SA, SL>(
-    archived: Option<SA>, live: Option<SL>,
+    archived: Option<SA>, live: Option<SL>, limits: Limits,
 ) -> impl Stream<Item = global::DataReply> + Send + 'static + Unpin
 where
     SA: Stream<Item = global::DataReply> + Send + 'static + Unpin,
     SL: Stream<Item = global::DataReply> + Send + 'static + Unpin,
 {
-    DataMerge::new(archived, live)
+    DataMerge::new(archived, live, limits)
 }
 
 impl<SA, SL> DataMerge<SA, SL>
@@ -48,11 +54,12 @@ where
     SA: Stream<Item = global::DataReply> + Send + 'static + Unpin,
     SL: Stream<Item = global::DataReply> + Send + 'static + Unpin,
 {
-    pub fn new(archived: Option<SA>, live: Option<SL>) -> Self {
+    pub fn new(archived: Option<SA>, live: Option<SL>, limits: Limits) -> Self {
         DataMerge {
             archived,
             live,
             pending: HashMap::new(),
+            limits,
         }
     }
 
@@ -79,17 +86,14 @@ where
                 return None;
             }
             match &item.result {
-                global::DataType::StatusReply(global::StatusReply {
-                    status,
-                }) if *status < 0 => {
+                global::DataType::StatusReply(reply) if reply.status < 0 => {
                     // Fatal: emit, advance watermark, stop.
+                    warn!("stream ended by {}", reply);
                     **watermark = watermark.max(item.timestamp);
                     done = true;
                     Some(Some(item))
                 }
-                global::DataType::StatusReply(global::StatusReply {
-                    status,
-                }) if *status > 0 => {
+                global::DataType::StatusReply(reply) if reply.status > 0 => {
                     // Warning: emit without touching the watermark.
                     Some(Some(item))
                 }
@@ -120,6 +124,8 @@ where
         mut self: Pin<&mut Self>, ctxt: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let this = &mut *self;
+        let limits = this.limits;
+
         loop {
             // See if there's any archive data to process. If so, pass it
             // through the associated data channel.
@@ -130,7 +136,7 @@ where
                         let (chan, latest) = this
                             .pending
                             .entry(ref_id)
-                            .or_insert_with(|| (DataChannel::new(), 0.0));
+                            .or_insert_with(|| (DataChannel::new(limits), 0.0));
 
                         if let Some(raw) = chan.process_archive_data(data) {
                             let data = Self::filter_and_update_latest(
@@ -151,6 +157,44 @@ where
                 }
             }
 
+            // Return the next piece of any channel that's draining its
+            // buffer before accepting more live data. Pieces that are
+            // still being read from a spill file aren't waited for; live
+            // data is accepted in the meantime.
+
+            let mut drained = false;
+
+            for (&ref_id, (chan, latest)) in
+                this.pending.iter_mut().filter(|(_, v)| v.0.is_draining())
+            {
+                match chan.poll_buffer(ctxt) {
+                    Poll::Ready(BufferResult::Data(Some(raw))) => {
+                        let data = Self::filter_and_update_latest(
+                            raw.into_iter(),
+                            latest,
+                        );
+
+                        if !data.is_empty() {
+                            return Poll::Ready(Some(global::DataReply {
+                                ref_id,
+                                data,
+                            }));
+                        }
+                    }
+                    Poll::Ready(BufferResult::Data(None)) => {}
+                    Poll::Ready(BufferResult::Overflow) => {
+                        warn!("lost buffered data for ref_id {ref_id}");
+                        return Poll::Ready(None);
+                    }
+                    Poll::Pending => continue,
+                }
+                drained = true;
+                break;
+            }
+            if drained {
+                continue;
+            }
+
             if let Some(ref mut live) = this.live {
                 match live.poll_next_unpin(ctxt) {
                     Poll::Ready(Some(global::DataReply { ref_id, data }))
@@ -159,7 +203,7 @@ where
                         let (chan, latest) = this
                             .pending
                             .entry(ref_id)
-                            .or_insert_with(|| (DataChannel::new(), 0.0));
+                            .or_insert_with(|| (DataChannel::new(limits), 0.0));
 
                         match chan
                             .process_live_data(data, this.archived.is_none())
@@ -197,20 +241,30 @@ where
             // in the channels before shutting down the stream.
 
             if this.archived.is_none() && this.live.is_none() {
-                if let Some(&ref_id) = this.pending.keys().next() {
-                    let (mut chan, mut latest) =
-                        this.pending.remove(&ref_id).unwrap();
-                    if let Some(raw) = chan.get_buffer() {
-                        let data = Self::filter_and_update_latest(
-                            raw.into_iter(),
-                            &mut latest,
-                        );
-                        if !data.is_empty() {
-                            return Poll::Ready(Some(global::DataReply {
-                                ref_id,
-                                data,
-                            }));
+                if let Some((&ref_id, (chan, latest))) =
+                    this.pending.iter_mut().next()
+                {
+                    match chan.poll_buffer(ctxt) {
+                        Poll::Ready(BufferResult::Data(Some(raw))) => {
+                            let data = Self::filter_and_update_latest(
+                                raw.into_iter(),
+                                latest,
+                            );
+                            if !data.is_empty() {
+                                return Poll::Ready(Some(global::DataReply {
+                                    ref_id,
+                                    data,
+                                }));
+                            }
+                        }
+                        Poll::Ready(BufferResult::Data(None)) => {
+                            this.pending.remove(&ref_id);
+                        }
+                        Poll::Ready(BufferResult::Overflow) => {
+                            warn!("lost buffered data for ref_id {ref_id}");
+                            return Poll::Ready(None);
                         }
+                        Poll::Pending => return Poll::Pending,
                     }
                     continue;
                 }
@@ -224,8 +278,20 @@ where
 
 #[cfg(test)]
 mod test {
+    use super::super::datachannel::{Budget, Limits};
     use super::{DataMerge, global};
 
+    // Returns limits large enough that the tests' live data is never
+    // spilled.
+
+    fn limits() -> Limits {
+        Limits {
+            memory: 1_048_576,
+            disk: 0,
+            budget: Box::leak(Box::new(Budget::new(0))),
+        }
+    }
+
     fn data_info(ts: f64) -> global::DataInfo {
         global::DataInfo {
             timestamp: ts,
@@ -240,6 +306,7 @@ mod test {
             timestamp: ts,
             result: global::DataType::StatusReply(global::StatusReply {
                 status: -1,
+                message: None,
             }),
         }
     }
@@ -249,6 +316,7 @@ mod test {
             timestamp: ts,
             result: global::DataType::StatusReply(global::StatusReply {
                 status: 1,
+                message: None,
             }),
         }
     }
@@ -380,6 +448,7 @@ mod test {
         let mut s = super::merge(
             None::<stream::Empty<global::DataReply>>,
             None::<stream::Empty<global::DataReply>>,
+            limits(),
         );
         assert!(s.next().await.is_none());
     }
@@ -402,6 +471,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             None::<stream::Empty<_>>,
+            limits(),
         );
 
         let r = s.next().await.unwrap();
@@ -430,6 +500,7 @@ mod test {
         let mut s = super::merge(
             None::<stream::Empty<_>>,
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         assert_eq!(
@@ -476,6 +547,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         assert_eq!(
@@ -533,6 +605,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         // Archive data comes first.
@@ -573,6 +646,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         let r = s.next().await.unwrap();
@@ -617,6 +691,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         // Expect archive data for ref 0 and 1
@@ -658,6 +733,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             None::<stream::Empty<_>>,
+            limits(),
         );
 
         let r0a = s.next().await.unwrap();
@@ -701,6 +777,7 @@ mod test {
         let mut s = super::merge(
             None::<stream::Empty<_>>,
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         let r = s.next().await.unwrap();
@@ -749,6 +826,7 @@ mod test {
         let mut s = super::merge(
             None::<stream::Empty<_>>,
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         // The status reply must come through.
@@ -782,6 +860,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             None::<stream::Empty<_>>,
+            limits(),
         );
 
         assert_eq!(s.next().await.unwrap().data.len(), 2);
@@ -822,6 +901,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             Some(stream::iter(live_input)),
+            limits(),
         );
 
         let r = s.next().await.unwrap();
@@ -877,6 +957,7 @@ mod test {
         let mut s = super::merge(
             Some(stream::iter(archive_input)),
             None::<stream::Empty<_>>,
+            limits(),
         );
 
         let r = s.next().await.unwrap();
@@ -887,4 +968,49 @@ mod test {
 
         assert!(s.next().await.is_none());
     }
+
+    // A long archive pull on a fast device: live data keeps arriving
+    // while the archive streams and overflows the memory buffer. None
+    // of it is lost and it follows the archived data, in order.
+    #[tokio::test]
+    async fn test_long_archive_pull_keeps_live_data() {
+        use futures::stream::{self, StreamExt};
+
+        let archive_input = (0..2000)
+            .map(|i| global::DataReply {
+                ref_id: 0,
+                data: vec![data_info(i as f64)],
+            })
+            .chain(std::iter::once(global::DataReply {
+                ref_id: 0,
+                data: vec![],
+            }));
+
+        // Each archive packet is delayed a poll so the live stream is
+        // read in between.
+
+        let archived = stream::iter(archive_input)
+            .then(|v| async move {
+                tokio::task::yield_now().await;
+                v
+            })
+            .boxed();
+        let live = stream::iter((2000..5000).map(|i| global::DataReply {
+            ref_id: 0,
+            data: vec![data_info(i as f64)],
+        }));
+        let limits = Limits {
+            memory: 100 * std::mem::size_of::<global::DataInfo>(),
+            disk: 10_000_000,
+            budget: Box::leak(Box::new(Budget::new(usize::MAX))),
+        };
+        let s = DataMerge::new(Some(archived), Some(live), limits);
+        let result: Vec<_> = s
+            .flat_map(|v| stream::iter(v.data))
+            .map(|v| v.timestamp)
+            .collect()
+            .await;
+
+        assert_eq!(result, (0..5000).map(|i| i as f64).collect::<Vec<_>>());
+    }
 }
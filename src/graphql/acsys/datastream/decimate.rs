@@ -0,0 +1,437 @@
+// Defines a stream that reduces the rate of the data it forwards. The
+// timeline of each device is divided into buckets of a fixed width
+// and each bucket's readings are reduced to one (or two, for the
+// envelope) readings using the selected strategy.
+//
+// A bucket is sent when a reading for a later bucket arrives. So that
+// slow devices don't sit in a bucket waiting for the next reading, a
+// timer also sends pending buckets once the wall clock passes the end
+// of their interval. The timer is then re-armed for the end of the
+// earliest remaining bucket, so a bucket is only sent early when the
+// stream ends.
+// Status replies are never decimated; any pending bucket of the device
+// is sent before the status.
+
+use super::{global, now, types::Decimation};
+use futures::{Stream, future::Either};
+use futures_util::StreamExt;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+use tokio::time::{Sleep, sleep};
+
+struct Bucket {
+    index: i64,
+    points: Vec<global::DataInfo>,
+}
+
+pub struct Decimate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    s: Option<S>,
+    interval: f64,
+    strategy: Decimation,
+    buckets: HashMap<i32, Bucket>,
+    ready: VecDeque<global::DataReply>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+// Returns the scalar value of a reading, if it has one.
+
+fn scalar(info: &global::DataInfo) -> Option<f64> {
+    match info.result {
+        global::DataType::Scalar(global::Scalar { scalar_value }) => {
+            Some(scalar_value)
+        }
+        _ => None,
+    }
+}
+
+// Reduces the readings of a bucket. The envelope and mean strategies
+// only apply to scalar readings; if the bucket holds anything else,
+// the latest reading is used.
+
+pub fn reduce(
+    mut points: Vec<global::DataInfo>, strategy: Decimation,
+) -> Vec<global::DataInfo> {
+    let all_scalar = points.iter().all(|v| scalar(v).is_some());
+
+    match strategy {
+        Decimation::Envelope if all_scalar && points.len() > 2 => {
+            let by_value = |a: &&global::DataInfo, b: &&global::DataInfo| {
+                scalar(a).unwrap().total_cmp(&scalar(b).unwrap())
+            };
+            let lo = points.iter().min_by(by_value).cloned().unwrap();
+            let hi = points.iter().max_by(by_value).cloned().unwrap();
+
+            if lo.timestamp <= hi.timestamp {
+                vec![lo, hi]
+            } else {
+                vec![hi, lo]
+            }
+        }
+        Decimation::Envelope if all_scalar => points,
+        Decimation::Mean if all_scalar && !points.is_empty() => {
+            let total: f64 = points.iter().filter_map(scalar).sum();
+            let mean = total / points.len() as f64;
+
+            vec![global::DataInfo {
+                timestamp: points.last().unwrap().timestamp,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: mean,
+                }),
+            }]
+        }
+        _ => points.pop().into_iter().collect(),
+    }
+}
+
+impl<S> Decimate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    pub fn new(s: S, interval: f64, strategy: Decimation) -> Self {
+        Decimate {
+            s: Some(s),
+            interval,
+            strategy,
+            buckets: HashMap::new(),
+            ready: VecDeque::new(),
+            timer: None,
+        }
+    }
+
+    // Returns the time, in seconds since 1970, at which a bucket's
+    // interval ends.
+
+    fn end(&self, bucket: &Bucket) -> f64 {
+        (bucket.index + 1) as f64 * self.interval
+    }
+
+    // Sends the pending buckets whose interval ended before `until`.
+    // The timer is then set to expire when the earliest remaining
+    // bucket's interval ends.
+
+    fn flush_until(&mut self, until: f64) {
+        let mut ref_ids: Vec<_> = self
+            .buckets
+            .iter()
+            .filter(|(_, bucket)| self.end(bucket) <= until)
+            .map(|(ref_id, _)| *ref_id)
+            .collect();
+
+        ref_ids.sort_unstable();
+        for ref_id in ref_ids {
+            let data = self.flush(ref_id);
+
+            if !data.is_empty() {
+                self.ready.push_back(global::DataReply { ref_id, data });
+            }
+        }
+        self.timer = None;
+        self.arm();
+    }
+
+    // Sets the timer, if it isn't running, to expire when the earliest
+    // pending bucket's interval ends. If that's too far away to be
+    // represented, no timer is set; the bucket is sent when a later
+    // reading arrives or the stream ends.
+
+    fn arm(&mut self) {
+        if self.timer.is_none()
+            && let Some(end) = self
+                .buckets
+                .values()
+                .map(|bucket| self.end(bucket))
+                .min_by(f64::total_cmp)
+        {
+            let delay = (end - now()).max(0.0);
+
+            self.timer = Duration::try_from_secs_f64(delay)
+                .ok()
+                .map(|delay| Box::pin(sleep(delay)));
+        }
+    }
+
+    // Removes a device's bucket and returns its reduced readings.
+
+    fn flush(&mut self, ref_id: i32) -> Vec<global::DataInfo> {
+        self.buckets
+            .remove(&ref_id)
+            .map(|b| reduce(b.points, self.strategy))
+            .unwrap_or_default()
+    }
+
+    // Adds a reply's readings to the device's bucket. Any readings that
+    // are ready to be sent are queued.
+
+    fn process(&mut self, reply: global::DataReply) {
+        // Empty replies carry meaning to other parts of the pipeline so
+        // they're forwarded as-is.
+
+        if reply.data.is_empty() {
+            self.ready.push_back(reply);
+            return;
+        }
+
+        let ref_id = reply.ref_id;
+        let mut out = vec![];
+
+        for info in reply.data {
+            if let global::DataType::StatusReply(_) = info.result {
+                out.append(&mut self.flush(ref_id));
+                out.push(info);
+                continue;
+            }
+
+            let index = (info.timestamp / self.interval).floor() as i64;
+
+            match self.buckets.get_mut(&ref_id) {
+                Some(bucket) if bucket.index == index => {
+                    bucket.points.push(info)
+                }
+                _ => {
+                    out.append(&mut self.flush(ref_id));
+                    self.buckets.insert(
+                        ref_id,
+                        Bucket {
+                            index,
+                            points: vec![info],
+                        },
+                    );
+                }
+            }
+        }
+
+        if !out.is_empty() {
+            self.ready
+                .push_back(global::DataReply { ref_id, data: out });
+        }
+
+        self.arm();
+    }
+}
+
+// Decimates a stream of live data. If `interval` is `None`, the stream
+// is returned unmodified.
+
+#[inline(never)]
+pub fn decimate(
+    s: impl Stream<Item = global::DataReply> + Send + 'static + Unpin,
+    interval: Option<f64>, strategy: Decimation,
+) -> impl Stream<Item = global::DataReply> + Send + 'static + Unpin {
+    match interval {
+        Some(interval) if interval > 0.0 => {
+            Either::Left(Decimate::new(s, interval, strategy))
+        }
+        _ => Either::Right(s),
+    }
+}
+
+impl<S> Stream for Decimate<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    type Item = global::DataReply;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, ctxt: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(reply) = self.ready.pop_front() {
+                break Poll::Ready(Some(reply));
+            }
+
+            let Some(s) = self.s.as_mut() else {
+                break Poll::Ready(None);
+            };
+
+            match s.poll_next_unpin(ctxt) {
+                Poll::Ready(Some(reply)) => self.process(reply),
+
+                // The source closed. Send what's left and end the
+                // stream.
+                Poll::Ready(None) => {
+                    self.s = None;
+                    self.flush_until(f64::INFINITY);
+                }
+
+                // Nothing available from the source. If buckets'
+                // intervals have ended, send them.
+                Poll::Pending => {
+                    let expired = self
+                        .timer
+                        .as_mut()
+                        .is_some_and(|t| t.as_mut().poll(ctxt).is_ready());
+
+                    if expired {
+                        self.flush_until(now())
+                    } else {
+                        break Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn scalar_info(ts: f64, v: f64) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::Scalar(global::Scalar {
+                scalar_value: v,
+            }),
+        }
+    }
+
+    fn status_info(ts: f64) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: None,
+            }),
+        }
+    }
+
+    fn reply(ref_id: i32, data: Vec<global::DataInfo>) -> global::DataReply {
+        global::DataReply { ref_id, data }
+    }
+
+    #[test]
+    fn test_reduce() {
+        let pts = vec![
+            scalar_info(1.0, 5.0),
+            scalar_info(1.1, 9.0),
+            scalar_info(1.2, 1.0),
+            scalar_info(1.3, 3.0),
+        ];
+
+        assert_eq!(
+            reduce(pts.clone(), Decimation::Latest),
+            vec![scalar_info(1.3, 3.0)]
+        );
+        assert_eq!(
+            reduce(pts.clone(), Decimation::Envelope),
+            vec![scalar_info(1.1, 9.0), scalar_info(1.2, 1.0)]
+        );
+        assert_eq!(reduce(pts, Decimation::Mean), vec![scalar_info(1.3, 4.5)]);
+    }
+
+    #[test]
+    fn test_reduce_non_scalars() {
+        let wave = global::DataInfo {
+            timestamp: 2.0,
+            result: global::DataType::ScalarArray(global::ScalarArray {
+                scalar_array_value: vec![1.0, 2.0],
+            }),
+        };
+        let pts = vec![scalar_info(1.0, 5.0), wave.clone()];
+
+        assert_eq!(reduce(pts.clone(), Decimation::Mean), vec![wave.clone()]);
+        assert_eq!(reduce(pts, Decimation::Envelope), vec![wave]);
+    }
+
+    #[tokio::test]
+    async fn test_buckets() {
+        let input = vec![
+            reply(0, vec![scalar_info(10.0, 1.0), scalar_info(10.2, 2.0)]),
+            reply(1, vec![scalar_info(10.3, 7.0)]),
+            reply(0, vec![scalar_info(10.6, 3.0), scalar_info(11.1, 4.0)]),
+        ];
+        let s = decimate(stream::iter(input), Some(0.5), Decimation::Latest);
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(
+            result,
+            vec![
+                reply(0, vec![scalar_info(10.2, 2.0), scalar_info(10.6, 3.0)]),
+                reply(0, vec![scalar_info(11.1, 4.0)]),
+                reply(1, vec![scalar_info(10.3, 7.0)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status_flushes_bucket() {
+        let input = vec![reply(
+            0,
+            vec![
+                scalar_info(10.0, 1.0),
+                scalar_info(10.1, 3.0),
+                status_info(10.2),
+            ],
+        )];
+        let s = decimate(stream::iter(input), Some(1.0), Decimation::Mean);
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(
+            result,
+            vec![reply(0, vec![scalar_info(10.1, 2.0), status_info(10.2)])]
+        );
+    }
+
+    #[tokio::test]
+    // Without an interval, the stream is passed through.
+    #[tokio::test]
+    async fn test_no_interval() {
+        let input =
+            vec![reply(0, vec![scalar_info(10.0, 1.0)]), reply(0, vec![])];
+        let s = decimate(stream::iter(input.clone()), None, Decimation::Mean);
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(result, input);
+    }
+
+    // A bucket whose interval hasn't ended is held.
+    #[tokio::test]
+    async fn test_timer_waits_for_interval() {
+        let input = stream::iter(vec![reply(0, vec![scalar_info(now(), 1.0)])])
+            .chain(stream::pending());
+        let mut s = decimate(input, Some(3600.0), Decimation::Latest);
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), s.next())
+                .await
+                .is_err()
+        );
+    }
+
+    // An interval too long for a timer holds the bucket until the
+    // stream ends.
+    #[tokio::test]
+    async fn test_huge_interval() {
+        let input = vec![reply(0, vec![scalar_info(now(), 1.0)])];
+        let s = decimate(
+            stream::iter(input.clone()),
+            Some(1e30),
+            Decimation::Latest,
+        );
+        let result: Vec<_> = s.collect().await;
+
+        assert_eq!(result, input);
+    }
+
+    // A bucket is sent after its interval even if no more data arrives.
+    #[tokio::test]
+    async fn test_timer_flushes_slow_devices() {
+        let input = stream::iter(vec![reply(0, vec![scalar_info(10.0, 1.0)])])
+            .chain(stream::pending());
+        let mut s = decimate(input, Some(0.01), Decimation::Latest);
+
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), s.next()).await,
+            Ok(Some(reply(0, vec![scalar_info(10.0, 1.0)])))
+        );
+    }
+}
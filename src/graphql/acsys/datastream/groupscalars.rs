@@ -3,6 +3,12 @@
 // pending readings are sent. If a waveform comes in, each waveform is
 // forwarded one at a time. The first element returned by the stream
 // determines what data is forwarded.
+//
+// Readings that are small and of a fixed size -- scalars, enumerated
+// values and text (an EPICS string holds at most 40 characters) -- are
+// grouped like scalars. Text arrays are forwarded like waveforms.
+// Statuses received before the type of the data is known are forwarded
+// as they arrive; afterwards, they're kept in sequence with the data.
 
 use super::global;
 use futures::Stream;
@@ -12,7 +18,7 @@ use tracing::warn;
 
 enum StreamState {
     Unknown,
-    Scalar(global::DataReply),
+    Grouped(global::DataReply),
     Waveform,
     Done,
 }
@@ -77,11 +83,14 @@ where
                     // the first element in the data array to
                     // determine the stream type.
                     StreamState::Unknown => match *payload.data.as_slice() {
-                        // If the stream has scalar data, set the type
-                        // to "scalar".
+                        // If the stream has scalar-like data, set the
+                        // type to "grouped".
                         [
                             global::DataInfo {
-                                result: global::DataType::Scalar(_),
+                                result:
+                                    global::DataType::Scalar(_)
+                                    | global::DataType::EnumValue(_)
+                                    | global::DataType::Text(_),
                                 ..
                             },
                             ..,
@@ -94,10 +103,10 @@ where
 
                                 tmp.data
                                     .extend(payload.data.drain(..MAX_PAYLOAD));
-                                self.state = StreamState::Scalar(payload);
+                                self.state = StreamState::Grouped(payload);
                                 break Poll::Ready(Some(tmp));
                             } else {
-                                self.state = StreamState::Scalar(payload);
+                                self.state = StreamState::Grouped(payload);
                             }
                         }
 
@@ -106,7 +115,9 @@ where
                         // data.
                         [
                             global::DataInfo {
-                                result: global::DataType::ScalarArray(_),
+                                result:
+                                    global::DataType::ScalarArray(_)
+                                    | global::DataType::TextArray(_),
                                 ..
                             },
                             ..,
@@ -115,13 +126,30 @@ where
                             break Poll::Ready(Some(payload));
                         }
 
-                        // We don't handle other types of data yet.
-                        _ => {
+                        // A status doesn't tell us the type of the
+                        // stream. Forward it and let the data that
+                        // follows decide.
+                        [
+                            global::DataInfo {
+                                result: global::DataType::StatusReply(_),
+                                ..
+                            },
+                            ..,
+                        ] => {
+                            break Poll::Ready(Some(payload));
+                        }
+
+                        // Archives don't return raw or structured
+                        // data.
+                        [_, ..] => {
                             warn!(
-                                "archive stream contained non-scalar / non-waveform data"
+                                "archive stream contained raw or structured data"
                             );
                             break Poll::Ready(None);
                         }
+
+                        // Skip empty packets.
+                        [] => (),
                     },
 
                     // For waveform streams, each packet is simply
@@ -130,11 +158,11 @@ where
                         break Poll::Ready(Some(payload));
                     }
 
-                    // If this is a Scalar stream, we append the
+                    // If this is a grouped stream, we append the
                     // incoming data to the pending data until it
                     // reaches MAX_PAYLOAD. Once it fills, the
                     // accumulated data is returned.
-                    StreamState::Scalar(pending) => {
+                    StreamState::Grouped(pending) => {
                         let space =
                             MAX_PAYLOAD.saturating_sub(pending.data.len());
                         let amount_to_move =
@@ -167,10 +195,10 @@ where
 
                 // Stream is done.
                 Poll::Ready(None) => {
-                    // If the stream returns scalar data, return
+                    // If the stream returns grouped data, return
                     // any pending data.
 
-                    if let StreamState::Scalar(pending) = &mut self.state
+                    if let StreamState::Grouped(pending) = &mut self.state
                         && !pending.data.is_empty()
                     {
                         let mut tmp = global::DataReply {
@@ -223,6 +251,7 @@ mod test {
             timestamp: ts,
             result: global::DataType::StatusReply(global::StatusReply {
                 status: -1,
+                message: None,
             }),
         }
     }
@@ -586,8 +615,8 @@ mod test {
     // -----------------------------------------------------------------------
     // Unknown / unsupported data-type tests
 
-    // A packet whose first element is neither Scalar nor ScalarArray must
-    // cause the stream to terminate immediately.
+    // A packet whose first element is raw data must cause the stream to
+    // terminate immediately.
     #[tokio::test]
     async fn test_unknown_data_type_terminates_stream() {
         use futures::stream::{self, StreamExt};
@@ -595,7 +624,12 @@ mod test {
         let input = &[
             global::DataReply {
                 ref_id: 0,
-                data: vec![status_info(1.0)],
+                data: vec![global::DataInfo {
+                    timestamp: 1.0,
+                    result: global::DataType::Raw(global::Raw {
+                        raw_value: vec![1, 2],
+                    }),
+                }],
             },
             // This packet should never be reached.
             global::DataReply {
@@ -609,6 +643,123 @@ mod test {
         assert!(s.next().await.is_none());
     }
 
+    // Statuses that arrive before any data are forwarded without
+    // deciding the type of the stream.
+    #[tokio::test]
+    async fn test_leading_status_is_forwarded() {
+        use futures::stream::{self, StreamExt};
+
+        let input = &[
+            global::DataReply {
+                ref_id: 0,
+                data: vec![status_info(1.0)],
+            },
+            global::DataReply {
+                ref_id: 0,
+                data: vec![scalar_info(2.0)],
+            },
+            global::DataReply {
+                ref_id: 0,
+                data: vec![scalar_info(3.0)],
+            },
+        ];
+        let mut s = super::group_scalars::<4, _>(stream::iter(input.clone()));
+
+        assert_eq!(
+            s.next().await.unwrap(),
+            global::DataReply {
+                ref_id: 0,
+                data: vec![status_info(1.0)]
+            },
+        );
+        assert_eq!(
+            s.next().await.unwrap(),
+            global::DataReply {
+                ref_id: 0,
+                data: vec![scalar_info(2.0), scalar_info(3.0)]
+            },
+        );
+        assert!(s.next().await.is_none());
+    }
+
+    // -----------------------------------------------------------------------
+    // Text and enumerated value tests
+
+    fn text_info(ts: f64) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::Text(global::Text {
+                text_value: format!("state {}", ts),
+            }),
+        }
+    }
+
+    fn enum_info(ts: f64) -> global::DataInfo {
+        global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::EnumValue(global::EnumValue {
+                enum_value: ts as i32,
+                label: Some("On".into()),
+            }),
+        }
+    }
+
+    // Text and enumerated values are grouped like scalars.
+    #[tokio::test]
+    async fn test_grouping_text_and_enums() {
+        use futures::stream::{self, StreamExt};
+
+        for info in [text_info as fn(f64) -> global::DataInfo, enum_info] {
+            let input: Vec<global::DataReply> = (0..5)
+                .map(|i| global::DataReply {
+                    ref_id: 1,
+                    data: vec![info(i as f64)],
+                })
+                .collect();
+            let mut s = super::group_scalars::<4, _>(stream::iter(input));
+
+            assert_eq!(
+                s.next().await.unwrap(),
+                global::DataReply {
+                    ref_id: 1,
+                    data: (0..4).map(|i| info(i as f64)).collect()
+                },
+            );
+            assert_eq!(
+                s.next().await.unwrap(),
+                global::DataReply {
+                    ref_id: 1,
+                    data: vec![info(4.0)]
+                },
+            );
+            assert!(s.next().await.is_none());
+        }
+    }
+
+    // Text arrays are forwarded one packet at a time, like waveforms.
+    #[tokio::test]
+    async fn test_text_arrays_are_forwarded() {
+        use futures::stream::{self, StreamExt};
+
+        let text_array_info = |ts: f64| global::DataInfo {
+            timestamp: ts,
+            result: global::DataType::TextArray(global::TextArray {
+                text_array_value: vec!["a".into(), format!("{}", ts)],
+            }),
+        };
+        let input: Vec<global::DataReply> = (0..2)
+            .map(|i| global::DataReply {
+                ref_id: 0,
+                data: vec![text_array_info(i as f64)],
+            })
+            .collect();
+        let mut s = super::group_scalars::<4, _>(stream::iter(input.clone()));
+
+        assert_eq!(s.next().await.unwrap(), input[0]);
+        assert_eq!(s.next().await.unwrap(), input[1]);
+        assert!(s.next().await.is_none());
+    }
+
     // -----------------------------------------------------------------------
     // Boundary / accumulation tests
 
@@ -0,0 +1,37 @@
+// Builds the readings, replies and archive streams used by the tests
+// of the data streams and of the modules that use them.
+
+use super::global;
+use futures::{Stream, stream};
+
+// Returns a scalar reading.
+
+pub fn scalar_info(ts: f64, v: f64) -> global::DataInfo {
+    global::DataInfo {
+        timestamp: ts,
+        result: global::DataType::Scalar(global::Scalar { scalar_value: v }),
+    }
+}
+
+// Returns a scalar reading whose value is its timestamp.
+
+pub fn info(ts: f64) -> global::DataInfo {
+    scalar_info(ts, ts)
+}
+
+pub fn scalar(v: f64) -> Option<global::DataType> {
+    Some(global::DataType::Scalar(global::Scalar { scalar_value: v }))
+}
+
+pub fn reply(data: Vec<global::DataInfo>) -> global::DataReply {
+    global::DataReply { ref_id: 0, data }
+}
+
+// Returns an archive stream holding `data`. Like the archive streams
+// of the service, it ends with an empty reply.
+
+pub fn archive(
+    data: Vec<global::DataInfo>,
+) -> impl Stream<Item = global::DataReply> + Send + 'static + Unpin {
+    stream::iter(vec![reply(data), reply(vec![])])
+}
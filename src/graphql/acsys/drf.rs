@@ -0,0 +1,459 @@
+// Contains a small, partial DRF parser. It only understands enough of
+// the syntax to determine which ACNET device, and which property of
+// that device, a DRF string refers to. Data acquisition still passes
+// the original DRF string to DPM; this parser is used when we need to
+// look up the device in DevDB or choose the archive that holds its
+// data.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Property {
+    Reading,
+    Setting,
+    Status,
+    Control,
+    AnalogAlarm,
+    DigitalAlarm,
+    Description,
+}
+
+impl Property {
+    // Returns the property associated with the "qualifier" character
+    // of an ACNET device name (i.e. the second character in
+    // "M:OUTTMP" or "M_OUTTMP".)
+
+    fn from_qualifier(ch: char) -> Option<Self> {
+        match ch {
+            ':' | '?' => Some(Property::Reading),
+            '_' => Some(Property::Setting),
+            '|' => Some(Property::Status),
+            '&' => Some(Property::Control),
+            '@' => Some(Property::AnalogAlarm),
+            '$' => Some(Property::DigitalAlarm),
+            '~' => Some(Property::Description),
+            _ => None,
+        }
+    }
+
+    // Returns the property associated with a property name found
+    // after the device name (e.g. "M:OUTTMP.SETTING".)
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "READING" | "READ" | "PRREAD" => Some(Property::Reading),
+            "SETTING" | "SET" | "PRSET" => Some(Property::Setting),
+            "STATUS" | "BASIC_STATUS" | "STS" | "PRBSTS" => {
+                Some(Property::Status)
+            }
+            "CONTROL" | "BASIC_CONTROL" | "CTRL" | "PRBCTL" => {
+                Some(Property::Control)
+            }
+            "ANALOG" | "ANALOG_ALARM" | "AA" | "PRANAB" => {
+                Some(Property::AnalogAlarm)
+            }
+            "DIGITAL" | "DIGITAL_ALARM" | "DA" | "PRDABL" => {
+                Some(Property::DigitalAlarm)
+            }
+            "DESCRIPTION" | "DESC" => Some(Property::Description),
+            _ => None,
+        }
+    }
+
+    // Returns the name DPM uses for the property.
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Property::Reading => "READING",
+            Property::Setting => "SETTING",
+            Property::Status => "STATUS",
+            Property::Control => "CONTROL",
+            Property::AnalogAlarm => "ANALOG",
+            Property::DigitalAlarm => "DIGITAL",
+            Property::Description => "DESCRIPTION",
+        }
+    }
+}
+
+// The field of a property selected by a DRF string (e.g. the `RAW` in
+// "M:OUTTMP.SETTING.RAW".) Only the fields that change how a reading
+// or setting is scaled are distinguished; the others are kept by name.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Scaled,
+    Primary,
+    Raw,
+    Other(String),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_uppercase();
+
+        match name.as_str() {
+            "SCALED" | "COMMON" => Some(Field::Scaled),
+            "PRIMARY" | "VOLTS" => Some(Field::Primary),
+            "RAW" => Some(Field::Raw),
+            "ALL" | "TEXT" | "EXTENDED_TEXT" | "ON" | "READY" | "REMOTE"
+            | "POSITIVE" | "RAMP" | "BIT_VALUE" | "BIT_NAMES"
+            | "BIT_VALUES" | "MIN" | "MAX" | "NOM" | "TOL" | "ALARM_ENABLE"
+            | "ALARM_STATUS" | "TRIES_NEEDED" | "TRIES_NOW" | "ALARM_FTD"
+            | "ABORT" | "ABORT_INHIBIT" | "FLAGS" | "MASK" => {
+                Some(Field::Other(name))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Field::Scaled => "SCALED",
+            Field::Primary => "PRIMARY",
+            Field::Raw => "RAW",
+            Field::Other(name) => name,
+        }
+    }
+}
+
+// The device, property and field referred to by a DRF string. The
+// device name is normalized to the form DevDB uses (upper case with a
+// ':' qualifier.) `field` is `None` when the DRF doesn't give one.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub device: String,
+    pub property: Property,
+    pub field: Option<Field>,
+}
+
+impl Target {
+    // Builds a DRF string which refers to a different property of the
+    // same device.
+
+    pub fn drf(&self, property: Property) -> String {
+        format!("{}.{}", self.device, property.name())
+    }
+}
+
+// Returns `true` if the name looks like an ACNET device name: a
+// letter or digit followed by a qualifier character followed by the
+// rest of the name.
+
+pub fn is_acnet_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(a), Some(q), Some(_))
+            if a.is_ascii_alphanumeric() && Property::from_qualifier(q).is_some()
+    )
+}
+
+// Returns `true` if a DRF string refers to an ACNET device, whether or
+// not the rest of it can be parsed.
+
+pub fn is_acnet_drf(drf: &str) -> bool {
+    is_acnet_name(entity(drf).split(['.', '[']).next().unwrap_or(""))
+}
+
+// Returns the portion of a DRF string that precedes the event and
+// source specifications.
+
+pub fn entity(drf: &str) -> &str {
+    let drf = drf.trim();
+
+    // An '@' in the qualifier position is part of the name (it selects
+    // the analog alarm property) so the search for the event and
+    // source delimiters starts after it.
+
+    let end = drf
+        .char_indices()
+        .skip(2)
+        .find(|(_, ch)| *ch == '@' || *ch == '<')
+        .map(|(idx, _)| idx)
+        .unwrap_or(drf.len());
+
+    drf[..end].trim_end()
+}
+
+// Returns the event specification of a DRF string, including its
+// leading '@' (e.g. "@p,1000"), or `None` if it doesn't have one.
+
+pub fn event(drf: &str) -> Option<&str> {
+    let drf = drf.trim();
+    let rest = &drf[entity(drf).len()..];
+    let rest = rest.split("<-").next().unwrap_or("").trim();
+
+    rest.starts_with('@').then_some(rest)
+}
+
+// The archive that holds a device's past readings. `Logger` refers to
+// the ACNET data loggers and may name the logger node to use.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveSource {
+    Logger(Option<String>),
+    Epics,
+}
+
+// Determines which archive holds the data for a DRF string. An
+// explicit `<-LOGGER`, `<-LOGGER:node` or `<-EPICS` source is used as
+// given. Without a source, ACNET device names use the data loggers and
+// anything else is treated as an EPICS PV. The returned flag is `true`
+// when the source was explicit. Other sources have no archived data,
+// so they're rejected.
+
+pub fn archive_source(drf: &str) -> Result<(ArchiveSource, bool), String> {
+    let Some(idx) = drf.find("<-") else {
+        return Ok(if is_acnet_drf(drf) {
+            (ArchiveSource::Logger(None), false)
+        } else {
+            (ArchiveSource::Epics, false)
+        });
+    };
+
+    let source = drf[idx + 2..].trim();
+    let mut parts = source.split(':');
+
+    match (
+        parts.next().map(str::to_ascii_uppercase).as_deref(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some("LOGGER"), None, None) => Ok((ArchiveSource::Logger(None), true)),
+        (Some("LOGGER"), Some(node), None)
+            if !node.is_empty()
+                && node.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Ok((ArchiveSource::Logger(Some(node.to_ascii_uppercase())), true))
+        }
+        (Some("EPICS"), None, None) => Ok((ArchiveSource::Epics, true)),
+        _ => Err(format!("{:?} isn't an archived data source", source)),
+    }
+}
+
+// Parses the device, property and field out of a DRF string. If the
+// DRF string doesn't refer to an ACNET device (e.g. it's an EPICS PV),
+// or names a property or field that doesn't exist, `None` is returned.
+// Use `is_acnet_drf` to tell the two apart.
+
+pub fn parse_target(drf: &str) -> Option<Target> {
+    let entity = entity(drf);
+
+    // Remove any range specification. Only a field may follow it.
+
+    let (head, tail) = match entity.find('[') {
+        Some(idx) => {
+            let rest = &entity[idx..];
+
+            (&entity[..idx], &rest[rest.find(']')? + 1..])
+        }
+        None => (entity, ""),
+    };
+    let mut parts = head.split('.');
+    let name = parts.next()?;
+
+    if !is_acnet_name(name) {
+        return None;
+    }
+
+    let mut parts: Vec<_> = parts.collect();
+
+    if !tail.is_empty() {
+        parts.push(tail.strip_prefix('.')?);
+    }
+
+    let qualifier = name.chars().nth(1)?;
+    let (property, field) = match parts.as_slice() {
+        [] => (Property::from_qualifier(qualifier)?, None),
+        [part] => match Property::from_name(part) {
+            Some(property) => (property, None),
+            None => (
+                Property::from_qualifier(qualifier)?,
+                Some(Field::from_name(part)?),
+            ),
+        },
+        [prop, field] => {
+            (Property::from_name(prop)?, Some(Field::from_name(field)?))
+        }
+        _ => return None,
+    };
+
+    Some(Target {
+        device: format!("{}:{}", &name[..1], &name[2..]).to_ascii_uppercase(),
+        property,
+        field,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(device: &str, property: Property) -> Option<Target> {
+        Some(Target {
+            device: device.into(),
+            property,
+            field: None,
+        })
+    }
+
+    #[test]
+    fn test_acnet_names() {
+        assert!(is_acnet_name("M:OUTTMP"));
+        assert!(is_acnet_name("M_OUTTMP"));
+        assert!(is_acnet_name("z@OUTTMP"));
+        assert!(!is_acnet_name("M:"));
+        assert!(!is_acnet_name("MOUTTMP"));
+        assert!(!is_acnet_name("PIP2:BPM:X"));
+        assert!(!is_acnet_name(""));
+    }
+
+    #[test]
+    fn test_qualifier_determines_property() {
+        assert_eq!(
+            parse_target("M:OUTTMP"),
+            target("M:OUTTMP", Property::Reading)
+        );
+        assert_eq!(
+            parse_target("M_OUTTMP"),
+            target("M:OUTTMP", Property::Setting)
+        );
+        assert_eq!(
+            parse_target("M|OUTTMP"),
+            target("M:OUTTMP", Property::Status)
+        );
+        assert_eq!(
+            parse_target("M&OUTTMP"),
+            target("M:OUTTMP", Property::Control)
+        );
+        assert_eq!(
+            parse_target("M@OUTTMP"),
+            target("M:OUTTMP", Property::AnalogAlarm)
+        );
+    }
+
+    #[test]
+    fn test_property_name_overrides_qualifier() {
+        assert_eq!(
+            parse_target("M:OUTTMP.SETTING"),
+            target("M:OUTTMP", Property::Setting)
+        );
+        assert_eq!(
+            parse_target("m:outtmp.control"),
+            target("M:OUTTMP", Property::Control)
+        );
+        assert_eq!(parse_target("M:OUTTMP.BOGUS"), None);
+        assert_eq!(parse_target("M:OUTTMP.SETTING.BOGUS"), None);
+        assert!(is_acnet_drf("M:OUTTMP.BOGUS"));
+        assert!(!is_acnet_drf("PIP2:BPM:X"));
+    }
+
+    #[test]
+    fn test_fields() {
+        let field = |drf, property, field| {
+            assert_eq!(
+                parse_target(drf),
+                Some(Target {
+                    device: "M:OUTTMP".into(),
+                    property,
+                    field: Some(field),
+                })
+            );
+        };
+
+        field("M:OUTTMP.STATUS.RAW", Property::Status, Field::Raw);
+        field("M_OUTTMP.RAW", Property::Setting, Field::Raw);
+        field("M:OUTTMP.SETTING.VOLTS", Property::Setting, Field::Primary);
+        field("M_OUTTMP[0:3].common@i", Property::Setting, Field::Scaled);
+        field(
+            "M@OUTTMP.MAX",
+            Property::AnalogAlarm,
+            Field::Other("MAX".into()),
+        );
+        assert_eq!(parse_target("M:OUTTMP.SETTING.RAW.RAW"), None);
+    }
+
+    #[test]
+    fn test_events_ranges_and_sources_are_ignored() {
+        assert_eq!(
+            parse_target("M:OUTTMP.SETTING@p,1000"),
+            target("M:OUTTMP", Property::Setting)
+        );
+        assert_eq!(
+            parse_target("M_OUTTMP[0:3]@i"),
+            target("M:OUTTMP", Property::Setting)
+        );
+        assert_eq!(
+            parse_target(" M:OUTTMP <-LOGGER"),
+            target("M:OUTTMP", Property::Reading)
+        );
+        assert_eq!(
+            parse_target("M@OUTTMP@i"),
+            target("M:OUTTMP", Property::AnalogAlarm)
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(event("M:OUTTMP@p,1000"), Some("@p,1000"));
+        assert_eq!(event("M:OUTTMP.SETTING @i <-LOGGER"), Some("@i"));
+        assert_eq!(event("M@OUTTMP@e,02"), Some("@e,02"));
+        assert_eq!(event("M@OUTTMP"), None);
+        assert_eq!(event("M:OUTTMP<-LOGGER"), None);
+        assert_eq!(entity("M@OUTTMP@i"), "M@OUTTMP");
+    }
+
+    #[test]
+    fn test_epics_names_are_not_parsed() {
+        assert_eq!(parse_target("PIP2:BPM:X"), None);
+        assert_eq!(parse_target("ACC-RF.VAL"), None);
+    }
+
+    #[test]
+    fn test_archive_source() {
+        let logger =
+            |node: Option<&str>| ArchiveSource::Logger(node.map(String::from));
+
+        assert_eq!(archive_source("M:OUTTMP"), Ok((logger(None), false)));
+        assert_eq!(
+            archive_source("M:OUTTMP[0:3]@p,1000"),
+            Ok((logger(None), false))
+        );
+        assert_eq!(
+            archive_source("PIP2:BPM:X"),
+            Ok((ArchiveSource::Epics, false))
+        );
+        assert_eq!(
+            archive_source("ACC-RF.VAL"),
+            Ok((ArchiveSource::Epics, false))
+        );
+        assert_eq!(
+            archive_source("M:OUTTMP <-LOGGER"),
+            Ok((logger(None), true))
+        );
+        assert_eq!(
+            archive_source("M:OUTTMP<-logger:backup"),
+            Ok((logger(Some("BACKUP")), true))
+        );
+        assert_eq!(
+            archive_source("PIP2:BPM:X<-LOGGER:MCR"),
+            Ok((logger(Some("MCR")), true))
+        );
+        assert_eq!(
+            archive_source("M:OUTTMP<-EPICS"),
+            Ok((ArchiveSource::Epics, true))
+        );
+        assert!(archive_source("M:OUTTMP<-LOGGER:").is_err());
+        assert!(archive_source("M:OUTTMP<-LOGGER:1:2").is_err());
+        assert!(archive_source("M:OUTTMP<-EPICS:X").is_err());
+        assert!(archive_source("M:OUTTMP<-SRFILE").is_err());
+    }
+
+    #[test]
+    fn test_target_drf() {
+        let t = parse_target("M_OUTTMP@i").unwrap();
+
+        assert_eq!(t.drf(Property::Setting), "M:OUTTMP.SETTING");
+        assert_eq!(t.drf(Property::Control), "M:OUTTMP.CONTROL");
+    }
+}
@@ -0,0 +1,891 @@
+// Implements an HTTP endpoint that downloads archived data as a file.
+// GraphQL subscriptions work well for plots but are a poor fit for
+// exporting millions of points, so this endpoint streams the data as
+// CSV, JSON Lines or Parquet instead.
+//
+// The request is a `GET` with these query parameters:
+//
+//   drf         The device(s) to export. Repeat it for each device.
+//   start       The start of the range, in seconds since 1970 UTC.
+//   end         The end of the range (defaults to now.)
+//   format      `csv` (the default), `jsonl` or `parquet`.
+//   timestamps  `seconds` (the default), `millis` or `iso`.
+//
+// The output has a timestamp column followed by one column per device.
+// Each row holds the readings taken at that time; devices without a
+// reading at that time are empty. The data is retrieved and encoded as
+// it's sent. What's held in memory is each device's data that's been
+// read ahead of the rows being built (a few replies of each chunk; see
+// `datastream::retrieve_chunks`) and, for Parquet, the row group being
+// written. A Parquet file's footer describes every row group, so it
+// grows slowly with the size of the range.
+
+use super::{archive::ArchiveSources, global, now};
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures::{Stream, stream::BoxStream};
+use futures_util::{StreamExt, stream as fstream};
+use serde_json::Value;
+use std::collections::VecDeque;
+use tracing::{info, instrument, warn};
+
+// Text output is sent once this many bytes have been encoded.
+
+const FLUSH_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    JsonLines,
+    #[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+    Parquet,
+}
+
+impl Format {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(Format::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => {
+                Err("this service was built without Parquet support".into())
+            }
+            _ => Err(format!("unknown export format: {:?}", s)),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::JsonLines => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+// How timestamps are written.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeFormat {
+    Seconds,
+    Millis,
+    Iso,
+}
+
+impl TimeFormat {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "seconds" => Ok(TimeFormat::Seconds),
+            "millis" => Ok(TimeFormat::Millis),
+            "iso" => Ok(TimeFormat::Iso),
+            _ => Err(format!("unknown timestamp format: {:?}", s)),
+        }
+    }
+
+    fn millis(ts: f64) -> i64 {
+        (ts * 1_000.0).round() as i64
+    }
+
+    fn iso(ts: f64) -> String {
+        chrono::DateTime::from_timestamp_micros(
+            (ts * 1_000_000.0).round() as i64
+        )
+        .map(|v| v.to_rfc3339_opts(chrono::SecondsFormat::Micros, true))
+        .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    drfs: Vec<String>,
+    start: f64,
+    end: f64,
+    format: Format,
+    time: TimeFormat,
+}
+
+fn seconds(key: &str, value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| format!("bad {} parameter: {:?}", key, value))
+}
+
+// Builds a request from the query parameters. Only past data is
+// exported, so the end of the range is limited to `now`.
+
+fn parse_request(
+    params: &[(String, String)], now: f64,
+) -> Result<Request, String> {
+    let mut drfs: Vec<String> = vec![];
+    let mut start = None;
+    let mut end = None;
+    let mut format = Format::Csv;
+    let mut time = TimeFormat::Seconds;
+
+    for (key, value) in params {
+        match key.as_str() {
+            "drf" if drfs.contains(value) => {
+                return Err(format!("{} is listed more than once", value));
+            }
+            "drf" => drfs.push(value.clone()),
+            "start" => start = Some(seconds(key, value)?),
+            "end" => end = Some(seconds(key, value)?),
+            "format" => format = Format::parse(value)?,
+            "timestamps" => time = TimeFormat::parse(value)?,
+            _ => return Err(format!("unknown parameter: {:?}", key)),
+        }
+    }
+
+    let start = start.ok_or("the start parameter is required")?;
+    let end = end.unwrap_or(now).min(now);
+
+    if drfs.is_empty() {
+        Err("at least one drf parameter is required".into())
+    } else if start >= end {
+        Err("the start of the range must be before its end (and now)".into())
+    } else {
+        Ok(Request {
+            drfs,
+            start,
+            end,
+            format,
+            time,
+        })
+    }
+}
+
+// One line of the export: a timestamp and each device's reading at
+// that time.
+
+#[derive(Debug, PartialEq)]
+struct Row {
+    timestamp: f64,
+    values: Vec<Option<global::DataType>>,
+}
+
+// A device's archived data and the readings that have been received
+// but not yet placed in a row.
+
+struct Column<S> {
+    s: Option<S>,
+    queue: VecDeque<global::DataInfo>,
+}
+
+// Merges the archived data of several devices into rows, in time
+// order. Warnings are logged and dropped. An error status means a
+// device's data couldn't be retrieved, so it's returned as an error
+// and the stream ends; a partial export shouldn't look complete.
+
+fn rows<S>(
+    streams: Vec<S>,
+) -> impl Stream<Item = Result<Row, String>> + Send + 'static
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    let columns: Vec<_> = streams
+        .into_iter()
+        .map(|s| Column {
+            s: Some(s),
+            queue: VecDeque::new(),
+        })
+        .collect();
+
+    fstream::unfold(Some(columns), |columns| async move {
+        let mut columns = columns?;
+
+        for col in columns.iter_mut() {
+            while col.queue.is_empty() {
+                let Some(s) = col.s.as_mut() else {
+                    break;
+                };
+                let Some(reply) = s.next().await else {
+                    col.s = None;
+                    break;
+                };
+
+                for info in reply.data {
+                    match info.result {
+                        global::DataType::StatusReply(ref status)
+                            if status.status < 0 =>
+                        {
+                            return Some((Err(status.to_string()), None));
+                        }
+                        global::DataType::StatusReply(ref status) => {
+                            warn!("export skipped status {}", status)
+                        }
+                        _ => col.queue.push_back(info),
+                    }
+                }
+            }
+        }
+
+        let timestamp = columns
+            .iter()
+            .filter_map(|col| col.queue.front().map(|v| v.timestamp))
+            .min_by(f64::total_cmp)?;
+        let values = columns
+            .iter_mut()
+            .map(|col| match col.queue.front() {
+                Some(v) if v.timestamp == timestamp => {
+                    col.queue.pop_front().map(|v| v.result)
+                }
+                _ => None,
+            })
+            .collect();
+
+        Some((Ok(Row { timestamp, values }), Some(columns)))
+    })
+}
+
+// Converts a reading to JSON. Enumerated values are written as their
+// label, when it's known.
+
+fn to_json(value: &global::DataType) -> Value {
+    match value {
+        global::DataType::Scalar(v) => Value::from(v.scalar_value),
+        global::DataType::ScalarArray(v) => {
+            Value::from(v.scalar_array_value.clone())
+        }
+        global::DataType::Raw(v) => Value::from(v.raw_value.clone()),
+        global::DataType::Text(v) => Value::from(v.text_value.clone()),
+        global::DataType::TextArray(v) => {
+            Value::from(v.text_array_value.clone())
+        }
+        global::DataType::EnumValue(v) => match v.label {
+            Some(ref label) => Value::from(label.clone()),
+            None => Value::from(v.enum_value),
+        },
+        global::DataType::StructData(v) => {
+            let mut map = serde_json::Map::new();
+
+            map.insert(v.key.clone(), to_json(&v.struct_value));
+            Value::Object(map)
+        }
+        global::DataType::StatusReply(_) => Value::Null,
+    }
+}
+
+// Quotes a CSV field, if needed.
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.into()
+    }
+}
+
+fn csv_header(drfs: &[String]) -> String {
+    std::iter::once("timestamp".to_string())
+        .chain(drfs.iter().map(|v| csv_field(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+        + "\n"
+}
+
+fn csv_row(time: TimeFormat, row: &Row) -> String {
+    let ts = match time {
+        TimeFormat::Seconds => row.timestamp.to_string(),
+        TimeFormat::Millis => TimeFormat::millis(row.timestamp).to_string(),
+        TimeFormat::Iso => TimeFormat::iso(row.timestamp),
+    };
+
+    std::iter::once(ts)
+        .chain(row.values.iter().map(|v| match v {
+            None => String::new(),
+            Some(global::DataType::Scalar(v)) => v.scalar_value.to_string(),
+            Some(global::DataType::Text(v)) => csv_field(&v.text_value),
+            Some(global::DataType::EnumValue(global::EnumValue {
+                label: Some(label),
+                ..
+            })) => csv_field(label),
+            Some(v) => csv_field(&to_json(v).to_string()),
+        }))
+        .collect::<Vec<_>>()
+        .join(",")
+        + "\n"
+}
+
+fn json_row(drfs: &[String], time: TimeFormat, row: &Row) -> String {
+    let ts = match time {
+        TimeFormat::Seconds => Value::from(row.timestamp),
+        TimeFormat::Millis => Value::from(TimeFormat::millis(row.timestamp)),
+        TimeFormat::Iso => Value::from(TimeFormat::iso(row.timestamp)),
+    };
+    let mut line = format!("{{\"timestamp\":{}", ts);
+
+    for (drf, value) in drfs.iter().zip(row.values.iter()) {
+        line.push(',');
+        line.push_str(&Value::from(drf.as_str()).to_string());
+        line.push(':');
+        line.push_str(
+            &value
+                .as_ref()
+                .map(to_json)
+                .unwrap_or(Value::Null)
+                .to_string(),
+        );
+    }
+    line + "}\n"
+}
+
+// Encodes rows as CSV or JSON Lines.
+
+fn text_body<S>(
+    drfs: Vec<String>, format: Format, time: TimeFormat, rows: S,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Row, String>> + Send + 'static,
+{
+    stream! {
+        let mut rows = std::pin::pin!(rows);
+        let mut buf = if format == Format::Csv {
+            csv_header(&drfs)
+        } else {
+            String::new()
+        };
+
+        while let Some(row) = rows.next().await {
+            let row = match row {
+                Ok(v) => v,
+                Err(e) => {
+                    yield Err(std::io::Error::other(e));
+                    return;
+                }
+            };
+
+            if format == Format::Csv {
+                buf.push_str(&csv_row(time, &row));
+            } else {
+                buf.push_str(&json_row(&drfs, time, &row));
+            }
+
+            if buf.len() >= FLUSH_SIZE {
+                yield Ok(Bytes::from(std::mem::take(&mut buf)));
+            }
+        }
+
+        if !buf.is_empty() {
+            yield Ok(Bytes::from(buf));
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_body {
+    // Encodes rows as a Parquet file. The timestamp column is a double
+    // (seconds), a 64-bit integer (milliseconds) or a string (ISO
+    // 8601.) Each device is an optional double column, so only scalar
+    // readings are exported; others are stored as nulls. Rows are
+    // written in row groups, and each group is sent as soon as it's
+    // written.
+
+    use super::{Bytes, Row, Stream, StreamExt, TimeFormat, global, stream};
+    use parquet::{
+        basic::{Compression, ConvertedType, Repetition, Type as PhysicalType},
+        data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+        errors::Result,
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::types::Type,
+    };
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    const ROWS_PER_GROUP: usize = 65_536;
+
+    // The writer's output. Parquet writers own their output so this is
+    // shared with the stream, which sends what has been written after
+    // each row group.
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn take(&self) -> Bytes {
+            Bytes::from(std::mem::take(&mut *self.0.lock().unwrap()))
+        }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn schema(drfs: &[String], time: TimeFormat) -> Result<Type> {
+        let timestamp = match time {
+            TimeFormat::Seconds => {
+                Type::primitive_type_builder("timestamp", PhysicalType::DOUBLE)
+            }
+            TimeFormat::Millis => {
+                Type::primitive_type_builder("timestamp", PhysicalType::INT64)
+            }
+            TimeFormat::Iso => Type::primitive_type_builder(
+                "timestamp",
+                PhysicalType::BYTE_ARRAY,
+            )
+            .with_converted_type(ConvertedType::UTF8),
+        }
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+        let mut fields = vec![Arc::new(timestamp)];
+
+        for drf in drfs {
+            fields.push(Arc::new(
+                Type::primitive_type_builder(drf, PhysicalType::DOUBLE)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .build()?,
+            ));
+        }
+        Type::group_type_builder("export")
+            .with_fields(fields)
+            .build()
+    }
+
+    fn write_group(
+        writer: &mut SerializedFileWriter<SharedBuf>, time: TimeFormat,
+        rows: &[Row],
+    ) -> Result<()> {
+        let mut group = writer.next_row_group()?;
+        let mut idx = 0;
+
+        while let Some(mut col) = group.next_column()? {
+            if idx == 0 {
+                let ts = rows.iter().map(|v| v.timestamp);
+
+                match time {
+                    TimeFormat::Seconds => {
+                        col.typed::<DoubleType>().write_batch(
+                            &ts.collect::<Vec<_>>(),
+                            None,
+                            None,
+                        )?;
+                    }
+                    TimeFormat::Millis => {
+                        col.typed::<Int64Type>().write_batch(
+                            &ts.map(TimeFormat::millis).collect::<Vec<_>>(),
+                            None,
+                            None,
+                        )?;
+                    }
+                    TimeFormat::Iso => {
+                        col.typed::<ByteArrayType>().write_batch(
+                            &ts.map(|v| {
+                                ByteArray::from(TimeFormat::iso(v).into_bytes())
+                            })
+                            .collect::<Vec<_>>(),
+                            None,
+                            None,
+                        )?;
+                    }
+                }
+            } else {
+                let mut values = vec![];
+                let mut levels = vec![];
+
+                for row in rows {
+                    if let Some(global::DataType::Scalar(ref v)) =
+                        row.values[idx - 1]
+                    {
+                        values.push(v.scalar_value);
+                        levels.push(1);
+                    } else {
+                        levels.push(0);
+                    }
+                }
+                col.typed::<DoubleType>().write_batch(
+                    &values,
+                    Some(&levels),
+                    None,
+                )?;
+            }
+            col.close()?;
+            idx += 1;
+        }
+        group.close()?;
+        Ok(())
+    }
+
+    pub fn parquet_body<S>(
+        drfs: Vec<String>, time: TimeFormat, rows: S,
+    ) -> impl Stream<Item = std::result::Result<Bytes, std::io::Error>>
+    + Send
+    + 'static
+    where
+        S: Stream<Item = std::result::Result<Row, String>> + Send + 'static,
+    {
+        stream! {
+            let buf = SharedBuf::default();
+            let props = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let mut writer = match schema(&drfs, time).and_then(|schema| {
+                SerializedFileWriter::new(buf.clone(), Arc::new(schema), Arc::new(props))
+            }) {
+                Ok(v) => v,
+                Err(e) => {
+                    yield Err(std::io::Error::other(e));
+                    return;
+                }
+            };
+            let mut rows = std::pin::pin!(rows);
+            let mut group = Vec::with_capacity(ROWS_PER_GROUP);
+
+            loop {
+                let row = match rows.next().await.transpose() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        yield Err(std::io::Error::other(e));
+                        return;
+                    }
+                };
+                let done = row.is_none();
+
+                group.extend(row);
+                if group.len() < ROWS_PER_GROUP && !done {
+                    continue;
+                }
+
+                let result = if group.is_empty() {
+                    Ok(())
+                } else {
+                    write_group(&mut writer, time, &group)
+                };
+
+                if let Err(e) = result {
+                    yield Err(std::io::Error::other(e));
+                    return;
+                }
+                group.clear();
+
+                if done {
+                    break;
+                }
+                yield Ok(buf.take());
+            }
+
+            if let Err(e) = writer.close() {
+                yield Err(std::io::Error::other(e));
+                return;
+            }
+            yield Ok(buf.take());
+        }
+    }
+}
+
+#[instrument(name = "EXPORT", skip(sources, headers, params))]
+pub async fn handler(
+    State(sources): State<ArchiveSources>, headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Response {
+    let req = match parse_request(&params, now()) {
+        Ok(v) => v,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let token = global::AuthInfo::new(
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from),
+    )
+    .token();
+    let mut streams = Vec::with_capacity(req.drfs.len());
+
+    info!(
+        "exporting {:?} from {} to {}",
+        &req.drfs, req.start, req.end
+    );
+
+    for drf in &req.drfs {
+        match sources
+            .fetch(token.clone(), drf, req.start, req.end, None)
+            .await
+        {
+            Ok(s) => streams.push(s),
+            Err(e) => {
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("{}: {}", drf, e.message),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let rows = rows(streams);
+    let body: BoxStream<'static, Result<Bytes, std::io::Error>> =
+        match req.format {
+            #[cfg(feature = "parquet")]
+            Format::Parquet => {
+                parquet_body::parquet_body(req.drfs, req.time, rows).boxed()
+            }
+            format => text_body(req.drfs, format, req.time, rows).boxed(),
+        };
+
+    (
+        [
+            (header::CONTENT_TYPE, req.format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"archive.{}\"",
+                    req.format.extension()
+                ),
+            ),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::{
+        reply, scalar, scalar_info,
+    };
+
+    fn params(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn sample_rows() -> Vec<Result<Row, String>> {
+        vec![
+            Ok(Row {
+                timestamp: 1.5,
+                values: vec![scalar(1.0), None],
+            }),
+            Ok(Row {
+                timestamp: 2.0,
+                values: vec![
+                    scalar(2.0),
+                    Some(global::DataType::Text(global::Text {
+                        text_value: "a,\"b\"".into(),
+                    })),
+                ],
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(
+                &params(&[
+                    ("drf", "M:OUTTMP"),
+                    ("drf", "PIP2:BPM:X"),
+                    ("start", "100"),
+                    ("end", "500"),
+                    ("format", "JSONL"),
+                    ("timestamps", "iso"),
+                ]),
+                1000.0
+            ),
+            Ok(Request {
+                drfs: vec!["M:OUTTMP".into(), "PIP2:BPM:X".into()],
+                start: 100.0,
+                end: 500.0,
+                format: Format::JsonLines,
+                time: TimeFormat::Iso,
+            })
+        );
+
+        // The end defaults to, and is limited to, now.
+
+        let req = parse_request(
+            &params(&[("drf", "M:OUTTMP"), ("start", "100")]),
+            1000.0,
+        )
+        .unwrap();
+
+        assert_eq!(
+            (req.end, req.format, req.time),
+            (1000.0, Format::Csv, TimeFormat::Seconds)
+        );
+
+        let check =
+            |v: &[(&str, &str)]| parse_request(&params(v), 1000.0).is_err();
+
+        assert!(check(&[("start", "100")]));
+        assert!(check(&[("drf", "M:OUTTMP")]));
+        assert!(check(&[("drf", "M:OUTTMP"), ("start", "2000")]));
+        assert!(check(&[
+            ("drf", "M:OUTTMP"),
+            ("drf", "M:OUTTMP"),
+            ("start", "1")
+        ]));
+        assert!(check(&[("drf", "M:OUTTMP"), ("start", "x")]));
+        assert!(check(&[
+            ("drf", "M:OUTTMP"),
+            ("start", "1"),
+            ("format", "xml")
+        ]));
+        assert!(check(&[
+            ("drf", "M:OUTTMP"),
+            ("start", "1"),
+            ("bogus", "1")
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_rows() {
+        let a = fstream::iter(vec![
+            reply(vec![scalar_info(1.0, 10.0), scalar_info(3.0, 30.0)]),
+            reply(vec![scalar_info(4.0, 40.0)]),
+            reply(vec![]),
+        ]);
+        let b = fstream::iter(vec![
+            reply(vec![global::DataInfo {
+                timestamp: 100.0,
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status: 1 + 3 * 256,
+                    message: None,
+                }),
+            }]),
+            reply(vec![scalar_info(2.0, 20.0), scalar_info(3.0, 31.0)]),
+        ]);
+        let result: Vec<_> = rows(vec![a, b]).collect().await;
+
+        assert_eq!(
+            result,
+            vec![
+                Ok(Row {
+                    timestamp: 1.0,
+                    values: vec![scalar(10.0), None],
+                }),
+                Ok(Row {
+                    timestamp: 2.0,
+                    values: vec![None, scalar(20.0)],
+                }),
+                Ok(Row {
+                    timestamp: 3.0,
+                    values: vec![scalar(30.0), scalar(31.0)],
+                }),
+                Ok(Row {
+                    timestamp: 4.0,
+                    values: vec![scalar(40.0), None],
+                }),
+            ]
+        );
+
+        // An error status ends the rows with an error.
+
+        let a = fstream::iter(vec![reply(vec![scalar_info(1.0, 10.0)])]);
+        let b = fstream::iter(vec![reply(vec![global::DataInfo {
+            timestamp: 100.0,
+            result: global::DataType::StatusReply(global::StatusReply {
+                status: -1,
+                message: Some("no data".into()),
+            }),
+        }])]);
+        let result: Vec<_> = rows(vec![a, b]).collect().await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0].as_ref().is_err_and(|e| e.contains("no data")));
+    }
+
+    async fn encode(format: Format, time: TimeFormat) -> String {
+        let drfs = vec!["M:OUTTMP".to_string(), "G:NAME,X".to_string()];
+        let body: Vec<_> =
+            text_body(drfs, format, time, fstream::iter(sample_rows()))
+                .collect()
+                .await;
+
+        body.into_iter()
+            .map(|v| String::from_utf8(v.unwrap().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_csv() {
+        assert_eq!(
+            encode(Format::Csv, TimeFormat::Seconds).await,
+            "timestamp,M:OUTTMP,\"G:NAME,X\"\n\
+             1.5,1,\n\
+             2,2,\"a,\"\"b\"\"\"\n"
+        );
+        assert_eq!(
+            encode(Format::Csv, TimeFormat::Iso).await,
+            "timestamp,M:OUTTMP,\"G:NAME,X\"\n\
+             1970-01-01T00:00:01.500000Z,1,\n\
+             1970-01-01T00:00:02.000000Z,2,\"a,\"\"b\"\"\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_export() {
+        let rows = fstream::iter(vec![
+            sample_rows().remove(0),
+            Err(String::from("no data")),
+        ]);
+        let body: Vec<_> = text_body(
+            vec!["M:OUTTMP".into(), "G:NAME,X".into()],
+            Format::Csv,
+            TimeFormat::Seconds,
+            rows,
+        )
+        .collect()
+        .await;
+
+        assert!(body.last().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_lines() {
+        assert_eq!(
+            encode(Format::JsonLines, TimeFormat::Millis).await,
+            "{\"timestamp\":1500,\"M:OUTTMP\":1.0,\"G:NAME,X\":null}\n\
+             {\"timestamp\":2000,\"M:OUTTMP\":2.0,\"G:NAME,X\":\"a,\\\"b\\\"\"}\n"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn test_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let drfs = vec!["M:OUTTMP".to_string(), "G:NAME,X".to_string()];
+        let body: Vec<_> = parquet_body::parquet_body(
+            drfs,
+            TimeFormat::Millis,
+            fstream::iter(sample_rows()),
+        )
+        .collect()
+        .await;
+        let file: Vec<u8> =
+            body.into_iter().flat_map(|v| v.unwrap().to_vec()).collect();
+        let reader = SerializedFileReader::new(Bytes::from(file)).unwrap();
+        let meta = reader.metadata().file_metadata();
+
+        assert_eq!(meta.num_rows(), 2);
+        assert_eq!(meta.schema_descr().num_columns(), 3);
+        assert_eq!(meta.schema_descr().column(1).name(), "M:OUTTMP");
+    }
+}
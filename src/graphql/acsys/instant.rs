@@ -0,0 +1,365 @@
+// Finds the archived values of devices at an instant. Rather than
+// retrieving the whole search window, each device's archive is
+// searched in widening windows, starting with the minute before (or
+// after) the instant, so the usual case -- a device logged every few
+// seconds -- only reads a small amount of data. Devices are searched
+// several at a time.
+
+use super::{archive::ArchiveSources, global, status, types};
+use futures::{Future, Stream, future};
+use futures_util::StreamExt;
+
+// The width, in seconds, of the first window searched. Each following
+// window is ten times wider.
+
+const FIRST_WINDOW: f64 = 60.0;
+
+// The search window used when a request doesn't specify one, and the
+// largest allowed.
+
+const DEFAULT_WINDOW: f64 = 3_600.0;
+const MAX_WINDOW: f64 = 7.0 * 86_400.0;
+
+// How many devices are searched at once.
+
+pub const PARALLEL: usize = 16;
+
+// Returns the search window of a request, in seconds.
+
+pub fn search_window(window: Option<f64>) -> Result<f64, String> {
+    match window {
+        None => Ok(DEFAULT_WINDOW),
+        Some(v) if v.is_finite() && v > 0.0 && v <= MAX_WINDOW => Ok(v),
+        Some(v) => Err(format!(
+            "window must be a positive number of seconds, no more than {} \
+             -- got {}",
+            MAX_WINDOW, v
+        )),
+    }
+}
+
+// Splits the `window` seconds before `t` into widening ranges, nearest
+// first.
+
+fn windows_before(t: f64, window: f64) -> Vec<(f64, f64)> {
+    let limit = t - window;
+    let mut result = vec![];
+    let mut end = t;
+    let mut size = FIRST_WINDOW;
+
+    while end > limit {
+        let start = (t - size).max(limit);
+
+        result.push((start, end));
+        end = start;
+        size *= 10.0;
+    }
+    result
+}
+
+// Splits the time from `t` to `limit` into widening ranges, nearest
+// first.
+
+fn windows_after(t: f64, limit: f64) -> Vec<(f64, f64)> {
+    let mut result = vec![];
+    let mut start = t;
+    let mut size = FIRST_WINDOW;
+
+    while start < limit {
+        let end = (t + size).min(limit);
+
+        result.push((start, end));
+        start = end;
+        size *= 10.0;
+    }
+    result
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Before,
+    After,
+}
+
+// Searches `windows`, in order, for the reading nearest `t` in the
+// given direction. A reading at `t` counts as being before it. `fetch`
+// opens the stream of a window's archived data. Only the best candidate
+// is kept as the stream is read.
+
+async fn search<F, Fut, S>(
+    t: f64, windows: Vec<(f64, f64)>, direction: Direction, fetch: F,
+) -> Result<Option<global::DataInfo>, global::StatusReply>
+where
+    F: Fn(f64, f64) -> Fut,
+    Fut: Future<Output = Result<S, global::StatusReply>>,
+    S: Stream<Item = global::DataReply> + Unpin,
+{
+    for (start, end) in windows {
+        let mut best: Option<global::DataInfo> = None;
+        let mut replies = fetch(start, end).await?;
+
+        while let Some(reply) = replies.next().await {
+            for info in reply.data {
+                let ts = info.timestamp;
+
+                match info.result {
+                    global::DataType::StatusReply(ref status)
+                        if status.status < 0 =>
+                    {
+                        return Err(status.clone());
+                    }
+                    global::DataType::StatusReply(_) => (),
+                    _ if direction == Direction::Before
+                        && ts >= start
+                        && ts <= t
+                        && best.as_ref().is_none_or(|v| ts >= v.timestamp) =>
+                    {
+                        best = Some(info)
+                    }
+                    _ if direction == Direction::After
+                        && ts > t
+                        && ts <= end
+                        && best.as_ref().is_none_or(|v| ts < v.timestamp) =>
+                    {
+                        best = Some(info)
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if best.is_some() {
+            return Ok(best);
+        }
+    }
+    Ok(None)
+}
+
+// Builds the status reported when a reading wasn't found.
+
+fn no_data(window: f64, before: bool, after: bool) -> global::StatusReply {
+    let side = match (before, after) {
+        (true, true) => "before or after",
+        (true, false) => "before",
+        _ => "after",
+    };
+
+    global::StatusReply {
+        status: status::EXTAPI_NO_DATA,
+        message: Some(format!(
+            "no data within {} seconds {} the instant",
+            window, side
+        )),
+    }
+}
+
+// Combines the results of the searches of a device.
+
+fn instant_value(
+    ref_id: i32, before: Result<Option<global::DataInfo>, global::StatusReply>,
+    after: Option<Result<Option<global::DataInfo>, global::StatusReply>>,
+    window: f64,
+) -> types::InstantValue {
+    let mut status = None;
+    let mut found =
+        |v: Result<Option<global::DataInfo>, global::StatusReply>| {
+            v.unwrap_or_else(|e| {
+                status.get_or_insert(e);
+                None
+            })
+        };
+    let want_after = after.is_some();
+    let before = found(before);
+    let after = after.and_then(&mut found);
+
+    if status.is_none() && (before.is_none() || (want_after && after.is_none()))
+    {
+        status = Some(no_data(
+            window,
+            before.is_none(),
+            want_after && after.is_none(),
+        ));
+    }
+
+    types::InstantValue {
+        ref_id,
+        before,
+        after,
+        status,
+    }
+}
+
+// Returns the readings of a device nearest `t`. The reading after `t`
+// is only searched for if `want_after` is set; those searches end at
+// `now`.
+
+#[allow(clippy::too_many_arguments)]
+pub async fn value_at(
+    sources: &ArchiveSources, token: Option<String>, ref_id: i32, drf: &str,
+    t: f64, window: f64, want_after: bool, now: f64,
+) -> types::InstantValue {
+    let fetch = |start: f64, end: f64| {
+        let token = token.clone();
+
+        async move {
+            let s = sources.fetch(token, drf, start, end, None).await.map_err(
+                |e| global::StatusReply {
+                    status: -1,
+                    message: Some(e.message),
+                },
+            )?;
+
+            Ok(s)
+        }
+    };
+    let before =
+        search(t, windows_before(t, window), Direction::Before, &fetch);
+
+    if want_after {
+        let after = search(
+            t,
+            windows_after(t, (t + window).min(now)),
+            Direction::After,
+            &fetch,
+        );
+        let (before, after) = future::join(before, after).await;
+
+        instant_value(ref_id, before, Some(after), window)
+    } else {
+        instant_value(ref_id, before.await, None, window)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::info;
+    use futures::stream;
+
+    type Replies = stream::Iter<std::vec::IntoIter<global::DataReply>>;
+
+    // A fake archive with readings at `times`. Like the EPICS archiver,
+    // it also returns the reading preceding the range.
+
+    fn archive(
+        times: &[f64], start: f64, end: f64,
+    ) -> Result<Replies, global::StatusReply> {
+        let first = times.iter().rposition(|v| *v < start);
+        let data = times
+            .iter()
+            .enumerate()
+            .filter(|(idx, v)| {
+                Some(*idx) == first || (**v >= start && **v <= end)
+            })
+            .map(|(_, v)| info(*v))
+            .collect();
+
+        Ok(stream::iter(vec![global::DataReply { ref_id: 0, data }]))
+    }
+
+    #[test]
+    fn test_windows() {
+        assert_eq!(
+            windows_before(1000.0, 1000.0),
+            vec![(940.0, 1000.0), (400.0, 940.0), (0.0, 400.0)]
+        );
+        assert_eq!(windows_before(1000.0, 30.0), vec![(970.0, 1000.0)]);
+        assert_eq!(
+            windows_after(1000.0, 1100.0),
+            vec![(1000.0, 1060.0), (1060.0, 1100.0)]
+        );
+        assert_eq!(windows_after(1000.0, 1000.0), vec![]);
+        assert!(search_window(None).is_ok());
+        assert!(search_window(Some(0.0)).is_err());
+        assert!(search_window(Some(MAX_WINDOW * 2.0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let times = [100.0, 500.0, 990.0, 1000.5, 1500.0];
+        let fetch = |start, end| future::ready(archive(&times, start, end));
+
+        assert_eq!(
+            search(
+                1000.0,
+                windows_before(1000.0, 1000.0),
+                Direction::Before,
+                fetch
+            )
+            .await,
+            Ok(Some(info(990.0)))
+        );
+        assert_eq!(
+            search(
+                980.0,
+                windows_before(980.0, 1000.0),
+                Direction::Before,
+                fetch
+            )
+            .await,
+            Ok(Some(info(500.0)))
+        );
+        assert_eq!(
+            search(
+                980.0,
+                windows_before(980.0, 100.0),
+                Direction::Before,
+                fetch
+            )
+            .await,
+            Ok(None)
+        );
+        assert_eq!(
+            search(
+                1000.0,
+                windows_after(1000.0, 2000.0),
+                Direction::After,
+                fetch
+            )
+            .await,
+            Ok(Some(info(1000.5)))
+        );
+
+        // Archive errors are returned.
+
+        let failed = |_, _| {
+            future::ready(Err::<Replies, _>(global::StatusReply {
+                status: -1,
+                message: None,
+            }))
+        };
+
+        assert!(
+            search(
+                1000.0,
+                windows_before(1000.0, 100.0),
+                Direction::Before,
+                failed
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_instant_value() {
+        let v = instant_value(3, Ok(Some(info(1.0))), None, 60.0);
+
+        assert_eq!((v.ref_id, v.before, v.status), (3, Some(info(1.0)), None));
+
+        let v = instant_value(0, Ok(Some(info(1.0))), Some(Ok(None)), 60.0);
+
+        assert_eq!(
+            v.status.and_then(|v| v.message),
+            Some("no data within 60 seconds after the instant".into())
+        );
+
+        let error = global::StatusReply {
+            status: -1,
+            message: Some("failed".into()),
+        };
+        let v = instant_value(0, Err(error.clone()), Some(Ok(None)), 60.0);
+
+        assert_eq!(v.status, Some(error));
+    }
+}
@@ -0,0 +1,386 @@
+// Implements knobbing sessions. GraphQL subscriptions can't receive
+// data from the client once they've started, so knobbing is split
+// across several entry points: a mutation starts a session, another
+// mutation sends step increments to it and a subscription streams the
+// resulting settings and readbacks.
+//
+// Each session has a background task which applies the accumulated
+// steps to the device. While a setting is in progress, or the session
+// is waiting out its update interval, new increments are added to the
+// pending count so that a burst of knob turns becomes a single
+// setting.
+
+use super::{drf, global, reading_to_reply, types};
+use crate::{
+    g_rpc::{dpm, dpm::Connection, proto::common::device},
+    graphql::devdb::{cache, types::KnobInfo},
+};
+use futures_util::{Stream, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicI64, Ordering},
+    },
+};
+use tokio::{
+    sync::{Notify, watch},
+    time::{Duration, sleep, timeout},
+};
+use tokio_stream::wrappers::WatchStream;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// The shortest time between settings sent by a session. Increments
+// received within this window are combined.
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+// Sessions that don't receive any increments for this long are closed.
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// How long to wait for DPM to return the setting property.
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Session {
+    device: String,
+    owner: String,
+    knob: KnobInfo,
+    pending: AtomicI64,
+    closed: AtomicBool,
+    wakeup: Notify,
+    replies: watch::Sender<types::KnobReply>,
+}
+
+// Holds the active knobbing sessions. An instance of this is added to
+// the ACSys schema's data.
+
+#[derive(Default)]
+pub struct KnobSessions(Arc<Mutex<HashMap<String, Arc<Session>>>>);
+
+// Computes the next setting of a knobbed device. The setting moves by
+// `steps` increments of the knob's step size and is kept within the
+// knob's limits. Like settings, a knob whose limits are the same is
+// considered to not have any limits.
+
+pub fn next_value(current: f64, steps: i64, knob: &KnobInfo) -> f64 {
+    let value = current + steps as f64 * knob.step;
+
+    if knob.min_val == knob.max_val {
+        value
+    } else {
+        value.clamp(knob.min_val, knob.max_val)
+    }
+}
+
+// Reads the current value of a device's setting property. If DPM
+// returns a status instead of a value, the status is described in the
+// error.
+
+async fn read_setting(
+    conn: &Connection, token: Option<&String>, drf: &str,
+) -> Result<f64, String> {
+    let mut s = dpm::acquire_devices(conn, token, vec![format!("{}@i", drf)])
+        .await
+        .map_err(|e| e.message().to_owned())?
+        .into_inner();
+
+    match timeout(READ_TIMEOUT, s.next()).await {
+        Ok(Some(Ok(reply))) => {
+            match reading_to_reply(reply).data.first().map(|v| &v.result) {
+                Some(global::DataType::Scalar(global::Scalar {
+                    scalar_value,
+                })) => Ok(*scalar_value),
+                Some(global::DataType::StatusReply(status)) => {
+                    Err(status.to_string())
+                }
+                _ => Err("setting isn't a scalar value".into()),
+            }
+        }
+        Ok(Some(Err(e))) => Err(e.message().to_owned()),
+        Ok(None) => Err("DPM closed the stream".into()),
+        Err(_) => Err("timed-out waiting for DPM".into()),
+    }
+}
+
+// The body of the task that applies a session's increments.
+
+async fn run_session(
+    sessions: Arc<Mutex<HashMap<String, Arc<Session>>>>, id: String,
+    session: Arc<Session>, conn: Connection, token: Option<String>,
+    mut value: f64,
+) {
+    let drf = format!("{}.SETTING", session.device);
+
+    loop {
+        if timeout(IDLE_TIMEOUT, session.wakeup.notified())
+            .await
+            .is_err()
+        {
+            info!("knob session {} idle -- closing", &id);
+            break;
+        }
+
+        if session.closed.load(Ordering::Acquire) {
+            break;
+        }
+
+        let steps = session.pending.swap(0, Ordering::AcqRel);
+
+        if steps == 0 {
+            continue;
+        }
+
+        let target = next_value(value, steps, &session.knob);
+        let status = match dpm::set_device(
+            &conn,
+            token.clone(),
+            drf.clone(),
+            device::Value {
+                value: Some(device::value::Value::Scalar(target)),
+            },
+        )
+        .await
+        {
+            Ok(status) => status.first().copied().unwrap_or(0) as i16,
+            Err(e) => {
+                error!("knob session {} : setting failed -- {}", &id, &e);
+                -1
+            }
+        };
+
+        if status != 0 {
+            warn!(
+                "knob session {} : setting returned {}",
+                &id,
+                global::StatusReply {
+                    status,
+                    message: None,
+                }
+            );
+        }
+
+        let readback = read_setting(&conn, token.as_ref(), &drf)
+            .await
+            .inspect_err(|e| {
+                warn!("knob session {} : readback failed -- {}", &id, e)
+            })
+            .ok();
+
+        // Further steps are relative to what the device reports. If it
+        // can't be read, assume the setting succeeded.
+
+        value = readback.unwrap_or(target);
+
+        let _ = session.replies.send(types::KnobReply {
+            timestamp: super::now(),
+            setting: target,
+            readback,
+            steps: steps.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            status,
+        });
+
+        sleep(UPDATE_INTERVAL).await;
+    }
+
+    // Remove the session. Once the last reference to the session is
+    // dropped, the watch channel closes and any subscriptions end.
+
+    sessions.lock().unwrap().remove(&id);
+}
+
+impl KnobSessions {
+    fn find(&self, id: &str) -> Result<Arc<Session>, String> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("no knob session with ID {}", id))
+    }
+
+    // Finds a session that belongs to `account`. Every operation on a
+    // session is authorized this way; `account` must be one that was
+    // verified by the authentication service.
+
+    fn find_owned(
+        &self, id: &str, account: &str,
+    ) -> Result<Arc<Session>, String> {
+        let session = self.find(id)?;
+
+        if session.owner == account {
+            Ok(session)
+        } else {
+            Err("knob session belongs to another account".into())
+        }
+    }
+
+    // Starts a new knobbing session for a device. The device must be
+    // an ACNET device whose setting property is knobbable. The session
+    // belongs to `account`, which must have been verified. Returns the
+    // ID of the session.
+
+    pub async fn start(
+        &self, conn: &Connection, token: Option<String>, account: String,
+        device: &str,
+    ) -> Result<String, String> {
+        let target = drf::parse_target(device)
+            .ok_or_else(|| format!("{} isn't an ACNET device", device))?;
+        let info = cache::device_info(&target.device).await?;
+        let knob = info
+            .setting
+            .as_ref()
+            .and_then(|prop| prop.knob_config())
+            .ok_or_else(|| format!("{} can't be knobbed", target.device))?;
+        let value = read_setting(
+            conn,
+            token.as_ref(),
+            &target.drf(drf::Property::Setting),
+        )
+        .await
+        .map_err(|e| {
+            format!("couldn't read the setting of {}: {}", target.device, e)
+        })?;
+        let id = Uuid::new_v4().as_hyphenated().to_string();
+        let (tx, _) = watch::channel(types::KnobReply {
+            timestamp: super::now(),
+            setting: value,
+            readback: Some(value),
+            steps: 0,
+            status: 0,
+        });
+        let session = Arc::new(Session {
+            device: target.device,
+            owner: account,
+            knob,
+            pending: AtomicI64::new(0),
+            closed: AtomicBool::new(false),
+            wakeup: Notify::new(),
+            replies: tx,
+        });
+
+        info!("starting knob session {} for {}", &id, &session.device);
+        self.0.lock().unwrap().insert(id.clone(), session.clone());
+        tokio::spawn(run_session(
+            self.0.clone(),
+            id.clone(),
+            session,
+            conn.clone(),
+            token,
+            value,
+        ));
+        Ok(id)
+    }
+
+    // Adds increments to a session. The steps are applied by the
+    // session's task.
+
+    pub fn step(
+        &self, id: &str, account: &str, steps: i32,
+    ) -> Result<(), String> {
+        let session = self.find_owned(id, account)?;
+
+        session.pending.fetch_add(steps as i64, Ordering::AcqRel);
+        session.wakeup.notify_one();
+        Ok(())
+    }
+
+    // Closes a session. Any pending increments are discarded.
+
+    pub fn stop(&self, id: &str, account: &str) -> Result<(), String> {
+        let session = self.find_owned(id, account)?;
+
+        session.closed.store(true, Ordering::Release);
+        session.wakeup.notify_one();
+        Ok(())
+    }
+
+    // Returns a stream of the session's updates. The stream starts with
+    // the most recent update and closes when the session ends.
+
+    pub fn watch(
+        &self, id: &str, account: &str,
+    ) -> Result<impl Stream<Item = types::KnobReply> + Send + 'static, String>
+    {
+        let session = self.find_owned(id, account)?;
+
+        if session.closed.load(Ordering::Acquire) {
+            warn!("subscribing to closed knob session {}", id);
+        }
+        Ok(WatchStream::new(session.replies.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_steps_are_scaled() {
+        let knob = KnobInfo::new(0.0, 10.0, 0.5);
+
+        assert_eq!(next_value(5.0, 1, &knob), 5.5);
+        assert_eq!(next_value(5.0, -3, &knob), 3.5);
+        assert_eq!(next_value(5.0, 0, &knob), 5.0);
+    }
+
+    #[test]
+    fn test_steps_are_bounded() {
+        let knob = KnobInfo::new(10.0, 0.0, 0.5);
+
+        assert_eq!(next_value(9.75, 1, &knob), 10.0);
+        assert_eq!(next_value(1.0, -100, &knob), 0.0);
+        assert_eq!(next_value(12.0, 0, &knob), 10.0);
+    }
+
+    #[test]
+    fn test_unbounded_knob() {
+        let knob = KnobInfo::new(0.0, 0.0, 16.0);
+
+        assert_eq!(next_value(100.0, 2, &knob), 132.0);
+        assert_eq!(next_value(0.0, -1, &knob), -16.0);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_session() {
+        let sessions = KnobSessions::default();
+
+        assert!(sessions.step("bogus", "alice", 1).is_err());
+        assert!(sessions.stop("bogus", "alice").is_err());
+        assert!(sessions.watch("bogus", "alice").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_other_account() {
+        let sessions = KnobSessions::default();
+        let (tx, _) = watch::channel(types::KnobReply {
+            timestamp: crate::graphql::acsys::now(),
+            setting: 0.0,
+            readback: None,
+            steps: 0,
+            status: 0,
+        });
+
+        sessions.0.lock().unwrap().insert(
+            "id".into(),
+            Arc::new(Session {
+                device: "M:OUTTMP".into(),
+                owner: "alice".into(),
+                knob: KnobInfo::new(0.0, 10.0, 0.5),
+                pending: AtomicI64::new(0),
+                closed: AtomicBool::new(false),
+                wakeup: Notify::new(),
+                replies: tx,
+            }),
+        );
+
+        assert!(sessions.step("id", "mallory", 1).is_err());
+        assert!(sessions.watch("id", "mallory").is_err());
+        assert!(sessions.stop("id", "mallory").is_err());
+        assert!(sessions.step("id", "alice", 1).is_ok());
+        assert!(sessions.watch("id", "alice").is_ok());
+        assert!(sessions.stop("id", "alice").is_ok());
+    }
+}
@@ -0,0 +1,599 @@
+// Shares live data streams between subscribers. Many clients (e.g.
+// control room displays) make the same request for the same devices at
+// the same rate. Rather than open a DPM request for each of them, the
+// multiplexer opens one upstream request per distinct list of DRF
+// strings and forwards its replies, through a broadcast channel, to
+// every subscriber of that list.
+//
+// Streams are only shared between requests made by the same account.
+// When the last subscriber of a stream goes away, the upstream request
+// is cancelled.
+
+use super::{drf, global, xlat_reply};
+use crate::{
+    g_rpc::dpm::{self, Connection},
+    graphql::status,
+};
+use futures_util::{Stream, StreamExt, future, stream};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::{sync::broadcast, task::AbortHandle};
+use tokio_stream::wrappers::{
+    BroadcastStream, errors::BroadcastStreamRecvError,
+};
+use tracing::{info, warn};
+
+// The number of replies that can be queued for a subscriber. A
+// subscriber that falls further behind than this is sent a status for
+// each of its devices and its stream is ended.
+
+const CHANNEL_SIZE: usize = 256;
+
+// Determines who may share an upstream request. Requests without
+// credentials are shared with each other. Requests with credentials
+// are shared with requests made by the same account, as verified by
+// the authentication service. If the credentials can't be verified,
+// the request is only shared with requests using the same token.
+//
+// The upstream request is made with the first subscriber's token.
+// Since every subscriber has the same identity, DPM grants them all
+// the same access.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Anonymous,
+    Account(String),
+    Token(String),
+}
+
+impl Scope {
+    pub async fn of(auth: Option<&global::AuthInfo>) -> Scope {
+        let Some((auth, token)) =
+            auth.and_then(|auth| auth.token().map(|token| (auth, token)))
+        else {
+            return Scope::Anonymous;
+        };
+
+        match auth.account().await {
+            Ok(account) => Scope::Account(account),
+            Err(_) => Scope::Token(token),
+        }
+    }
+}
+
+// Identifies an upstream stream.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    drfs: Vec<String>,
+    scope: Scope,
+}
+
+// The most recent reply of each device of an upstream request.
+
+type Latest = Arc<Mutex<Vec<Option<global::DataReply>>>>;
+
+// The replies of an upstream request.
+
+type Replies = Pin<Box<dyn Stream<Item = global::DataReply> + Send>>;
+
+struct Upstream {
+    id: u64,
+    tx: broadcast::Sender<global::DataReply>,
+    latest: Latest,
+    replies: Arc<AtomicU64>,
+    subscribers: usize,
+    started: f64,
+    task: Option<AbortHandle>,
+}
+
+#[derive(Default)]
+struct Inner {
+    streams: Mutex<HashMap<Key, Upstream>>,
+    next_id: AtomicU64,
+    subscriptions: AtomicU64,
+}
+
+// The live data multiplexer. An instance of this is added to the ACSys
+// schema's data.
+
+#[derive(Default, Clone)]
+pub struct LiveMux(Arc<Inner>);
+
+// Returns the form of a DRF string used to determine whether two
+// requests are the same. Whitespace is removed and, since ACNET names,
+// properties and events are case-insensitive, ACNET requests are
+// converted to upper case. Other names (e.g. EPICS PVs) are
+// case-sensitive so they're left alone.
+
+pub fn normalize(drf: &str) -> String {
+    let drf: String = drf.split_whitespace().collect();
+
+    if drf::is_acnet_name(&drf) {
+        drf.to_ascii_uppercase()
+    } else {
+        drf
+    }
+}
+
+// Removes a subscriber when it's dropped. If it was the last
+// subscriber, the upstream request is cancelled.
+
+struct Guard {
+    mux: Arc<Inner>,
+    key: Key,
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut streams = self.mux.streams.lock().unwrap();
+
+        if let Some(entry) = streams.get_mut(&self.key)
+            && entry.id == self.id
+        {
+            entry.subscribers -= 1;
+            if entry.subscribers == 0 {
+                info!("no subscribers left for {:?}", &self.key.drfs);
+                if let Some(task) = entry.task.take() {
+                    task.abort();
+                }
+                streams.remove(&self.key);
+            }
+        }
+    }
+}
+
+// The stream returned to a subscriber. It starts with the most recent
+// reply of each device of the upstream request, followed by the
+// replies that arrive after subscribing.
+
+pub struct Subscription {
+    inner: Pin<Box<dyn Stream<Item = global::DataReply> + Send>>,
+    _guard: Guard,
+}
+
+impl Stream for Subscription {
+    type Item = global::DataReply;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, ctxt: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(ctxt)
+    }
+}
+
+// Statistics of one upstream request.
+
+pub struct StreamStats {
+    pub drfs: Vec<String>,
+    pub scoped: bool,
+    pub subscribers: usize,
+    pub started: f64,
+    pub replies: u64,
+}
+
+// Statistics of the multiplexer.
+
+pub struct Stats {
+    pub upstreams: u64,
+    pub subscriptions: u64,
+    pub streams: Vec<StreamStats>,
+}
+
+// DPM reports errors that affect the whole request with a `ref_id` of
+// -1. Subscribers expect every reply to refer to one of their DRF
+// strings, so these are sent as a reply for each of them.
+
+fn per_device(
+    reply: global::DataReply, count: usize,
+) -> Vec<global::DataReply> {
+    if reply.ref_id < 0 {
+        (0..count)
+            .map(|idx| global::DataReply {
+                ref_id: idx as i32,
+                data: reply.data.clone(),
+            })
+            .collect()
+    } else {
+        vec![reply]
+    }
+}
+
+// Builds the replies sent to a subscriber that fell too far behind: a
+// status for each of its devices.
+
+fn overrun(count: usize, lost: u64) -> Vec<global::DataReply> {
+    let timestamp = super::now();
+
+    (0..count)
+        .map(|idx| global::DataReply {
+            ref_id: idx as i32,
+            data: vec![global::DataInfo {
+                timestamp,
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status: status::ACNET_QUEFULL,
+                    message: Some(format!(
+                        "the client fell behind and lost {} replies",
+                        lost
+                    )),
+                }),
+            }],
+        })
+        .collect()
+}
+
+// Makes the upstream request to DPM.
+
+async fn open(
+    conn: Connection, token: Option<String>, drfs: Vec<String>,
+) -> Replies {
+    match dpm::acquire_devices(&conn, token.as_ref(), drfs).await {
+        Ok(s) => Box::pin(s.into_inner().map(xlat_reply)),
+        Err(e) => {
+            warn!("couldn't start upstream request -- {}", &e);
+            Box::pin(stream::iter([xlat_reply(Err(e))]))
+        }
+    }
+}
+
+// Forwards the replies of an upstream request to the broadcast
+// channel. When the upstream request ends, the stream is removed from
+// the multiplexer so that its subscribers' streams end and new
+// subscribers start a new request.
+
+async fn forward(
+    mux: Arc<Inner>, key: Key, id: u64,
+    upstream: impl Future<Output = Replies>,
+    tx: broadcast::Sender<global::DataReply>, latest: Latest,
+    replies: Arc<AtomicU64>,
+) {
+    let mut s = upstream.await;
+
+    while let Some(reply) = s.next().await {
+        for reply in per_device(reply, key.drfs.len()) {
+            let mut last = latest.lock().unwrap();
+
+            replies.fetch_add(1, Ordering::Relaxed);
+            if let Some(slot) = last.get_mut(reply.ref_id as usize) {
+                *slot = Some(reply.clone());
+            }
+
+            // An error means there are no receivers. The last
+            // subscriber's guard will cancel this task.
+
+            let _ = tx.send(reply);
+        }
+    }
+    info!("upstream request for {:?} ended", &key.drfs);
+
+    let mut streams = mux.streams.lock().unwrap();
+
+    if streams.get(&key).is_some_and(|entry| entry.id == id) {
+        streams.remove(&key);
+    }
+}
+
+impl LiveMux {
+    // Subscribes to the live data of a list of DRF strings. If another
+    // client, in the same scope, is already receiving the same list,
+    // the upstream request is shared. Otherwise a new request is made
+    // to DPM. The `ref_id` of the returned replies is the index of the
+    // DRF string in the list.
+
+    pub fn subscribe(
+        &self, conn: &Connection, token: Option<String>, scope: Scope,
+        drfs: &[String],
+    ) -> Subscription {
+        let drfs: Vec<_> = drfs.iter().map(|v| normalize(v)).collect();
+        let conn = conn.clone();
+        let request = drfs.clone();
+
+        self.start(Key { drfs, scope }, move || open(conn, token, request))
+    }
+
+    // Adds a subscriber to the stream identified by `key`. If the
+    // stream isn't running, `upstream` is called to start it.
+
+    fn start<F, U>(&self, key: Key, upstream: F) -> Subscription
+    where
+        F: FnOnce() -> U,
+        U: Future<Output = Replies> + Send + 'static,
+    {
+        let count = key.drfs.len();
+        let mut streams = self.0.streams.lock().unwrap();
+
+        self.0.subscriptions.fetch_add(1, Ordering::Relaxed);
+
+        let entry = streams.entry(key.clone()).or_insert_with(|| {
+            let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+            let (tx, _) = broadcast::channel(CHANNEL_SIZE);
+
+            info!("starting upstream request for {:?}", &key.drfs);
+            Upstream {
+                id,
+                tx,
+                latest: Arc::new(Mutex::new(vec![None; count])),
+                replies: Arc::new(AtomicU64::new(0)),
+                subscribers: 0,
+                started: super::now(),
+                task: None,
+            }
+        });
+
+        // The upstream task holds the lock of the latest replies while
+        // it sends to the channel so, by holding it while subscribing,
+        // no replies are missed or duplicated.
+
+        let (rx, latest) = {
+            let latest = entry.latest.lock().unwrap();
+
+            (entry.tx.subscribe(), latest.clone())
+        };
+
+        entry.subscribers += 1;
+        if entry.task.is_none() {
+            entry.task = Some(
+                tokio::spawn(forward(
+                    self.0.clone(),
+                    key.clone(),
+                    entry.id,
+                    upstream(),
+                    entry.tx.clone(),
+                    entry.latest.clone(),
+                    entry.replies.clone(),
+                ))
+                .abort_handle(),
+            );
+        }
+
+        let guard = Guard {
+            mux: self.0.clone(),
+            key,
+            id: entry.id,
+        };
+        let live = BroadcastStream::new(rx)
+            .scan(false, move |lagged, v| {
+                future::ready(if *lagged {
+                    None
+                } else {
+                    Some(match v {
+                        Ok(reply) => vec![reply],
+                        Err(BroadcastStreamRecvError::Lagged(n)) => {
+                            warn!(
+                                "subscriber fell behind -- lost {} replies",
+                                n
+                            );
+                            *lagged = true;
+                            overrun(count, n)
+                        }
+                    })
+                })
+            })
+            .flat_map(stream::iter);
+
+        Subscription {
+            inner: Box::pin(
+                stream::iter(latest.into_iter().flatten()).chain(live),
+            ),
+            _guard: guard,
+        }
+    }
+
+    // Returns the current statistics of the multiplexer.
+
+    pub fn stats(&self) -> Stats {
+        let streams = self.0.streams.lock().unwrap();
+
+        Stats {
+            upstreams: self.0.next_id.load(Ordering::Relaxed),
+            subscriptions: self.0.subscriptions.load(Ordering::Relaxed),
+            streams: streams
+                .iter()
+                .map(|(key, entry)| StreamStats {
+                    drfs: key.drfs.clone(),
+                    scoped: key.scope != Scope::Anonymous,
+                    subscribers: entry.subscribers,
+                    started: entry.started,
+                    replies: entry.replies.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc;
+
+    fn key(drfs: &[&str]) -> Key {
+        Key {
+            drfs: drfs.iter().map(|v| normalize(v)).collect(),
+            scope: Scope::Anonymous,
+        }
+    }
+
+    fn reply(ref_id: i32, timestamp: f64) -> global::DataReply {
+        global::DataReply {
+            ref_id,
+            data: vec![global::DataInfo {
+                timestamp,
+                result: global::DataType::Scalar(global::Scalar {
+                    scalar_value: timestamp,
+                }),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("M:OUTTMP@p,1000"), "M:OUTTMP@P,1000");
+        assert_eq!(normalize(" m:outtmp @ p,1000 "), "M:OUTTMP@P,1000");
+        assert_eq!(normalize("m_outtmp.SETTING@e,2"), "M_OUTTMP.SETTING@E,2");
+        assert_eq!(normalize("Pip2:Bpm:X@p,1000"), "Pip2:Bpm:X@p,1000");
+    }
+
+    #[test]
+    fn test_key_includes_scope() {
+        let a = key(&["M:OUTTMP@p,1000"]);
+        let b = key(&["m:outtmp@P,1000"]);
+        let c = Key {
+            scope: Scope::Account("user".into()),
+            ..key(&["M:OUTTMP@p,1000"])
+        };
+        let d = key(&["M:OUTTMP@p,1000", "G:AMANDA@p,1000"]);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, d);
+    }
+
+    #[tokio::test]
+    async fn test_unverified_scope() {
+        assert_eq!(Scope::of(None).await, Scope::Anonymous);
+        assert_eq!(
+            Scope::of(Some(&global::AuthInfo::new(None))).await,
+            Scope::Anonymous
+        );
+        assert_eq!(
+            Scope::of(Some(&global::AuthInfo::new(Some(
+                "Bearer forged".into()
+            ))))
+            .await,
+            Scope::Token("forged".into())
+        );
+    }
+
+    #[test]
+    fn test_per_device() {
+        assert_eq!(per_device(reply(1, 1.0), 3), vec![reply(1, 1.0)]);
+        assert_eq!(
+            per_device(reply(-1, 1.0), 2),
+            vec![reply(0, 1.0), reply(1, 1.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_share_upstream() {
+        let mux = LiveMux::default();
+        let key = key(&["M:OUTTMP@p,1000", "G:AMANDA@p,1000"]);
+        let (tx, rx) = mpsc::unbounded();
+        let mut s1 = mux
+            .start(key.clone(), move || async move { Box::pin(rx) as Replies });
+
+        tx.unbounded_send(reply(0, 1.0)).unwrap();
+        tx.unbounded_send(reply(1, 2.0)).unwrap();
+        tx.unbounded_send(reply(0, 3.0)).unwrap();
+        assert_eq!(s1.next().await, Some(reply(0, 1.0)));
+        assert_eq!(s1.next().await, Some(reply(1, 2.0)));
+        assert_eq!(s1.next().await, Some(reply(0, 3.0)));
+
+        // The second subscriber shares the upstream request and starts
+        // with the latest reply of each device.
+
+        let mut s2 = mux.start(key.clone(), || async {
+            Box::pin(stream::empty()) as Replies
+        });
+
+        assert_eq!(s2.next().await, Some(reply(0, 3.0)));
+        assert_eq!(s2.next().await, Some(reply(1, 2.0)));
+
+        tx.unbounded_send(reply(-1, 4.0)).unwrap();
+        for s in [&mut s1, &mut s2] {
+            assert_eq!(s.next().await, Some(reply(0, 4.0)));
+            assert_eq!(s.next().await, Some(reply(1, 4.0)));
+        }
+
+        let stats = mux.stats();
+
+        assert_eq!((stats.upstreams, stats.subscriptions), (1, 2));
+        assert_eq!(stats.streams[0].subscribers, 2);
+        assert_eq!(stats.streams[0].replies, 5);
+
+        // When the upstream request ends, so do the subscriptions.
+
+        drop(tx);
+        assert_eq!(s1.next().await, None);
+        assert_eq!(s2.next().await, None);
+        assert!(mux.stats().streams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_is_ended() {
+        let mux = LiveMux::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut s = mux.start(key(&["M:OUTTMP@p,1000"]), move || async move {
+            Box::pin(rx) as Replies
+        });
+        let total = CHANNEL_SIZE as u64 + 10;
+
+        for ii in 0..total {
+            tx.unbounded_send(reply(0, ii as f64)).unwrap();
+        }
+        while mux.stats().streams[0].replies < total {
+            tokio::task::yield_now().await;
+        }
+
+        let Some(global::DataReply { ref_id: 0, data }) = s.next().await else {
+            panic!("expected a reply for device 0");
+        };
+
+        assert!(matches!(
+            data[..],
+            [global::DataInfo {
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status: status::ACNET_QUEFULL,
+                    ..
+                }),
+                ..
+            }]
+        ));
+        assert_eq!(s.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_guard_removes_last_subscriber() {
+        let mux = LiveMux::default();
+        let key = key(&["M:OUTTMP@P,1000"]);
+        let (tx, _) = broadcast::channel(CHANNEL_SIZE);
+
+        mux.0.streams.lock().unwrap().insert(
+            key.clone(),
+            Upstream {
+                id: 7,
+                tx,
+                latest: Arc::new(Mutex::new(vec![None])),
+                replies: Arc::new(AtomicU64::new(0)),
+                subscribers: 2,
+                started: 0.0,
+                task: None,
+            },
+        );
+
+        let guard = |id| Guard {
+            mux: mux.0.clone(),
+            key: key.clone(),
+            id,
+        };
+
+        // A guard from an older stream, with the same key, doesn't
+        // affect the current one.
+
+        drop(guard(3));
+        assert_eq!(mux.stats().streams[0].subscribers, 2);
+
+        drop(guard(7));
+        assert_eq!(mux.stats().streams[0].subscribers, 1);
+
+        drop(guard(7));
+        assert!(mux.stats().streams.is_empty());
+    }
+}
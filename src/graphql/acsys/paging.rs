@@ -0,0 +1,469 @@
+// Retrieves a device's archived data one page at a time. Each page
+// ends with a cursor which holds the device, the archive the data came
+// from and the timestamp of the page's last reading. Passing it back
+// resumes the retrieval after that reading, from the same archive, so a
+// long range can be pulled with plain queries and a failed page can be
+// retried without starting over.
+//
+// Several readings can share a timestamp so the cursor also counts the
+// readings with the last timestamp that were already returned.
+//
+// A page is read from widening windows, starting with the minute after
+// the cursor, so each page only retrieves about as much data as it
+// returns rather than the rest of the range.
+
+use super::{archive::ArchiveSources, device_name, drf, global, types};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use futures::{Stream, future::Either};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+// The number of readings in a page when a request doesn't say, and the
+// most a page may hold.
+
+const DEFAULT_LIMIT: usize = 1_000;
+const MAX_LIMIT: usize = 10_000;
+
+// The width, in seconds, of the first window read for a page. Each
+// following window is ten times wider.
+
+const FIRST_WINDOW: f64 = 60.0;
+
+// Returns the number of readings in a page.
+
+pub fn page_limit(limit: Option<i32>) -> Result<usize, String> {
+    match limit {
+        None => Ok(DEFAULT_LIMIT),
+        Some(v) if v > 0 && v as usize <= MAX_LIMIT => Ok(v as usize),
+        Some(v) => Err(format!(
+            "limit must be between 1 and {} -- got {}",
+            MAX_LIMIT, v
+        )),
+    }
+}
+
+// Returns the name of an archive, as reported to clients and saved in
+// cursors.
+
+fn source_name(source: &drf::ArchiveSource) -> String {
+    match source {
+        drf::ArchiveSource::Epics => "EPICS".into(),
+        drf::ArchiveSource::Logger(None) => "LOGGER".into(),
+        drf::ArchiveSource::Logger(Some(node)) => format!("LOGGER:{}", node),
+    }
+}
+
+fn parse_source(name: &str) -> Option<drf::ArchiveSource> {
+    match name.split_once(':') {
+        None if name == "EPICS" => Some(drf::ArchiveSource::Epics),
+        None if name == "LOGGER" => Some(drf::ArchiveSource::Logger(None)),
+        Some(("LOGGER", node)) if !node.is_empty() => {
+            Some(drf::ArchiveSource::Logger(Some(node.into())))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Cursor {
+    device: String,
+    source: String,
+    timestamp: f64,
+    skip: usize,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(v: &str) -> Result<Self, String> {
+        URL_SAFE_NO_PAD
+            .decode(v)
+            .ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .ok_or_else(|| "bad cursor".into())
+    }
+}
+
+// Splits `from..end` into widening windows, nearest first. There's
+// always at least one window.
+
+fn windows(from: f64, end: f64) -> Vec<(f64, f64)> {
+    let mut result = vec![];
+    let mut start = from;
+    let mut size = FIRST_WINDOW;
+
+    while start < end {
+        let next = (from + size).min(end);
+
+        result.push((start, next));
+        start = next;
+        size *= 10.0;
+    }
+    if result.is_empty() {
+        result.push((from, end));
+    }
+    result
+}
+
+// Collects a page of readings from the archive streams of successive
+// windows.
+
+struct PageReader<'a> {
+    resume: Option<&'a Cursor>,
+    skip: usize,
+    start: f64,
+    end: f64,
+    limit: usize,
+    data: Vec<global::DataInfo>,
+    warning: Option<global::StatusReply>,
+}
+
+impl<'a> PageReader<'a> {
+    fn new(
+        resume: Option<&'a Cursor>, start: f64, end: f64, limit: usize,
+    ) -> Self {
+        PageReader {
+            resume,
+            skip: resume.map(|v| v.skip).unwrap_or(0),
+            start,
+            end,
+            limit,
+            data: Vec::with_capacity(limit),
+            warning: None,
+        }
+    }
+
+    // Adds the readings of a window's archive stream to the page.
+    // Readings outside the range, outside the window (archives return
+    // the samples bordering a window, which belong to its neighbors) or
+    // before the cursor's position are dropped. Returns `true` once the
+    // page is full and another reading follows. A negative status is
+    // returned as an error.
+
+    async fn read<S>(
+        &mut self, mut s: S, (from, to): (f64, f64),
+    ) -> Result<bool, String>
+    where
+        S: Stream<Item = global::DataReply> + Unpin,
+    {
+        let start = self.start.max(from);
+        let last = to >= self.end;
+
+        while let Some(reply) = s.next().await {
+            for info in reply.data {
+                let ts = info.timestamp;
+
+                match info.result {
+                    global::DataType::StatusReply(ref status)
+                        if status.status < 0 =>
+                    {
+                        return Err(status.to_string());
+                    }
+                    global::DataType::StatusReply(status) => {
+                        self.warning = Some(status)
+                    }
+                    _ if ts < start || ts > self.end || (!last && ts >= to) => {
+                    }
+                    _ => match self.resume {
+                        Some(r) if ts < r.timestamp => (),
+                        Some(r) if ts == r.timestamp && self.skip > 0 => {
+                            self.skip -= 1
+                        }
+                        _ if self.data.len() == self.limit => return Ok(true),
+                        _ => self.data.push(info),
+                    },
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Builds the cursor that resumes after a page.
+
+fn next_cursor(
+    device: &str, source: &str, data: &[global::DataInfo],
+    resume: Option<&Cursor>,
+) -> Option<Cursor> {
+    let last = data.last()?.timestamp;
+    let same = data
+        .iter()
+        .rev()
+        .take_while(|v| v.timestamp == last)
+        .count();
+    let earlier = resume
+        .filter(|v| v.timestamp == last && same == data.len())
+        .map(|v| v.skip)
+        .unwrap_or(0);
+
+    Some(Cursor {
+        device: device.into(),
+        source: source.into(),
+        timestamp: last,
+        skip: same + earlier,
+    })
+}
+
+// Returns a page of a device's archived data between `start` and
+// `end`. If `after` is a cursor from an earlier page, the page
+// continues from it.
+
+pub async fn page(
+    sources: &ArchiveSources, token: Option<String>, drf: &str, start: f64,
+    end: f64, after: Option<&str>, limit: usize,
+) -> Result<types::ArchivedDataPage, String> {
+    let device = device_name(drf);
+    let resume = after.map(Cursor::decode).transpose()?;
+
+    if let Some(ref cursor) = resume
+        && cursor.device != device
+    {
+        return Err(format!(
+            "the cursor is for {}, not {}",
+            cursor.device, device
+        ));
+    }
+
+    let from = resume
+        .as_ref()
+        .map(|v| v.timestamp.max(start))
+        .unwrap_or(start);
+    let mut windows = windows(from, end).into_iter();
+    let first = windows.next().unwrap_or((from, end));
+
+    // The first window also decides which archive holds the data. The
+    // rest of the page comes from the same one.
+
+    let (source, s) = match resume {
+        Some(ref cursor) => {
+            let source = parse_source(&cursor.source)
+                .ok_or_else(|| "bad cursor".to_string())?;
+            let s = sources
+                .fetch_from(token.clone(), drf, &source, first.0, first.1, None)
+                .await
+                .map_err(|e| e.message)?;
+
+            (source, Either::Left(s))
+        }
+        None => {
+            let (source, s) = sources
+                .fetch_with_source(token.clone(), drf, first.0, first.1, None)
+                .await
+                .map_err(|e| e.message)?;
+
+            (source, Either::Right(s))
+        }
+    };
+    let mut reader = PageReader::new(resume.as_ref(), start, end, limit);
+    let mut more = reader.read(s, first).await?;
+
+    for window in windows {
+        if more {
+            break;
+        }
+
+        let s = sources
+            .fetch_from(token.clone(), drf, &source, window.0, window.1, None)
+            .await
+            .map_err(|e| e.message)?;
+
+        more = reader.read(s, window).await?;
+    }
+
+    let source = source_name(&source);
+    let PageReader {
+        data,
+        warning: status,
+        ..
+    } = reader;
+    let next_cursor = if more {
+        next_cursor(device, &source, &data, resume.as_ref()).map(|v| v.encode())
+    } else {
+        None
+    };
+
+    Ok(types::ArchivedDataPage {
+        data,
+        next_cursor,
+        source,
+        status,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::{info, reply};
+    use futures::stream;
+
+    fn archive(times: &[f64]) -> impl Stream<Item = global::DataReply> + Unpin {
+        stream::iter(vec![reply(times.iter().map(|v| info(*v)).collect())])
+    }
+
+    // Like the EPICS archiver, returns the readings in a window and the
+    // one preceding it.
+
+    fn window_archive(
+        times: &[f64], (from, to): (f64, f64),
+    ) -> impl Stream<Item = global::DataReply> + Unpin {
+        let first = times.iter().rposition(|v| *v < from).unwrap_or(0);
+        let data: Vec<_> = times[first..]
+            .iter()
+            .copied()
+            .filter(|v| *v <= to)
+            .collect();
+
+        archive(&data)
+    }
+
+    // Reads a page from a single window covering the range.
+
+    async fn read_page<S>(
+        s: S, resume: Option<&Cursor>, start: f64, end: f64, limit: usize,
+    ) -> Result<
+        (Vec<global::DataInfo>, bool, Option<global::StatusReply>),
+        String,
+    >
+    where
+        S: Stream<Item = global::DataReply> + Unpin,
+    {
+        let mut reader = PageReader::new(resume, start, end, limit);
+        let more = reader.read(s, (start, end)).await?;
+
+        Ok((reader.data, more, reader.warning))
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor {
+            device: "M:OUTTMP".into(),
+            source: "LOGGER:NODE".into(),
+            timestamp: 1_700_000_000.25,
+            skip: 2,
+        };
+
+        assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+        assert!(Cursor::decode("garbage").is_err());
+
+        for source in [
+            drf::ArchiveSource::Epics,
+            drf::ArchiveSource::Logger(None),
+            drf::ArchiveSource::Logger(Some("NODE".into())),
+        ] {
+            assert_eq!(parse_source(&source_name(&source)), Some(source));
+        }
+        assert_eq!(parse_source("LOGGER:"), None);
+        assert_eq!(page_limit(None), Ok(DEFAULT_LIMIT));
+        assert!(page_limit(Some(0)).is_err());
+        assert!(page_limit(Some(MAX_LIMIT as i32 + 1)).is_err());
+    }
+
+    // Paging through readings that share timestamps returns each
+    // reading once. Readings outside the range are dropped.
+
+    #[tokio::test]
+    async fn test_pages() {
+        let times = [0.5, 1.0, 2.0, 2.0, 2.0, 2.0, 3.0, 4.0, 9.0];
+        let mut cursor = None;
+        let mut result = vec![];
+
+        loop {
+            let (data, more, _) =
+                read_page(archive(&times), cursor.as_ref(), 1.0, 5.0, 2)
+                    .await
+                    .unwrap();
+
+            result.push(data.iter().map(|v| v.timestamp).collect::<Vec<_>>());
+            if !more {
+                break;
+            }
+            cursor = next_cursor("M:OUTTMP", "LOGGER", &data, cursor.as_ref());
+        }
+
+        assert_eq!(
+            result,
+            vec![vec![1.0, 2.0], vec![2.0, 2.0], vec![2.0, 3.0], vec![4.0]]
+        );
+    }
+
+    #[test]
+    fn test_windows() {
+        assert_eq!(
+            windows(0.0, 1000.0),
+            vec![(0.0, 60.0), (60.0, 600.0), (600.0, 1000.0)]
+        );
+        assert_eq!(windows(10.0, 40.0), vec![(10.0, 40.0)]);
+        assert_eq!(windows(10.0, 10.0), vec![(10.0, 10.0)]);
+    }
+
+    // Pages read from widening windows return each reading once, even
+    // though the archive returns the readings bordering each window.
+
+    #[tokio::test]
+    async fn test_windowed_pages() {
+        let times = [10.0, 50.0, 60.0, 60.0, 100.0, 700.0, 5000.0];
+        let mut cursor: Option<Cursor> = None;
+        let mut result = vec![];
+
+        loop {
+            let from = cursor.as_ref().map(|v| v.timestamp).unwrap_or(0.0);
+            let mut reader = PageReader::new(cursor.as_ref(), 0.0, 10_000.0, 2);
+            let mut more = false;
+
+            for window in windows(from, 10_000.0) {
+                more = reader
+                    .read(window_archive(&times, window), window)
+                    .await
+                    .unwrap();
+                if more {
+                    break;
+                }
+            }
+
+            result.push(
+                reader.data.iter().map(|v| v.timestamp).collect::<Vec<_>>(),
+            );
+            if !more {
+                break;
+            }
+            cursor =
+                next_cursor("M:OUTTMP", "EPICS", &reader.data, cursor.as_ref());
+        }
+
+        assert_eq!(
+            result,
+            vec![
+                vec![10.0, 50.0],
+                vec![60.0, 60.0],
+                vec![100.0, 700.0],
+                vec![5000.0]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_page_status() {
+        let status = |status| global::DataReply {
+            ref_id: 0,
+            data: vec![global::DataInfo {
+                timestamp: 0.0,
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status,
+                    message: None,
+                }),
+            }],
+        };
+        let s = stream::iter(vec![status(1), reply(vec![info(1.0)])]);
+        let (data, more, warning) =
+            read_page(s, None, 0.0, 5.0, 10).await.unwrap();
+
+        assert_eq!((data.len(), more), (1, false));
+        assert_eq!(warning.map(|v| v.status), Some(1));
+
+        let s = stream::iter(vec![status(-1)]);
+
+        assert!(read_page(s, None, 0.0, 5.0, 10).await.is_err());
+    }
+}
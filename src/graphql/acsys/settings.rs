@@ -0,0 +1,449 @@
+// Pre-flight validation of settings. Before a setting is forwarded to
+// DPM, the device's DevDB configuration is consulted to make sure the
+// targeted property can be set and that the value lies within the
+// limits recorded for the device.
+
+use super::{
+    drf::{self, Field, Property},
+    global,
+};
+use crate::graphql::devdb::{cache, types::DeviceInfo};
+use tracing::info;
+
+// Checks a scalar value against the limits. If `clamp` is true, an
+// out-of-range value is moved to the nearest limit, otherwise an error
+// is returned.
+
+fn check_scalar(
+    val: f64, min: f64, max: f64, clamp: bool,
+) -> Result<f64, String> {
+    if !val.is_finite() {
+        Err(format!("{} is not a valid setting", val))
+    } else if val < min || val > max {
+        if clamp {
+            Ok(val.clamp(min, max))
+        } else {
+            Err(format!("{} is outside the limits [{}, {}]", val, min, max))
+        }
+    } else {
+        Ok(val)
+    }
+}
+
+// Checks the numeric portion of a setting against the device's limits.
+// DevDB sometimes stores the limits in reverse order so they're sorted
+// before being used. If both limits are the same, the device doesn't
+// have limits configured and any value is accepted. Non-numeric
+// settings (raw and text values) aren't checked.
+
+pub fn check_limits(
+    value: &mut global::DevValue, min: f64, max: f64, clamp: bool,
+) -> Result<(), String> {
+    let (min, max) = (min.min(max), min.max(max));
+
+    if min == max {
+        return Ok(());
+    }
+
+    if let Some(v) = value.int_val {
+        let (lo, hi) = (min.ceil(), max.floor());
+
+        if lo > hi {
+            return Err(format!(
+                "no integer lies within the limits [{}, {}]",
+                min, max
+            ));
+        }
+        value.int_val = Some(check_scalar(v as f64, lo, hi, clamp)? as i32);
+    } else if let Some(v) = value.scalar_val {
+        value.scalar_val = Some(check_scalar(v, min, max, clamp)?);
+    } else if let Some(ref mut arr) = value.scalar_array_val {
+        for (idx, v) in arr.iter_mut().enumerate() {
+            *v = check_scalar(*v, min, max, clamp)
+                .map_err(|e| format!("element {}: {}", idx, e))?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the integer command value of a setting, if it has one.
+
+fn command_value(value: &global::DevValue) -> Option<i32> {
+    value.int_val.or_else(|| {
+        value
+            .scalar_val
+            .filter(|v| v.fract() == 0.0)
+            .map(|v| v as i32)
+    })
+}
+
+// Validates a setting using the device's configuration. The value may
+// be modified, if clamping was requested.
+//
+// DevDB records the limits in common units. Raw and primary-unit
+// settings would have to be converted with the device's scaling
+// transforms before they could be compared, which is left to the
+// front-end; only the property is checked for those fields.
+
+pub fn check_setting(
+    info: &DeviceInfo, property: Property, field: Option<&Field>,
+    value: &mut global::DevValue, clamp: bool,
+) -> Result<(), String> {
+    match property {
+        Property::Setting => match (info.setting.as_ref(), field) {
+            (None, _) => Err("device doesn't have a setting property".into()),
+            (Some(prop), None | Some(Field::Scaled)) => {
+                check_limits(value, prop.min_val, prop.max_val, clamp)
+            }
+            (Some(_), Some(field @ (Field::Raw | Field::Primary))) => {
+                info!(
+                    "limits are in common units : not checking {} setting",
+                    field.name()
+                );
+                Ok(())
+            }
+            (Some(_), Some(field)) => {
+                Err(format!("the {} field can't be set", field.name()))
+            }
+        },
+        Property::Control if field.is_some() => {
+            Err("the control property doesn't have fields".into())
+        }
+        Property::Control => match info.dig_control {
+            Some(ref ctrl) if ctrl.entries.is_empty() => Ok(()),
+            Some(ref ctrl) => match command_value(value) {
+                Some(cmd) if ctrl.entries.iter().any(|e| e.value == cmd) => {
+                    Ok(())
+                }
+                _ => Err("value isn't a control command defined for the \
+			  device"
+                    .into()),
+            },
+            None => Err("device doesn't have a control property".into()),
+        },
+        _ => Err(format!("the {} property can't be set", property.name())),
+    }
+}
+
+// Looks up a control command, by name, in the device's configuration
+// and returns the value to send to the device. Either the short or the
+// long name of the command may be used; case is ignored. If the
+// command isn't found, the error lists the device's commands.
+
+pub fn resolve_command(
+    info: &DeviceInfo, command: &str,
+) -> Result<i32, String> {
+    let Some(ref ctrl) = info.dig_control else {
+        return Err("device doesn't have a control property".into());
+    };
+    let command = command.trim();
+
+    ctrl.entries
+        .iter()
+        .find(|e| {
+            e.short_name.trim().eq_ignore_ascii_case(command)
+                || e.long_name.trim().eq_ignore_ascii_case(command)
+        })
+        .map(|e| e.value)
+        .ok_or_else(|| {
+            let names: Vec<_> =
+                ctrl.entries.iter().map(|e| e.short_name.trim()).collect();
+
+            format!(
+                "unknown command \"{}\" -- valid commands are: {}",
+                command,
+                names.join(", ")
+            )
+        })
+}
+
+// Validates a setting request. Only ACNET devices have configurations
+// in DevDB; settings to any other device are passed through unchecked.
+// An ACNET device with an unknown property or field is rejected.
+
+pub async fn validate(
+    device: &str, value: &mut global::DevValue, clamp: bool,
+) -> Result<(), String> {
+    let Some(target) = drf::parse_target(device) else {
+        if drf::is_acnet_drf(device) {
+            return Err(format!("{}: unknown property or field", device));
+        }
+        info!("{} isn't an ACNET device : skipping validation", device);
+        return Ok(());
+    };
+    let info = cache::device_info(&target.device).await.map_err(|e| {
+        format!("couldn't retrieve {} from DevDB: {}", target.device, e)
+    })?;
+
+    check_setting(&info, target.property, target.field.as_ref(), value, clamp)
+        .map_err(|e| format!("{}: {}", device, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::devdb::types::{
+        DigControl, DigControlEntry, SettingProp,
+    };
+
+    fn scalar(v: f64) -> global::DevValue {
+        global::DevValue {
+            int_val: None,
+            scalar_val: Some(v),
+            scalar_array_val: None,
+            raw_val: None,
+            text_val: None,
+            text_array_val: None,
+        }
+    }
+
+    fn integer(v: i32) -> global::DevValue {
+        global::DevValue {
+            int_val: Some(v),
+            ..scalar(0.0)
+        }
+    }
+
+    fn device(setting: Option<(f64, f64)>, commands: &[i32]) -> DeviceInfo {
+        DeviceInfo {
+            description: "test device".into(),
+            reading: None,
+            setting: setting.map(|(min_val, max_val)| SettingProp {
+                primary_units: None,
+                common_units: None,
+                min_val,
+                max_val,
+                primary_index: 0,
+                common_index: 0,
+                coeff: vec![],
+                is_step_motor: false,
+                is_destructive_read: false,
+                is_fe_scaling: false,
+                is_contr_setting: false,
+                is_knobbable: false,
+            }),
+            dig_control: if commands.is_empty() {
+                None
+            } else {
+                Some(DigControl {
+                    entries: commands
+                        .iter()
+                        .map(|v| DigControlEntry {
+                            value: *v,
+                            short_name: format!("CMD{}", v),
+                            long_name: format!("Command {}", v),
+                        })
+                        .collect(),
+                })
+            },
+            dig_status: None,
+        }
+    }
+
+    #[test]
+    fn test_values_within_limits() {
+        let mut v = scalar(5.0);
+
+        assert!(check_limits(&mut v, 0.0, 10.0, false).is_ok());
+        assert_eq!(v.scalar_val, Some(5.0));
+
+        let mut v = scalar(10.0);
+
+        assert!(check_limits(&mut v, 0.0, 10.0, false).is_ok());
+        assert_eq!(v.scalar_val, Some(10.0));
+    }
+
+    #[test]
+    fn test_out_of_range_rejected() {
+        assert!(check_limits(&mut scalar(10.5), 0.0, 10.0, false).is_err());
+        assert!(check_limits(&mut scalar(-0.5), 0.0, 10.0, false).is_err());
+        assert!(check_limits(&mut integer(11), 0.0, 10.0, false).is_err());
+        assert!(check_limits(&mut scalar(f64::NAN), 0.0, 10.0, true).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_clamped() {
+        let mut v = scalar(10.5);
+
+        assert!(check_limits(&mut v, 0.0, 10.0, true).is_ok());
+        assert_eq!(v.scalar_val, Some(10.0));
+
+        let mut v = integer(-3);
+
+        assert!(check_limits(&mut v, 0.5, 10.0, true).is_ok());
+        assert_eq!(v.int_val, Some(1));
+    }
+
+    #[test]
+    fn test_reversed_and_missing_limits() {
+        let mut v = scalar(5.0);
+
+        assert!(check_limits(&mut v, 10.0, 0.0, false).is_ok());
+        assert!(check_limits(&mut scalar(1e9), 0.0, 0.0, false).is_ok());
+    }
+
+    #[test]
+    fn test_array_elements_checked() {
+        let mut v = global::DevValue {
+            scalar_array_val: Some(vec![1.0, 12.0, -1.0]),
+            ..scalar(0.0)
+        };
+
+        assert!(check_limits(&mut v, 0.0, 10.0, false).is_err());
+        assert!(check_limits(&mut v, 0.0, 10.0, true).is_ok());
+        assert_eq!(v.scalar_array_val, Some(vec![1.0, 10.0, 0.0]));
+    }
+
+    #[test]
+    fn test_setting_property() {
+        let info = device(Some((0.0, 10.0)), &[]);
+
+        assert!(
+            check_setting(
+                &info,
+                Property::Setting,
+                None,
+                &mut scalar(1.0),
+                false
+            )
+            .is_ok()
+        );
+        assert!(
+            check_setting(
+                &info,
+                Property::Reading,
+                None,
+                &mut scalar(1.0),
+                false
+            )
+            .is_err()
+        );
+        assert!(
+            check_setting(
+                &info,
+                Property::Control,
+                None,
+                &mut integer(1),
+                false
+            )
+            .is_err()
+        );
+
+        let info = device(None, &[]);
+
+        assert!(
+            check_setting(
+                &info,
+                Property::Setting,
+                None,
+                &mut scalar(1.0),
+                false
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_setting_fields() {
+        let info = device(Some((0.0, 10.0)), &[1]);
+        let check = |property, field, v: f64| {
+            check_setting(&info, property, field, &mut scalar(v), false)
+        };
+
+        assert!(check(Property::Setting, Some(&Field::Scaled), 11.0).is_err());
+
+        // Raw and primary-unit values aren't compared to the limits.
+
+        assert!(check(Property::Setting, Some(&Field::Raw), 4096.0).is_ok());
+        assert!(check(Property::Setting, Some(&Field::Primary), 11.0).is_ok());
+        assert!(
+            check(Property::Setting, Some(&Field::Other("MAX".into())), 1.0)
+                .is_err()
+        );
+        assert!(check(Property::Control, Some(&Field::Raw), 1.0).is_err());
+        assert!(
+            check_setting(
+                &device(None, &[]),
+                Property::Setting,
+                Some(&Field::Raw),
+                &mut scalar(1.0),
+                false
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_property_rejected() {
+        assert!(
+            validate("M:OUTTMP.BOGUS", &mut scalar(1.0), false)
+                .await
+                .is_err()
+        );
+        assert!(
+            validate("PIP2:BPM:X", &mut scalar(1.0), false)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_command_names() {
+        let info = device(None, &[1, 2, 5]);
+
+        assert_eq!(resolve_command(&info, "CMD2"), Ok(2));
+        assert_eq!(resolve_command(&info, "cmd5"), Ok(5));
+        assert_eq!(resolve_command(&info, " command 1 "), Ok(1));
+
+        let err = resolve_command(&info, "RESET").unwrap_err();
+
+        assert!(err.contains("CMD1, CMD2, CMD5"));
+        assert!(resolve_command(&device(None, &[]), "CMD1").is_err());
+    }
+
+    #[test]
+    fn test_control_property() {
+        let info = device(None, &[1, 2, 5]);
+
+        assert!(
+            check_setting(
+                &info,
+                Property::Control,
+                None,
+                &mut integer(2),
+                false
+            )
+            .is_ok()
+        );
+        assert!(
+            check_setting(
+                &info,
+                Property::Control,
+                None,
+                &mut scalar(5.0),
+                false
+            )
+            .is_ok()
+        );
+        assert!(
+            check_setting(
+                &info,
+                Property::Control,
+                None,
+                &mut integer(3),
+                false
+            )
+            .is_err()
+        );
+        assert!(
+            check_setting(
+                &info,
+                Property::Control,
+                None,
+                &mut scalar(2.5),
+                false
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,483 @@
+// Supports saving and restoring the settings of a group of devices.
+// A snapshot records the setting of each device at the time it was
+// taken. Snapshots are kept in the document store described by
+// `SNAPSHOT_DIR` (see `store::Documents::from_env`.)
+
+use super::{
+    drf, global, read_once, store::Documents, strip_event, strip_source, types,
+};
+use crate::g_rpc::dpm::Connection;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tracing::warn;
+
+const SNAPSHOT_DIR: &str = "SNAPSHOT_DIR";
+
+// The value of a device when the snapshot was taken. If the device
+// couldn't be read, the status is saved instead; those devices aren't
+// restored.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SavedValue {
+    Scalar(f64),
+    ScalarArray(Vec<f64>),
+    Raw(Vec<u8>),
+    Text(String),
+    TextArray(Vec<String>),
+    Status(i16),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub drf: String,
+    pub value: SavedValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub owner: Option<String>,
+    pub created: f64,
+    pub entries: Vec<Entry>,
+}
+
+// Describes how one device differs between two sets of values.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub drf: String,
+    pub saved: Option<SavedValue>,
+    pub current: Option<SavedValue>,
+}
+
+impl SavedValue {
+    // Converts a reading into a value that can be saved. Structured
+    // data can't be used as a setting so it isn't supported.
+
+    pub fn from_data(data: &global::DataType) -> Option<Self> {
+        match data {
+            global::DataType::Scalar(v) => {
+                Some(SavedValue::Scalar(v.scalar_value))
+            }
+            global::DataType::ScalarArray(v) => {
+                Some(SavedValue::ScalarArray(v.scalar_array_value.clone()))
+            }
+            global::DataType::Raw(v) => {
+                Some(SavedValue::Raw(v.raw_value.clone()))
+            }
+            global::DataType::Text(v) => {
+                Some(SavedValue::Text(v.text_value.clone()))
+            }
+            global::DataType::TextArray(v) => {
+                Some(SavedValue::TextArray(v.text_array_value.clone()))
+            }
+            global::DataType::EnumValue(v) => {
+                Some(SavedValue::Scalar(v.enum_value.into()))
+            }
+            global::DataType::StatusReply(v) => {
+                Some(SavedValue::Status(v.status))
+            }
+            global::DataType::StructData(_) => None,
+        }
+    }
+
+    pub fn to_data(&self) -> global::DataType {
+        match self {
+            SavedValue::Scalar(v) => {
+                global::DataType::Scalar(global::Scalar { scalar_value: *v })
+            }
+            SavedValue::ScalarArray(v) => {
+                global::DataType::ScalarArray(global::ScalarArray {
+                    scalar_array_value: v.clone(),
+                })
+            }
+            SavedValue::Raw(v) => global::DataType::Raw(global::Raw {
+                raw_value: v.clone(),
+            }),
+            SavedValue::Text(v) => global::DataType::Text(global::Text {
+                text_value: v.clone(),
+            }),
+            SavedValue::TextArray(v) => {
+                global::DataType::TextArray(global::TextArray {
+                    text_array_value: v.clone(),
+                })
+            }
+            SavedValue::Status(v) => {
+                global::DataType::StatusReply(global::StatusReply {
+                    status: *v,
+                    message: None,
+                })
+            }
+        }
+    }
+
+    // Returns the setting that restores this value. Saved statuses
+    // can't be restored.
+
+    pub fn to_setting(&self) -> Option<global::DevValue> {
+        let empty = global::DevValue {
+            int_val: None,
+            scalar_val: None,
+            scalar_array_val: None,
+            raw_val: None,
+            text_val: None,
+            text_array_val: None,
+        };
+
+        match self {
+            SavedValue::Scalar(v) => Some(global::DevValue {
+                scalar_val: Some(*v),
+                ..empty
+            }),
+            SavedValue::ScalarArray(v) => Some(global::DevValue {
+                scalar_array_val: Some(v.clone()),
+                ..empty
+            }),
+            SavedValue::Raw(v) => Some(global::DevValue {
+                raw_val: Some(v.clone()),
+                ..empty
+            }),
+            SavedValue::Text(v) => Some(global::DevValue {
+                text_val: Some(v.clone()),
+                ..empty
+            }),
+            SavedValue::TextArray(v) => Some(global::DevValue {
+                text_array_val: Some(v.clone()),
+                ..empty
+            }),
+            SavedValue::Status(_) => None,
+        }
+    }
+}
+
+// Returns the DRF string used to save and restore a device. Events and
+// sources are removed. For ACNET devices, the setting property is used
+// (a reading name, like "M:OUTTMP", refers to its setting.) Other
+// properties can't be saved. Non-ACNET names are used as-is.
+
+pub fn setting_drf(drf: &str) -> Result<String, String> {
+    match drf::parse_target(drf) {
+        Some(target)
+            if matches!(
+                target.property,
+                drf::Property::Reading | drf::Property::Setting
+            ) =>
+        {
+            Ok(target.drf(drf::Property::Setting))
+        }
+        Some(target) => Err(format!(
+            "{}: the {} property can't be saved",
+            target.device,
+            target.property.name()
+        )),
+        None => match strip_source(strip_event(drf)).trim() {
+            "" => Err("empty device name".into()),
+            name => Ok(name.to_owned()),
+        },
+    }
+}
+
+// Compares two sets of values. Devices found in only one of the sets
+// are reported, as are devices whose values differ.
+
+pub fn compare(saved: &[Entry], current: &[Entry]) -> Vec<Difference> {
+    let lookup: HashMap<_, _> =
+        current.iter().map(|e| (e.drf.as_str(), &e.value)).collect();
+    let mut result: Vec<_> = saved
+        .iter()
+        .filter_map(|e| match lookup.get(e.drf.as_str()) {
+            Some(v) if **v == e.value => None,
+            v => Some(Difference {
+                drf: e.drf.clone(),
+                saved: Some(e.value.clone()),
+                current: v.map(|v| (*v).clone()),
+            }),
+        })
+        .collect();
+
+    result.extend(
+        current
+            .iter()
+            .filter(|e| !saved.iter().any(|s| s.drf == e.drf))
+            .map(|e| Difference {
+                drf: e.drf.clone(),
+                saved: None,
+                current: Some(e.value.clone()),
+            }),
+    );
+    result
+}
+
+// Reads the current value of each device. Devices that can't be read
+// hold the status of the read.
+
+pub async fn read_current(
+    conn: &Connection, token: Option<&String>, drfs: &[String],
+) -> Result<Vec<Entry>, String> {
+    let requests = drfs.iter().map(|v| format!("{}@i", v)).collect();
+    let replies = read_once(conn, token, requests, super::READ_TIMEOUT)
+        .await
+        .map_err(|e| e.message)?;
+
+    Ok(drfs
+        .iter()
+        .zip(replies)
+        .map(|(drf, reply)| Entry {
+            drf: drf.clone(),
+            value: reply
+                .data
+                .first()
+                .and_then(|v| SavedValue::from_data(&v.result))
+                .unwrap_or_else(|| {
+                    warn!("{} returned an unsupported type", drf);
+                    SavedValue::Status(-1)
+                }),
+        })
+        .collect())
+}
+
+impl From<Snapshot> for types::DeviceSnapshot {
+    fn from(snapshot: Snapshot) -> Self {
+        types::DeviceSnapshot {
+            name: snapshot.name,
+            owner: snapshot.owner,
+            created: snapshot.created,
+            values: snapshot
+                .entries
+                .into_iter()
+                .map(|e| types::SnapshotValue {
+                    value: e.value.to_data(),
+                    drf: e.drf,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<Difference> for types::SnapshotDifference {
+    fn from(diff: Difference) -> Self {
+        types::SnapshotDifference {
+            drf: diff.drf,
+            saved: diff.saved.map(|v| v.to_data()),
+            current: diff.current.map(|v| v.to_data()),
+        }
+    }
+}
+
+// Makes sure a snapshot name can be used as a file name.
+
+pub fn check_name(name: &str) -> Result<&str, String> {
+    if !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        Ok(name)
+    } else {
+        Err(format!(
+            "invalid snapshot name {:?} -- use letters, digits, '.', '_' \
+	     and '-'",
+            name
+        ))
+    }
+}
+
+// The snapshot store used by the ACSys schema. Snapshots are saved as
+// JSON documents, keyed by name. Saves are serialized so the check for
+// an existing snapshot and the write that follows can't interleave
+// with another save.
+
+#[derive(Clone)]
+pub struct Snapshots {
+    docs: Documents,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Snapshots {
+    pub fn new(docs: Documents) -> Self {
+        Snapshots {
+            docs,
+            lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Snapshots::new(Documents::from_env(SNAPSHOT_DIR, "snapshots"))
+    }
+
+    pub async fn load(&self, name: &str) -> Result<Option<Snapshot>, String> {
+        self.docs.load_json(check_name(name)?).await
+    }
+
+    // Saves a snapshot on behalf of `account`. If a snapshot with the
+    // same name exists, it's only replaced when `overwrite` is set and
+    // it belongs to `account`. Snapshots saved without an owner can't
+    // be replaced.
+
+    pub async fn save(
+        &self, snapshot: &Snapshot, account: &str, overwrite: bool,
+    ) -> Result<(), String> {
+        let name = check_name(&snapshot.name)?;
+        let _guard = self.lock.lock().await;
+
+        if let Some(existing) = self.docs.load_json::<Snapshot>(name).await? {
+            if !overwrite {
+                return Err(format!("snapshot {} already exists", name));
+            }
+            if existing.owner.as_deref() != Some(account) {
+                return Err(format!(
+                    "snapshot {} belongs to another account",
+                    name
+                ));
+            }
+        }
+        self.docs.save_json(name, snapshot).await.map(|_| ())
+    }
+
+    // Returns all the snapshots, sorted by name.
+
+    pub async fn list(&self) -> Result<Vec<Snapshot>, String> {
+        let mut result: Vec<Snapshot> = self.docs.list_json().await?;
+
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::store::MemoryStore;
+
+    fn entry(drf: &str, value: SavedValue) -> Entry {
+        Entry {
+            drf: drf.into(),
+            value,
+        }
+    }
+
+    fn snapshot(name: &str) -> Snapshot {
+        Snapshot {
+            name: name.into(),
+            owner: Some("jdoe".into()),
+            created: 1000.0,
+            entries: vec![
+                entry("M:OUTTMP.SETTING", SavedValue::Scalar(5.0)),
+                entry("Z:CUBE.SETTING", SavedValue::Status(-6 * 256 + 1)),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_setting_drf() {
+        assert_eq!(
+            setting_drf("M:OUTTMP@p,1000"),
+            Ok("M:OUTTMP.SETTING".into())
+        );
+        assert_eq!(setting_drf("m_outtmp"), Ok("M:OUTTMP.SETTING".into()));
+        assert_eq!(
+            setting_drf("PIP2:PS:CURRENT <-EPICS"),
+            Ok("PIP2:PS:CURRENT".into())
+        );
+        assert!(setting_drf("M|OUTTMP").is_err());
+        assert!(setting_drf("M:OUTTMP.CONTROL").is_err());
+        assert!(setting_drf(" ").is_err());
+    }
+
+    #[test]
+    fn test_values() {
+        let v = SavedValue::ScalarArray(vec![1.0, 2.0]);
+
+        assert_eq!(SavedValue::from_data(&v.to_data()), Some(v.clone()));
+        assert_eq!(
+            v.to_setting().and_then(|v| v.scalar_array_val),
+            Some(vec![1.0, 2.0])
+        );
+        assert_eq!(SavedValue::Status(-1).to_setting(), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let saved = vec![
+            entry("A:ONE.SETTING", SavedValue::Scalar(1.0)),
+            entry("A:TWO.SETTING", SavedValue::Scalar(2.0)),
+            entry("A:THREE.SETTING", SavedValue::Scalar(3.0)),
+        ];
+        let current = vec![
+            entry("A:ONE.SETTING", SavedValue::Scalar(1.0)),
+            entry("A:TWO.SETTING", SavedValue::Scalar(2.5)),
+            entry("A:FOUR.SETTING", SavedValue::Scalar(4.0)),
+        ];
+
+        assert_eq!(
+            compare(&saved, &current),
+            vec![
+                Difference {
+                    drf: "A:TWO.SETTING".into(),
+                    saved: Some(SavedValue::Scalar(2.0)),
+                    current: Some(SavedValue::Scalar(2.5)),
+                },
+                Difference {
+                    drf: "A:THREE.SETTING".into(),
+                    saved: Some(SavedValue::Scalar(3.0)),
+                    current: None,
+                },
+                Difference {
+                    drf: "A:FOUR.SETTING".into(),
+                    saved: None,
+                    current: Some(SavedValue::Scalar(4.0)),
+                },
+            ]
+        );
+        assert!(compare(&saved, &saved).is_empty());
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(check_name("before-study_2.1").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("../x").is_err());
+        assert!(check_name("a b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let store =
+            Snapshots::new(Documents::new(Arc::new(MemoryStore::default())));
+
+        assert_eq!(store.load("a").await, Ok(None));
+        assert!(store.save(&snapshot("b"), "jdoe", false).await.is_ok());
+        assert!(store.save(&snapshot("a"), "jdoe", false).await.is_ok());
+        assert_eq!(store.load("a").await, Ok(Some(snapshot("a"))));
+
+        let names: Vec<_> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+
+        assert_eq!(names, vec!["a", "b"]);
+        assert!(store.save(&snapshot("../a"), "jdoe", false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_overwrite() {
+        let store =
+            Snapshots::new(Documents::new(Arc::new(MemoryStore::default())));
+        let mut legacy = snapshot("legacy");
+
+        legacy.owner = None;
+        assert!(store.save(&snapshot("a"), "jdoe", false).await.is_ok());
+        assert!(store.save(&legacy, "jdoe", false).await.is_ok());
+
+        // Only the owner may replace a snapshot, and only when asked to.
+
+        assert!(store.save(&snapshot("a"), "jdoe", false).await.is_err());
+        assert!(store.save(&snapshot("a"), "other", true).await.is_err());
+        assert!(store.save(&snapshot("a"), "jdoe", true).await.is_ok());
+        assert!(store.save(&legacy, "jdoe", true).await.is_err());
+    }
+}
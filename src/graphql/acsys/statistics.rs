@@ -0,0 +1,303 @@
+// Computes statistics of devices' data over a time range. The readings
+// are consumed as they arrive, so only the running totals of each bin
+// are kept -- the result's size depends on the number of bins, not on
+// the number of readings.
+//
+// Only scalar readings contribute to the statistics. The range is
+// divided into bins that start at the beginning of the range; a
+// request without a bin size has one bin covering the whole range.
+
+use super::{global, types};
+
+// The most bins a request may have.
+
+const MAX_BINS: f64 = 10_000.0;
+
+// Returns the width of the bins for a range. `bin_size`, if given,
+// must be positive and can't create more than `MAX_BINS` bins.
+
+pub fn bin_width(
+    start: f64, end: f64, bin_size: Option<f64>,
+) -> Result<f64, String> {
+    match bin_size {
+        None => Ok(end - start),
+        Some(v) if v.is_finite() && v > 0.0 => {
+            if (end - start) / v > MAX_BINS {
+                Err(format!(
+                    "a bin size of {} seconds results in more than {} bins",
+                    v, MAX_BINS
+                ))
+            } else {
+                Ok(v)
+            }
+        }
+        Some(v) => {
+            Err(format!("binSize must be a positive number -- got {}", v))
+        }
+    }
+}
+
+// The running totals of a bin. The mean and variance are accumulated
+// with Welford's method, which doesn't lose precision when there are
+// many readings.
+
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+    first: Option<global::DataInfo>,
+    last: Option<global::DataInfo>,
+}
+
+impl Accumulator {
+    fn add(&mut self, info: &global::DataInfo, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+
+        self.count += 1;
+
+        let delta = value - self.mean;
+
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+
+        // Readings from different sources may arrive out of order, so
+        // the first and last are picked by their timestamps.
+
+        if self
+            .first
+            .as_ref()
+            .is_none_or(|v| info.timestamp < v.timestamp)
+        {
+            self.first = Some(info.clone());
+        }
+        if self
+            .last
+            .as_ref()
+            .is_none_or(|v| info.timestamp >= v.timestamp)
+        {
+            self.last = Some(info.clone());
+        }
+    }
+
+    fn finish(self, start: f64, end: f64) -> types::StatisticsBin {
+        let some = |v: f64| (self.count > 0).then_some(v);
+
+        types::StatisticsBin {
+            start,
+            end,
+            count: self.count,
+            min: some(self.min),
+            max: some(self.max),
+            mean: some(self.mean),
+            std_dev: some(if self.count < 2 {
+                0.0
+            } else {
+                (self.m2 / (self.count - 1) as f64).sqrt()
+            }),
+            first: self.first,
+            last: self.last,
+        }
+    }
+}
+
+struct Device {
+    bins: Vec<Accumulator>,
+    status: Option<global::StatusReply>,
+    failed: bool,
+}
+
+// Collects the statistics of a request's devices. Replies are
+// associated with the devices by their `ref_id`.
+
+pub struct Collector {
+    start: f64,
+    end: f64,
+    width: f64,
+    devices: Vec<Device>,
+}
+
+impl Collector {
+    pub fn new(devices: usize, start: f64, end: f64, width: f64) -> Self {
+        let n_bins = ((end - start) / width).ceil().max(1.0) as usize;
+
+        Collector {
+            start,
+            end,
+            width,
+            devices: (0..devices)
+                .map(|_| Device {
+                    bins: (0..n_bins).map(|_| Accumulator::default()).collect(),
+                    status: None,
+                    failed: false,
+                })
+                .collect(),
+        }
+    }
+
+    // Adds a reply's readings. The most recent status of a device is
+    // saved. An error status means the device's data is incomplete so
+    // its later readings are ignored.
+
+    pub fn add(&mut self, reply: &global::DataReply) {
+        let Some(device) = usize::try_from(reply.ref_id)
+            .ok()
+            .and_then(|idx| self.devices.get_mut(idx))
+        else {
+            return;
+        };
+
+        for info in reply.data.iter() {
+            match info.result {
+                global::DataType::StatusReply(ref status) => {
+                    device.failed = device.failed || status.status < 0;
+                    device.status = Some(status.clone());
+                }
+                global::DataType::Scalar(ref v)
+                    if !device.failed
+                        && info.timestamp >= self.start
+                        && info.timestamp < self.end =>
+                {
+                    let idx = ((info.timestamp - self.start) / self.width)
+                        .floor() as usize;
+
+                    if let Some(bin) = device.bins.get_mut(idx) {
+                        bin.add(info, v.scalar_value)
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<types::DeviceStatistics> {
+        let (start, end, width) = (self.start, self.end, self.width);
+
+        self.devices
+            .into_iter()
+            .enumerate()
+            .map(|(idx, device)| types::DeviceStatistics {
+                ref_id: idx as i32,
+                bins: device
+                    .bins
+                    .into_iter()
+                    .enumerate()
+                    .map(|(n, bin)| {
+                        let bin_start = start + n as f64 * width;
+
+                        bin.finish(bin_start, (bin_start + width).min(end))
+                    })
+                    .collect(),
+                status: device.status,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reply(ref_id: i32, data: &[(f64, f64)]) -> global::DataReply {
+        global::DataReply {
+            ref_id,
+            data: data
+                .iter()
+                .map(|(ts, v)| global::DataInfo {
+                    timestamp: *ts,
+                    result: global::DataType::Scalar(global::Scalar {
+                        scalar_value: *v,
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    fn status_reply(ref_id: i32, status: i16) -> global::DataReply {
+        global::DataReply {
+            ref_id,
+            data: vec![global::DataInfo {
+                timestamp: 0.0,
+                result: global::DataType::StatusReply(global::StatusReply {
+                    status,
+                    message: None,
+                }),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_bin_width() {
+        assert_eq!(bin_width(0.0, 100.0, None), Ok(100.0));
+        assert_eq!(bin_width(0.0, 100.0, Some(10.0)), Ok(10.0));
+        assert!(bin_width(0.0, 100.0, Some(0.0)).is_err());
+        assert!(bin_width(0.0, 100.0, Some(f64::NAN)).is_err());
+        assert!(bin_width(0.0, 1e6, Some(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut c = Collector::new(2, 0.0, 10.0, 10.0);
+
+        // Readings outside the range are ignored and the first and last
+        // readings are found by time.
+
+        c.add(&reply(0, &[(5.0, 4.0), (-1.0, 100.0), (10.0, 100.0)]));
+        c.add(&reply(0, &[(1.0, 2.0), (9.0, 6.0)]));
+        c.add(&status_reply(1, -1));
+        c.add(&reply(1, &[(1.0, 1.0)]));
+
+        let result = c.finish();
+        let bin = &result[0].bins[0];
+
+        assert_eq!(result.len(), 2);
+        assert_eq!((bin.start, bin.end, bin.count), (0.0, 10.0, 3));
+        assert_eq!(
+            (bin.min, bin.max, bin.mean),
+            (Some(2.0), Some(6.0), Some(4.0))
+        );
+        assert_eq!(bin.std_dev, Some(2.0));
+        assert_eq!(bin.first.as_ref().map(|v| v.timestamp), Some(1.0));
+        assert_eq!(bin.last.as_ref().map(|v| v.timestamp), Some(9.0));
+        assert_eq!(result[0].status, None);
+
+        // The second device failed so it has no statistics.
+
+        assert_eq!(result[1].bins[0].count, 0);
+        assert_eq!(result[1].bins[0].mean, None);
+        assert_eq!(result[1].status.as_ref().map(|v| v.status), Some(-1));
+    }
+
+    #[test]
+    fn test_bins() {
+        let mut c = Collector::new(1, 0.0, 25.0, 10.0);
+
+        c.add(&reply(
+            0,
+            &[(1.0, 1.0), (11.0, 3.0), (12.0, 5.0), (24.0, 7.0)],
+        ));
+
+        let bins: Vec<_> = c.finish()[0]
+            .bins
+            .iter()
+            .map(|v| (v.start, v.end, v.count, v.mean))
+            .collect();
+
+        assert_eq!(
+            bins,
+            vec![
+                (0.0, 10.0, 1, Some(1.0)),
+                (10.0, 20.0, 2, Some(4.0)),
+                (20.0, 25.0, 1, Some(7.0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+// A store of JSON documents, each identified by a key. It holds the
+// data this service saves on behalf of its clients (e.g. device
+// snapshots and users' plot configurations.) The storage is hidden
+// behind the `Store` trait so the documents can be kept in a directory
+// or, for development and testing, in memory.
+
+use rust_env_var_lib::env_var;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+// A document and when it was last saved (in seconds since 1970, UTC.)
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub text: Arc<str>,
+    pub last_modified: f64,
+}
+
+// The operations a document store has to support. These are blocking
+// calls; async code should use them through `Documents`.
+
+pub trait Store: Send + Sync {
+    fn load(&self, key: &str) -> Result<Option<Document>, String>;
+    fn save(&self, key: &str, text: Arc<str>) -> Result<Document, String>;
+    fn list(&self) -> Result<Vec<(String, Document)>, String>;
+}
+
+fn to_seconds(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|v| v.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+// Makes sure a key can safely be used as a file name. Keys are names
+// chosen by clients, or account names (usernames or e-mail addresses),
+// so anything outside that character set is rejected.
+
+pub fn check_key(key: &str) -> Result<&str, String> {
+    if !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c))
+    {
+        Ok(key)
+    } else {
+        Err(format!("invalid name: {:?}", key))
+    }
+}
+
+// Keeps documents in memory. They're lost when the service restarts.
+
+#[derive(Default)]
+pub struct MemoryStore(Mutex<HashMap<String, Document>>);
+
+impl Store for MemoryStore {
+    fn load(&self, key: &str) -> Result<Option<Document>, String> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, text: Arc<str>) -> Result<Document, String> {
+        let doc = Document {
+            text,
+            last_modified: to_seconds(SystemTime::now()),
+        };
+
+        self.0
+            .lock()
+            .unwrap()
+            .insert(check_key(key)?.to_owned(), doc.clone());
+        Ok(doc)
+    }
+
+    fn list(&self) -> Result<Vec<(String, Document)>, String> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+// Keeps each document in its own file. The file's modification time is
+// the document's last-modified time.
+
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("couldn't create {}: {}", dir.display(), e))?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        Ok(self.dir.join(format!("{}.json", check_key(key)?)))
+    }
+
+    fn read(path: &PathBuf) -> Result<Option<Document>, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let modified = fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .map(to_seconds)
+                    .unwrap_or(0.0);
+
+                Ok(Some(Document {
+                    text: text.into(),
+                    last_modified: modified,
+                }))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("couldn't read {}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn load(&self, key: &str) -> Result<Option<Document>, String> {
+        Self::read(&self.path(key)?)
+    }
+
+    // Writes the document to a temporary file and renames it so a
+    // reader never sees a partially written file.
+
+    fn save(&self, key: &str, text: Arc<str>) -> Result<Document, String> {
+        let path = self.path(key)?;
+        let tmp = path.with_extension("json.tmp");
+
+        fs::write(&tmp, text.as_bytes())
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| format!("couldn't write {}: {}", path.display(), e))?;
+
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Document {
+            text,
+            last_modified: to_seconds(modified),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<(String, Document)>, String> {
+        let dir = fs::read_dir(&self.dir).map_err(|e| {
+            format!("couldn't read {}: {}", self.dir.display(), e)
+        })?;
+
+        Ok(dir
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let key = path.file_stem()?.to_str()?.to_owned();
+                let doc = Self::read(&path).inspect_err(|e| warn!("{}", e));
+
+                Some((key, doc.ok()??))
+            })
+            .collect())
+    }
+}
+
+// The document store used by the ACSys schema. It runs the store's
+// blocking operations on tokio's blocking thread pool.
+
+#[derive(Clone)]
+pub struct Documents(Arc<dyn Store>);
+
+impl Documents {
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Documents(store)
+    }
+
+    // Creates the store described by the environment. If the variable
+    // `name` is set, documents are saved in the directory it names.
+    // Otherwise they're kept in memory. `what` describes the documents
+    // in log messages.
+
+    pub fn from_env(name: &str, what: &str) -> Self {
+        match env_var::get(name).to_option::<String>() {
+            Some(dir) => match FileStore::new(&dir) {
+                Ok(store) => {
+                    info!("saving {} in {}", what, &dir);
+                    Documents::new(Arc::new(store))
+                }
+                Err(e) => panic!("bad {} setting -- {}", name, e),
+            },
+            None => {
+                warn!("{} not set -- {} won't survive a restart", name, what);
+                Documents::new(Arc::new(MemoryStore::default()))
+            }
+        }
+    }
+
+    pub async fn load(&self, key: &str) -> Result<Option<Document>, String> {
+        let store = self.0.clone();
+        let key = key.to_owned();
+
+        tokio::task::spawn_blocking(move || store.load(&key))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+
+    pub async fn save(
+        &self, key: &str, text: Arc<str>,
+    ) -> Result<Document, String> {
+        let store = self.0.clone();
+        let key = key.to_owned();
+
+        tokio::task::spawn_blocking(move || store.save(&key, text))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, Document)>, String> {
+        let store = self.0.clone();
+
+        tokio::task::spawn_blocking(move || store.list())
+            .await
+            .map_err(|e| e.to_string())?
+    }
+
+    // Loads a document and decodes it from JSON.
+
+    pub async fn load_json<T: DeserializeOwned>(
+        &self, key: &str,
+    ) -> Result<Option<T>, String> {
+        match self.load(key).await? {
+            Some(doc) => serde_json::from_str(&doc.text)
+                .map(Some)
+                .map_err(|e| format!("couldn't decode {}: {}", key, e)),
+            None => Ok(None),
+        }
+    }
+
+    // Encodes a value as JSON and saves it.
+
+    pub async fn save_json<T: Serialize>(
+        &self, key: &str, value: &T,
+    ) -> Result<Document, String> {
+        let text =
+            serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+
+        self.save(key, text.into()).await
+    }
+
+    // Returns every document that can be decoded. Documents that can't
+    // be are logged and skipped.
+
+    pub async fn list_json<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Vec<T>, String> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|(key, doc)| {
+                serde_json::from_str(&doc.text)
+                    .inspect_err(|e| warn!("couldn't decode {}: {}", key, e))
+                    .ok()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        assert!(check_key("jdoe").is_ok());
+        assert!(check_key("j.doe@fnal.gov").is_ok());
+        assert!(check_key("before-study_2.1").is_ok());
+        assert!(check_key("").is_err());
+        assert!(check_key("../etc/passwd").is_err());
+        assert!(check_key(".hidden").is_err());
+        assert!(check_key("a/b").is_err());
+        assert!(check_key("a b").is_err());
+    }
+
+    async fn exercise(store: Documents) {
+        assert_eq!(store.load("a").await, Ok(None));
+
+        let saved = store.save("a", "{\"a\":1}".into()).await.unwrap();
+        let loaded = store.load("a").await.unwrap().unwrap();
+
+        assert_eq!(loaded.text, saved.text);
+        assert!(loaded.last_modified > 0.0);
+
+        store.save("a", "{\"a\":2}".into()).await.unwrap();
+        store.save("b", "not json".into()).await.unwrap();
+        assert_eq!(
+            store.load_json::<serde_json::Value>("a").await,
+            Ok(Some(serde_json::json!({ "a": 2 })))
+        );
+        assert!(store.load_json::<serde_json::Value>("b").await.is_err());
+        assert_eq!(
+            store.list_json::<serde_json::Value>().await,
+            Ok(vec![serde_json::json!({ "a": 2 })])
+        );
+        assert_eq!(store.list().await.map(|v| v.len()), Ok(2));
+        assert!(store.save("../a", "{}".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        exercise(Documents::new(Arc::new(MemoryStore::default()))).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir()
+            .join(format!("documents-{}", uuid::Uuid::new_v4()));
+
+        exercise(Documents::new(Arc::new(FileStore::new(&dir).unwrap()))).await;
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,276 @@
+// Builds triggered plots from archived data. Live triggered plots group
+// the devices' data by the occurrences of a clock event, as they're
+// reported by the clock service. For past time ranges, the occurrences
+// come from the archived readings of a logged event device: each
+// reading's timestamp is an occurrence of the event.
+//
+// The event device is configured with `CLOCK_EVENT_DEVICE`, a DRF in
+// which `{event}` is replaced by the event number in hex (e.g. `02`.)
+// Each bundle holds the data from one occurrence of the event up to the
+// next, with timestamps relative to the occurrence, just like the
+// replies of a live triggered plot.
+
+use super::{archive::ArchiveSources, global, types};
+use async_stream::stream;
+use futures::Stream;
+use futures_util::StreamExt;
+use rust_env_var_lib::env_var;
+use std::{collections::VecDeque, sync::LazyLock};
+
+const EVENT_DEVICE: &str = "CLOCK_EVENT_DEVICE";
+
+static EVENT_TEMPLATE: LazyLock<Option<String>> =
+    LazyLock::new(|| env_var::get(EVENT_DEVICE).to_option::<String>());
+
+// Returns the DRF of the device that logs an event.
+
+fn event_drf(template: &str, event: u8) -> String {
+    template.replace("{event}", &format!("{:02X}", event))
+}
+
+// Returns the sorted timestamps of the event readings in the range.
+// Only the times are kept as the readings arrive.
+
+async fn event_times<S>(
+    mut replies: S, start: f64, end: f64,
+) -> Result<Vec<f64>, String>
+where
+    S: Stream<Item = global::DataReply> + Unpin,
+{
+    let mut result = vec![];
+
+    while let Some(reply) = replies.next().await {
+        for info in reply.data {
+            match info.result {
+                global::DataType::StatusReply(ref status)
+                    if status.status < 0 =>
+                {
+                    return Err(format!(
+                        "couldn't retrieve the event history -- {}",
+                        status
+                    ));
+                }
+                global::DataType::StatusReply(_) => (),
+                _ if info.timestamp >= start && info.timestamp <= end => {
+                    result.push(info.timestamp)
+                }
+                _ => (),
+            }
+        }
+    }
+    result.sort_by(f64::total_cmp);
+    result.dedup();
+    Ok(result)
+}
+
+// Retrieves the times at which `event` occurred during the range.
+
+pub async fn event_history(
+    sources: &ArchiveSources, token: Option<String>, event: u8, start: f64,
+    end: f64,
+) -> Result<Vec<f64>, String> {
+    let template = EVENT_TEMPLATE.as_deref().ok_or_else(|| {
+        format!("archived triggered plots need the {} setting", EVENT_DEVICE)
+    })?;
+    let replies = sources
+        .fetch(token, &event_drf(template, event), start, end, None)
+        .await
+        .map_err(|e| e.message)?;
+
+    event_times(replies, start, end).await
+}
+
+// A device's archived data and its readings that haven't been placed
+// in a bundle.
+
+struct Channel<S> {
+    s: Option<S>,
+    queue: VecDeque<global::DataInfo>,
+}
+
+impl<S> Channel<S>
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    // Returns the device's next reading, reading more of its stream if
+    // needed.
+
+    async fn front(&mut self) -> Option<&global::DataInfo> {
+        while self.queue.is_empty() {
+            match self.s.as_mut()?.next().await {
+                Some(reply) => self.queue.extend(reply.data),
+                None => self.s = None,
+            }
+        }
+        self.queue.front()
+    }
+
+    // Moves the readings taken before `end` into `out`. Readings before
+    // `start` preceded the event so they're dropped. Statuses are
+    // reported in the channel's status.
+
+    async fn fill(
+        &mut self, out: &mut types::PlotChannelData, start: f64, end: f64,
+    ) {
+        loop {
+            let Some(info) = self.front().await else {
+                break;
+            };
+
+            if info.timestamp >= end
+                && !matches!(info.result, global::DataType::StatusReply(_))
+            {
+                break;
+            }
+
+            let mut info = self.queue.pop_front().unwrap();
+
+            match info.result {
+                global::DataType::StatusReply(ref status) => {
+                    out.channel_status = status.status;
+                    out.status_string = Some(status.to_string());
+                }
+                _ if info.timestamp < start => (),
+                _ => {
+                    info.timestamp -= start;
+                    out.channel_data.push(info);
+                }
+            }
+        }
+    }
+}
+
+// Returns a stream of plot replies, one per occurrence of the event
+// that has data. `template` is an empty reply for the request's
+// devices.
+
+pub fn bundles<S>(
+    streams: Vec<S>, events: Vec<f64>, template: types::PlotReplyData,
+) -> impl Stream<Item = types::PlotReplyData> + Send + 'static
+where
+    S: Stream<Item = global::DataReply> + Send + 'static + Unpin,
+{
+    stream! {
+        let mut channels: Vec<_> = streams
+            .into_iter()
+            .map(|s| Channel {
+                s: Some(s),
+                queue: VecDeque::new(),
+            })
+            .collect();
+
+        for (idx, start) in events.iter().enumerate() {
+            let end = events.get(idx + 1).copied().unwrap_or(f64::INFINITY);
+            let mut out = template.clone();
+
+            out.trigger_timestamp = Some(*start);
+            for (chan, data) in channels.iter_mut().zip(out.data.iter_mut()) {
+                chan.fill(data, *start, end).await;
+            }
+
+            if out
+                .data
+                .iter()
+                .any(|v| !v.channel_data.is_empty() || v.channel_status != 0)
+            {
+                yield out;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::datastream::testing::{info, reply};
+    use futures::stream;
+
+    fn template(n: usize) -> types::PlotReplyData {
+        types::PlotReplyData {
+            plot_id: "demo".into(),
+            timestamp: 0.0,
+            trigger_timestamp: None,
+            data: (0..n)
+                .map(|_| types::PlotChannelData {
+                    channel_rate: "Unknown".into(),
+                    channel_units: "V".into(),
+                    status_string: None,
+                    channel_status: 0,
+                    channel_data: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    fn times(reply: &types::PlotReplyData) -> Vec<Vec<f64>> {
+        reply
+            .data
+            .iter()
+            .map(|v| v.channel_data.iter().map(|v| v.timestamp).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_event_times() {
+        assert_eq!(event_drf("G:EV{event}", 2), "G:EV02");
+        assert_eq!(event_drf("G:EV{event}", 0xa9), "G:EVA9");
+        assert_eq!(
+            event_times(
+                stream::iter(vec![
+                    reply(vec![info(5.0), info(1.0), info(3.0)]),
+                    reply(vec![])
+                ]),
+                2.0,
+                10.0
+            )
+            .await,
+            Ok(vec![3.0, 5.0])
+        );
+        assert!(
+            event_times(
+                stream::iter(vec![reply(vec![global::DataInfo {
+                    timestamp: 0.0,
+                    result: global::DataType::StatusReply(
+                        global::StatusReply {
+                            status: -1,
+                            message: None,
+                        }
+                    ),
+                }])]),
+                0.0,
+                10.0
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bundles() {
+        let a = stream::iter(vec![
+            reply(vec![info(0.5), info(1.0), info(1.5)]),
+            reply(vec![info(2.25), info(5.5)]),
+        ]);
+        let b = stream::iter(vec![reply(vec![info(1.25), info(4.0)])]);
+        let result: Vec<_> =
+            bundles(vec![a, b], vec![1.0, 2.0, 3.0, 3.5, 5.0], template(2))
+                .collect()
+                .await;
+
+        // The event at 3.0 has no data so it isn't sent. Data before the
+        // first event is dropped.
+
+        assert_eq!(result.len(), 4);
+        assert_eq!(
+            result
+                .iter()
+                .map(|v| v.trigger_timestamp)
+                .collect::<Vec<_>>(),
+            vec![Some(1.0), Some(2.0), Some(3.5), Some(5.0)]
+        );
+        assert_eq!(times(&result[0]), vec![vec![0.0, 0.5], vec![0.25]]);
+        assert_eq!(times(&result[1]), vec![vec![0.25], vec![]]);
+        assert_eq!(times(&result[2]), vec![vec![], vec![0.5]]);
+        assert_eq!(times(&result[3]), vec![vec![0.5], vec![]]);
+    }
+}
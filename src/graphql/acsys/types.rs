@@ -56,3 +56,382 @@ impl PlotReplyData {
             + Duration::microseconds((self.timestamp * 1_000_000.0) as i64)
     }
 }
+
+#[doc = "Reports the state of a knobbing session. A reply is sent when the \
+	 session starts and after each setting is sent to the device."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct KnobReply {
+    #[doc = "The time the reply was generated, in seconds since 1970, UTC."]
+    pub timestamp: f64,
+    #[doc = "The setting that was sent to the device."]
+    pub setting: f64,
+    #[doc = "The setting property read back from the device after the \
+	     setting was applied. This will be `null` if the device couldn't \
+	     be read."]
+    pub readback: Option<f64>,
+    #[doc = "The number of knob steps that were combined into this setting. \
+	     Increments that arrive faster than the session's update rate \
+	     are coalesced."]
+    pub steps: i32,
+    #[doc = "The ACNET status returned by the setting. A value of 0 \
+	     indicates success."]
+    pub status: i16,
+}
+
+#[doc = "The decoded state of one entry of a device's basic status \
+	 definition."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct StatusBit {
+    #[doc = "The name of the status. For per-bit definitions, this is the \
+	     name associated with the bit's current state."]
+    pub name: String,
+    #[doc = "A longer description of the status."]
+    pub description: String,
+    #[doc = "For \"power supply\" style entries, this is `true` when the \
+	     masked status matches the expected value. For per-bit \
+	     definitions, it's the state of the bit."]
+    pub value: bool,
+    #[doc = "The string to display for the current state."]
+    pub text: String,
+    #[doc = "The color to use for the current state."]
+    pub color: u32,
+    #[doc = "The character to display for the current state. Per-bit \
+	     definitions don't provide a character so this will be `null` \
+	     for them."]
+    pub character: Option<String>,
+}
+
+#[doc = "A decoded basic status reading."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct BasicStatus {
+    #[doc = "Timestamp representing when the status was sampled. This value \
+	     is provided as seconds since 1970, UTC."]
+    pub timestamp: f64,
+    #[doc = "The raw status bits. This is `null` if the reading returned an \
+	     error status."]
+    pub raw_status: Option<u32>,
+    #[doc = "The decoded status, in the order of the DevDB definitions."]
+    pub bits: Vec<StatusBit>,
+    #[doc = "If the reading failed, this holds the ACNET status."]
+    pub status: Option<global::StatusReply>,
+}
+
+#[doc = "The decoded basic status readings of a device."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct BasicStatusReply {
+    #[doc = "The index of the device in the request."]
+    pub ref_id: i32,
+    pub data: Vec<BasicStatus>,
+}
+
+#[doc = "Describes one upstream live data request and the subscriptions \
+	 sharing it."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct LiveStreamStatistics {
+    #[doc = "The normalized DRF strings of the request."]
+    pub drfs: Vec<String>,
+    #[doc = "If `true`, the request was made with a user's credentials and \
+	     is only shared with requests made by the same account."]
+    pub scoped: bool,
+    #[doc = "The number of subscriptions currently receiving the data."]
+    pub subscribers: usize,
+    #[doc = "When the upstream request was started, in seconds since 1970, \
+	     UTC."]
+    pub started: f64,
+    #[doc = "The number of replies received from the upstream request."]
+    pub replies: u64,
+}
+
+#[doc = "Statistics of the sharing of live data requests."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct LiveDataStatistics {
+    #[doc = "The total number of upstream requests made to DPM since the \
+	     service started."]
+    pub upstream_requests: u64,
+    #[doc = "The total number of live data subscriptions since the service \
+	     started. The difference between this and `upstreamRequests` is \
+	     the number of requests that were saved by sharing."]
+    pub subscriptions: u64,
+    #[doc = "The active upstream requests."]
+    pub streams: Vec<LiveStreamStatistics>,
+}
+
+#[doc = "Selects how live data is reduced when a subscription limits its \
+	 data rate."]
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decimation {
+    #[doc = "Return the last reading of each interval."]
+    Latest,
+    #[doc = "Return the minimum and maximum readings of each interval, in \
+	     time order. Non-scalar devices return the last reading."]
+    Envelope,
+    #[doc = "Return the mean of each interval's readings, timestamped with \
+	     the last reading. Non-scalar devices return the last reading."]
+    Mean,
+}
+
+#[doc = "A user's saved plot configuration."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct UserConfig {
+    #[doc = "The configuration, as saved by the application."]
+    pub config: Arc<str>,
+    #[doc = "When the configuration was saved, in seconds since 1970, UTC."]
+    pub last_modified: f64,
+}
+
+#[doc = "The saved value of one device in a snapshot."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct SnapshotValue {
+    #[doc = "The DRF string used to read, and restore, the device."]
+    pub drf: String,
+    #[doc = "The value when the snapshot was taken. If the device couldn't \
+	     be read, this holds the status and the device won't be \
+	     restored."]
+    pub value: global::DataType,
+}
+
+#[doc = "A named set of device settings."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct DeviceSnapshot {
+    pub name: String,
+    #[doc = "The account that took the snapshot, if known."]
+    pub owner: Option<String>,
+    #[doc = "When the snapshot was taken, in seconds since 1970, UTC."]
+    pub created: f64,
+    pub values: Vec<SnapshotValue>,
+}
+
+#[doc = "Describes a device whose value differs between a snapshot and \
+	 the values it's compared against."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct SnapshotDifference {
+    pub drf: String,
+    #[doc = "The value in the snapshot. This is `null` if the device isn't \
+	     in the snapshot."]
+    pub saved: Option<global::DataType>,
+    #[doc = "The value being compared. This is `null` if the device isn't \
+	     in the other set of values."]
+    pub current: Option<global::DataType>,
+}
+
+#[doc = "The result of restoring one device of a snapshot."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct RestoreResult {
+    pub drf: String,
+    pub status: global::StatusReply,
+}
+
+#[doc = "The reductions that can be applied to archived data."]
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregationOperator {
+    #[doc = "The mean of each bin's samples."]
+    Mean,
+    #[doc = "The smallest sample of each bin."]
+    Min,
+    #[doc = "The largest sample of each bin."]
+    Max,
+    #[doc = "The first sample of each bin."]
+    First,
+    #[doc = "The last sample of each bin."]
+    Last,
+    #[doc = "The number of samples in each bin."]
+    Count,
+    #[doc = "The standard deviation of each bin's samples."]
+    Std,
+    #[doc = "The median of each bin's samples."]
+    Median,
+    #[doc = "Every nth sample. For this operator, `binSize` is the number \
+	     of samples, rather than seconds."]
+    Nth,
+}
+
+#[doc = "Describes how archived data is reduced. The timeline is divided \
+	 into bins of `binSize` seconds and each bin's samples are reduced \
+	 to one value, timestamped at the start of the bin. Only scalar \
+	 samples are reduced; other samples are returned as-is."]
+#[derive(InputObject, Clone, Copy, Debug, PartialEq)]
+pub struct Aggregation {
+    pub operator: AggregationOperator,
+    #[doc = "The width of the bins, in seconds. It's rounded to a whole \
+	     number of seconds and must be at least 1."]
+    pub bin_size: f64,
+}
+
+#[doc = "The counters of the cache that holds recently retrieved archived \
+	 data."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct ArchiveCacheStatistics {
+    #[doc = "The number of archive requests answered from the cache."]
+    pub hits: u64,
+    #[doc = "The number of cacheable archive requests that had to be \
+	     retrieved."]
+    pub misses: u64,
+    #[doc = "The number of cached ranges dropped to make room."]
+    pub evictions: u64,
+    #[doc = "The number of cached ranges."]
+    pub entries: u64,
+    #[doc = "The approximate memory, in bytes, used by the cached data."]
+    pub bytes: u64,
+    #[doc = "The most memory, in bytes, the cache may use. Zero means the \
+	     cache is disabled."]
+    pub capacity: u64,
+}
+
+#[doc = "Selects how a device's value is found for each row of aligned \
+	 archived data."]
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    #[doc = "Use the device's latest reading at, or before, the row's \
+	     time."]
+    Previous,
+    #[doc = "Interpolate between the readings on either side of the row's \
+	     time. Non-scalar devices use the previous reading. Rows outside \
+	     the device's data are `null`."]
+    Linear,
+    #[doc = "Only use a reading taken exactly at the row's time. This is \
+	     mostly useful with a master device whose timestamps are shared \
+	     by the other devices (e.g. devices logged on the same clock \
+	     event.)"]
+    None,
+}
+
+#[doc = "The values of the requested devices at one time."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct AlignedRow {
+    #[doc = "The time of the row, in seconds since 1970, UTC."]
+    pub timestamp: f64,
+    #[doc = "The value of each device, in the order of the request's DRFs. \
+	     An entry is `null` if the device has no value at this time."]
+    pub values: Vec<Option<global::DataType>>,
+}
+
+#[doc = "A status reported by one of the devices of an aligned request."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct AlignedStatus {
+    #[doc = "The index of the device in the request."]
+    pub ref_id: i32,
+    pub status: global::StatusReply,
+}
+
+#[doc = "A block of aligned archived data."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct AlignedData {
+    pub rows: Vec<AlignedRow>,
+    #[doc = "Statuses reported by the devices since the previous block. A \
+	     negative status ends the stream."]
+    pub statuses: Vec<AlignedStatus>,
+}
+
+#[doc = "Statistics of a device's scalar readings during a period. Other \
+	 types of readings aren't included."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct StatisticsBin {
+    #[doc = "The start of the period, in seconds since 1970, UTC."]
+    pub start: f64,
+    #[doc = "The end of the period, in seconds since 1970, UTC. Readings at \
+	     this time belong to the next period."]
+    pub end: f64,
+    #[doc = "The number of readings in the period."]
+    pub count: u64,
+    #[doc = "The smallest reading. This, and the other statistics, are \
+	     `null` if there were no readings."]
+    pub min: Option<f64>,
+    #[doc = "The largest reading."]
+    pub max: Option<f64>,
+    #[doc = "The mean of the readings."]
+    pub mean: Option<f64>,
+    #[doc = "The sample standard deviation of the readings. It's 0 if there \
+	     was only one reading."]
+    pub std_dev: Option<f64>,
+    #[doc = "The earliest reading."]
+    pub first: Option<global::DataInfo>,
+    #[doc = "The latest reading."]
+    pub last: Option<global::DataInfo>,
+}
+
+#[doc = "The statistics of one device of a `deviceStatistics` request."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct DeviceStatistics {
+    #[doc = "The index of the device in the request."]
+    pub ref_id: i32,
+    #[doc = "The statistics of each bin, in time order."]
+    pub bins: Vec<StatisticsBin>,
+    #[doc = "The last status reported while retrieving the device's data. \
+	     If it's negative, the device's data couldn't all be retrieved \
+	     and the statistics only cover the readings received before the \
+	     error."]
+    pub status: Option<global::StatusReply>,
+}
+
+#[doc = "The archived value of a device at an instant."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct InstantValue {
+    #[doc = "The index of the device in the request."]
+    pub ref_id: i32,
+    #[doc = "The last reading at, or before, the instant. This is `null` if \
+	     the device has no reading within the search window."]
+    pub before: Option<global::DataInfo>,
+    #[doc = "The first reading after the instant. This is only retrieved \
+	     when requested and is `null` if there's no reading within the \
+	     search window."]
+    pub after: Option<global::DataInfo>,
+    #[doc = "Explains a missing reading. A negative status means the \
+	     archive couldn't be searched. A reading that wasn't found \
+	     within the search window is reported with a positive status \
+	     whose `message` gives the window."]
+    pub status: Option<global::StatusReply>,
+}
+
+#[doc = "A page of a device's archived data."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct ArchivedDataPage {
+    #[doc = "The readings, in time order."]
+    pub data: Vec<global::DataInfo>,
+    #[doc = "Pass this as `after` to retrieve the next page. It's `null` \
+	     when this is the last page."]
+    pub next_cursor: Option<String>,
+    #[doc = "The archive the data came from: `EPICS`, `LOGGER` or \
+	     `LOGGER:node`. Later pages use the same archive."]
+    pub source: String,
+    #[doc = "A warning about the data (e.g. that it came from the data \
+	     loggers because the EPICS archiver couldn't supply it.)"]
+    pub status: Option<global::StatusReply>,
+}
+
+#[doc = "Describes how an EPICS PV is archived. Fields the archiver \
+	 doesn't report are `null`."]
+#[derive(SimpleObject, Clone, Debug, Default, PartialEq)]
+pub struct PvMetadata {
+    #[doc = "The name of the PV."]
+    pub name: String,
+    #[doc = "`true` if an archiver appliance holds the PV."]
+    pub archived: bool,
+    #[doc = "The archiving status reported by the appliance (e.g. \
+	     \"Being archived\" or \"Paused\".)"]
+    pub status: String,
+    #[doc = "The identity of the appliance holding the PV."]
+    pub appliance: Option<String>,
+    #[doc = "The engineering units of the PV (its `EGU` field.)"]
+    pub units: Option<String>,
+    #[doc = "The number of digits after the decimal point (the PV's \
+	     `PREC` field.)"]
+    pub precision: Option<i32>,
+    #[doc = "How the PV is sampled: `MONITOR` or `SCAN`."]
+    pub sampling_method: Option<String>,
+    #[doc = "The sampling period, in seconds."]
+    pub sampling_period: Option<f64>,
+    #[doc = "The EPICS type of the PV's values (e.g. \
+	     `DBR_SCALAR_DOUBLE`.)"]
+    pub data_type: Option<String>,
+    #[doc = "The number of elements in each sample."]
+    pub element_count: Option<i32>,
+    #[doc = "When the PV started being archived, in seconds since 1970, \
+	     UTC."]
+    pub archived_since: Option<f64>,
+    #[doc = "The time of the first archived sample, in seconds since 1970, \
+	     UTC. It's searched for in the week after archiving started."]
+    pub first_sample: Option<f64>,
+    #[doc = "The time of the most recent archived sample, in seconds since \
+	     1970, UTC. It's searched for in the last week."]
+    pub last_sample: Option<f64>,
+}
@@ -0,0 +1,73 @@
+// Stores each user's default plot configuration. The configurations
+// are documents, keyed by account name, in the store described by
+// `USER_CONFIG_DIR` (see `store::Documents::from_env`.)
+
+use super::store::{Document, Documents};
+use std::sync::Arc;
+
+const CONFIG_DIR: &str = "USER_CONFIG_DIR";
+
+// A configuration and when it was last saved (in seconds since 1970,
+// UTC.)
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredConfig {
+    pub config: Arc<str>,
+    pub last_modified: f64,
+}
+
+impl From<Document> for StoredConfig {
+    fn from(doc: Document) -> Self {
+        StoredConfig {
+            config: doc.text,
+            last_modified: doc.last_modified,
+        }
+    }
+}
+
+// The configuration store used by the ACSys schema.
+
+#[derive(Clone)]
+pub struct UserConfigs(Documents);
+
+impl UserConfigs {
+    pub fn new(docs: Documents) -> Self {
+        UserConfigs(docs)
+    }
+
+    pub fn from_env() -> Self {
+        UserConfigs::new(Documents::from_env(CONFIG_DIR, "user configurations"))
+    }
+
+    pub async fn load(
+        &self, account: &str,
+    ) -> Result<Option<StoredConfig>, String> {
+        Ok(self.0.load(account).await?.map(StoredConfig::from))
+    }
+
+    pub async fn save(
+        &self, account: &str, config: Arc<str>,
+    ) -> Result<StoredConfig, String> {
+        self.0.save(account, config).await.map(StoredConfig::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphql::acsys::store::MemoryStore;
+
+    #[tokio::test]
+    async fn test_user_configs() {
+        let store =
+            UserConfigs::new(Documents::new(Arc::new(MemoryStore::default())));
+
+        assert_eq!(store.load("jdoe").await, Ok(None));
+
+        let saved = store.save("jdoe", "{\"a\":1}".into()).await.unwrap();
+
+        assert_eq!(store.load("jdoe").await, Ok(Some(saved)));
+        assert_eq!(store.load("other").await, Ok(None));
+        assert!(store.save("../jdoe", "{}".into()).await.is_err());
+    }
+}
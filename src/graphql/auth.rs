@@ -0,0 +1,167 @@
+// Verifies the bearer tokens that accompany requests. `AuthInfo` only
+// decodes a token, which anyone can forge. Before an account is
+// trusted (e.g. to own a knob session or a saved configuration), the
+// token is sent to KeyCloak's OIDC `userinfo` endpoint, which only
+// answers if the token's signature is valid and it hasn't expired.
+//
+// Verified accounts are remembered for a minute so a burst of requests
+// doesn't reach KeyCloak each time.
+
+use rust_env_var_lib::env_var;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+const USERINFO_URL: &str = "AUTH_USERINFO_URL";
+
+// How long a verified account is remembered and how long KeyCloak has
+// to answer.
+
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The most tokens remembered. When full, expired entries are removed;
+// if that doesn't make room, the account isn't remembered.
+
+const MAX_ENTRIES: usize = 10_000;
+
+static URL: LazyLock<Option<String>> =
+    LazyLock::new(|| env_var::get(USERINFO_URL).to_option::<String>());
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+static VERIFIED: LazyLock<Mutex<HashMap<String, (Instant, String)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize)]
+struct UserInfo {
+    preferred_username: Option<String>,
+}
+
+fn remembered(token: &str) -> Option<String> {
+    VERIFIED
+        .lock()
+        .unwrap()
+        .get(token)
+        .filter(|(expires, _)| *expires > Instant::now())
+        .map(|(_, account)| account.clone())
+}
+
+fn remember(token: &str, account: &str) {
+    let mut verified = VERIFIED.lock().unwrap();
+    let now = Instant::now();
+
+    if verified.len() >= MAX_ENTRIES {
+        verified.retain(|_, (expires, _)| *expires > now);
+    }
+    if verified.len() < MAX_ENTRIES {
+        verified.insert(token.into(), (now + VERIFIED_TTL, account.into()));
+    }
+}
+
+// Asks the `userinfo` endpoint at `url` who owns a token.
+
+async fn verify_with(url: &str, token: &str) -> Result<String, String> {
+    if let Some(account) = remembered(token) {
+        return Ok(account);
+    }
+
+    let reply = CLIENT
+        .get(url)
+        .bearer_auth(token)
+        .timeout(VERIFY_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| {
+            warn!("couldn't reach {} -- {}", url, e);
+            String::from("couldn't reach the authentication service")
+        })?;
+
+    if !reply.status().is_success() {
+        return Err("unable to verify user credentials".into());
+    }
+
+    let account = reply
+        .json::<UserInfo>()
+        .await
+        .map_err(|e| {
+            format!("bad reply from the authentication service: {}", e)
+        })?
+        .preferred_username
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| String::from("the credentials don't name an account"))?;
+
+    remember(token, &account);
+    Ok(account)
+}
+
+// Returns the account that owns a token, once KeyCloak has confirmed
+// the token is genuine. If `AUTH_USERINFO_URL` isn't set, no token can
+// be verified.
+
+pub async fn verify(token: &str) -> Result<String, String> {
+    match URL.as_deref() {
+        Some(url) => verify_with(url, token).await,
+        None => {
+            warn!("{} isn't set : can't verify credentials", USERINFO_URL);
+            Err("unable to verify user credentials".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{
+        Json, Router,
+        http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+        response::IntoResponse,
+        routing::get,
+    };
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    #[tokio::test]
+    async fn test_verify() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/userinfo", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/userinfo",
+            get(move |headers: HeaderMap| async move {
+                count.fetch_add(1, Ordering::Relaxed);
+                match headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+                    Some("Bearer genuine") => Json(serde_json::json!({
+                        "preferred_username": "alice"
+                    }))
+                    .into_response(),
+                    Some("Bearer anonymous") => {
+                        Json(serde_json::json!({})).into_response()
+                    }
+                    _ => StatusCode::UNAUTHORIZED.into_response(),
+                }
+            }),
+        );
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        assert_eq!(verify_with(&url, "genuine").await, Ok("alice".into()));
+        assert!(verify_with(&url, "forged").await.is_err());
+        assert!(verify_with(&url, "anonymous").await.is_err());
+
+        // The verified account is remembered.
+
+        let n = requests.load(Ordering::Relaxed);
+
+        assert_eq!(verify_with(&url, "genuine").await, Ok("alice".into()));
+        assert_eq!(requests.load(Ordering::Relaxed), n);
+    }
+}
@@ -16,6 +16,7 @@ use super::types as global;
 
 // Pull in our local types.
 
+pub mod cache;
 pub mod types;
 
 // Converts a `DigitalControlItem`, from the gRPC API, into a
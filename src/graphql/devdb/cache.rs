@@ -0,0 +1,74 @@
+// Maintains a short-lived cache of device information. Resolvers that
+// need DevDB information while processing another request (e.g.
+// validating a setting) use this cache so that every request doesn't
+// result in a round trip to the DevDB service.
+
+use super::{to_info_result, types};
+use crate::g_rpc::devdb;
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+// How long an entry remains valid. Device configurations rarely
+// change, but when they do we don't want to hold on to stale limits
+// for very long.
+
+const ENTRY_LIFETIME: Duration = Duration::from_secs(300);
+
+type Entry = (Instant, Arc<types::DeviceInfo>);
+
+static CACHE: LazyLock<Mutex<HashMap<String, Entry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn lookup(device: &str) -> Option<Arc<types::DeviceInfo>> {
+    let mut cache = CACHE.lock().unwrap();
+
+    match cache.get(device) {
+        Some((stamp, info)) if stamp.elapsed() < ENTRY_LIFETIME => {
+            Some(info.clone())
+        }
+        Some(_) => {
+            cache.remove(device);
+            None
+        }
+        None => None,
+    }
+}
+
+// Returns the device information for a single device. The device name
+// should be in the form DevDB expects (e.g. "M:OUTTMP".) If DevDB
+// reports an error for the device, the error message is returned.
+// Errors are not cached.
+
+pub async fn device_info(
+    device: &str,
+) -> Result<Arc<types::DeviceInfo>, String> {
+    if let Some(info) = lookup(device) {
+        return Ok(info);
+    }
+
+    let reply = devdb::get_device_info(&[device.to_owned()])
+        .await
+        .map_err(|e| e.message().to_owned())?
+        .into_inner();
+
+    match reply.set.first().map(to_info_result) {
+        Some(types::DeviceInfoResult::DeviceInfo(info)) => {
+            let info = Arc::new(info);
+
+            info!("caching device info for {}", device);
+            CACHE
+                .lock()
+                .unwrap()
+                .insert(device.to_owned(), (Instant::now(), info.clone()));
+            Ok(info)
+        }
+        Some(types::DeviceInfoResult::ErrorReply(global_err)) => {
+            Err(global_err.message)
+        }
+        None => Err(format!("DevDB returned no information for {}", device)),
+    }
+}
@@ -77,7 +77,7 @@ pub struct ReadingProp {
 }
 
 #[doc = "Holds information about \"knobbing\" a device's setting value."]
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
 pub struct KnobInfo {
     #[doc = "The minimum value of the device. When knobbing, the setting \
 	     shouldn't go lower than this value."]
@@ -158,11 +158,11 @@ pub struct SettingProp {
     pub is_knobbable: bool,
 }
 
-#[ComplexObject]
 impl SettingProp {
-    #[doc = "If the device has associated \"knobbing\" information, this \
-	     field will specify the configuration."]
-    async fn knob_info(&self) -> Option<KnobInfo> {
+    // Returns the knobbing configuration of the property, if the device
+    // can be knobbed.
+
+    pub fn knob_config(&self) -> Option<KnobInfo> {
         if self.is_knobbable {
             if self.common_index == 40 && self.coeff.len() >= 6 {
                 Some(KnobInfo::new(self.coeff[3], self.coeff[4], self.coeff[5]))
@@ -180,12 +180,21 @@ impl SettingProp {
     }
 }
 
+#[ComplexObject]
+impl SettingProp {
+    #[doc = "If the device has associated \"knobbing\" information, this \
+	     field will specify the configuration."]
+    async fn knob_info(&self) -> Option<KnobInfo> {
+        self.knob_config()
+    }
+}
+
 #[doc = "Represents a legacy form to describe a basic status bit.
 
 The BASIC STATUS property of a device traditionally modeled a power supply's \
 set of status bits (on/off, ready/tripped, etc.) This structure models the \
 data associated with each of these statuses and allows them to be renamed."]
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Clone)]
 pub struct DigStatusEntry {
     #[doc = "This value is logically ANDed with the active, raw status to \
 	     filter the bit that aren't related to the current status."]
@@ -225,7 +234,7 @@ pub struct DigStatusEntry {
 }
 
 #[doc = "Represents a more modern way to define the bits in the basic status."]
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Clone)]
 pub struct DigExtStatusEntry {
     #[doc = "Indicates with which bit in the status this entry corresponds. \
 	     The LSB is 0."]
@@ -251,7 +260,7 @@ pub struct DigExtStatusEntry {
 
 This structure contains both the legacy and modern forms of configurations \
 used to describe a device's basic status property."]
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Clone)]
 pub struct DigStatus {
     #[doc = "Holds the legacy, \"power supply\" configuration."]
     pub entries: Vec<DigStatusEntry>,
@@ -0,0 +1,247 @@
+// Maps ACNET status codes to their facility names, error mnemonics
+// and descriptions. The table is built from a data file compiled into
+// the service. Sites can add entries, or replace existing ones, by
+// pointing the `ACNET_STATUS_FILE` environment variable at a file with
+// the same format.
+
+use rust_env_var_lib::env_var;
+use std::{collections::HashMap, sync::LazyLock};
+use tracing::{info, warn};
+
+const STATUS_FILE: &str = "ACNET_STATUS_FILE";
+
+// The status used when a device doesn't reply in time.
+
+pub const ACNET_REQTMO: i16 = 1 - 6 * 256;
+
+// The status sent when a client falls so far behind a stream of data
+// that replies had to be discarded.
+
+pub const ACNET_QUEFULL: i16 = 1 - 7 * 256;
+
+// The facility code of the statuses this service generates itself.
+// They're described in the built-in table with the facility name
+// "EXTAPI".
+
+const EXTAPI: i16 = 250;
+
+// The warning sent when archived data had to come from a different
+// archive than the one the device's name implies. The accompanying
+// message carries the explanation.
+
+pub const EXTAPI_FALLBACK: i16 = EXTAPI + 256;
+
+// The warning sent when an archive has no data for a device near the
+// requested time. Like `EXTAPI_FALLBACK`, its message carries the
+// details.
+
+pub const EXTAPI_NO_DATA: i16 = EXTAPI + 2 * 256;
+
+// The status codes that are built into the service.
+
+const DEFAULT_TABLE: &str = include_str!("status/acnet.csv");
+
+// The information associated with a single status code.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub description: String,
+}
+
+// A table of facilities and the status codes they generate.
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    facilities: HashMap<u8, String>,
+    errors: HashMap<(u8, i8), Entry>,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| {
+    let mut registry = Registry::default();
+
+    registry
+        .load(DEFAULT_TABLE)
+        .expect("built-in ACNET status table is invalid");
+
+    if let Some(path) = env_var::get(STATUS_FILE).to_option::<String>() {
+        match std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| registry.load(&text))
+        {
+            Ok(()) => info!("loaded ACNET status codes from {}", &path),
+            Err(e) => {
+                warn!("couldn't load ACNET status codes from {}: {}", &path, e)
+            }
+        }
+    }
+    registry
+});
+
+// Splits a status value into its facility code and error code.
+
+pub fn split(status: i16) -> (u8, i8) {
+    ((status & 0xff) as u8, (status >> 8) as i8)
+}
+
+impl Registry {
+    // Adds the entries in `text` to the registry. Each non-blank line,
+    // that isn't a comment, holds five comma-separated fields: facility
+    // code, facility name, error code, mnemonic and description. The
+    // description is the remainder of the line, so it may contain
+    // commas. If any line is malformed, the registry is left unchanged.
+
+    pub fn load(&mut self, text: &str) -> Result<(), String> {
+        let mut facilities = vec![];
+        let mut errors = vec![];
+
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.splitn(5, ',').map(str::trim).collect();
+
+            let [fac, fac_name, err, name, desc] = fields[..] else {
+                return Err(format!("line {}: expected 5 fields", idx + 1));
+            };
+            let fac = fac.parse::<u8>().map_err(|e| {
+                format!("line {}: bad facility code: {}", idx + 1, e)
+            })?;
+            let err = err.parse::<i8>().map_err(|e| {
+                format!("line {}: bad error code: {}", idx + 1, e)
+            })?;
+
+            facilities.push((fac, fac_name.to_owned()));
+            errors.push((
+                (fac, err),
+                Entry {
+                    name: name.to_owned(),
+                    description: desc.to_owned(),
+                },
+            ));
+        }
+
+        self.facilities.extend(facilities);
+        self.errors.extend(errors);
+        Ok(())
+    }
+
+    // Returns the name of a facility.
+
+    pub fn facility_name(&self, status: i16) -> Option<&str> {
+        self.facilities.get(&split(status).0).map(String::as_str)
+    }
+
+    // Returns the entry for a status code.
+
+    pub fn lookup(&self, status: i16) -> Option<&Entry> {
+        self.errors.get(&split(status))
+    }
+
+    // Returns a human-readable form of a status code, suitable for log
+    // and error messages (e.g. "ACNET_REQTMO [1 -6] (request timed
+    // out)".) Unknown codes are shown as "[facility error]".
+
+    pub fn describe(&self, status: i16) -> String {
+        let (fac, err) = split(status);
+
+        match self.lookup(status) {
+            Some(Entry { name, description }) => {
+                format!("{} [{} {}] ({})", name, fac, err, description)
+            }
+            None => format!("[{} {}]", fac, err),
+        }
+    }
+}
+
+// Returns the registry used by the service.
+
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(fac: u8, err: i8) -> i16 {
+        fac as i16 + err as i16 * 256
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(0), (0, 0));
+        assert_eq!(split(status(1, -6)), (1, -6));
+        assert_eq!(split(status(17, 1)), (17, 1));
+        assert_eq!(split(status(255, -128)), (255, -128));
+    }
+
+    #[test]
+    fn test_constants() {
+        assert_eq!(ACNET_REQTMO, status(1, -6));
+        assert_eq!(ACNET_QUEFULL, status(1, -7));
+        assert_eq!(EXTAPI_FALLBACK, status(250, 1));
+        assert_eq!(EXTAPI_NO_DATA, status(250, 2));
+    }
+
+    #[test]
+    fn test_default_table() {
+        let mut reg = Registry::default();
+
+        assert!(reg.load(DEFAULT_TABLE).is_ok());
+        assert_eq!(reg.facility_name(status(1, -6)), Some("ACNET"));
+        assert_eq!(
+            reg.lookup(status(1, -6)).map(|e| e.name.as_str()),
+            Some("ACNET_REQTMO")
+        );
+        assert_eq!(
+            reg.describe(status(1, -6)),
+            "ACNET_REQTMO [1 -6] (request timed out)"
+        );
+        assert_eq!(reg.facility_name(status(17, 1)), Some("DPM"));
+        assert_eq!(
+            reg.lookup(status(17, -17)).map(|e| e.name.as_str()),
+            Some("DPM_PRIV")
+        );
+        assert_eq!(reg.facility_name(EXTAPI_FALLBACK), Some("EXTAPI"));
+        assert_eq!(
+            reg.lookup(EXTAPI_NO_DATA).map(|e| e.name.as_str()),
+            Some("EXTAPI_NO_DATA")
+        );
+        assert_eq!(reg.facility_name(status(99, -1)), None);
+        assert_eq!(reg.describe(status(99, -1)), "[99 -1]");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut reg = Registry::default();
+
+        assert!(reg.load(DEFAULT_TABLE).is_ok());
+        assert!(
+            reg.load(
+                "# comment\n\n\
+                 1,ACNET,-6,ACNET_REQTMO,timeout, retry\n\
+                 17,DPM,1,DPM_PEND,pending\n"
+            )
+            .is_ok()
+        );
+        assert_eq!(
+            reg.lookup(status(1, -6)).map(|e| e.description.as_str()),
+            Some("timeout, retry")
+        );
+        assert_eq!(reg.facility_name(status(17, 1)), Some("DPM"));
+    }
+
+    #[test]
+    fn test_malformed_table() {
+        let mut reg = Registry::default();
+
+        assert!(reg.load("1,ACNET,-6,ACNET_REQTMO").is_err());
+        assert!(reg.load("1,ACNET,x,ACNET_REQTMO,timeout").is_err());
+        assert!(reg.load("1,ACNET,-6,A,b\n300,X,0,X,y").is_err());
+        assert_eq!(reg.lookup(status(1, -6)), None);
+    }
+}
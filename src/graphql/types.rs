@@ -1,8 +1,9 @@
 use crate::g_rpc::proto::common::device;
 use async_graphql::{ComplexObject, InputObject, SimpleObject, Union};
-use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
 use chrono::{DateTime, Duration, Utc};
-use serde_json::{self, Value};
+use std::fmt;
+
+use super::status;
 
 #[derive(Debug)]
 pub struct AuthInfo {
@@ -26,17 +27,15 @@ impl AuthInfo {
         self.bearer_token.clone()
     }
 
-    pub fn unsafe_account(&self) -> Option<String> {
-        self.bearer_token.as_deref().and_then(|token| {
-            let body = token.split('.').nth(1)?;
-            let json = STANDARD_NO_PAD.decode(body).ok()?;
-            let result: Value = serde_json::from_slice(&json).ok()?;
-
-            result
-                .get("preferred_username")
-                .and_then(Value::as_str)
-                .map(String::from)
-        })
+    // Returns the account that owns the token, after the authentication
+    // service has confirmed the token is genuine. The token's own claims
+    // are never trusted.
+
+    pub async fn account(&self) -> Result<String, String> {
+        match self.bearer_token.as_deref() {
+            Some(token) => super::auth::verify(token).await,
+            None => Err("no user credentials provided".into()),
+        }
     }
 }
 
@@ -51,8 +50,59 @@ pub struct ErrorReply {
 	 returns these status codes, but they may go away in the future \
 	 since EPICS has its own set of error codes."]
 #[derive(SimpleObject, Clone, Debug, PartialEq)]
+#[graphql(complex)]
 pub struct StatusReply {
     pub status: i16,
+    #[doc = "Explains the status, when the service has more to say than \
+	     the status code's description (e.g. why archived data came \
+	     from a different archive than expected.)"]
+    pub message: Option<String>,
+}
+
+#[ComplexObject]
+impl StatusReply {
+    #[doc = "The facility that generated the status (the low byte of \
+	     `status`.)"]
+    pub async fn facility_code(&self) -> i32 {
+        status::split(self.status).0 as i32
+    }
+
+    #[doc = "The facility-specific error code (the high byte of `status`.) \
+	     Negative values are errors, positive values are warnings and \
+	     zero indicates success."]
+    pub async fn error_code(&self) -> i32 {
+        status::split(self.status).1 as i32
+    }
+
+    #[doc = "The name of the facility, if it's known."]
+    pub async fn facility_name(&self) -> Option<&str> {
+        status::registry().facility_name(self.status)
+    }
+
+    #[doc = "The mnemonic of the status code (e.g. `ACNET_REQTMO`), if it's \
+	     known."]
+    pub async fn error_name(&self) -> Option<&str> {
+        status::registry()
+            .lookup(self.status)
+            .map(|e| e.name.as_str())
+    }
+
+    #[doc = "A short description of the status code, if it's known."]
+    pub async fn description(&self) -> Option<&str> {
+        status::registry()
+            .lookup(self.status)
+            .map(|e| e.description.as_str())
+    }
+}
+
+impl fmt::Display for StatusReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&status::registry().describe(self.status))?;
+        match self.message {
+            Some(ref msg) => write!(f, " -- {}", msg),
+            None => Ok(()),
+        }
+    }
 }
 
 #[doc = "Represents a simple, floating point value."]
@@ -85,6 +135,15 @@ pub struct TextArray {
     pub text_array_value: Vec<String>,
 }
 
+#[doc = "Represents the value of an enumerated device, such as an EPICS \
+	 `mbbi` or `bi` record. `enumValue` is the index of the state and \
+	 `label` is its name, if it's known."]
+#[derive(SimpleObject, Clone, Debug, PartialEq)]
+pub struct EnumValue {
+    pub enum_value: i32,
+    pub label: Option<String>,
+}
+
 #[doc = "Represents a generic return type. EPICS devices have a hierarchy \
 	 and this return type can model those values. Note that the value \
 	 associated with the key can be another `StructData`, so arbitrarily \
@@ -122,6 +181,9 @@ pub enum DataType {
     #[doc = "Used for devices that return arrays of strings."]
     TextArray(TextArray),
 
+    #[doc = "Used for devices whose value is one of a set of named states."]
+    EnumValue(EnumValue),
+
     #[doc = "Represents structured data. The value is a map type where the \
 	     key is a string that represents a field name and the value is \
 	     one of the values of this enumeration. This means you can nest \